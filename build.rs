@@ -0,0 +1,23 @@
+use std::process::Command;
+
+/// Records the commit sup-rs is built from for `--doctor`, when built from
+/// a git checkout.
+fn main() {
+    // A missing path would rerun the script on every build, as in a
+    // crates.io tarball, which has no .git
+    println!("cargo:rerun-if-changed=build.rs");
+    for path in [".git/HEAD", ".git/refs/heads"] {
+        if std::path::Path::new(path).exists() {
+            println!("cargo:rerun-if-changed={}", path);
+        }
+    }
+    let hash = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string());
+    if let Some(hash) = hash {
+        println!("cargo:rustc-env=SUP_GIT_HASH={}", hash);
+    }
+}
@@ -0,0 +1,81 @@
+use std::collections::BTreeSet;
+use std::sync::{Mutex, MutexGuard};
+use tokio::process::{Child, Command};
+use tracing::{debug, warn};
+
+/// Pids of spawned local processes (ssh, docker, local commands) that have
+/// not been waited for yet.
+static RUNNING: Mutex<BTreeSet<u32>> = Mutex::new(BTreeSet::new());
+
+fn running() -> MutexGuard<'static, BTreeSet<u32>> {
+    RUNNING.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Spawns `command` and tracks the process until `reaped` is called.
+pub fn spawn(command: &mut Command) -> std::io::Result<Child> {
+    let child = command.spawn()?;
+    running().extend(child.id());
+    Ok(child)
+}
+
+/// Stops tracking a process that was waited for.
+pub fn reaped(pid: Option<u32>) {
+    if let Some(pid) = pid {
+        running().remove(&pid);
+    }
+}
+
+/// Kills and reaps the tracked processes nobody waited for, e.g. sessions
+/// abandoned when a run was interrupted, so none outlives sup. Returns the
+/// pids that were still running and had to be killed.
+pub fn reap_all() -> Vec<u32> {
+    let pids = std::mem::take(&mut *running());
+    let mut killed = Vec::new();
+    for pid in pids {
+        match reap(pid) {
+            Reaped::Killed => {
+                warn!("Killed child process {} that was still running at exit", pid);
+                killed.push(pid);
+            }
+            Reaped::Exited => debug!("Reaped child process {}", pid),
+            Reaped::Gone => {}
+        }
+    }
+    killed
+}
+
+enum Reaped {
+    Killed,
+    /// It had exited but was never waited for
+    Exited,
+    /// It is not a child of this process any more
+    Gone,
+}
+
+#[cfg(unix)]
+fn reap(pid: u32) -> Reaped {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return Reaped::Gone;
+    };
+    let mut status = 0;
+    // SAFETY: waitpid and kill only take plain integers and a status
+    // pointer that outlives the calls; a pid that is no longer our child
+    // makes waitpid fail before kill is ever reached
+    unsafe {
+        match libc::waitpid(pid, &mut status, libc::WNOHANG) {
+            0 => {
+                libc::kill(pid, libc::SIGKILL);
+                libc::waitpid(pid, &mut status, 0);
+                Reaped::Killed
+            }
+            reaped if reaped == pid => Reaped::Exited,
+            _ => Reaped::Gone,
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn reap(_pid: u32) -> Reaped {
+    // Children are killed on drop; there are no zombies to collect
+    Reaped::Gone
+}
@@ -0,0 +1,11 @@
+use chrono::{DateTime, Local, TimeZone};
+
+/// The current time, or the time in `SUP_FAKE_NOW` (seconds since the
+/// Unix epoch) when it is set, so two runs with the same inputs record the
+/// same `SUP_TIME` and report timestamps.
+pub fn now() -> DateTime<Local> {
+    std::env::var("SUP_FAKE_NOW").ok()
+        .and_then(|seconds| seconds.trim().parse::<i64>().ok())
+        .and_then(|seconds| Local.timestamp_opt(seconds, 0).single())
+        .unwrap_or_else(Local::now)
+}
@@ -1,39 +1,753 @@
 use anyhow::{Context, Result};
+use indexmap::IndexMap;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::path::Path;
+use crate::output::NetworkStyle;
+use crate::shell;
+use std::time::Duration;
+
+/// Env variables in declaration order, so later values can build on
+/// earlier ones.
+pub type EnvMap = IndexMap<String, String>;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Supfile {
     pub version: String,
-    #[serde(default)]
-    pub env: Option<HashMap<String, String>>,
+    #[serde(default, deserialize_with = "scalar_values::deserialize")]
+    pub env: Option<EnvMap>,
     pub networks: HashMap<String, Network>,
     pub commands: HashMap<String, Command>,
     #[serde(default)]
-    pub targets: HashMap<String, Vec<String>>,
+    pub targets: HashMap<String, Target>,
+    /// Program used instead of `ssh`, e.g. `/opt/homebrew/bin/ssh` or `tsh ssh`
+    #[serde(default)]
+    pub ssh_binary: Option<String>,
+    /// Arguments appended to every ssh invocation
+    #[serde(default)]
+    pub ssh_options: Vec<String>,
+    /// User for hosts whose entry has no `user@`, unless their network sets one
+    #[serde(default)]
+    pub user: Option<String>,
+    /// Start the env from sup's own environment (inherited unless set to
+    /// false, or with `--no-env-inherit`)
+    #[serde(default)]
+    pub inherit_env: Option<bool>,
+    /// Variables still taken from sup's environment when inheritance is off
+    #[serde(default)]
+    pub inherit_env_only: Vec<String>,
+    /// Names of env variables whose values are masked in all output
+    #[serde(default)]
+    pub secrets: Vec<String>,
+    /// Regexes of env variable names whose values are redacted from the
+    /// commands shown in debug logs
+    #[serde(default = "default_redact")]
+    pub redact: Vec<String>,
+    /// Network used when none is given on the command line
+    #[serde(default)]
+    pub default_network: Option<String>,
+    /// Path of the run history log
+    #[serde(default)]
+    pub history: Option<String>,
+    /// Local commands run when the run ends, e.g. to post to chat
+    #[serde(default)]
+    pub notify: Vec<Notification>,
+    /// Pinned host key fingerprints by ssh host (`host` or `host:port`);
+    /// once any are set, every host must match its pin
+    #[serde(default)]
+    pub host_keys: BTreeMap<String, String>,
+    /// Command names by alias, filled in when the Supfile is loaded
+    #[serde(skip)]
+    pub aliases: HashMap<String, String>,
+}
+
+/// Run outcome a notification is sent for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NotifyOn {
+    Success,
+    Failure,
+}
+
+/// Local command run at the end of a run, with the outcome in `SUP_*` env
+/// variables.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Notification {
+    /// Outcomes to notify on; both when omitted
+    #[serde(default = "all_outcomes")]
+    pub on: Vec<NotifyOn>,
+    pub command: String,
+    /// Kill the command after this long; defaults to 30s
+    #[serde(default, with = "duration_str", skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<Duration>,
+}
+
+/// Env variables whose names look like credentials.
+pub const DEFAULT_REDACT: &str = "(?i)(pass|token|secret|key)";
+
+fn default_redact() -> Vec<String> {
+    vec![DEFAULT_REDACT.to_string()]
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn all_outcomes() -> Vec<NotifyOn> {
+    vec![NotifyOn::Success, NotifyOn::Failure]
 }
 
 impl Supfile {
     pub fn from_file(path: &Path) -> Result<Self> {
         let contents = std::fs::read_to_string(path)
             .context("Failed to read Supfile")?;
-        serde_yaml::from_str(&contents)
-            .context("Failed to parse Supfile")
+        let mut supfile: Self = serde_yaml::from_str(&contents)
+            .context("Failed to parse Supfile")?;
+        supfile.validate()?;
+        supfile.aliases = supfile.alias_map()?;
+        Ok(supfile)
+    }
+
+    /// Command names by the aliases of the commands. An alias may not be
+    /// empty or also name a command, a target or another alias.
+    pub fn alias_map(&self) -> Result<HashMap<String, String>> {
+        let mut names = self.commands.keys().collect::<Vec<_>>();
+        names.sort();
+        let mut aliases = HashMap::new();
+        for name in names {
+            for alias in self.commands[name].aliases.iter().flatten() {
+                if alias.trim().is_empty() {
+                    anyhow::bail!("Command {} has an empty alias", name);
+                }
+                if self.commands.contains_key(alias) {
+                    anyhow::bail!("Alias {} of command {} is also the name of a command", alias, name);
+                }
+                if self.targets.contains_key(alias) {
+                    anyhow::bail!("Alias {} of command {} is also the name of a target", alias, name);
+                }
+                if let Some(other) = aliases.insert(alias.clone(), name.clone()) {
+                    anyhow::bail!("Alias {} is given to both command {} and command {}", alias, other, name);
+                }
+            }
+        }
+        Ok(aliases)
+    }
+
+    /// The command `name` stands for, itself or by alias, with the name it
+    /// is defined under.
+    pub fn find_command(&self, name: &str) -> Option<(&str, &Command)> {
+        let name = self.aliases.get(name).map_or(name, String::as_str);
+        self.commands.get_key_value(name).map(|(name, command)| (name.as_str(), command))
+    }
+
+    /// Rejects settings that parse but contradict each other.
+    fn validate(&self) -> Result<()> {
+        let mut networks = self.networks.iter().collect::<Vec<_>>();
+        networks.sort_by_key(|(name, _)| *name);
+        for (name, network) in networks {
+            for spec in network.hosts.iter().filter_map(|entry| match entry {
+                HostEntry::Host(spec) => Some(spec),
+                HostEntry::Address(_) => None,
+            }) {
+                if spec.host.is_empty() || spec.host.contains("://") {
+                    anyhow::bail!("Invalid host {:?} in network {}: the map form is for ssh hosts", spec.host, name);
+                }
+                if spec.alias.as_deref().is_some_and(|alias| alias.trim().is_empty()) {
+                    anyhow::bail!("Empty alias for host {} in network {}", spec.host, name);
+                }
+            }
+            if !network.include.is_empty() {
+                self.network_members(name)?;
+            }
+            for pattern in &network.excluded_hosts {
+                regex::Regex::new(pattern)
+                    .with_context(|| format!("Invalid excluded_hosts regex of network {}: {}", name, pattern))?;
+            }
+        }
+
+        let mut names = self.commands.keys().collect::<Vec<_>>();
+        names.sort();
+        for name in names {
+            let command = &self.commands[name];
+            let flat = command.local.is_some() || command.script.is_some() || command.run.is_some()
+                || command.upload.is_some() || command.template.is_some();
+            if flat && !command.steps.is_empty() {
+                anyhow::bail!(
+                    "Command {} sets both steps and local/script/run/upload/template; move them into steps",
+                    name
+                );
+            }
+            if command.once && command.serial.is_some() {
+                anyhow::bail!("Command {} sets both once and serial; a once command runs on a single host", name);
+            }
+            let feeds = [("stdin", command.stdin), ("stdin_data", command.stdin_data.is_some()), ("stdin_file", command.stdin_file.is_some())]
+                .into_iter()
+                .filter_map(|(field, set)| set.then_some(field))
+                .collect::<Vec<_>>();
+            if let [first, second, ..] = feeds.as_slice() {
+                anyhow::bail!("Command {} sets both {} and {}; pick one", name, first, second);
+            }
+            if let (true, Some(feed)) = (command.detach, feeds.first()) {
+                anyhow::bail!("Command {} sets both detach and {}; a detached command reads no input", name, feed);
+            }
+            if command.sudo && !command.ordered_steps().iter().any(|step| matches!(step, Step::Run(_))) {
+                anyhow::bail!("Command {} sets sudo without a run step; sudo only applies to run", name);
+            }
+            if command.once_per_batch && command.serial.is_none() {
+                anyhow::bail!("Command {} sets once_per_batch without serial; set serial to size the batches", name);
+            }
+            if let OnceOn::Regex(pattern) = &command.once_on {
+                regex::Regex::new(pattern)
+                    .with_context(|| format!("Invalid once_on regex of command {}: {}", name, pattern))?;
+            }
+            for step in command.ordered_steps() {
+                let Step::Upload(uploads) = step else { continue };
+                for upload in uploads {
+                    match (upload.src.is_empty(), &upload.from_command) {
+                        (true, None) => anyhow::bail!("Upload to {} in command {} needs src or from_command", upload.dst, name),
+                        (false, Some(_)) => anyhow::bail!("Upload to {} in command {} sets both src and from_command", upload.dst, name),
+                        _ => {}
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The part of sup's environment `vars` the env starts from: all of it,
+    /// or only the `inherit_env_only` variables when `inherit` is false.
+    pub fn inherited_env(&self, inherit: bool, vars: impl IntoIterator<Item = (String, String)>) -> EnvMap {
+        vars.into_iter()
+            .filter(|(key, _)| inherit || self.inherit_env_only.contains(key))
+            .collect()
+    }
+
+    /// `network` with the settings it leaves unset taken from the Supfile.
+    pub fn inherit(&self, mut network: Network) -> Network {
+        network.user = network.user.or_else(|| self.user.clone());
+        network
+    }
+
+    /// Env of commands run against `network`, in increasing precedence:
+    /// `base` (sup's own environment and the `SUP_*` variables), the Supfile
+    /// env, the network env, then `overrides` from `-e` and `--secret`.
+    pub fn network_env(
+        &self,
+        network: &Network,
+        base: EnvMap,
+        overrides: &[(String, String)],
+    ) -> Result<EnvMap> {
+        let mut env = base;
+        for vars in [&self.env, &network.env].into_iter().flatten() {
+            merge_env(&mut env, vars)?;
+        }
+        env.extend(overrides.iter().cloned());
+        Ok(env)
+    }
+
+    /// Names of the env variables the Supfile, `network` and `overrides`
+    /// set, as opposed to those inherited from sup's environment.
+    pub fn declared_env<'a>(&'a self, network: &'a Network, overrides: &'a [(String, String)]) -> BTreeSet<&'a str> {
+        [&self.env, &network.env].into_iter()
+            .flatten()
+            .flat_map(|vars| vars.keys())
+            .chain(overrides.iter().map(|(key, _)| key))
+            .map(String::as_str)
+            .collect()
+    }
+
+    /// One network standing for the union of `members` (`prod-us,prod-eu`):
+    /// their hosts together and their env merged in order, later networks
+    /// winning with a warning where they disagree. A network's own env
+    /// overrides what it includes without one. `desc`, `color` and `banner`
+    /// come from the last network setting them, so a composite's own win;
+    /// confirmation and pre-flight checks apply when any member asks for
+    /// them. Members with hosts must agree on how hosts are reached, as the
+    /// run shares one transport.
+    pub fn union_network(members: &[(String, Network)]) -> Result<Network> {
+        let has_hosts = |network: &Network| !network.hosts.is_empty() || network.inventory.is_some();
+        let Some((first_name, first)) = members.iter().find(|(_, network)| has_hosts(network)).or(members.first()) else {
+            anyhow::bail!("No networks to combine");
+        };
+        let reached = |network: &Network| (
+            network.ssh_binary.clone(),
+            network.ssh_options.clone(),
+            network.host_key_checking,
+            network.connect_timeout,
+            network.multiplex,
+            network.kube_context.clone(),
+        );
+        let mut union = Network { include: Vec::new(), ..first.clone() };
+        union.hosts.clear();
+        let mut env = EnvMap::new();
+        let mut inventories = Vec::new();
+        for (name, network) in members {
+            if has_hosts(network) && reached(network) != reached(first) {
+                anyhow::bail!(
+                    "Networks {} and {} reach their hosts differently (ssh, host key or kubectl settings); run them separately",
+                    first_name, name
+                );
+            }
+            for (key, value) in network.env.iter().flatten() {
+                if network.include.is_empty() && env.get(key).is_some_and(|previous| previous != value) {
+                    tracing::warn!("Env {} differs between the combined networks; using {}'s value", key, name);
+                }
+                env.insert(key.clone(), value.clone());
+            }
+            for entry in &network.hosts {
+                if !union.hosts.contains(entry) {
+                    union.hosts.push(entry.clone());
+                }
+            }
+            inventories.extend(network.inventory.clone());
+            union.desc = network.desc.clone().or(union.desc);
+            union.color = network.color.clone().or(union.color);
+            union.banner = network.banner.clone().or(union.banner);
+            union.confirm |= network.confirm;
+            union.preflight |= network.preflight;
+        }
+        union.env = (!env.is_empty()).then_some(env);
+        // Only shown; each member runs its own inventory
+        union.inventory = (!inventories.is_empty()).then(|| inventories.join("; "));
+        Ok(union)
+    }
+
+    /// The networks `name` stands for: itself, or for a network with
+    /// `include`, the networks it includes (recursively, in order) followed
+    /// by itself for its own hosts and env. A network reached twice counts
+    /// once.
+    pub fn network_members(&self, name: &str) -> Result<Members> {
+        let mut members = Members::new();
+        self.collect_members(name, true, &mut Vec::new(), &mut members)?;
+        Ok(members)
+    }
+
+    fn collect_members<'a>(&'a self, name: &'a str, with_env: bool, path: &mut Vec<&'a str>, members: &mut Members) -> Result<()> {
+        if path.contains(&name) {
+            path.push(name);
+            anyhow::bail!("Network {} includes itself: {}", name, path.join(" -> "));
+        }
+        let Some(network) = self.networks.get(name) else {
+            anyhow::bail!("Network {} includes unknown network {}", path.last().copied().unwrap_or_default(), name);
+        };
+        path.push(name);
+        let include_env = with_env && network.include_env.unwrap_or(true);
+        for included in &network.include {
+            self.collect_members(included, include_env, path, members)?;
+        }
+        path.pop();
+
+        if !members.iter().any(|(known, _)| known == name) {
+            let mut network = self.inherit(network.clone());
+            if !with_env {
+                network.env = None;
+            }
+            members.push((name.to_string(), network));
+        }
+        Ok(())
+    }
+
+    /// Name of the network to use when none is given: `default_network`,
+    /// or the only network defined.
+    pub fn default_network_name(&self) -> Result<String> {
+        if let Some(name) = &self.default_network {
+            return Ok(name.clone());
+        }
+        if self.networks.len() == 1 {
+            return Ok(self.networks.keys().next().cloned().unwrap_or_default());
+        }
+
+        let mut names = self.networks.keys().map(String::as_str).collect::<Vec<_>>();
+        names.sort();
+        anyhow::bail!(
+            "No network given and no default_network set; available networks: {}",
+            names.join(", ")
+        )
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// A named sequence of commands: a plain list of steps, or
+/// `{desc, steps}` to describe it in `--list`, `--plan` and pickers.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(from = "TargetRepr")]
+pub struct Target {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub desc: Option<String>,
+    pub steps: Vec<TargetStage>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TargetRepr {
+    Steps(Vec<TargetStage>),
+    Described {
+        #[serde(default)]
+        desc: Option<String>,
+        steps: Vec<TargetStage>,
+    },
+}
+
+impl From<TargetRepr> for Target {
+    fn from(repr: TargetRepr) -> Self {
+        match repr {
+            TargetRepr::Steps(steps) => Target { desc: None, steps },
+            TargetRepr::Described { desc, steps } => Target { desc, steps },
+        }
+    }
+}
+
+impl From<Vec<TargetStep>> for Target {
+    fn from(steps: Vec<TargetStep>) -> Self {
+        Target { desc: None, steps: steps.into_iter().map(TargetStage::Step).collect() }
+    }
+}
+
+/// One stage of a target: a single step, or a list of steps run in
+/// parallel, all finishing before the next stage starts.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum TargetStage {
+    Step(TargetStep),
+    Parallel(Vec<TargetStep>),
+}
+
+impl TargetStage {
+    /// The steps of the stage, in the order they were listed.
+    pub fn steps(&self) -> &[TargetStep] {
+        match self {
+            TargetStage::Step(step) => std::slice::from_ref(step),
+            TargetStage::Parallel(steps) => steps,
+        }
+    }
+}
+
+impl std::fmt::Display for TargetStage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TargetStage::Step(step) => step.fmt(f),
+            TargetStage::Parallel(steps) => {
+                let steps = steps.iter().map(ToString::to_string).collect::<Vec<_>>();
+                write!(f, "[{}]", steps.join(", "))
+            }
+        }
+    }
+}
+
+/// One step of a target: a command name, or `{command, network}` to run
+/// it against another network than the one selected on the command line.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum TargetStep {
+    Command(String),
+    Step {
+        command: String,
+        #[serde(default)]
+        network: Option<String>,
+    },
+}
+
+impl TargetStep {
+    pub fn command(&self) -> &str {
+        match self {
+            TargetStep::Command(command) | TargetStep::Step { command, .. } => command,
+        }
+    }
+
+    /// Network the step runs on, if it overrides the selected one.
+    pub fn network(&self) -> Option<&str> {
+        match self {
+            TargetStep::Command(_) => None,
+            TargetStep::Step { network, .. } => network.as_deref(),
+        }
+    }
+}
+
+impl std::fmt::Display for TargetStep {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.network() {
+            Some(network) => write!(f, "{} ({})", self.command(), network),
+            None => f.write_str(self.command()),
+        }
+    }
+}
+
+/// Up to three of `candidates` closest to a mistyped `name`, best first.
+pub fn suggest<'a>(name: &str, candidates: impl IntoIterator<Item = &'a str>) -> Vec<&'a str> {
+    let mut scored = candidates.into_iter()
+        .map(|candidate| (strsim::jaro_winkler(name, candidate), candidate))
+        .filter(|(score, candidate)| {
+            *score >= 0.85 || strsim::levenshtein(name, candidate) <= (name.len() / 3).max(1)
+        })
+        .collect::<Vec<_>>();
+    scored.sort_by(|a, b| b.0.total_cmp(&a.0).then_with(|| a.1.cmp(b.1)));
+    scored.dedup_by(|a, b| a.1 == b.1);
+    scored.into_iter().take(3).map(|(_, candidate)| candidate).collect()
+}
+
+/// Merges `vars` into `env` in declaration order. Values written as
+/// `$(command)` are run locally with the env merged so far and replaced by
+/// their trimmed stdout.
+pub fn merge_env(env: &mut EnvMap, vars: &EnvMap) -> Result<()> {
+    for (key, value) in vars {
+        let value = match shell_substitution(value) {
+            Some(cmd) => evaluate_env_command(key, cmd, env)?,
+            None => value.clone(),
+        };
+        env.insert(key.clone(), value);
+    }
+    Ok(())
+}
+
+fn shell_substitution(value: &str) -> Option<&str> {
+    value.trim()
+        .strip_prefix("$(")
+        .and_then(|rest| rest.strip_suffix(')'))
+}
+
+fn evaluate_env_command(key: &str, cmd: &str, env: &EnvMap) -> Result<String> {
+    let output = shell::command(cmd)
+        .env_clear()
+        .envs(env)
+        .output()
+        .with_context(|| format!("Failed to evaluate env {}", key))?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        anyhow::bail!("Env {} command `{}` failed: {}", key, cmd, stderr.trim());
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Network {
+    /// What the network is, shown by `--list` and confirmation prompts
+    #[serde(default)]
+    pub desc: Option<String>,
     #[serde(default)]
-    pub hosts: Vec<String>,
+    pub hosts: Vec<HostEntry>,
     #[serde(default)]
     pub inventory: Option<String>,
+    /// User for hosts whose entry has no `user@`; the Supfile's when unset
     #[serde(default)]
-    pub env: Option<HashMap<String, String>>,
+    pub user: Option<String>,
+    #[serde(default, deserialize_with = "scalar_values::deserialize")]
+    pub env: Option<EnvMap>,
+    #[serde(default)]
+    pub kube_context: Option<String>,
+    /// Reuse ssh connections across commands (enabled unless set to false).
+    #[serde(default)]
+    pub multiplex: Option<bool>,
+    #[serde(default)]
+    pub host_key_checking: HostKeyChecking,
+    #[serde(default)]
+    pub ssh_binary: Option<String>,
+    #[serde(default)]
+    pub ssh_options: Vec<String>,
+    /// Seconds to wait when connecting to a host
+    #[serde(default)]
+    pub connect_timeout: Option<u64>,
+    /// Require interactive confirmation before running against this network
+    #[serde(default)]
+    pub confirm: bool,
+    /// Check that every host is reachable before running commands
+    #[serde(default)]
+    pub preflight: bool,
+    /// Refuse to run while another run holds this network's lock
+    #[serde(default)]
+    pub lock: Lock,
+    /// Color of the network's banners, host prefixes, prompts and summary
+    #[serde(default)]
+    pub color: Option<NetworkColor>,
+    /// Text shown prominently before and during runs, e.g. `⚠ PRODUCTION`
+    #[serde(default)]
+    pub banner: Option<String>,
+    /// Networks whose hosts (and env) this one adds to its own
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
+    /// Take the env of included networks too (the default unless false)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub include_env: Option<bool>,
+    /// Regexes of hosts left out of runs, matched after the inventory ran
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub excluded_hosts: Vec<String>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// The networks a union run (`prod-us,prod-eu`) combines, by name, in the
+/// order given.
+pub type Members = Vec<(String, Network)>;
+
+impl Network {
+    /// How output about this network stands out.
+    pub fn style(&self) -> NetworkStyle {
+        NetworkStyle {
+            color: self.color.as_ref().map(|color| color.color),
+            banner: self.banner.clone().filter(|banner| !banner.trim().is_empty()),
+        }
+    }
+}
+
+/// A network's `color`: a terminal color name such as `red` or
+/// `bright yellow`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct NetworkColor {
+    name: String,
+    color: colored::Color,
+}
+
+impl TryFrom<String> for NetworkColor {
+    type Error = String;
+
+    fn try_from(name: String) -> std::result::Result<Self, Self::Error> {
+        let color = name.parse()
+            .map_err(|()| format!("unknown color {:?}, expected e.g. red, yellow, blue or bright red", name))?;
+        Ok(NetworkColor { name, color })
+    }
+}
+
+impl From<NetworkColor> for String {
+    fn from(color: NetworkColor) -> Self {
+        color.name
+    }
+}
+
+/// One `hosts:` entry: a `user@host[:port]` string (or a docker/kubectl
+/// address), or a map naming the parts with an optional alias.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum HostEntry {
+    Address(String),
+    Host(HostSpec),
+}
+
+/// The map form of a host entry, e.g.
+/// `{host: 10.0.1.5, user: deploy, port: 2222, alias: web1}`, or with
+/// `disabled: true` to keep it listed but out of runs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HostSpec {
+    pub host: String,
+    #[serde(default)]
+    pub user: Option<String>,
+    #[serde(default)]
+    pub port: Option<u16>,
+    /// Name shown in output and matched by `--only`/`--except` in place
+    /// of the address
+    #[serde(default)]
+    pub alias: Option<String>,
+    /// Leave the host out of runs, e.g. while it is in maintenance
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub disabled: bool,
+}
+
+impl HostEntry {
+    /// The address connected to, as `user@host[:port]`.
+    pub fn address(&self) -> String {
+        match self {
+            HostEntry::Address(address) => address.clone(),
+            HostEntry::Host(spec) => {
+                let mut address = match &spec.user {
+                    Some(user) => format!("{}@{}", user, spec.host),
+                    None => spec.host.clone(),
+                };
+                if let Some(port) = spec.port {
+                    address = format!("{}:{}", address, port);
+                }
+                address
+            }
+        }
+    }
+
+    pub fn alias(&self) -> Option<&str> {
+        match self {
+            HostEntry::Host(spec) => spec.alias.as_deref(),
+            HostEntry::Address(_) => None,
+        }
+    }
+
+    /// Whether the entry is kept out of runs with `disabled: true`.
+    pub fn disabled(&self) -> bool {
+        matches!(self, HostEntry::Host(spec) if spec.disabled)
+    }
+
+    /// The name the host goes by in output: its alias, else its address.
+    pub fn name(&self) -> String {
+        self.alias().map_or_else(|| self.address(), str::to_string)
+    }
+}
+
+impl From<&str> for HostEntry {
+    fn from(address: &str) -> Self {
+        HostEntry::Address(address.to_string())
+    }
+}
+
+impl From<String> for HostEntry {
+    fn from(address: String) -> Self {
+        HostEntry::Address(address)
+    }
+}
+
+/// Where a network's run lock is kept (`lock: true`, `remote` or `local`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "LockRepr", into = "LockRepr")]
+pub enum Lock {
+    #[default]
+    Off,
+    /// A lock directory on the first resolved host
+    Remote,
+    /// A lock directory on the machine running sup
+    Local,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum LockRepr {
+    Enabled(bool),
+    Mode(String),
+}
+
+impl TryFrom<LockRepr> for Lock {
+    type Error = String;
+
+    fn try_from(repr: LockRepr) -> std::result::Result<Self, Self::Error> {
+        match repr {
+            LockRepr::Enabled(false) => Ok(Lock::Off),
+            LockRepr::Enabled(true) => Ok(Lock::Remote),
+            LockRepr::Mode(mode) => match mode.as_str() {
+                "remote" => Ok(Lock::Remote),
+                "local" => Ok(Lock::Local),
+                _ => Err(format!("invalid lock value {:?}, expected true, false, \"remote\" or \"local\"", mode)),
+            },
+        }
+    }
+}
+
+impl From<Lock> for LockRepr {
+    fn from(lock: Lock) -> Self {
+        match lock {
+            Lock::Off => LockRepr::Enabled(false),
+            Lock::Remote => LockRepr::Enabled(true),
+            Lock::Local => LockRepr::Mode("local".to_string()),
+        }
+    }
+}
+
+/// How ssh treats unknown or changed host keys.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HostKeyChecking {
+    /// Leave ssh's own StrictHostKeyChecking behavior in place
+    #[default]
+    Strict,
+    /// Record keys of new hosts, still reject changed keys
+    AcceptNew,
+    /// Skip verification entirely and don't record keys
+    Off,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Command {
     #[serde(default)]
     pub desc: Option<String>,
@@ -45,18 +759,541 @@ pub struct Command {
     pub script: Option<String>,
     #[serde(default)]
     pub upload: Option<Vec<Upload>>,
+    /// Files rendered with `{{ VAR }}` substitution and written to each host
+    #[serde(default)]
+    pub template: Option<Vec<Template>>,
+    /// Steps run in the listed order, in place of the fields above
+    #[serde(default)]
+    pub steps: Vec<Step>,
     #[serde(default)]
     pub stdin: bool,
+    /// Text fed to the stdin of `run` on every host
+    #[serde(default)]
+    pub stdin_data: Option<String>,
+    /// File streamed to the stdin of `run` on every host, relative to the
+    /// Supfile; `$VAR` is expanded from the env
+    #[serde(default)]
+    pub stdin_file: Option<String>,
     #[serde(default)]
     pub once: bool,
+    /// Host a `once` command runs on
+    #[serde(default)]
+    pub once_on: OnceOn,
+    #[serde(default)]
+    pub serial: Option<Serial>,
+    /// Run on the first host of each serial batch only, skipping the rest
+    #[serde(default)]
+    pub once_per_batch: bool,
+    /// Pause between serial batches, e.g. `30s`
+    #[serde(default, with = "duration_str")]
+    pub serial_delay: Option<Duration>,
+    /// Shell check run on each host first; non-zero exit skips the host
+    #[serde(default)]
+    pub when: Option<String>,
+    /// Skip a host when this path already exists on it
+    #[serde(default)]
+    pub creates: Option<String>,
+    /// Skip a host when this path does not exist on it
+    #[serde(default)]
+    pub removes: Option<String>,
+    /// Run on one host first and confirm before the rest
+    #[serde(default)]
+    pub canary: bool,
+    /// Regex picking the canary host; defaults to the first host
+    #[serde(default)]
+    pub canary_host: Option<String>,
+    /// Fail unless arguments are passed after `--`
+    #[serde(default)]
+    pub require_args: bool,
+    /// Show nothing but errors unless the command fails, like `--quiet`
+    #[serde(default)]
+    pub quiet: bool,
+    /// Give `local`/`script` the terminal instead of streaming their output
+    /// through the host output, for commands that prompt
+    #[serde(default)]
+    pub inherit_stdio: bool,
+    /// Check polled on each host after `run` succeeds there
+    #[serde(default)]
+    pub wait_for: Option<WaitFor>,
+    /// Run `run`, `when` and `wait_for` as root through `sudo -E`, keeping
+    /// the env; a `sudo` written in the command itself is left alone
+    #[serde(default)]
+    pub sudo: bool,
+    /// Warn and carry on when the command is left with no hosts, instead
+    /// of failing
+    #[serde(default)]
+    pub allow_empty_hosts: bool,
+    /// Start `run` in the background, detached from the ssh session, and
+    /// return once it is running
+    #[serde(default)]
+    pub detach: bool,
+    /// Remote file a detached command's output goes to; defaults to
+    /// `/tmp/sup-<command>.log`
     #[serde(default)]
-    pub serial: Option<usize>,
+    pub detach_log: Option<String>,
+    /// Networks the command may run against; any when unset
+    #[serde(default)]
+    pub networks: Option<Vec<String>>,
+    /// Networks the command must never run against
+    #[serde(default)]
+    pub forbidden_networks: Vec<String>,
+    /// Other names the command can be run by, e.g. the one it had before
+    /// a rename
+    #[serde(default)]
+    pub aliases: Option<Vec<String>>,
 }
 
+impl Command {
+    /// Remote log of the command `name` when it is detached.
+    pub fn detach_log(&self, name: &str) -> String {
+        self.detach_log.clone().unwrap_or_else(|| format!("/tmp/sup-{}.log", name))
+    }
+
+    /// The steps in the order they run: `steps` as listed, or else the
+    /// `local`, `script`, `run`, `upload` and `template` fields in that order.
+    pub fn ordered_steps(&self) -> Vec<Step> {
+        if !self.steps.is_empty() {
+            return self.steps.clone();
+        }
+        let mut steps = Vec::new();
+        steps.extend(self.local.clone().map(Step::Local));
+        steps.extend(self.script.clone().map(Step::Script));
+        steps.extend(self.run.clone().map(Step::Run));
+        steps.extend(self.upload.clone().map(Step::Upload));
+        steps.extend(self.template.clone().map(Step::Template));
+        steps
+    }
+
+    /// Whether any step runs on the network's hosts.
+    pub fn is_remote(&self) -> bool {
+        self.ordered_steps().iter().any(Step::is_remote)
+    }
+
+    /// Fails if the command `name` may not run against `network`.
+    pub fn check_network(&self, name: &str, network: &str) -> Result<()> {
+        if self.forbidden_networks.iter().any(|forbidden| forbidden == network) {
+            anyhow::bail!("Command {} must never run against network {}", name, network);
+        }
+        if let Some(allowed) = &self.networks {
+            if !allowed.iter().any(|allowed| allowed == network) {
+                anyhow::bail!(
+                    "Command {} may not run against network {}; allowed networks: {}",
+                    name, network, allowed.join(", ")
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// One item of a command's `steps:` list, e.g. `{upload: [...]}` or
+/// `{run: docker compose up -d}`.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Upload {
+#[serde(try_from = "StepRepr", into = "StepRepr")]
+pub enum Step {
+    Local(String),
+    Script(String),
+    Run(String),
+    Upload(Vec<Upload>),
+    Template(Vec<Template>),
+}
+
+impl Step {
+    pub fn is_remote(&self) -> bool {
+        matches!(self, Step::Run(_) | Step::Upload(_) | Step::Template(_))
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StepRepr {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    local: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    script: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    run: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    upload: Option<Vec<Upload>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    template: Option<Vec<Template>>,
+}
+
+impl TryFrom<StepRepr> for Step {
+    type Error = String;
+
+    fn try_from(repr: StepRepr) -> std::result::Result<Self, Self::Error> {
+        let steps = [
+            repr.local.map(Step::Local),
+            repr.script.map(Step::Script),
+            repr.run.map(Step::Run),
+            repr.upload.map(Step::Upload),
+            repr.template.map(Step::Template),
+        ];
+        let mut steps = steps.into_iter().flatten();
+        match (steps.next(), steps.next()) {
+            (Some(step), None) => Ok(step),
+            _ => Err("a step must set exactly one of local, script, run, upload or template".to_string()),
+        }
+    }
+}
+
+impl From<Step> for StepRepr {
+    fn from(step: Step) -> Self {
+        match step {
+            Step::Local(cmd) => StepRepr { local: Some(cmd), ..Default::default() },
+            Step::Script(path) => StepRepr { script: Some(path), ..Default::default() },
+            Step::Run(cmd) => StepRepr { run: Some(cmd), ..Default::default() },
+            Step::Upload(uploads) => StepRepr { upload: Some(uploads), ..Default::default() },
+            Step::Template(templates) => StepRepr { template: Some(templates), ..Default::default() },
+        }
+    }
+}
+
+/// Remote check polled after a command's `run` until it exits 0, e.g. a
+/// health endpoint coming up after a restart.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WaitFor {
+    pub command: String,
+    /// Give up and fail the host after this long; defaults to 60s
+    #[serde(default, with = "duration_str")]
+    pub timeout: Option<Duration>,
+    /// Pause between attempts; defaults to 2s
+    #[serde(default, with = "duration_str")]
+    pub interval: Option<Duration>,
+}
+
+impl WaitFor {
+    pub fn timeout(&self) -> Duration {
+        self.timeout.unwrap_or(Duration::from_secs(60))
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval.unwrap_or(Duration::from_secs(2))
+    }
+}
+
+/// Host a `once` command runs on: the first (default), a random one, or
+/// the first whose name matches a regex. Hosts skipped by a guard fall
+/// through to the next candidate.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub enum OnceOn {
+    #[default]
+    First,
+    Random,
+    Regex(String),
+}
+
+impl From<String> for OnceOn {
+    fn from(value: String) -> Self {
+        match value.as_str() {
+            "first" => OnceOn::First,
+            "random" => OnceOn::Random,
+            _ => OnceOn::Regex(value),
+        }
+    }
+}
+
+impl From<OnceOn> for String {
+    fn from(once_on: OnceOn) -> Self {
+        match once_on {
+            OnceOn::First => "first".to_string(),
+            OnceOn::Random => "random".to_string(),
+            OnceOn::Regex(regex) => regex,
+        }
+    }
+}
+
+/// Batch size for serial runs: a fixed host count or a percentage of the
+/// resolved hosts (`serial: 2` or `serial: "25%"`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "SerialRepr", into = "SerialRepr")]
+pub enum Serial {
+    Count(usize),
+    Percent(u8),
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum SerialRepr {
+    Count(usize),
+    Text(String),
+}
+
+impl TryFrom<SerialRepr> for Serial {
+    type Error = String;
+
+    fn try_from(repr: SerialRepr) -> std::result::Result<Self, Self::Error> {
+        match repr {
+            SerialRepr::Count(0) => Err("serial must be at least 1".to_string()),
+            SerialRepr::Count(count) => Ok(Serial::Count(count)),
+            SerialRepr::Text(text) => {
+                let percent = text.trim()
+                    .strip_suffix('%')
+                    .and_then(|percent| percent.trim().parse::<u8>().ok())
+                    .filter(|percent| (1..=100).contains(percent))
+                    .ok_or_else(|| format!("invalid serial value {:?}, expected a count or a percentage like \"25%\"", text))?;
+                Ok(Serial::Percent(percent))
+            }
+        }
+    }
+}
+
+impl From<Serial> for SerialRepr {
+    fn from(serial: Serial) -> Self {
+        match serial {
+            Serial::Count(count) => SerialRepr::Count(count),
+            Serial::Percent(percent) => SerialRepr::Text(format!("{}%", percent)),
+        }
+    }
+}
+
+impl std::str::FromStr for Serial {
+    type Err = String;
+
+    /// Parses the command-line form: a host count or a percentage.
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        match value.trim().parse::<usize>() {
+            Ok(count) => SerialRepr::Count(count),
+            Err(_) => SerialRepr::Text(value.to_string()),
+        }
+        .try_into()
+    }
+}
+
+impl std::fmt::Display for Serial {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Serial::Count(count) => write!(f, "{}", count),
+            Serial::Percent(percent) => write!(f, "{}%", percent),
+        }
+    }
+}
+
+impl Serial {
+    /// Number of hosts per batch for a run over `hosts` hosts.
+    pub fn batch_size(&self, hosts: usize) -> usize {
+        match *self {
+            Serial::Count(count) => count,
+            Serial::Percent(percent) => (hosts * percent as usize).div_ceil(100).max(1),
+        }
+    }
+}
+
+/// Parses durations such as `500ms`, `30s`, `5m` or `1h`; a bare number
+/// is taken as seconds.
+pub fn parse_duration(text: &str) -> Result<Duration> {
+    let text = text.trim();
+    let split = text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len());
+    let (value, unit) = text.split_at(split);
+    let value: u64 = value.parse()
+        .with_context(|| format!("Invalid duration: {:?}", text))?;
+    match unit.trim() {
+        "ms" => Ok(Duration::from_millis(value)),
+        "" | "s" => Ok(Duration::from_secs(value)),
+        "m" => Ok(Duration::from_secs(value * 60)),
+        "h" => Ok(Duration::from_secs(value * 3600)),
+        _ => anyhow::bail!("Invalid duration unit in {:?}, expected ms, s, m or h", text),
+    }
+}
+
+/// Accepts a single string or a list of strings.
+mod one_or_many {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    pub fn serialize<S: Serializer>(values: &[String], serializer: S) -> Result<S::Ok, S::Error> {
+        match values {
+            [value] => value.serialize(serializer),
+            values => values.serialize(serializer),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+        Ok(match OneOrMany::deserialize(deserializer)? {
+            OneOrMany::One(value) => vec![value],
+            OneOrMany::Many(values) => values,
+        })
+    }
+}
+
+/// Serde helpers for env maps whose values may be any YAML scalar, so
+/// `PORT: 8000` and `DEBUG: true` need no quotes. Sequences and maps are
+/// rejected with the key they were found under.
+mod scalar_values {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer};
+    use serde_yaml::Value;
+
+    pub fn deserialize<'de, D, M>(deserializer: D) -> Result<Option<M>, D::Error>
+    where
+        D: Deserializer<'de>,
+        M: FromIterator<(String, String)>,
+    {
+        let Some(values) = Option::<indexmap::IndexMap<String, Value>>::deserialize(deserializer)? else {
+            return Ok(None);
+        };
+        values.into_iter()
+            .map(|(key, value)| Ok((key.clone(), scalar(&key, value)?)))
+            .collect::<Result<M, String>>()
+            .map(Some)
+            .map_err(D::Error::custom)
+    }
+
+    /// Like `deserialize`, for maps that are empty when omitted.
+    pub fn deserialize_map<'de, D, M>(deserializer: D) -> Result<M, D::Error>
+    where
+        D: Deserializer<'de>,
+        M: FromIterator<(String, String)> + Default,
+    {
+        Ok(deserialize(deserializer)?.unwrap_or_default())
+    }
+
+    /// Canonical string form of a scalar value.
+    fn scalar(key: &str, value: Value) -> Result<String, String> {
+        let kind = match value {
+            Value::String(text) => return Ok(text),
+            Value::Number(number) => return Ok(number.to_string()),
+            Value::Bool(flag) => return Ok(flag.to_string()),
+            Value::Tagged(tagged) => return scalar(key, tagged.value),
+            Value::Null => "nothing",
+            Value::Sequence(_) => "a list",
+            Value::Mapping(_) => "a map",
+        };
+        Err(format!("{}: expected a string, number or boolean, found {}; quote the value to keep it as text", key, kind))
+    }
+}
+
+/// Serde helpers for optional durations written as strings like `30s`.
+mod duration_str {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(value: &Option<Duration>, serializer: S) -> Result<S::Ok, S::Error> {
+        match value {
+            Some(duration) if duration.subsec_millis() > 0 => {
+                serializer.serialize_str(&format!("{}ms", duration.as_millis()))
+            }
+            Some(duration) => serializer.serialize_str(&format!("{}s", duration.as_secs())),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|text| super::parse_duration(&text).map_err(serde::de::Error::custom))
+            .transpose()
+    }
+}
+
+/// A local template rendered per host from the env plus `vars`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Template {
     pub src: String,
     pub dst: String,
+    #[serde(default, deserialize_with = "scalar_values::deserialize_map")]
+    pub vars: EnvMap,
+    /// Octal permissions for the written file, e.g. `"0644"`
+    #[serde(default)]
+    pub mode: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Upload {
+    /// Paths or glob patterns, as one string or a list, sent as one tar stream
+    #[serde(default, with = "one_or_many", skip_serializing_if = "Vec::is_empty")]
+    pub src: Vec<String>,
+    /// Local command whose stdout is written to `dst` instead of uploading `src`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from_command: Option<String>,
+    /// Write an empty file when `from_command` prints nothing
+    #[serde(default)]
+    pub allow_empty: bool,
+    /// A directory when it ends in `/`, otherwise the name of the single upload
+    pub dst: String,
+    /// Skip sources that match nothing instead of failing
+    #[serde(default)]
+    pub optional: bool,
+    /// Octal permissions set on the uploaded entry, e.g. `"0644"`
+    #[serde(default)]
+    pub mode: Option<String>,
+    /// Owner set on the uploaded entry; uses sudo when not the ssh user
+    #[serde(default)]
+    pub owner: Option<String>,
+    #[serde(default)]
+    pub group: Option<String>,
+    /// Apply mode and ownership to everything under the entry
+    #[serde(default)]
+    pub recursive: bool,
+    /// Compare SHA-256 checksums of the uploaded files after extraction
+    #[serde(default)]
+    pub verify: bool,
+    #[serde(default)]
+    pub compression: Compression,
+    /// Level passed to the compressor, e.g. 1-9 for gzip or 1-19 for zstd
+    #[serde(default)]
+    pub compression_level: Option<u32>,
+    /// Times a failed transfer to a host is started over
+    #[serde(default)]
+    pub retries: u32,
+    /// Upload what symlinks point to instead of the links themselves
+    #[serde(default)]
+    pub follow_symlinks: bool,
+    /// Extract with the modes the files have locally rather than with the
+    /// remote umask applied
+    #[serde(default = "default_true")]
+    pub preserve_permissions: bool,
+}
+
+impl Default for Upload {
+    fn default() -> Self {
+        Self {
+            src: Vec::new(),
+            from_command: None,
+            allow_empty: false,
+            dst: String::new(),
+            optional: false,
+            mode: None,
+            owner: None,
+            group: None,
+            recursive: false,
+            verify: false,
+            compression: Compression::default(),
+            compression_level: None,
+            retries: 0,
+            follow_symlinks: false,
+            preserve_permissions: true,
+        }
+    }
+}
+
+impl Upload {
+    /// What the upload sends, for messages: its sources or its command.
+    pub fn source(&self) -> String {
+        match &self.from_command {
+            Some(command) => format!("output of {}", command),
+            None => self.src.join(", "),
+        }
+    }
+}
+
+/// How the tar stream of an upload is compressed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    /// Plain tar, best for already-compressed artifacts
+    None,
+    #[default]
+    Gzip,
+    /// Falls back to gzip on hosts whose tar lacks zstd support
+    Zstd,
 }
 
 #[cfg(test)]
@@ -94,8 +1331,8 @@ mod tests {
         // Test dev network hosts
         let dev_network = config.networks.get("dev").unwrap();
         assert_eq!(dev_network.hosts.len(), 2);
-        assert!(dev_network.hosts.contains(&"alex@bigbox".to_string()));
-        assert!(dev_network.hosts.contains(&"alex@100.106.66.7".to_string()));
+        assert!(dev_network.hosts.contains(&"alex@bigbox".into()));
+        assert!(dev_network.hosts.contains(&"alex@100.106.66.7".into()));
         
         // Test commands
         assert!(config.commands.contains_key("bash"));
@@ -113,7 +1350,7 @@ mod tests {
         let upload_cmd = config.commands.get("upload").unwrap();
         let uploads = upload_cmd.upload.as_ref().unwrap();
         assert_eq!(uploads.len(), 1);
-        assert_eq!(uploads[0].src, "./dist");
+        assert_eq!(uploads[0].src, ["./dist"]);
         assert_eq!(uploads[0].dst, "/tmp/");
         
         cleanup_test_file(path);
@@ -149,6 +1386,8 @@ mod tests {
         
         // Test prod-us network
         let prod_us = config.networks.get("prod-us").unwrap();
+        assert!(prod_us.confirm);
+        assert!(!config.networks.get("dev").unwrap().confirm);
         assert_eq!(prod_us.hosts.len(), 3);
         let prod_us_env = prod_us.env.as_ref().unwrap();
         assert_eq!(prod_us_env.get("ENV").unwrap(), "production");
@@ -157,23 +1396,64 @@ mod tests {
         // Test commands
         let rolling_update = config.commands.get("rolling-update").unwrap();
         assert!(rolling_update.run.is_some());
-        assert_eq!(rolling_update.serial, Some(2));
+        assert_eq!(rolling_update.serial, Some(Serial::Percent(25)));
+        assert_eq!(rolling_update.serial_delay, Some(Duration::from_secs(30)));
         
         // Test targets
         let targets = &config.targets;
-        let deploy_steps = targets.get("deploy").unwrap();
+        let deploy_steps = targets.get("deploy").unwrap().steps.iter().flat_map(TargetStage::steps).collect::<Vec<_>>();
         assert_eq!(deploy_steps.len(), 6);
-        assert!(deploy_steps.contains(&"build".to_string()));
-        assert!(deploy_steps.contains(&"test".to_string()));
-        assert!(deploy_steps.contains(&"push".to_string()));
-        assert!(deploy_steps.contains(&"upload-config".to_string()));
-        assert!(deploy_steps.contains(&"rolling-update".to_string()));
-        assert!(deploy_steps.contains(&"status".to_string()));
+        let step_commands = deploy_steps.iter().map(|step| step.command()).collect::<Vec<_>>();
+        assert_eq!(step_commands, ["build", "test", "push", "upload-config", "rolling-update", "status"]);
+        // Build steps run locally whichever network is selected
+        assert_eq!(deploy_steps[0].network(), Some("local"));
+        assert_eq!(deploy_steps[4].network(), None);
+        assert_eq!(deploy_steps[0].to_string(), "build (local)");
         
         cleanup_test_file(path);
         Ok(())
     }
 
+    #[test]
+    fn test_target_forms() -> Result<()> {
+        let supfile: Supfile = serde_yaml::from_str(r#"
+version: "0.4"
+networks:
+  prod-eu:
+    desc: Production in eu-west-1
+    hosts: [deploy@web1]
+commands: {}
+targets:
+  release: [build, {command: push, network: local}]
+  deploy:
+    desc: Build, push and roll out
+    steps:
+      - build
+      - rolling-update
+"#)?;
+        assert_eq!(supfile.networks["prod-eu"].desc.as_deref(), Some("Production in eu-west-1"));
+
+        let release = &supfile.targets["release"];
+        assert_eq!(release.desc, None);
+        assert_eq!(release.steps.iter().map(ToString::to_string).collect::<Vec<_>>(), ["build", "push (local)"]);
+
+        let deploy = &supfile.targets["deploy"];
+        assert_eq!(deploy.desc.as_deref(), Some("Build, push and roll out"));
+        assert_eq!(deploy.steps.iter().flat_map(TargetStage::steps).map(TargetStep::command).collect::<Vec<_>>(), ["build", "rolling-update"]);
+
+        assert!(serde_yaml::from_str::<Target>("desc: no steps").is_err());
+
+        // A nested list is a parallel group; groups do not nest
+        let grouped: Target = serde_yaml::from_str("[build, [push, {command: upload-config, network: local}], rolling-update]")?;
+        assert_eq!(grouped.steps[1], TargetStage::Parallel(vec![
+            TargetStep::Command("push".to_string()),
+            TargetStep::Step { command: "upload-config".to_string(), network: Some("local".to_string()) },
+        ]));
+        assert_eq!(grouped.steps.iter().map(ToString::to_string).collect::<Vec<_>>(), ["build", "[push, upload-config (local)]", "rolling-update"]);
+        assert!(serde_yaml::from_str::<Target>("[build, [push, [a, b]]]").is_err());
+        Ok(())
+    }
+
     #[test]
     fn test_invalid_yaml() {
         let invalid_yaml = "version: 0.4\nnetworks: not_a_map";
@@ -232,6 +1512,146 @@ commands: {}
         Ok(())
     }
 
+    #[test]
+    fn test_network_env_layers() -> Result<()> {
+        let supfile: Supfile = serde_yaml::from_str(r#"
+version: "0.4"
+env:
+  REGION: eu
+  TIER: supfile
+  IMAGE: $(echo app:$TIER)
+networks:
+  prod:
+    env:
+      TIER: network
+      URL: $(echo https://$REGION.example.com)
+commands: {}
+"#)?;
+        let network = &supfile.networks["prod"];
+        let base = EnvMap::from([
+            ("HOME".to_string(), "/home/alex".to_string()),
+            ("REGION".to_string(), "us".to_string()),
+        ]);
+        let overrides = [("URL".to_string(), "http://localhost".to_string())];
+
+        // Later layers win, and shell values see the env merged so far
+        let env = supfile.network_env(network, base, &overrides)?;
+        assert_eq!(env["HOME"], "/home/alex");
+        assert_eq!(env["REGION"], "eu");
+        assert_eq!(env["TIER"], "network");
+        assert_eq!(env["IMAGE"], "app:supfile");
+        assert_eq!(env["URL"], "http://localhost");
+
+        let declared = supfile.declared_env(network, &overrides);
+        assert_eq!(declared.into_iter().collect::<Vec<_>>(), ["IMAGE", "REGION", "TIER", "URL"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_host_key_checking() -> Result<()> {
+        let yaml = r#"
+version: "0.4"
+networks:
+  default: {}
+  fresh:
+    host_key_checking: accept-new
+  lab:
+    host_key_checking: "off"
+commands: {}
+"#;
+        let path = create_test_file(yaml, "test_host_keys.yml")?;
+
+        let config = Supfile::from_file(&path)?;
+        assert_eq!(config.networks["default"].host_key_checking, HostKeyChecking::Strict);
+        assert_eq!(config.networks["fresh"].host_key_checking, HostKeyChecking::AcceptNew);
+        assert_eq!(config.networks["lab"].host_key_checking, HostKeyChecking::Off);
+
+        cleanup_test_file(path);
+        Ok(())
+    }
+
+    #[test]
+    fn test_inherited_env() -> Result<()> {
+        let supfile: Supfile = serde_yaml::from_str(r#"
+version: "0.4"
+inherit_env: false
+inherit_env_only: [PATH, HOME]
+networks: {}
+commands: {}
+"#)?;
+        assert_eq!(supfile.inherit_env, Some(false));
+        let vars = || [
+            ("HOME".to_string(), "/home/dev".to_string()),
+            ("AWS_SECRET_ACCESS_KEY".to_string(), "poison".to_string()),
+            ("PATH".to_string(), "/usr/bin".to_string()),
+        ];
+        let env = supfile.inherited_env(false, vars());
+        assert_eq!(env.keys().collect::<Vec<_>>(), ["HOME", "PATH"]);
+        assert_eq!(supfile.inherited_env(true, vars()).len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_secrets() -> Result<()> {
+        let yaml = r#"
+version: "0.4"
+env:
+  REGISTRY: registry.example.com
+secrets:
+  - DOCKER_PASS
+  - API_TOKEN
+networks: {}
+commands: {}
+"#;
+        let path = create_test_file(yaml, "test_secrets.yml")?;
+
+        let config = Supfile::from_file(&path)?;
+        assert_eq!(config.secrets, ["DOCKER_PASS", "API_TOKEN"]);
+        assert_eq!(config.redact, [DEFAULT_REDACT]);
+
+        cleanup_test_file(path);
+        Ok(())
+    }
+
+    #[test]
+    fn test_env_shell_substitution() -> Result<()> {
+        let yaml = r#"
+version: "0.4"
+env:
+  NAME: app
+  GREETING: $(echo hello $NAME)
+  SHOUT: $(echo "$GREETING" | tr a-z A-Z)
+  LITERAL: cost $(5)
+  TODAY: $(date +%Y)
+networks: {}
+commands: {}
+"#;
+        let path = create_test_file(yaml, "test_env_eval.yml")?;
+        let config = Supfile::from_file(&path)?;
+        cleanup_test_file(path);
+
+        let mut env = EnvMap::new();
+        merge_env(&mut env, config.env.as_ref().unwrap())?;
+
+        assert_eq!(env["NAME"], "app");
+        assert_eq!(env["GREETING"], "hello app");
+        assert_eq!(env["SHOUT"], "HELLO APP");
+        assert_eq!(env["LITERAL"], "cost $(5)");
+        assert_eq!(env["TODAY"], chrono::Local::now().format("%Y").to_string());
+        Ok(())
+    }
+
+    #[test]
+    fn test_env_shell_substitution_failure() {
+        let mut vars = EnvMap::new();
+        vars.insert("BROKEN".to_string(), "$(echo oops >&2; exit 3)".to_string());
+
+        let err = merge_env(&mut EnvMap::new(), &vars).unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains("BROKEN"));
+        assert!(message.contains("oops"));
+    }
+
     #[test]
     fn test_command_properties() -> Result<()> {
         let yaml = r#"
@@ -243,8 +1663,17 @@ commands:
     local: "local_command"
     run: "remote_command"
     stdin: true
-    once: true
     serial: 5
+    serial_delay: 30s
+    when: "test -f /etc/app.conf"
+    creates: /usr/bin/docker
+    removes: /tmp/installer
+    canary: true
+    canary_host: "^web"
+  migrate:
+    run: "remote_command"
+    once: true
+    once_on: random
 "#;
         let path = create_test_file(yaml, "test_cmd.yml")?;
         
@@ -255,10 +1684,436 @@ commands:
         assert_eq!(cmd.local.as_deref(), Some("local_command"));
         assert_eq!(cmd.run.as_deref(), Some("remote_command"));
         assert!(cmd.stdin);
-        assert!(cmd.once);
-        assert_eq!(cmd.serial, Some(5));
+        assert!(!cmd.once);
+        assert_eq!(cmd.once_on, OnceOn::First);
+        assert_eq!(cmd.serial, Some(Serial::Count(5)));
+        assert_eq!(cmd.serial_delay, Some(Duration::from_secs(30)));
+        assert_eq!(cmd.when.as_deref(), Some("test -f /etc/app.conf"));
+        assert_eq!(cmd.creates.as_deref(), Some("/usr/bin/docker"));
+        assert_eq!(cmd.removes.as_deref(), Some("/tmp/installer"));
+        assert!(cmd.canary);
+        assert_eq!(cmd.canary_host.as_deref(), Some("^web"));
+        assert!(config.commands["migrate"].once);
+        assert_eq!(config.commands["migrate"].once_on, OnceOn::Random);
         
         cleanup_test_file(path);
         Ok(())
     }
-} 
\ No newline at end of file
+
+    #[test]
+    fn test_once_validation() -> Result<()> {
+        let parse = |yaml: &str| serde_yaml::from_str::<Command>(yaml);
+        assert_eq!(parse("once_on: first").unwrap().once_on, OnceOn::First);
+        assert_eq!(parse("once_on: ^db-primary").unwrap().once_on, OnceOn::Regex("^db-primary".to_string()));
+
+        let yaml = r#"
+version: "0.4"
+networks: {}
+commands:
+  migrate:
+    run: ./migrate
+    once: true
+    serial: 2
+"#;
+        let path = create_test_file(yaml, "test_once_serial.yml")?;
+        let err = Supfile::from_file(&path).unwrap_err().to_string();
+        assert!(err.contains("migrate sets both once and serial"), "{}", err);
+        cleanup_test_file(path);
+
+        let yaml = r#"
+version: "0.4"
+networks: {}
+commands:
+  migrate:
+    run: ./migrate
+    once: true
+    once_on: "db[1"
+"#;
+        let path = create_test_file(yaml, "test_once_regex.yml")?;
+        let err = Supfile::from_file(&path).unwrap_err().to_string();
+        assert!(err.contains("Invalid once_on regex of command migrate"), "{}", err);
+        cleanup_test_file(path);
+
+        let yaml = r#"
+version: "0.4"
+networks: {}
+commands:
+  warm:
+    run: ./warm-cache
+    once_per_batch: true
+"#;
+        let path = create_test_file(yaml, "test_once_per_batch.yml")?;
+        let err = Supfile::from_file(&path).unwrap_err().to_string();
+        assert!(err.contains("warm sets once_per_batch without serial"), "{}", err);
+        cleanup_test_file(path);
+        Ok(())
+    }
+
+    #[test]
+    fn test_steps() -> Result<()> {
+        let kinds = |command: &Command| command.ordered_steps().iter()
+            .map(|step| match step {
+                Step::Local(_) => "local",
+                Step::Script(_) => "script",
+                Step::Run(_) => "run",
+                Step::Upload(_) => "upload",
+                Step::Template(_) => "template",
+            })
+            .collect::<Vec<_>>();
+
+        // The flat fields keep their fixed order
+        let legacy: Command = serde_yaml::from_str("{upload: [{src: a, dst: /b/}], run: up, local: make}")?;
+        assert_eq!(kinds(&legacy), ["local", "run", "upload"]);
+
+        let steps: Command = serde_yaml::from_str(r#"
+steps:
+  - upload: [{src: compose.yml, dst: /srv/app/}]
+  - run: docker compose up -d
+  - upload: [{src: nginx.conf, dst: /etc/nginx/}]
+  - local: ./notify.sh
+"#)?;
+        assert_eq!(kinds(&steps), ["upload", "run", "upload", "local"]);
+        assert!(steps.is_remote());
+        assert!(!serde_yaml::from_str::<Command>("steps: [{local: make}]")?.is_remote());
+
+        let err = serde_yaml::from_str::<Command>("steps: [{run: a, local: b}]").unwrap_err().to_string();
+        assert!(err.contains("exactly one of local, script, run, upload or template"), "{}", err);
+        assert!(serde_yaml::from_str::<Command>("steps: [{fetch: a}]").is_err());
+
+        let yaml = r#"
+version: "0.4"
+networks: {}
+commands:
+  deploy:
+    run: ./deploy
+    steps:
+      - run: ./migrate
+"#;
+        let path = create_test_file(yaml, "test_steps_and_fields.yml")?;
+        let err = Supfile::from_file(&path).unwrap_err().to_string();
+        assert!(err.contains("deploy sets both steps and"), "{}", err);
+        cleanup_test_file(path);
+        Ok(())
+    }
+
+    #[test]
+    fn test_upload_sources() -> Result<()> {
+        let upload: Upload = serde_yaml::from_str("{from_command: ./render.sh, dst: /etc/app/config.yml}")?;
+        assert!(upload.src.is_empty());
+        assert_eq!(upload.source(), "output of ./render.sh");
+        // Links and modes are kept unless asked otherwise
+        assert!(upload.preserve_permissions && !upload.follow_symlinks);
+        let upload: Upload = serde_yaml::from_str("{src: ./dist, dst: /srv/, preserve_permissions: false}")?;
+        assert!(!upload.preserve_permissions);
+
+        for (entry, message) in [
+            ("{dst: /etc/app/}", "needs src or from_command"),
+            ("{src: a, from_command: b, dst: /etc/app/}", "sets both src and from_command"),
+        ] {
+            let yaml = format!("version: \"0.4\"\nnetworks: {{}}\ncommands:\n  ship:\n    upload: [{}]\n", entry);
+            let path = create_test_file(&yaml, "test_upload_sources.yml")?;
+            let err = Supfile::from_file(&path).unwrap_err().to_string();
+            assert!(err.contains(message), "{}", err);
+            cleanup_test_file(path);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_stdin_feeds() -> Result<()> {
+        for (fields, message) in [
+            ("stdin: true, stdin_data: x", "sets both stdin and stdin_data"),
+            ("stdin_data: x, stdin_file: y", "sets both stdin_data and stdin_file"),
+            ("stdin_file: y, detach: true", "sets both detach and stdin_file"),
+        ] {
+            let yaml = format!("version: \"0.4\"\nnetworks: {{}}\ncommands:\n  seed: {{run: psql -f -, {}}}\n", fields);
+            let path = create_test_file(&yaml, "test_stdin_feeds.yml")?;
+            let err = Supfile::from_file(&path).unwrap_err().to_string();
+            assert!(err.contains(message), "{}", err);
+            cleanup_test_file(path);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_sudo_needs_run() -> Result<()> {
+        let yaml = "version: \"0.4\"\nnetworks: {}\ncommands:\n  build: {local: make, sudo: true}\n";
+        let path = create_test_file(yaml, "test_sudo_needs_run.yml")?;
+        let err = Supfile::from_file(&path).unwrap_err().to_string();
+        assert!(err.contains("Command build sets sudo without a run step"), "{}", err);
+        cleanup_test_file(path);
+        Ok(())
+    }
+
+    #[test]
+    fn test_command_aliases() -> Result<()> {
+        let yaml = "version: \"0.4\"\nnetworks: {}\ncommands:\n  deploy-app: {run: ./deploy, aliases: [rolling-update, ru]}\n";
+        let path = create_test_file(yaml, "test_command_aliases.yml")?;
+        let supfile = Supfile::from_file(&path)?;
+        cleanup_test_file(path);
+        assert_eq!(supfile.find_command("ru").map(|(name, _)| name), Some("deploy-app"));
+        assert_eq!(supfile.find_command("deploy-app").map(|(name, _)| name), Some("deploy-app"));
+        assert!(supfile.find_command("deploy").is_none());
+
+        for (commands, message) in [
+            ("  a: {run: x, aliases: [b]}\n  b: {run: y}\n", "Alias b of command a is also the name of a command"),
+            ("  a: {run: x, aliases: [c]}\n  b: {run: y, aliases: [c]}\n", "Alias c is given to both command a and command b"),
+            ("  a: {run: x, aliases: [all]}\ntargets:\n  all: [a]\n", "Alias all of command a is also the name of a target"),
+            ("  a: {run: x, aliases: [\"\"]}\n", "Command a has an empty alias"),
+        ] {
+            let yaml = format!("version: \"0.4\"\nnetworks: {{}}\ncommands:\n{}", commands);
+            let path = create_test_file(&yaml, "test_command_aliases.yml")?;
+            let err = Supfile::from_file(&path).unwrap_err().to_string();
+            cleanup_test_file(path);
+            assert_eq!(err, message);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_host_entries() -> Result<()> {
+        let network: Network = serde_yaml::from_str(r#"
+hosts:
+  - deploy@web1
+  - {host: 10.0.1.5, user: deploy, port: 2222, alias: web2}
+  - {host: 10.0.1.6, user: deploy}
+"#)?;
+        assert_eq!(network.hosts[0], HostEntry::from("deploy@web1"));
+        assert_eq!(network.hosts[0].name(), "deploy@web1");
+        assert_eq!(network.hosts[1].address(), "deploy@10.0.1.5:2222");
+        assert_eq!(network.hosts[1].name(), "web2");
+        assert_eq!(network.hosts[2].name(), "deploy@10.0.1.6");
+
+        assert!(serde_yaml::from_str::<Network>("hosts: [{host: web1, aliases: x}]").is_err());
+
+        let yaml = r#"
+version: "0.4"
+networks:
+  prod:
+    hosts:
+      - {host: "docker://app", alias: app}
+commands: {}
+"#;
+        let path = create_test_file(yaml, "test_host_entries.yml")?;
+        let err = Supfile::from_file(&path).unwrap_err().to_string();
+        assert!(err.contains("the map form is for ssh hosts"), "{}", err);
+        cleanup_test_file(path);
+        Ok(())
+    }
+
+    #[test]
+    fn test_serial_forms() {
+        let parse = |yaml: &str| serde_yaml::from_str::<Command>(yaml);
+
+        assert_eq!(parse("serial: 3").unwrap().serial, Some(Serial::Count(3)));
+        assert_eq!(parse("serial: \"25%\"").unwrap().serial, Some(Serial::Percent(25)));
+        assert_eq!(parse("serial: 100%").unwrap().serial, Some(Serial::Percent(100)));
+        assert!(parse("serial: 0").is_err());
+        assert!(parse("serial: 0%").is_err());
+        assert!(parse("serial: 150%").is_err());
+        assert!(parse("serial: half").is_err());
+
+        assert_eq!("4".parse::<Serial>(), Ok(Serial::Count(4)));
+        assert_eq!("10%".parse::<Serial>(), Ok(Serial::Percent(10)));
+        assert!("0".parse::<Serial>().is_err());
+
+        assert_eq!(Serial::Percent(25).batch_size(10), 3);
+        assert_eq!(Serial::Percent(1).batch_size(3), 1);
+        assert_eq!(Serial::Count(2).batch_size(10), 2);
+    }
+
+    #[test]
+    fn test_scalar_env_values() -> Result<()> {
+        let network: Network = serde_yaml::from_str(r#"
+env:
+  HOST_PORT: 8000
+  RATIO: 0.75
+  DEBUG: true
+  NAME: app
+  QUOTED: "010"
+  CONFIG: |
+    [server]
+    port = 8000
+"#)?;
+        let env = network.env.unwrap();
+        assert_eq!(env["HOST_PORT"], "8000");
+        assert_eq!(env["RATIO"], "0.75");
+        assert_eq!(env["DEBUG"], "true");
+        assert_eq!(env["NAME"], "app");
+        assert_eq!(env["QUOTED"], "010");
+        assert_eq!(env["CONFIG"], "[server]\nport = 8000\n");
+        assert_eq!(env.keys().collect::<Vec<_>>(), ["HOST_PORT", "RATIO", "DEBUG", "NAME", "QUOTED", "CONFIG"]);
+
+        let supfile: Supfile = serde_yaml::from_str("version: \"0.4\"\nenv: {REPLICAS: 3}\nnetworks: {}\ncommands: {}")?;
+        assert_eq!(supfile.env.unwrap()["REPLICAS"], "3");
+        let template: Template = serde_yaml::from_str("src: a\ndst: b\nvars: {WORKERS: 4, TLS: false}")?;
+        assert_eq!(template.vars["WORKERS"], "4");
+        assert_eq!(template.vars["TLS"], "false");
+
+        let err = serde_yaml::from_str::<Network>("env:\n  PORTS: [80, 443]").unwrap_err().to_string();
+        assert!(err.contains("PORTS: expected a string, number or boolean, found a list"), "{}", err);
+        let err = serde_yaml::from_str::<Network>("env:\n  DB: {host: db1}").unwrap_err().to_string();
+        assert!(err.contains("DB: expected a string, number or boolean, found a map"), "{}", err);
+        let err = serde_yaml::from_str::<Network>("env:\n  EMPTY:").unwrap_err().to_string();
+        assert!(err.contains("EMPTY: expected a string, number or boolean, found nothing"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_duration() -> Result<()> {
+        assert_eq!(parse_duration("30s")?, Duration::from_secs(30));
+        assert_eq!(parse_duration("45")?, Duration::from_secs(45));
+        assert_eq!(parse_duration("2m")?, Duration::from_secs(120));
+        assert_eq!(parse_duration("1h")?, Duration::from_secs(3600));
+        assert_eq!(parse_duration("250ms")?, Duration::from_millis(250));
+        assert!(parse_duration("soon").is_err());
+        assert!(parse_duration("5d").is_err());
+        assert!(parse_duration("").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_lock_forms() -> Result<()> {
+        let parse = |value: &str| serde_yaml::from_str::<Network>(&format!("lock: {}", value));
+        assert_eq!(parse("true")?.lock, Lock::Remote);
+        assert_eq!(parse("false")?.lock, Lock::Off);
+        assert_eq!(parse("local")?.lock, Lock::Local);
+        assert_eq!(serde_yaml::from_str::<Network>("hosts: []")?.lock, Lock::Off);
+        assert!(parse("shared").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_union_network() -> Result<()> {
+        let network = |yaml: &str| -> Result<Network> { Ok(serde_yaml::from_str(yaml)?) };
+        let members = vec![
+            ("us".to_string(), network("{hosts: [a, b], env: {REGION: us, TIER: prod}, inventory: list-us}")?),
+            ("eu".to_string(), network("{hosts: [b, c], env: {REGION: eu}, confirm: true}")?),
+        ];
+        let union = Supfile::union_network(&members)?;
+        assert_eq!(union.hosts, ["a", "b", "c"].map(|host| HostEntry::Address(host.to_string())));
+        let env = union.env.unwrap();
+        assert_eq!(env.iter().collect::<Vec<_>>(), [(&"REGION".to_string(), &"eu".to_string()), (&"TIER".to_string(), &"prod".to_string())]);
+        assert_eq!(union.inventory.as_deref(), Some("list-us"));
+        assert!(union.confirm);
+
+        let other = ("k8s".to_string(), network("{kube_context: staging, hosts: [api]}")?);
+        let error = Supfile::union_network(&[members[0].clone(), other]).unwrap_err();
+        assert!(error.to_string().contains("Networks us and k8s reach their hosts differently"), "{}", error);
+        Ok(())
+    }
+
+    #[test]
+    fn test_network_includes() -> Result<()> {
+        let supfile: Supfile = serde_yaml::from_str(r#"
+version: "0.4"
+networks:
+  us-east: {hosts: [e1], env: {REGION: us-east, TIER: prod}}
+  us-west: {hosts: [w1], env: {REGION: us-west}}
+  eu: {hosts: [eu1], env: {REGION: eu, EU_ONLY: yes}, user: ops}
+  us: {include: [us-east, us-west], env: {REGION: us}}
+  prod:
+    include: [us, eu]
+    hosts: [bastion]
+    env: {TIER: production}
+    banner: PRODUCTION
+  hosts-only: {include: [eu], include_env: false}
+commands: {}
+"#)?;
+        let names = |members: &Members| members.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>().join(",");
+
+        // Two levels deep, each network after what it includes
+        let members = supfile.network_members("prod")?;
+        assert_eq!(names(&members), "us-east,us-west,us,eu,prod");
+        assert_eq!(members[3].1.user.as_deref(), Some("ops"));
+        let union = Supfile::union_network(&members)?;
+        assert_eq!(union.hosts, ["e1", "w1", "eu1", "bastion"].map(|host| HostEntry::Address(host.to_string())));
+        assert_eq!(union.banner.as_deref(), Some("PRODUCTION"));
+
+        // Later includes win, and a network's own env wins over them all
+        let env = union.env.unwrap();
+        assert_eq!(env["REGION"], "eu");
+        assert_eq!(env["TIER"], "production");
+        assert_eq!(Supfile::union_network(&supfile.network_members("us")?)?.env.unwrap()["REGION"], "us");
+
+        assert!(Supfile::union_network(&supfile.network_members("hosts-only")?)?.env.is_none());
+        assert_eq!(names(&supfile.network_members("eu")?), "eu");
+        Ok(())
+    }
+
+    #[test]
+    fn test_network_include_errors() {
+        let load = |networks: &str| -> Result<Supfile> {
+            let path = create_test_file(&format!("version: \"0.4\"\nnetworks:\n{}commands: {{}}\n", networks), "test_includes.yml")?;
+            let supfile = Supfile::from_file(&path);
+            cleanup_test_file(path);
+            supfile
+        };
+        let error = load("  a: {include: [b]}\n  b: {include: [c]}\n  c: {include: [a]}\n").unwrap_err();
+        assert!(format!("{:#}", error).contains("Network a includes itself: a -> b -> c -> a"), "{:#}", error);
+        let error = load("  a: {include: [a]}\n").unwrap_err();
+        assert!(format!("{:#}", error).contains("Network a includes itself: a -> a"), "{:#}", error);
+        let error = load("  a: {include: [b]}\n").unwrap_err();
+        assert!(format!("{:#}", error).contains("Network a includes unknown network b"), "{:#}", error);
+        // The same network included twice is not a cycle
+        assert!(load("  a: {hosts: [h]}\n  b: {include: [a]}\n  c: {include: [a, b]}\n").is_ok());
+    }
+
+    #[test]
+    fn test_network_style() -> Result<()> {
+        let network: Network = serde_yaml::from_str("color: bright red\nbanner: \"⚠ PRODUCTION\"")?;
+        let style = network.style();
+        assert_eq!(style.color, Some(colored::Color::BrightRed));
+        assert_eq!(style.banner.as_deref(), Some("⚠ PRODUCTION"));
+        assert_eq!(serde_yaml::to_value(&network)?["color"], "bright red");
+        assert_eq!(serde_yaml::from_str::<Network>("{}")?.style(), NetworkStyle::default());
+
+        let error = serde_yaml::from_str::<Network>("color: scarlet").unwrap_err();
+        assert!(error.to_string().contains("unknown color \"scarlet\""), "{}", error);
+        Ok(())
+    }
+
+    #[test]
+    fn test_excluded_hosts() -> Result<()> {
+        let network: Network = serde_yaml::from_str("hosts: [web1, {host: web2, disabled: true}]\nexcluded_hosts: ['^db']")?;
+        let disabled = network.hosts.iter().map(HostEntry::disabled).collect::<Vec<_>>();
+        assert_eq!(disabled, [false, true]);
+        assert_eq!(network.excluded_hosts, ["^db"]);
+        // Left out when not set
+        let yaml = serde_yaml::to_string(&serde_yaml::from_str::<Network>("hosts: [{host: web2, user: app}]")?)?;
+        assert!(!yaml.contains("disabled") && !yaml.contains("excluded_hosts"), "{}", yaml);
+
+        let supfile: Supfile = serde_yaml::from_str("version: '0.4'\nnetworks: {prod: {excluded_hosts: ['web[']}}\ncommands: {}")?;
+        let error = supfile.validate().unwrap_err();
+        assert!(format!("{:#}", error).contains("Invalid excluded_hosts regex of network prod"), "{:#}", error);
+        Ok(())
+    }
+
+    #[test]
+    fn test_default_network_name() -> Result<()> {
+        let parse = |yaml: &str| -> Result<Supfile> { Ok(serde_yaml::from_str(yaml)?) };
+
+        let explicit = parse("version: '0.4'\ndefault_network: staging\nnetworks: {prod: {}, staging: {}}\ncommands: {}")?;
+        assert_eq!(explicit.default_network_name()?, "staging");
+
+        let single = parse("version: '0.4'\nnetworks: {prod: {}}\ncommands: {}")?;
+        assert_eq!(single.default_network_name()?, "prod");
+
+        let ambiguous = parse("version: '0.4'\nnetworks: {prod: {}, dev: {}}\ncommands: {}")?;
+        let err = ambiguous.default_network_name().unwrap_err().to_string();
+        assert!(err.contains("available networks: dev, prod"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn test_suggest() {
+        let names = ["deploy", "deploy-app", "build", "rolling-update", "logs"];
+
+        assert_eq!(suggest("deploi", names), ["deploy", "deploy-app"]);
+        assert_eq!(suggest("biuld", names), ["build"]);
+        assert_eq!(suggest("rolling-updte", names), ["rolling-update"]);
+        assert_eq!(suggest("log", names), ["logs"]);
+        assert!(suggest("zzzzzz", names).is_empty());
+        assert!(suggest("restart", names).is_empty());
+    }
+}
@@ -0,0 +1,194 @@
+use crate::config::Supfile;
+use crate::transport::TransportOptions;
+use colored::*;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// How a `--doctor` check came out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Ok,
+    /// Worth knowing, but runs can work
+    Warning,
+    /// Runs will fail or misbehave until it is fixed
+    Problem,
+}
+
+/// The outcome of one `--doctor` check.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Check {
+    pub name: String,
+    pub status: Status,
+    pub detail: String,
+}
+
+impl Check {
+    fn new(name: &str, status: Status, detail: impl Into<String>) -> Self {
+        Self { name: name.to_string(), status, detail: detail.into() }
+    }
+}
+
+/// sup's own version, with the commit it was built from when known.
+pub fn version() -> Check {
+    let hash = option_env!("SUP_GIT_HASH").unwrap_or("unknown commit");
+    Check::new("sup-rs", Status::Ok, format!("{} ({}, {} {})", env!("CARGO_PKG_VERSION"), hash, std::env::consts::OS, std::env::consts::ARCH))
+}
+
+/// The version `program` reports for `args`: the first line it prints on
+/// stdout or stderr (`ssh -V` uses stderr). A missing program is
+/// `missing`.
+pub fn binary_version(name: &str, mut program: Command, args: &[&str], missing: Status) -> Check {
+    let output = match program.args(args).output() {
+        Ok(output) => output,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let program = program.get_program().to_string_lossy().into_owned();
+            return Check::new(name, missing, format!("{} not found on PATH", program));
+        }
+        Err(e) => return Check::new(name, missing, format!("failed to run: {}", e)),
+    };
+    let text = [&output.stdout, &output.stderr].map(|bytes| String::from_utf8_lossy(bytes).into_owned());
+    match text.iter().flat_map(|text| text.lines()).map(str::trim).find(|line| !line.is_empty()) {
+        Some(line) => Check::new(name, Status::Ok, line),
+        None => Check::new(name, Status::Warning, format!("printed no version ({})", output.status)),
+    }
+}
+
+/// Whether the Supfile at `path` loads.
+pub fn supfile(path: &Path) -> (Check, Option<Supfile>) {
+    match Supfile::from_file(path) {
+        Ok(supfile) => {
+            let detail = format!("{} parses: {} networks, {} commands", path.display(), supfile.networks.len(), supfile.commands.len());
+            (Check::new("Supfile", Status::Ok, detail), Some(supfile))
+        }
+        Err(e) => (Check::new("Supfile", Status::Problem, format!("{:#}", e)), None),
+    }
+}
+
+/// Whether an ssh agent is reachable through `auth_sock`, the value of
+/// `SSH_AUTH_SOCK`.
+pub fn ssh_agent(auth_sock: Option<OsString>) -> Check {
+    match auth_sock.filter(|sock| !sock.is_empty()) {
+        None => Check::new("ssh agent", Status::Warning, "SSH_AUTH_SOCK is not set; keys need an identity file without a passphrase"),
+        Some(sock) if Path::new(&sock).exists() => Check::new("ssh agent", Status::Ok, Path::new(&sock).display().to_string()),
+        Some(sock) => Check::new("ssh agent", Status::Problem, format!("SSH_AUTH_SOCK points to {}, which does not exist", Path::new(&sock).display())),
+    }
+}
+
+/// Identity files named by `-i PATH` or `-o IdentityFile=PATH` in
+/// `ssh_options`, with `~/` expanded.
+fn identity_files(ssh_options: &[String]) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    let mut options = ssh_options.iter();
+    while let Some(option) = options.next() {
+        let value = match option.as_str() {
+            "-i" | "-o" => options.next().cloned(),
+            _ => option.strip_prefix("-i").or_else(|| option.strip_prefix("-o")).map(str::to_string),
+        };
+        let file = match (option.starts_with("-i"), value) {
+            (true, Some(file)) => file,
+            (false, Some(setting)) => match setting.split_once(['=', ' ']) {
+                Some((key, file)) if key.eq_ignore_ascii_case("IdentityFile") => file.trim().to_string(),
+                _ => continue,
+            },
+            (_, None) => continue,
+        };
+        files.push(match (file.strip_prefix("~/"), dirs::home_dir()) {
+            (Some(rest), Some(home)) => home.join(rest),
+            _ => PathBuf::from(file),
+        });
+    }
+    files
+}
+
+/// Whether every identity file in `ssh_options` can be read.
+pub fn identities(ssh_options: &[String]) -> Vec<Check> {
+    identity_files(ssh_options).into_iter()
+        .map(|file| match std::fs::File::open(&file) {
+            Ok(_) => Check::new("identity", Status::Ok, file.display().to_string()),
+            Err(e) => Check::new("identity", Status::Problem, format!("{} cannot be read: {}", file.display(), e)),
+        })
+        .collect()
+}
+
+/// Runs every check for the Supfile at `path` and the ssh settings of
+/// `network` (the default network when `None`).
+pub fn run(path: &Path, network: Option<&str>, ssh_override: Option<String>) -> Vec<Check> {
+    let mut checks = vec![version()];
+    let (supfile_check, supfile) = supfile(path);
+    checks.push(supfile_check);
+
+    let mut transport = match &supfile {
+        Some(supfile) => {
+            let name = network.map(str::to_string).or_else(|| supfile.default_network_name().ok());
+            let network = name.and_then(|name| supfile.networks.get(&name).cloned()).unwrap_or_default();
+            TransportOptions::new(supfile, &network)
+        }
+        None => TransportOptions::default(),
+    };
+    if let Some(ssh) = ssh_override {
+        transport.ssh_binary = Some(ssh);
+    }
+    checks.push(binary_version("ssh", transport.ssh_program(), &["-V"], Status::Problem));
+    // Archives are built in-process; hosts unpack them with their own tar
+    checks.push(binary_version("tar", Command::new("tar"), &["--version"], Status::Warning));
+    checks.push(ssh_agent(std::env::var_os("SSH_AUTH_SOCK")));
+    checks.extend(identities(&transport.ssh_options));
+    checks
+}
+
+/// Formats the checks one per line, aligned by name.
+pub fn report(checks: &[Check]) -> Vec<String> {
+    let width = checks.iter().map(|check| check.name.len()).max().unwrap_or(0);
+    checks.iter()
+        .map(|check| {
+            let status = match check.status {
+                Status::Ok => "OK     ".green(),
+                Status::Warning => "WARNING".yellow(),
+                Status::Problem => "PROBLEM".red(),
+            };
+            format!("{} {:<width$} {}", status, check.name, check.detail, width = width)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_binary_version() {
+        let mut sh = Command::new("sh");
+        sh.args(["-c", "echo; echo 'OpenSSH_9.6p1, OpenSSL 3.0.13' >&2"]);
+        assert_eq!(binary_version("ssh", sh, &[], Status::Problem).detail, "OpenSSH_9.6p1, OpenSSL 3.0.13");
+
+        let missing = binary_version("rsync", Command::new("sup-no-such-binary"), &["--version"], Status::Warning);
+        assert_eq!(missing, Check::new("rsync", Status::Warning, "sup-no-such-binary not found on PATH"));
+    }
+
+    #[test]
+    fn test_ssh_agent() {
+        assert_eq!(ssh_agent(None).status, Status::Warning);
+        assert_eq!(ssh_agent(Some(OsString::new())).status, Status::Warning);
+        assert_eq!(ssh_agent(Some(std::env::temp_dir().into_os_string())).status, Status::Ok);
+        assert_eq!(ssh_agent(Some("/nonexistent/agent.sock".into())).status, Status::Problem);
+    }
+
+    #[test]
+    fn test_identities() {
+        let options = ["-i", "/etc/hostname", "-o", "IdentityFile=/nonexistent/id_ed25519", "-oidentityfile /tmp", "-p", "2222"]
+            .map(str::to_string);
+        assert_eq!(identity_files(&options), ["/etc/hostname", "/nonexistent/id_ed25519", "/tmp"].map(PathBuf::from));
+        let checks = identities(&options[2..4]);
+        assert_eq!(checks.len(), 1);
+        assert_eq!(checks[0].status, Status::Problem);
+        assert!(checks[0].detail.starts_with("/nonexistent/id_ed25519 cannot be read"), "{}", checks[0].detail);
+    }
+
+    #[test]
+    fn test_report() {
+        colored::control::set_override(false);
+        let checks = [Check::new("ssh", Status::Ok, "OpenSSH_9.6p1"), Check::new("ssh agent", Status::Warning, "not set")];
+        assert_eq!(report(&checks), ["OK      ssh       OpenSSH_9.6p1", "WARNING ssh agent not set"]);
+    }
+}
@@ -1,80 +1,330 @@
-use crate::config::{Command, Network, Upload};
+use crate::children;
+use crate::clock;
+use crate::config::{Command, Compression, EnvMap, HostEntry, Members, Network, OnceOn, Serial, Step, Template, Upload, WaitFor};
+use crate::exit::ExitCode;
+use crate::filter::HostFilter;
+use crate::host_keys::Observed;
+use crate::hosts;
+use crate::output::{self, HostEvent, NetworkStyle, OutputMode, OutputPrinter, OutputStream};
+use crate::ping::PingResult;
+use crate::plan::{ActionPlan, StepPlan};
+use crate::prompt;
+use crate::shell;
+use crate::sink::ConsoleSink;
+use crate::summary::{HostResult, HostStatus, Summary, LOCAL_HOST};
+use crate::transport::{forward_lines, ExitCodeError, OutputLine, SessionCancelled, Target, Transport};
+use crate::upload::{self, Payload, UploadPlan};
 use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
 use colored::*;
 use regex::Regex;
-use std::io::{BufRead, BufReader, Read, Write};
-use std::path::Path;
-use std::process::{Command as ProcessCommand, Stdio};
-use tokio::sync::mpsc;
-use tokio::task::JoinHandle;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::process::Command as AsyncCommand;
+use tokio::sync::{mpsc, OnceCell};
 use tracing::{debug, info, warn};
-use shell_quote;
 
-#[derive(Debug, Clone)]
-struct SshHost {
-    username: String,
-    hostname: String,
+/// Run-wide settings taken from the command line.
+#[derive(Debug, Clone, Default)]
+pub struct ExecutorOptions {
+    /// Host list or regex hosts must match to be included.
+    pub only: Option<String>,
+    /// Host list or regex of hosts to exclude.
+    pub except: Option<String>,
+    /// Match `only` and `except` against the whole `user@host:port` entry
+    /// instead of the hostname.
+    pub only_full: bool,
+    /// User every ssh host is reached as, whatever its entry says.
+    pub user: Option<String>,
+    pub disable_prefix: bool,
+    /// Print remote stderr like stdout instead of in red, or on the local
+    /// stderr with `disable_prefix`.
+    pub combine_output: bool,
+    /// Answer interactive prompts (such as canary confirmation) with yes.
+    pub assume_yes: bool,
+    /// Keep only the first N hosts after filtering and ordering.
+    pub limit: Option<usize>,
+    pub order: HostOrder,
+    /// Seed for `HostOrder::Shuffle`; random when unset.
+    pub seed: Option<u64>,
+    /// Run every command as if it had `quiet: true`.
+    pub quiet: bool,
+    pub output_mode: OutputMode,
+    /// Bytes of output kept in memory per host in `OutputMode::Group`.
+    pub group_buffer: Option<usize>,
+    /// Batch size used for every command instead of its `serial`.
+    pub serial: Option<Serial>,
+    /// Run every command on the first host only.
+    pub once: bool,
+    /// Ignore `serial` and run every command on all hosts at once.
+    pub parallel: bool,
+    /// Arguments given after `--`, passed to commands as `SUP_ARGS`.
+    pub args: Vec<String>,
+    /// Upload directories whole, ignoring `.supignore` files.
+    pub no_supignore: bool,
+    /// The Supfile of the run; its directory is searched for a fallback
+    /// `.supignore`.
+    pub supfile: Option<PathBuf>,
+    /// The env holds only the allow-listed part of sup's environment, so
+    /// local processes get nothing else from it, `BASE_ENV` included.
+    pub no_env_inherit: bool,
+    /// Run every command as if it had `allow_empty_hosts: true`.
+    pub allow_empty_hosts: bool,
+    /// Where printed host output is teed to.
+    pub sink: ConsoleSink,
 }
 
-impl SshHost {
-    fn parse(host_str: &str) -> Result<Self> {
-        // Parse user@host
-        let (username, hostname) = host_str.split_once('@')
-            .context("Host must be in format user@host")?;
+/// Order hosts run in, applied after filtering and before batching.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum HostOrder {
+    /// Keep the order of the network's hosts and inventory
+    #[default]
+    Inventory,
+    /// Sort hosts alphabetically
+    Sorted,
+    /// Shuffle hosts, reproducibly when a seed is given
+    Shuffle,
+}
 
-        Ok(Self {
-            username: username.to_string(),
-            hostname: hostname.to_string(),
-        })
-    }
+/// Variables of sup's own environment that every local process (inventory,
+/// `local:` and `script:`) gets under the run's env, so the tools they call
+/// are found however the env was built.
+const BASE_ENV: &[&str] = &["PATH", "HOME", "USER", "LOGNAME", "SHELL", "LANG", "TMPDIR", "SSH_AUTH_SOCK"];
+
+/// Upper bound on a single pre-flight reachability check.
+const PREFLIGHT_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Pause before a failed upload is started over, multiplied by the attempt.
+const UPLOAD_RETRY_DELAY: Duration = Duration::from_secs(1);
+/// Summary entry name for hosts dropped by the pre-flight check.
+const PREFLIGHT: &str = "preflight";
+/// Exit status of ssh itself when it could not reach the host.
+const SSH_FAILED: i32 = 255;
+/// Variables sup sets for the run that remote commands see too, besides
+/// the `--` arguments and `SUP_HOST_INDEX`.
+const SUP_VARS: [&str; 5] = ["SUP_TIME", "SUP_USER", "SUP_NETWORK", "SUP_HOSTS", "SUP_HOST_COUNT"];
+
+/// `info!` unless the executor runs a quiet command, whose progress is only
+/// logged at debug level.
+macro_rules! progress {
+    ($executor:expr, $($arg:tt)+) => {
+        if $executor.quiet { debug!($($arg)+) } else { info!($($arg)+) }
+    };
+}
 
-    fn to_string(&self) -> String {
-        format!("{}@{}", self.username, self.hostname)
-    }
+/// Read-only state shared by an executor and all of its per-host tasks.
+#[derive(Debug, Clone)]
+struct ExecutorInner {
+    network: Network,
+    transport: Arc<dyn Transport>,
+    env: EnvMap,
+    /// Variables of `env` exported to remote commands: those the Supfile,
+    /// the network, `-e` and `--secret` set, not the inherited ones.
+    exported: BTreeSet<String>,
+    only: Option<HostFilter>,
+    except: Option<HostFilter>,
+    options: ExecutorOptions,
+    /// Shuffle seed fixed for the run so every resolve sees the same order.
+    seed: u64,
+    /// Networks a union run (`prod-us,prod-eu`) takes its hosts from, each
+    /// with its own hosts, inventory and user; empty for a single network.
+    members: Members,
+    /// Hosts printed by each source's inventory command, run at most once.
+    inventory: OnceCell<Vec<Vec<String>>>,
 }
 
+/// Runs commands against a network. Clones are cheap and share the same
+/// settings and summary, so one is moved into every per-host task.
 #[derive(Debug, Clone)]
 pub struct Executor {
-    network: Network,
-    env: std::collections::HashMap<String, String>,
-    only: Option<Regex>,
-    except: Option<Regex>,
-    disable_prefix: bool,
+    inner: Arc<ExecutorInner>,
+    /// Hosts dropped from the run by the pre-flight check.
+    unreachable: Arc<Vec<String>>,
+    /// Hosts the run is restricted to by `--retry-failed`.
+    retry: Option<Arc<Vec<String>>>,
+    /// Hosts of the running command in run order; a session's position
+    /// among them is its `SUP_HOST_INDEX`.
+    running: Arc<Vec<Target>>,
+    /// Whether the running command is quiet, which drops its progress
+    /// logging to debug level.
+    quiet: bool,
+    summary: Summary,
 }
 
 impl Executor {
     pub fn new(
         network: Network,
-        env: std::collections::HashMap<String, String>,
-        only: Option<String>,
-        except: Option<String>,
-        disable_prefix: bool,
+        transport: Arc<dyn Transport>,
+        mut env: EnvMap,
+        options: ExecutorOptions,
     ) -> Result<Self> {
-        let only = only.map(|r| Regex::new(&r)).transpose()?;
-        let except = except.map(|r| Regex::new(&r)).transpose()?;
+        env.extend(args_env(&options.args));
+        let only = options.only.as_deref().map(HostFilter::parse).transpose()?;
+        let except = options.except.as_deref().map(HostFilter::parse).transpose()?;
         
         Ok(Self {
-            network,
-            env,
-            only,
-            except,
-            disable_prefix,
+            inner: Arc::new(ExecutorInner {
+                network,
+                transport,
+                env,
+                exported: BTreeSet::new(),
+                only,
+                except,
+                seed: options.seed.unwrap_or_else(|| fastrand::u64(..)),
+                options,
+                members: Vec::new(),
+                inventory: OnceCell::new(),
+            }),
+            unreachable: Arc::default(),
+            retry: None,
+            running: Arc::default(),
+            quiet: false,
+            summary: Summary::default(),
+        })
+    }
+
+    /// An executor for another network with the same options, recording
+    /// into the same summary. Used for target steps that override the network.
+    pub fn for_network(
+        &self,
+        network: Network,
+        transport: Arc<dyn Transport>,
+        env: EnvMap,
+    ) -> Result<Self> {
+        let mut executor = Self::new(network, transport, env, self.inner.options.clone())?;
+        executor.summary = self.summary.clone();
+        Ok(executor)
+    }
+
+    /// Takes the hosts from each of `members` instead, remembering which
+    /// network each came from. `network` stays the merged view of them.
+    pub fn union_of(mut self, members: Members) -> Self {
+        let mut inner = (*self.inner).clone();
+        inner.members = members;
+        self.inner = Arc::new(inner);
+        self
+    }
+
+    /// Exports the variables `names` of the env to remote commands.
+    pub fn exporting<'a>(mut self, names: impl IntoIterator<Item = &'a str>) -> Self {
+        let mut inner = (*self.inner).clone();
+        inner.exported = names.into_iter().map(str::to_string).collect();
+        self.inner = Arc::new(inner);
+        self
+    }
+
+    /// Forgets the hosts of the inventory command so the next resolve runs
+    /// it again.
+    pub fn refresh_inventory(&mut self) {
+        let mut inner = (*self.inner).clone();
+        inner.inventory = OnceCell::new();
+        self.inner = Arc::new(inner);
+    }
+
+    /// Changes options and env between commands, as the REPL's
+    /// meta-commands do. Hosts from the inventory command are kept.
+    pub fn reconfigure(
+        &mut self,
+        change: impl FnOnce(&mut ExecutorOptions, &mut EnvMap),
+    ) -> Result<()> {
+        let mut inner = (*self.inner).clone();
+        change(&mut inner.options, &mut inner.env);
+        inner.only = inner.options.only.as_deref().map(HostFilter::parse).transpose()?;
+        inner.except = inner.options.except.as_deref().map(HostFilter::parse).transpose()?;
+        self.inner = Arc::new(inner);
+        Ok(())
+    }
+
+    pub fn transport(&self) -> Arc<dyn Transport> {
+        self.inner.transport.clone()
+    }
+
+    pub fn summary(&self) -> &Summary {
+        &self.summary
+    }
+
+    /// The merged env commands run with.
+    pub fn env(&self) -> &EnvMap {
+        &self.inner.env
+    }
+
+    /// Networks hosts are resolved from, named for union runs.
+    fn sources(&self) -> Vec<(Option<&str>, &Network)> {
+        if self.inner.members.is_empty() {
+            return vec![(None, &self.inner.network)];
+        }
+        self.inner.members.iter().map(|(name, network)| (Some(name.as_str()), network)).collect()
+    }
+
+    /// The entry for a resolved host with an alias, and its network.
+    fn aliased(&self, host: &str) -> Option<(&HostEntry, &Network)> {
+        self.sources().into_iter().find_map(|(_, network)| {
+            network.hosts.iter().find(|entry| entry.alias() == Some(host)).map(|entry| (entry, network))
         })
     }
 
+    fn with_user(&self, host: &str) -> String {
+        self.with_user_of(host, &self.inner.network)
+    }
+
+    /// `host` as `user@host` with the user it is reached as: `--user`, else
+    /// the user of the host string, the `user` of `network` (or the
+    /// Supfile's), then the local user name. Anything that isn't an ssh
+    /// host, like docker and kubectl hosts or a path, is kept as is.
+    fn with_user_of(&self, host: &str, network: &Network) -> String {
+        let (user, hostname) = match host.split_once('@') {
+            Some((user, hostname)) => (Some(user), hostname),
+            None => (None, host),
+        };
+        if hostname.is_empty() || hostname.contains(|c: char| c == '/' || c.is_whitespace()) {
+            return host.to_string();
+        }
+        let user = self.inner.options.user.as_deref()
+            .or(user)
+            .map(str::to_string)
+            .or_else(|| network.user.clone())
+            .unwrap_or_else(whoami::username);
+        format!("{}@{}", user, hostname)
+    }
+
+    /// The target a resolved host connects to; aliased hosts are reached at
+    /// their entry's address and keep the alias as their name.
+    pub fn target(&self, host: &str) -> Result<Target> {
+        let Some((entry, network)) = self.aliased(host) else {
+            let host = self.with_user(host);
+            return Target::parse(&host).with_context(|| format!("Invalid host {}", host));
+        };
+        let address = self.with_user_of(&entry.address(), network);
+        let mut target = Target::parse(&address).with_context(|| format!("Invalid host {} ({})", address, host))?;
+        if let Target::Ssh(ssh) = &mut target {
+            ssh.alias = Some(host.to_string());
+        }
+        Ok(target)
+    }
+
     fn filter_hosts(&self, hosts: &[String]) -> Vec<String> {
         hosts.iter()
             .filter(|host| {
+                // Aliased hosts match by alias or address
+                let address = self.aliased(host).map(|(entry, network)| self.with_user_of(&entry.address(), network));
+                let names = [Some(host.as_str()), address.as_deref()];
+                let full = self.inner.options.only_full;
+                let is_match = |filter: &HostFilter| names.iter().flatten().any(|name| filter.is_match(name, full));
+
                 // Apply --only filter
-                if let Some(only) = &self.only {
-                    if !only.is_match(host) {
+                if let Some(only) = &self.inner.only {
+                    if !is_match(only) {
                         return false;
                     }
                 }
                 
                 // Apply --except filter
-                if let Some(except) = &self.except {
-                    if except.is_match(host) {
+                if let Some(except) = &self.inner.except {
+                    if is_match(except) {
                         return false;
                     }
                 }
@@ -85,472 +335,2813 @@ impl Executor {
             .collect()
     }
 
-    async fn resolve_hosts(&self) -> Result<Vec<String>> {
-        let mut hosts = Vec::new();
+    async fn run_inventory(&self, inventory: &str) -> Result<Vec<String>> {
+        debug!("Running inventory command: {}", output::redact(inventory));
+        let output = self.local_shell(inventory).output().await?;
 
-        // Add static hosts
-        hosts.extend(self.network.hosts.clone());
-
-        // Run inventory command if present
-        if let Some(inventory) = &self.network.inventory {
-            debug!("Running inventory command: {}", inventory);
-            let output = ProcessCommand::new("sh")
-                .arg("-c")
-                .arg(inventory)
-                .env_clear()
-                .envs(&self.env)
-                .output()?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            anyhow::bail!("Inventory command failed: {}", stderr);
+        }
 
-            if !output.status.success() {
-                let stderr = String::from_utf8_lossy(&output.stderr);
-                anyhow::bail!("Inventory command failed: {}", stderr);
-            }
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        Ok(stdout.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(str::to_string)
+            .collect())
+    }
 
-            let stdout = String::from_utf8_lossy(&output.stdout);
-            for line in stdout.lines() {
-                if !line.trim().is_empty() {
-                    hosts.push(line.trim().to_string());
+    /// Every host of the network before filters, with the member network
+    /// it came from in union runs and whether the Supfile excludes it. A
+    /// host several members list is kept once, under the first.
+    async fn listed_hosts(&self) -> Result<Vec<(String, Option<&str>, bool)>> {
+        // Run the inventory commands once per run; later resolves reuse their hosts
+        let inventories = self.inner.inventory
+            .get_or_try_init(|| async {
+                let mut inventories = Vec::new();
+                for (_, network) in self.sources() {
+                    inventories.push(match &network.inventory {
+                        Some(inventory) => self.run_inventory(inventory).await?,
+                        None => Vec::new(),
+                    });
+                }
+                Ok::<_, anyhow::Error>(inventories)
+            })
+            .await?;
+
+        let mut hosts: Vec<(String, Option<&str>, bool)> = Vec::new();
+        for ((name, network), inventory_hosts) in self.sources().into_iter().zip(inventories) {
+            let excluded = network.excluded_hosts.iter()
+                .map(|pattern| Regex::new(pattern).with_context(|| format!("Invalid excluded_hosts regex {}", pattern)))
+                .collect::<Result<Vec<_>>>()?;
+            let disabled = network.hosts.iter().filter(|entry| entry.disabled()).map(HostEntry::name).collect::<Vec<_>>();
+            // Static hosts, expanding ranges and CIDR blocks, then the inventory's
+            let network_hosts = hosts::expand_all(&network.hosts, self.inner.options.assume_yes)?;
+            for host in network_hosts.into_iter().chain(inventory_hosts.iter().cloned()) {
+                let is_disabled = disabled.contains(&host);
+                // Every ssh host gets its effective user, so output shows who runs
+                let host = if self.aliased(&host).is_some() { host } else { self.with_user_of(&host, network) };
+                if name.is_none() || !hosts.iter().any(|(known, _, _)| *known == host) {
+                    let is_excluded = is_disabled || excluded.iter().any(|pattern| pattern.is_match(&host));
+                    hosts.push((host, name, is_excluded));
                 }
             }
         }
+        Ok(hosts)
+    }
 
-        // Apply host filters
-        Ok(self.filter_hosts(&hosts))
+    /// The hosts of `listed_hosts` the Supfile does not exclude.
+    async fn sourced_hosts(&self) -> Result<Vec<(String, Option<&str>)>> {
+        Ok(self.listed_hosts().await?.into_iter()
+            .filter(|(_, _, excluded)| !excluded)
+            .map(|(host, name, _)| (host, name))
+            .collect())
     }
 
-    pub async fn execute_local(&self, cmd: &str) -> Result<()> {
-        println!("{} {}", "LOCAL".green(), cmd);
-        
-        let status = ProcessCommand::new("sh")
-            .arg("-c")
-            .arg(cmd)
-            .env_clear()
-            .envs(&self.env)
-            .status()?;
+    /// Hosts left out by `disabled: true` or the network's
+    /// `excluded_hosts`, whatever the command-line filters select.
+    pub async fn excluded_hosts(&self) -> Result<Vec<String>> {
+        Ok(self.listed_hosts().await?.into_iter()
+            .filter(|(_, _, excluded)| *excluded)
+            .map(|(host, _, _)| host)
+            .collect())
+    }
 
-        if !status.success() {
-            anyhow::bail!("Local command failed with status: {}", status);
+    /// The network each resolved host came from, for union runs; empty
+    /// otherwise.
+    pub async fn host_networks(&self) -> Result<BTreeMap<String, String>> {
+        if self.inner.members.is_empty() {
+            return Ok(BTreeMap::new());
         }
-        Ok(())
+        let resolved = self.resolve_hosts().await?;
+        Ok(self.sourced_hosts().await?.into_iter()
+            .filter(|(host, _)| resolved.contains(host))
+            .filter_map(|(host, name)| Some((host, name?.to_string())))
+            .collect())
     }
 
-    pub async fn execute_script(&self, script: &str) -> Result<()> {
-        let script_path = Path::new(script);
-        if !script_path.exists() {
-            anyhow::bail!("Script file does not exist: {}", script);
+    /// Resolved hosts of the member network `name` in a union run, or all
+    /// resolved hosts otherwise.
+    pub async fn member_hosts(&self, name: &str) -> Result<Vec<String>> {
+        let mut hosts = self.resolve_hosts().await?;
+        if !self.inner.members.is_empty() {
+            let networks = self.host_networks().await?;
+            hosts.retain(|host| networks.get(host).is_some_and(|network| network == name));
         }
+        Ok(hosts)
+    }
 
-        println!("{} {}", "SCRIPT".green(), script);
-        
-        let status = ProcessCommand::new("sh")
-            .arg(script)
-            .env_clear()
-            .envs(&self.env)
-            .status()?;
+    pub async fn resolve_hosts(&self) -> Result<Vec<String>> {
+        let mut hosts = self.sourced_hosts().await?.into_iter()
+            .map(|(host, _)| host)
+            .collect::<Vec<_>>();
 
-        if !status.success() {
-            anyhow::bail!("Script failed with status: {}", status);
+        // Apply host filters, then ordering and the limit
+        hosts.retain(|host| !self.unreachable.contains(host));
+        if let Some(retry) = &self.retry {
+            hosts.retain(|host| retry.contains(host));
+        }
+        let mut hosts = self.filter_hosts(&hosts);
+        self.order_hosts(&mut hosts);
+        if let Some(limit) = self.inner.options.limit {
+            hosts.truncate(limit);
+        }
+        Ok(hosts)
+    }
+
+    /// Restricts the run to `hosts`, dropping with a warning any that the
+    /// network no longer resolves.
+    pub async fn retry_hosts(&mut self, hosts: Vec<String>) -> Result<()> {
+        let resolved = self.resolve_hosts().await?;
+        for host in hosts.iter().filter(|host| !resolved.contains(host)) {
+            warn!("Host {} from the previous report is no longer resolved; dropping it", host);
         }
+        self.retry = Some(Arc::new(hosts));
         Ok(())
     }
 
-    pub async fn execute_ssh(&self, cmd: &str, interactive: bool, serial: Option<usize>, once: bool) -> Result<()> {
-        let hosts = self.resolve_hosts().await?;
-        
-        if hosts.is_empty() {
-            warn!("No hosts matched the filters");
-            return Ok(());
+    fn order_hosts(&self, hosts: &mut [String]) {
+        match self.inner.options.order {
+            HostOrder::Inventory => {}
+            HostOrder::Sorted => hosts.sort(),
+            HostOrder::Shuffle => fastrand::Rng::with_seed(self.inner.seed).shuffle(hosts),
         }
+    }
 
-        if interactive {
-            // For interactive mode, we only support one host at a time
-            if hosts.len() > 1 {
-                anyhow::bail!("Interactive mode only supports one host at a time");
-            }
-            let host = SshHost::parse(&hosts[0])?;
-            self.handle_interactive_session(&host, cmd).await
-        } else if once {
-            // For once mode, only run on the first host
-            if let Some(host) = hosts.first() {
-                let host = SshHost::parse(host)?;
-                self.handle_ssh_session(&host, cmd, None).await?;
-            }
-            Ok(())
-        } else if let Some(batch_size) = serial {
-            // For serial mode, run on hosts in batches
-            for chunk in hosts.chunks(batch_size) {
-                let mut handles = Vec::new();
-                for host in chunk {
-                    let host = SshHost::parse(host)?;
-                    let cmd = cmd.to_string();
-                    let (tx, mut rx) = mpsc::channel(32);
-                    let executor = self.clone();
-                    
-                    let handle = tokio::spawn(async move {
-                        if let Err(e) = executor.handle_ssh_session(&host, &cmd, Some(tx)).await {
-                            eprintln!("Error on host {}: {}", host.to_string(), e);
-                        }
-                    });
-                    handles.push((handle, rx));
-                }
+    /// Checks that every resolved host is reachable by running `true` over
+    /// the transport, and prints a report. Unreachable hosts abort the run,
+    /// or with `skip_unreachable` are dropped from it and reported as skipped.
+    pub async fn preflight(&mut self, skip_unreachable: bool) -> Result<()> {
+        let mut handles = Vec::new();
+        for host_str in self.resolve_hosts().await? {
+            let host = self.target(&host_str)?;
+            let transport = self.inner.transport.clone();
+            handles.push(tokio::spawn(async move {
+                let error = probe(transport.as_ref(), &host).await.err();
+                (host_str, error)
+            }));
+        }
 
-                // Process output from all hosts in this batch
-                for (handle, mut rx) in handles {
-                    while let Some((host, output)) = rx.recv().await {
-                        if self.disable_prefix {
-                            print!("{}", output);
-                        } else {
-                            println!("{} {}", host.blue(), output);
-                        }
-                    }
-                    handle.await?;
+        let mut unreachable = Vec::new();
+        for handle in handles {
+            match handle.await? {
+                (host, None) => println!("{} {}", "REACHABLE  ".green(), host),
+                (host, Some(error)) => {
+                    println!("{} {}: {}", "UNREACHABLE".red(), host, output::mask(&error));
+                    unreachable.push(host);
                 }
             }
-            Ok(())
-        } else {
-            // For parallel mode, run on all hosts at once
-            self.handle_parallel_sessions(cmd).await
         }
-    }
 
-    pub async fn execute_upload(&self, uploads: &[Upload]) -> Result<()> {
-        debug!("Starting upload process for {} files", uploads.len());
-        let hosts = self.resolve_hosts().await?;
-        
-        for host_str in hosts {
-            let host = SshHost::parse(&host_str)?;
-            for upload in uploads {
-                self.handle_upload(&host, upload).await?;
-            }
+        if unreachable.is_empty() {
+            return Ok(());
+        }
+        if !skip_unreachable {
+            anyhow::bail!(ExitCode::Unreachable.error(format!(
+                "{} hosts unreachable: {}; pass --skip-unreachable to run without them",
+                unreachable.len(),
+                unreachable.join(", ")
+            )));
+        }
+        for host in &unreachable {
+            warn!("Skipping unreachable host {}", host);
+            self.summary.record(PREFLIGHT, host, HostStatus::Skipped("unreachable".to_string()));
         }
+        self.unreachable = Arc::new(unreachable);
         Ok(())
     }
 
-    async fn ensure_remote_dir(&self, host: &SshHost, dir: &str) -> Result<()> {
-        debug!("Ensuring remote directory exists: {}", dir);
-        let mut ssh_cmd = ProcessCommand::new("ssh");
-        ssh_cmd
-            .arg(&host.to_string())
-            .arg(format!("mkdir -p '{}'", dir))
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped());
+    /// Times `count` runs of `true` on every resolved host in parallel, over
+    /// the same transport and options as a real run. A host's probes run one
+    /// after another and stop at the first failure.
+    pub async fn ping(&self, count: usize) -> Result<Vec<PingResult>> {
+        let mut handles = Vec::new();
+        for host_str in self.resolve_hosts().await? {
+            let host = self.target(&host_str)?;
+            let transport = self.inner.transport.clone();
+            handles.push(tokio::spawn(async move {
+                let mut result = PingResult { host: host_str, samples: Vec::new(), error: None };
+                for _ in 0..count {
+                    let started = Instant::now();
+                    match probe(transport.as_ref(), &host).await {
+                        Ok(()) => result.samples.push(started.elapsed()),
+                        Err(error) => {
+                            result.error = Some(error);
+                            break;
+                        }
+                    }
+                }
+                result
+            }));
+        }
 
-        let output = ssh_cmd.output()?;
-        if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            anyhow::bail!("Failed to create remote directory: {}", stderr);
+        let mut results = Vec::new();
+        for handle in handles {
+            results.push(handle.await?);
         }
-        Ok(())
+        Ok(results)
     }
 
-    async fn handle_upload(&self, host: &SshHost, upload: &Upload) -> Result<()> {
-        let src_path = Path::new(&upload.src);
-        if !src_path.exists() {
-            anyhow::bail!("Source path does not exist: {}", upload.src);
+    /// Fingerprints of the keys the resolved ssh hosts present, by
+    /// `host[:port]`, each read in parallel over a connection of its own.
+    pub async fn host_keys(&self) -> Result<BTreeMap<String, Observed>> {
+        let mut handles = Vec::new();
+        for host_str in self.resolve_hosts().await? {
+            let host = self.target(&host_str)?;
+            let Target::Ssh(ssh_host) = &host else {
+                continue;
+            };
+            let hostname = ssh_host.hostname.clone();
+            let transport = self.inner.transport.clone();
+            handles.push(tokio::spawn(async move {
+                let key = match transport.host_key(&host).await {
+                    Ok(Some(fingerprint)) => Ok(fingerprint),
+                    Ok(None) => Err("ssh did not show a host key".to_string()),
+                    Err(e) => Err(output::mask(&format!("{:#}", e))),
+                };
+                (hostname, key)
+            }));
         }
 
-        info!("Uploading {} to {}:{}", upload.src, host.to_string(), upload.dst);
-
-        // Ensure remote directory exists
-        self.ensure_remote_dir(host, &upload.dst).await?;
-
-        // Get source file/directory info
-        let src_metadata = src_path.metadata()?;
-        debug!("Source metadata: {:?}", src_metadata);
-
-        // Create tar process to read from source
-        let mut tar_cmd = ProcessCommand::new("tar");
-        tar_cmd
-            .arg("-czf")
-            .arg("-")
-            .arg("-C")
-            .arg(src_path.parent().unwrap_or_else(|| Path::new(".")))
-            .arg(src_path.file_name().unwrap())
-            .stdout(Stdio::piped());
+        let mut keys = BTreeMap::new();
+        for handle in handles {
+            let (hostname, key) = handle.await?;
+            keys.insert(hostname, key);
+        }
+        Ok(keys)
+    }
 
-        debug!("Running tar command: {:?}", tar_cmd);
-        let mut tar_process = tar_cmd.spawn()?;
-        let tar_output = tar_process.stdout.take()
-            .context("Failed to get tar stdout")?;
+    /// `cmd` run through the local shell with the run's env, killed if the
+    /// awaiting future is dropped.
+    fn local_shell(&self, cmd: &str) -> AsyncCommand {
+        debug!("Running local command: {}", output::redact(cmd));
+        self.local_process(shell::command(cmd))
+    }
 
-        // Create SSH process to write to destination
-        let mut ssh_cmd = ProcessCommand::new("ssh");
-        ssh_cmd
-            .arg(&host.to_string())
-            .arg(format!("cd '{}' && tar xzf -", upload.dst))
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped());
+    fn local_process(&self, process: std::process::Command) -> AsyncCommand {
+        let mut process = AsyncCommand::from(process);
+        let base = BASE_ENV.iter()
+            .filter(|_| !self.inner.options.no_env_inherit)
+            .filter_map(|key| Some((*key, std::env::var(key).ok()?)));
+        process
+            .env_clear()
+            .envs(base)
+            .envs(&self.inner.env)
+            .kill_on_drop(true);
+        process
+    }
 
-        debug!("Running SSH command: {:#?}", ssh_cmd);
-        let mut ssh_process = ssh_cmd.spawn()?;
-        let mut ssh_input = ssh_process.stdin.take()
-            .context("Failed to get SSH stdin")?;
+    /// Runs a local process to completion. Its output goes through the
+    /// host output as the `local` host, unless the command inherits stdio;
+    /// quiet commands show it only if the process fails.
+    async fn local_status(&self, mut process: AsyncCommand, command: &Command) -> Result<ExitStatus> {
+        if command.inherit_stdio {
+            return if command.quiet { quiet_status(process).await } else { Ok(process.status().await?) };
+        }
 
-        // Copy tar output to SSH input
-        debug!("Starting file transfer");
-        let bytes_copied = std::io::copy(&mut BufReader::new(tar_output), &mut ssh_input)?;
-        debug!("Transferred {} bytes", bytes_copied);
-        drop(ssh_input); // Close stdin to signal EOF
+        let started = Instant::now();
+        let mut child = children::spawn(process.stdout(Stdio::piped()).stderr(Stdio::piped()))?;
+        let pid = child.id();
+        let stdout = child.stdout.take().context("Failed to capture stdout")?;
+        let stderr = child.stderr.take().context("Failed to capture stderr")?;
+
+        let mut printer = self.output_printer().quiet(command.quiet);
+        let (tx, mut lines) = mpsc::channel::<OutputLine>(32);
+        let print = async {
+            while let Some((host, stream, line)) = lines.recv().await {
+                printer.line(&host, stream, &line);
+            }
+        };
+        let (forwarded, ()) = tokio::join!(forward_lines(LOCAL_HOST, stdout, stderr, Some(tx)), print);
+        let status = child.wait().await?;
+        children::reaped(pid);
+        forwarded?;
+
+        printer.event(HostEvent::Done {
+            host: LOCAL_HOST.to_string(),
+            error: (!status.success()).then(|| status.to_string()),
+            elapsed: started.elapsed(),
+        });
+        printer.finish();
+        Ok(status)
+    }
 
-        // Wait for both processes and capture output
-        let tar_status = tar_process.wait()?;
-        if !tar_status.success() {
-            anyhow::bail!("Tar command failed with status: {}", tar_status);
+    /// Runs `cmd` on this machine.
+    pub async fn execute_local(&self, cmd: &str, command: &Command) -> Result<()> {
+        if !command.quiet {
+            println!("{} {}", "LOCAL".green(), output::mask(cmd));
         }
+        let status = self.local_status(self.local_shell(cmd), command).await?;
 
-        let ssh_output = ssh_process.wait_with_output()?;
-        if !ssh_output.status.success() {
-            let stderr = String::from_utf8_lossy(&ssh_output.stderr);
-            anyhow::bail!("SSH command failed: {}", stderr);
+        if let Some(code) = status.code().filter(|code| *code != 0) {
+            return Err(ExitCodeError { what: "Local command", code }.into());
+        }
+        if !status.success() {
+            anyhow::bail!("Local command failed with status: {}", status);
         }
-
-        info!("Successfully uploaded {} to {}:{}", upload.src, host.to_string(), upload.dst);
         Ok(())
     }
 
-    async fn handle_parallel_sessions(&self, cmd: &str) -> Result<()> {
-        let hosts = self.resolve_hosts().await?;
-        let (tx, mut rx) = mpsc::channel(32);
-        let mut handles = Vec::new();
-        
-        for host_str in hosts {
-            let tx = tx.clone();
-            let host = match SshHost::parse(&host_str) {
-                Ok(h) => h,
-                Err(e) => {
-                    eprintln!("Error parsing host {}: {}", host_str, e);
-                    continue;
-                }
-            };
-            info!("Connecting to {}", host.to_string());
-            let cmd = cmd.to_string();
-            let host_str = host_str.to_string();
-            let executor = self.clone();
-            
-            let handle = tokio::spawn(async move {
-                if let Err(e) = executor.handle_ssh_session(&host, &cmd, Some(tx)).await {
-                    eprintln!("Error on host {}: {}", host_str, e);
-                }
-            });
-            handles.push(handle);
+    pub async fn execute_script(&self, script: &str, command: &Command) -> Result<()> {
+        let script_path = Path::new(script);
+        if !script_path.exists() {
+            anyhow::bail!("Script file does not exist: {}", script);
         }
 
-        // Drop the original sender so the channel can close when all tasks complete
-        drop(tx);
-        
-        // Process output from all hosts
-        while let Some((host, output)) = rx.recv().await {
-            if self.disable_prefix {
-                print!("{}", output);
-            } else {
-                println!("{} {}", host.blue(), output);
-            }
+        if !command.quiet {
+            println!("{} {}", "SCRIPT".green(), output::mask(script));
         }
+        let status = self.local_status(self.local_process(shell::script(script)), command).await?;
 
-        // Wait for all tasks to complete
-        for handle in handles {
-            handle.await?;
+        if let Some(code) = status.code().filter(|code| *code != 0) {
+            return Err(ExitCodeError { what: "Script", code }.into());
+        }
+        if !status.success() {
+            anyhow::bail!("Script failed with status: {}", status);
         }
-        
         Ok(())
     }
 
-    async fn handle_interactive_session(&self, host: &SshHost, cmd: &str) -> Result<()> {
-        debug!("Starting interactive SSH session to {}", host.to_string());
+    pub async fn execute_ssh(&self, name: &str, command: &Command, cmd: &str, hosts: &[String]) -> Result<()> {
+        let hosts = hosts
+            .iter()
+            .map(|host| self.target(host))
+            .collect::<Result<Vec<_>>>()?;
+        
+        // execute_command has already warned about it
+        if hosts.is_empty() {
+            return Ok(());
+        }
+        let input = self.run_input(name, command)?;
+
+        if command.once {
+            // For once mode, run on the first candidate whose guards pass
+            for host in &self.once_candidates(name, command, hosts)? {
+                let started = clock::now();
+                match self.skip_reason(command, host).await {
+                    Ok(Some(reason)) => self.skip_host(name, host, reason),
+                    Err(e) => {
+                        let result = Err(e.context(format!("{} failed on {}", name, host)));
+                        self.record_result(name, &host.to_string(), &result, started);
+                        return result.map_err(|e| ExitCode::HostsFailed.tag(e));
+                    }
+                    Ok(None) => {
+                        // The single host's output is passed through unprefixed
+                        let mut printer = self.output_printer().disable_prefix(true).quiet(command.quiet);
+                        let (tx, mut lines) = mpsc::channel::<OutputLine>(32);
+                        let print = async {
+                            while let Some((host, stream, line)) = lines.recv().await {
+                                printer.line(&host, stream, &line);
+                            }
+                        };
+                        let run_started = Instant::now();
+                        let (result, ()) = tokio::join!(self.run_on_host(name, host, cmd, command, input.as_ref(), tx), print);
+                        printer.event(HostEvent::Done {
+                            host: host.to_string(),
+                            error: result.as_ref().err().map(|e| e.to_string()),
+                            elapsed: run_started.elapsed(),
+                        });
+                        printer.finish();
+                        return result
+                            .with_context(|| format!("{} failed on {}", name, host))
+                            .map_err(|e| ExitCode::HostsFailed.tag(e));
+                    }
+                }
+            }
+            return Ok(());
+        }
 
-        let mut ssh_cmd = ProcessCommand::new("ssh");
-        ssh_cmd
-            .arg("-tt") // Force TTY allocation
-            .arg(&host.to_string())
-            .arg(cmd)
-            .stdin(Stdio::inherit())
-            .stdout(Stdio::inherit())
-            .stderr(Stdio::inherit());
+        // Skipped hosts are dropped up front so they never hold a batch
+        // slot, as are hosts whose guards could not be checked
+        let (mut hosts, mut failed) = self.runnable_hosts(name, command, hosts).await?;
 
-        debug!("Running command: {:#?}", ssh_cmd);
-        let status = ssh_cmd.status()?;
+        if command.stdin {
+            // For interactive mode, we only support one host at a time
+            if hosts.len() + failed.len() > 1 {
+                anyhow::bail!("Interactive mode only supports one host at a time");
+            }
+            return match (hosts.first(), failed.first()) {
+                (Some(host), _) => self.handle_interactive_session(host, cmd).await,
+                (None, Some(host)) => Err(ExitCode::HostsFailed.error(format!("{} failed on {}", name, host)).into()),
+                (None, None) => Ok(()),
+            };
+        }
 
-        if !status.success() {
-            anyhow::bail!("SSH command failed with status: {}", status);
+        if command.canary {
+            let canary;
+            (hosts, canary) = self.run_canary(name, command, hosts, cmd, input.as_ref()).await?;
+            failed.extend(canary);
         }
+        let total = hosts.len() + failed.len();
 
-        Ok(())
-    }
+        if command.once_per_batch && command.serial.is_none() {
+            anyhow::bail!("{} runs once per batch and needs serial batches; it cannot run with --parallel", name);
+        }
 
-    fn prepare_remote_command(&self, cmd: &str) -> String {
-        // If command starts with sudo, ensure we preserve environment and handle quoting
-        if cmd.trim().starts_with("sudo") {
-            // Preserve environment variables with -E flag
-            // Use bash -c to properly handle complex commands
-            let quoted = shell_quote::bash::quote(cmd.trim_start_matches("sudo").trim());
-            format!("sudo -E bash -c '{}'", quoted.to_string_lossy())
+        if let Some(serial) = command.serial {
+            // For serial mode, run on hosts in batches, stopping after a
+            // batch with a failed host
+            let batch_size = serial.batch_size(hosts.len());
+            let mut printer = self.output_printer().quiet(command.quiet);
+            let mut batches = hosts.chunks(batch_size).enumerate();
+            for (batch, chunk) in batches.by_ref() {
+                if let (Some(delay), true) = (command.serial_delay, batch > 0) {
+                    progress!(self, "Waiting {:?} before the next batch", delay);
+                    tokio::time::sleep(delay).await;
+                }
+                let (runs, rest) = chunk.split_at(if command.once_per_batch { 1 } else { chunk.len() });
+                failed.extend(self.run_batch(name, runs, cmd, command, input.as_ref(), &mut printer).await?);
+                for host in rest {
+                    self.summary.record(name, &host.to_string(), HostStatus::Skipped("once per batch".to_string()));
+                }
+                if !failed.is_empty() {
+                    break;
+                }
+            }
+            printer.finish();
+            for host in batches.flat_map(|(_, chunk)| chunk) {
+                self.summary.record(name, &host.to_string(), HostStatus::Skipped("not run (earlier batch failed)".to_string()));
+            }
         } else {
-            cmd.to_string()
+            // For parallel mode, run on all hosts at once
+            failed.extend(self.handle_parallel_sessions(name, command, hosts, cmd, input.as_ref()).await?);
+        }
+
+        if !failed.is_empty() {
+            anyhow::bail!(ExitCode::HostsFailed.error(format!(
+                "{} failed on {} of {} hosts: {}", name, failed.len(), total, failed.join(", ")
+            )));
         }
+        Ok(())
     }
 
-    async fn handle_ssh_session(
+    /// Runs `cmd` on the canary host alone and asks whether to continue.
+    /// Returns the hosts still to run and the canary if it failed; declining
+    /// records the rest as not run and fails the command.
+    async fn run_canary(
         &self,
-        host: &SshHost,
+        name: &str,
+        command: &Command,
+        mut hosts: Vec<Target>,
         cmd: &str,
-        tx: Option<mpsc::Sender<(String, String)>>,
-    ) -> Result<()> {
-        debug!("Starting SSH session to {}", host.to_string());
-
-        let mut ssh_cmd = ProcessCommand::new("ssh");
-        ssh_cmd.arg(&host.to_string());
-
-        // Prepare the command with proper sudo handling
-        let prepared_cmd = self.prepare_remote_command(cmd);
-
-        // For non-interactive mode, use sh -c to properly handle command with arguments
-        ssh_cmd
-            .arg("sh")
-            .arg("-c")
-            .arg(&prepared_cmd);
-
-        ssh_cmd
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped());
-
-        debug!("Running command: {:#?}", ssh_cmd);
-        let mut child = ssh_cmd.spawn()?;
-        
-        let stdout = child.stdout.take()
-            .context("Failed to capture stdout")?;
-        let stderr = child.stderr.take()
-            .context("Failed to capture stderr")?;
-
-        // Read output line by line
-        let stdout_reader = BufReader::new(stdout);
-        let stderr_reader = BufReader::new(stderr);
-
-        if let Some(tx) = tx {
-            // Process stdout
-            for line in stdout_reader.lines() {
-                if let Ok(line) = line {
-                    tx.send((host.to_string(), format!("{}\n", line))).await?;
-                }
+        input: Option<&RunInput>,
+    ) -> Result<(Vec<Target>, Option<String>)> {
+        let index = match &command.canary_host {
+            Some(pattern) => {
+                let pattern = Regex::new(pattern)
+                    .with_context(|| format!("Invalid canary_host regex: {}", pattern))?;
+                hosts.iter()
+                    .position(|host| pattern.is_match(&host.to_string()))
+                    .unwrap_or(0)
             }
+            None => 0,
+        };
+        if hosts.is_empty() {
+            return Ok((hosts, None));
+        }
+        let canary = hosts.remove(index);
 
-            // Process stderr
-            for line in stderr_reader.lines() {
-                if let Ok(line) = line {
-                    tx.send((host.to_string(), format!("stderr: {}\n", line))).await?;
-                }
+        println!("{} {}", "CANARY".yellow().bold(), canary);
+        let (tx, mut rx) = mpsc::channel(32);
+        let executor = self.clone();
+        let (task_name, task_host, task_cmd) = (name.to_string(), canary.clone(), cmd.to_string());
+        let (command, input) = (command.clone(), input.cloned());
+        let handle = tokio::spawn(async move {
+            executor.run_on_host(&task_name, &task_host, &task_cmd, &command, input.as_ref(), tx).await
+        });
+        let mut printer = self.printer(OutputMode::Stream);
+        while let Some((host, stream, line)) = rx.recv().await {
+            printer.line(&host, stream, &line);
+        }
+        let failed = match handle.await? {
+            Ok(()) => {
+                println!("{} {} succeeded", "CANARY".yellow().bold(), canary);
+                None
             }
-        } else {
-            // Direct output mode
-            for line in stdout_reader.lines() {
-                if let Ok(line) = line {
-                    println!("{}", line);
-                }
+            Err(e) => {
+                println!("{} {} failed: {}", "CANARY".yellow().bold(), canary, output::mask(&e.to_string()));
+                Some(canary.to_string())
             }
+        };
 
-            for line in stderr_reader.lines() {
-                if let Ok(line) = line {
-                    eprintln!("stderr: {}", line);
-                }
+        if hosts.is_empty() {
+            return Ok((hosts, failed));
+        }
+        let question = format!("continue to remaining {} hosts? [y/N] ", hosts.len());
+        if !prompt::confirm_continue(&question, self.inner.options.assume_yes)? {
+            for host in &hosts {
+                self.summary.record(name, &host.to_string(), HostStatus::Skipped("not run (canary aborted)".to_string()));
             }
+            anyhow::bail!(ExitCode::Aborted.error(format!("Canary aborted; {} hosts not run", hosts.len())));
         }
+        Ok((hosts, failed))
+    }
 
-        let status = child.wait()?;
-        if !status.success() {
-            anyhow::bail!("SSH command failed with status: {}", status);
+    /// Checks the command's `creates`, `removes` and `when` guards on a
+    /// host, returning why the host should be skipped. Fails if the host
+    /// could not be reached to check them.
+    async fn skip_reason(&self, command: &Command, host: &Target) -> Result<Option<String>> {
+        if let Some(path) = &command.creates {
+            if self.remote_check(host, &exists_check(path)).await? {
+                return Ok(Some(format!("{} exists", path)));
+            }
         }
-
-        Ok(())
+        if let Some(path) = &command.removes {
+            if !self.remote_check(host, &exists_check(path)).await? {
+                return Ok(Some(format!("{} missing", path)));
+            }
+        }
+        if let Some(check) = &command.when {
+            // Wrapped like the command's run, so it sees the same env
+            let check = self.with_exports(host, &self.prepare_remote_command(&self.substitute_args(check), command.sudo));
+            if !self.remote_check(host, &check).await? {
+                return Ok(Some("when failed".to_string()));
+            }
+        }
+        Ok(None)
     }
 
-    pub async fn execute_command(&self, command: &Command) -> Result<()> {
-        if let Some(local_cmd) = &command.local {
-            self.execute_local(local_cmd).await?;
+    /// Runs `check` on a host and returns whether it exited 0. A transport
+    /// error or ssh's own exit status means the host was not reached, so
+    /// the check neither held nor failed.
+    async fn remote_check(&self, host: &Target, check: &str) -> Result<bool> {
+        let output = self.inner.transport.exec(host, check, None).await
+            .with_context(|| format!("Failed to reach {}", host))?;
+        if matches!(host, Target::Ssh(_)) && output.exit_code == Some(SSH_FAILED) {
+            anyhow::bail!("Failed to reach {}: {}", host, String::from_utf8_lossy(&output.stderr).trim());
         }
+        Ok(output.success())
+    }
 
-        if let Some(script) = &command.script {
-            self.execute_script(script).await?;
+    /// Evaluates the guards of all hosts concurrently and returns the hosts
+    /// that should run, in their original order, and the hosts that could
+    /// not be checked, which are recorded as failed.
+    async fn runnable_hosts(&self, name: &str, command: &Command, hosts: Vec<Target>) -> Result<(Vec<Target>, Vec<String>)> {
+        if command.creates.is_none() && command.removes.is_none() && command.when.is_none() {
+            return Ok((hosts, Vec::new()));
         }
 
-        if let Some(remote_cmd) = &command.run {
-            self.execute_ssh(
-                remote_cmd,
-                command.stdin,
-                command.serial,
-                command.once
-            ).await?;
+        let mut handles = Vec::new();
+        for host in hosts {
+            let command = command.clone();
+            let executor = self.clone();
+            handles.push(tokio::spawn(async move {
+                let started = clock::now();
+                let reason = executor.skip_reason(&command, &host).await;
+                (host, reason, started)
+            }));
         }
 
-        if let Some(uploads) = &command.upload {
-            self.execute_upload(uploads).await?;
+        let mut runnable = Vec::new();
+        let mut failed = Vec::new();
+        for handle in handles {
+            match handle.await? {
+                (host, Ok(Some(reason)), _) => self.skip_host(name, &host, reason),
+                (host, Ok(None), _) => runnable.push(host),
+                (host, Err(e), started) => {
+                    let line = format!("{}\n", output::mask(&format!("{:#}", e))).red().to_string();
+                    self.printer(OutputMode::Stream).line(&host.to_string(), OutputStream::Stderr, line.as_bytes());
+                    self.record_result::<()>(name, &host.to_string(), &Err(e), started);
+                    failed.push(host.to_string());
+                }
+            }
         }
+        Ok((runnable, failed))
+    }
 
-        Ok(())
+    /// Hosts a `once` command tries, in order, as picked by `once_on`. The
+    /// random pick is seeded by the run and the command name, so the banner
+    /// names the host the command then runs on.
+    fn once_candidates<T: ToString>(&self, name: &str, command: &Command, mut hosts: Vec<T>) -> Result<Vec<T>> {
+        match &command.once_on {
+            OnceOn::First => {}
+            OnceOn::Random => {
+                // FNV-1a, so a seed picks the same host across Rust releases
+                let hash = name.bytes().fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+                    (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+                });
+                fastrand::Rng::with_seed(self.inner.seed ^ hash).shuffle(&mut hosts);
+            }
+            OnceOn::Regex(pattern) => {
+                let regex = Regex::new(pattern)
+                    .with_context(|| format!("Invalid once_on regex: {}", pattern))?;
+                hosts.retain(|host| regex.is_match(&host.to_string()));
+                if hosts.is_empty() {
+                    anyhow::bail!("No host of {} matches once_on {}", name, pattern);
+                }
+            }
+        }
+        Ok(hosts)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::collections::HashMap;
+    fn skip_host(&self, name: &str, host: &Target, reason: String) {
+        let line = format!("SKIPPED ({})\n", reason).yellow().to_string();
+        self.printer(OutputMode::Stream).line(&host.to_string(), OutputStream::Stdout, line.as_bytes());
+        self.summary.record(name, &host.to_string(), HostStatus::Skipped(reason));
+    }
 
-    fn create_test_executor() -> Executor {
-        let network = Network {
-            hosts: vec!["test@localhost".to_string()],
-            inventory: None,
-            env: None,
+    /// Runs `cmd` on one host, then waits for the command's `wait_for` to
+    /// pass there, and records the outcome in the run summary.
+    async fn run_on_host(
+        &self,
+        name: &str,
+        host: &Target,
+        cmd: &str,
+        command: &Command,
+        input: Option<&RunInput>,
+        tx: mpsc::Sender<OutputLine>,
+    ) -> Result<()> {
+        let started = clock::now();
+        let progress = tx.clone();
+        let mut result = match input.map(RunInput::open).transpose() {
+            Ok(stdin) => self.handle_ssh_session(host, cmd, stdin, Some(tx)).await,
+            Err(e) => Err(e),
         };
-        let env = HashMap::new();
-        Executor::new(network, env, None, None, false).unwrap()
+        if let (Ok(()), Some(wait_for)) = (&result, &command.wait_for) {
+            result = self.wait_for(host, wait_for, command.sudo, &progress).await;
+        }
+        self.record_result(name, &host.to_string(), &result, started);
+        result
     }
 
-    #[test]
-    fn test_prepare_remote_command() {
-        let executor = create_test_executor();
+    /// Polls the `wait_for` check on `host` until it exits 0, printing a
+    /// line per failed attempt. Fails once the timeout lapses.
+    async fn wait_for(&self, host: &Target, wait_for: &WaitFor, sudo: bool, tx: &mpsc::Sender<OutputLine>) -> Result<()> {
+        let check = self.prepare_remote_command(&wait_for.command, sudo);
+        let deadline = tokio::time::Instant::now() + wait_for.timeout();
+        for attempt in 1.. {
+            // A hanging check counts against the timeout too
+            if let Ok(Ok(true)) = tokio::time::timeout_at(deadline, self.remote_check(host, &check)).await {
+                debug!("{} passed on {} after {} attempts", wait_for.command, host, attempt);
+                return Ok(());
+            }
+            if tokio::time::Instant::now() + wait_for.interval() >= deadline {
+                break;
+            }
+            let line = format!("waiting for {} (attempt {})\n", wait_for.command, attempt).yellow().to_string();
+            tx.send((host.to_string(), OutputStream::Stdout, line.into_bytes())).await?;
+            tokio::time::sleep(wait_for.interval()).await;
+        }
+        anyhow::bail!(
+            "Timed out after {} waiting for: {}",
+            output::format_duration(wait_for.timeout()),
+            wait_for.command
+        )
+    }
 
-        // Test regular command
-        let cmd = "echo 'hello world'";
-        assert_eq!(executor.prepare_remote_command(cmd), cmd.to_string());
-
-        // Test sudo command
-        let sudo_cmd = "sudo apt-get install -y package";
-        let prepared = executor.prepare_remote_command(sudo_cmd);
-        assert!(prepared.starts_with("sudo -E bash -c "));
-        assert!(prepared.contains("apt-get"));
-        assert!(prepared.contains("install"));
-        assert!(prepared.contains("package"));
-
-        // Test sudo command with complex arguments
-        let complex_sudo = r#"sudo sh -c 'echo "complex argument" > /etc/file'"#;
-        let prepared = executor.prepare_remote_command(complex_sudo);
-        assert!(prepared.starts_with("sudo -E bash -c "));
-        assert!(prepared.contains("complex argument"));
-        assert!(prepared.contains("/etc/file"));
-
-        // Test sudo command with environment variables
-        let env_sudo = r#"sudo DEBIAN_FRONTEND=noninteractive apt-get install -y package"#;
-        let prepared = executor.prepare_remote_command(env_sudo);
-        assert!(prepared.starts_with("sudo -E bash -c "));
-        assert!(prepared.contains("DEBIAN_FRONTEND=noninteractive"));
-        assert!(prepared.contains("apt-get"));
+    fn record_result<T>(&self, name: &str, host: &str, result: &Result<T>, started: DateTime<Local>) {
+        self.summary.push(host_result(name, host, result, started));
     }
 
-    #[test]
-    fn test_sudo_command_whitespace() {
-        let executor = create_test_executor();
+    /// Evaluates the command's guards for a `local:` command on this machine.
+    async fn local_skip_reason(&self, command: &Command) -> Option<String> {
+        if let Some(path) = &command.creates {
+            if Path::new(path).exists() {
+                return Some(format!("{} exists", path));
+            }
+        }
+        if let Some(path) = &command.removes {
+            if !Path::new(path).exists() {
+                return Some(format!("{} missing", path));
+            }
+        }
+        if let Some(check) = &command.when {
+            let holds = self.local_shell(check).status().await
+                .map(|status| status.success())
+                .unwrap_or(false);
+            if !holds {
+                return Some("when failed".to_string());
+            }
+        }
+        None
+    }
+
+    pub async fn execute_upload(&self, name: &str, uploads: &[Upload], hosts: &[String]) -> Result<()> {
+        debug!("Starting upload process for {} files", uploads.len());
+        let uploads = uploads.iter().enumerate()
+            .map(|(index, upload)| upload::with_env(upload, index + 1, &self.inner.env))
+            .collect::<Result<Vec<_>>>()?;
+        // Expand sources and run commands once, so a missing source or a
+        // failed command stops the upload before any transfer
+        let mut payloads = Vec::new();
+        for upload in &uploads {
+            if let Some(cmd) = &upload.from_command {
+                payloads.push(Payload::Generated(self.generate(upload, cmd).await?));
+                continue;
+            }
+            let mut plan = UploadPlan::new(upload)?;
+            if !self.inner.options.no_supignore {
+                plan = plan.with_supignore(self.supfile_dir())?;
+            }
+            payloads.push(Payload::Files(plan));
+        }
+        let mut completed = Vec::new();
+        let mut retried = Vec::new();
+        for (index, host_str) in hosts.iter().enumerate() {
+            let host = self.target(host_str)?;
+            let started = clock::now();
+            let mut bytes_uploaded = 0;
+            let mut result = Ok(());
+            for (upload, payload) in uploads.iter().zip(&payloads).filter(|(_, payload)| !payload.is_empty()) {
+                match self.upload_with_retries(&host, upload, payload).await {
+                    Ok((bytes, attempts)) => {
+                        bytes_uploaded += bytes;
+                        if attempts > 1 && !retried.contains(host_str) {
+                            retried.push(host_str.clone());
+                        }
+                    }
+                    Err(e) => {
+                        result = Err(e);
+                        break;
+                    }
+                }
+            }
+            self.summary.push(HostResult {
+                bytes_uploaded: Some(bytes_uploaded),
+                ..host_result(name, host_str, &result, started)
+            });
+            if let Err(e) = result {
+                // Spell out the partial state of the fleet
+                let rest = &hosts[index + 1..];
+                for host in rest {
+                    self.summary.record(name, host, HostStatus::Skipped("not run (earlier upload failed)".to_string()));
+                }
+                let mut state = vec![format!("Upload to {} failed", host_str)];
+                let list = |label: &str, hosts: &[String]| format!("{} {}", label, hosts.join(", "));
+                if !completed.is_empty() {
+                    state.push(list("completed on", &completed));
+                }
+                if !retried.is_empty() {
+                    state.push(list("retried on", &retried));
+                }
+                if !rest.is_empty() {
+                    state.push(list("not run on", rest));
+                }
+                return Err(ExitCode::HostsFailed.tag(e.context(state.join("; "))));
+            }
+            completed.push(host_str.clone());
+        }
+        Ok(())
+    }
+
+    /// `handle_upload` or `write_generated`, started over up to
+    /// `upload.retries` times after a failure. Returns the bytes sent and
+    /// the attempts it took.
+    async fn upload_with_retries(&self, host: &Target, upload: &Upload, payload: &Payload) -> Result<(u64, u32)> {
+        let mut attempt = 1;
+        loop {
+            let result = match payload {
+                Payload::Files(plan) => self.handle_upload(host, upload, plan).await,
+                Payload::Generated(contents) => self.write_generated(host, upload, contents).await,
+            };
+            match result {
+                Ok(bytes) => return Ok((bytes, attempt)),
+                Err(e) if attempt <= upload.retries => {
+                    warn!(
+                        "Upload of {} to {} failed (attempt {} of {}), retrying: {}",
+                        upload.source(),
+                        host,
+                        attempt,
+                        upload.retries + 1,
+                        output::mask(&format!("{:#}", e))
+                    );
+                    tokio::time::sleep(UPLOAD_RETRY_DELAY * attempt).await;
+                    attempt += 1;
+                }
+                Err(e) if attempt > 1 => return Err(e.context(format!("Gave up after {} attempts", attempt))),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Checks the uploaded files against checksums of the local sources.
+    async fn verify_upload(&self, host: &Target, plan: &UploadPlan) -> Result<()> {
+        let local = plan.manifest()?;
+        let paths = local.keys().flat_map(|path| [path.as_bytes(), b"\0"].concat()).collect::<Vec<_>>();
+        let output = self.inner.transport
+            .exec(host, upload::CHECKSUM_SCRIPT, Some(Box::new(std::io::Cursor::new(paths))))
+            .await?;
+        let remote = upload::parse_checksums(&String::from_utf8_lossy(&output.stdout));
+
+        let mismatches = upload::compare_manifests(&local, &remote);
+        if !mismatches.is_empty() {
+            anyhow::bail!("Checksum mismatch after upload to {}:\n  {}", host, mismatches.join("\n  "));
+        }
+        debug!("Verified {} uploaded files on {}", local.len(), host);
+        Ok(())
+    }
+
+    /// Renders each template for every host and writes it to the host's
+    /// `dst`, recording the bytes written like an upload.
+    pub async fn execute_templates(&self, name: &str, templates: &[Template], hosts: &[String]) -> Result<()> {
+        let sources = templates.iter()
+            .map(|template| std::fs::read_to_string(&template.src)
+                .with_context(|| format!("Failed to read template {}", template.src)))
+            .collect::<Result<Vec<_>>>()?;
+
+        for host_str in hosts {
+            let host = self.target(host_str)?;
+            let started = clock::now();
+            let mut bytes_uploaded = 0;
+            let mut result = Ok(());
+            for (template, source) in templates.iter().zip(&sources) {
+                match self.write_template(&host, host_str, template, source).await {
+                    Ok(bytes) => bytes_uploaded += bytes,
+                    Err(e) => {
+                        result = Err(e);
+                        break;
+                    }
+                }
+            }
+            self.summary.push(HostResult {
+                bytes_uploaded: Some(bytes_uploaded),
+                ..host_result(name, host_str, &result, started)
+            });
+            result.map_err(|e| ExitCode::HostsFailed.tag(e))?;
+        }
+        Ok(())
+    }
+
+    async fn write_template(&self, host: &Target, host_str: &str, template: &Template, source: &str) -> Result<u64> {
+        // Rendered per host since the env includes the host itself
+        let mut vars = self.inner.env.clone();
+        vars.insert("SUP_HOST".to_string(), host_str.to_string());
+        vars.extend(template.vars.clone());
+        let rendered = crate::template::render(source, &vars, &template.src)?;
+
+        progress!(self, "Writing template {} to {}:{}", template.src, host, template.dst);
+        let dst = shell_quote::sh::quote(&template.dst).to_string_lossy().into_owned();
+        let mut script = format!("mkdir -p \"$(dirname {dst})\" && cat > {dst}");
+        if let Some(mode) = &template.mode {
+            check_mode(mode, &template.src)?;
+            script.push_str(&format!(" && chmod {} {}", mode, dst));
+        }
+
+        let output = self.inner.transport
+            .exec(host, &script, Some(Box::new(std::io::Cursor::new(rendered.into_bytes()))))
+            .await?;
+        if !output.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            anyhow::bail!("Failed to write template {} to {}: {}", template.src, template.dst, stderr.trim());
+        }
+        Ok(output.bytes_sent)
+    }
+
+    /// Runs an upload's `from_command` on this machine, returning what it
+    /// printed on stdout. Its stderr is shown as output of the `local` host.
+    async fn generate(&self, upload: &Upload, cmd: &str) -> Result<Vec<u8>> {
+        use tokio::io::AsyncReadExt;
+
+        println!("{} {}", "LOCAL".green(), output::mask(cmd));
+        let started = Instant::now();
+        let mut process = self.local_shell(cmd);
+        let mut child = children::spawn(process.stdout(Stdio::piped()).stderr(Stdio::piped()))?;
+        let pid = child.id();
+        let mut stdout = child.stdout.take().context("Failed to capture stdout")?;
+        let stderr = child.stderr.take().context("Failed to capture stderr")?;
+
+        let mut printer = self.output_printer();
+        let (tx, mut lines) = mpsc::channel::<OutputLine>(32);
+        let print = async {
+            while let Some((host, stream, line)) = lines.recv().await {
+                printer.line(&host, stream, &line);
+            }
+        };
+        let mut contents = Vec::new();
+        let (read, forwarded, ()) = tokio::join!(
+            stdout.read_to_end(&mut contents),
+            forward_lines(LOCAL_HOST, tokio::io::empty(), stderr, Some(tx)),
+            print
+        );
+        let status = child.wait().await?;
+        children::reaped(pid);
+        read?;
+        forwarded?;
+
+        printer.event(HostEvent::Done {
+            host: LOCAL_HOST.to_string(),
+            error: (!status.success()).then(|| status.to_string()),
+            elapsed: started.elapsed(),
+        });
+        printer.finish();
+        if !status.success() {
+            anyhow::bail!("from_command of the upload to {} failed with {}", upload.dst, status);
+        }
+        if contents.is_empty() && !upload.allow_empty {
+            anyhow::bail!("from_command of the upload to {} printed nothing; set allow_empty: true to upload an empty file", upload.dst);
+        }
+        Ok(contents)
+    }
+
+    /// Writes the output of an upload's `from_command` to its `dst` on a
+    /// host, returning the bytes sent.
+    async fn write_generated(&self, host: &Target, upload: &Upload, contents: &[u8]) -> Result<u64> {
+        progress!(self, "Writing {} to {}:{}", upload.source(), host, upload.dst);
+        if upload::injected_failure(&host.to_string()) {
+            anyhow::bail!("Upload to {} aborted by {}", host, upload::FAIL_ONCE_ENV);
+        }
+
+        let dst = shell_quote::sh::quote(&upload.dst).to_string_lossy().into_owned();
+        let mut script = format!("mkdir -p \"$(dirname {dst})\" && cat > {dst}");
+        if let Some(steps) = ownership_steps(upload, std::slice::from_ref(&upload.dst))? {
+            script = format!("{} && {}", script, steps);
+        }
+        // Writing a file for another owner needs sudo from the start
+        let script = as_owner(upload, host.user(), script);
+
+        let output = self.inner.transport
+            .exec(host, &script, Some(Box::new(std::io::Cursor::new(contents.to_vec()))))
+            .await?;
+        if !output.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            anyhow::bail!("Failed to write {} to {}: {}", upload.source(), upload.dst, stderr.trim());
+        }
+        Ok(output.bytes_sent)
+    }
+
+    /// Uploads one entry's sources to a host, returning the bytes sent.
+    async fn handle_upload(&self, host: &Target, upload: &Upload, plan: &UploadPlan) -> Result<u64> {
+        let src = upload.src.join(", ");
+        let fallback;
+        let plan = if plan.compression == Compression::Zstd && !self.remote_check(host, upload::ZSTD_PROBE).await? {
+            warn!("tar on {} cannot read zstd; uploading {} with gzip instead", host, src);
+            fallback = plan.with_compression(Compression::Gzip);
+            &fallback
+        } else {
+            plan
+        };
+        progress!(self, "Uploading {} to {}:{}", src, host, upload.dst);
+
+        if upload::injected_failure(&host.to_string()) {
+            anyhow::bail!("Upload to {} aborted by {}", host, upload::FAIL_ONCE_ENV);
+        }
+
+        // Build the archive on a blocking thread, piping it to the target
+        let (archive, archive_writer) = std::io::pipe()?;
+        let archiver = {
+            let plan = plan.clone();
+            tokio::task::spawn_blocking(move || plan.write_archive(archive_writer))
+        };
+
+        // Stream the archive into the extraction command on the target
+        let script = plan.extract_script()?;
+        debug!("Starting file transfer: {}", output::redact(&script));
+        let ssh_output = self.inner.transport
+            .exec(host, &script, Some(Box::new(archive)))
+            .await?;
+
+        // A failed extraction also breaks the pipe, so report it first
+        if !ssh_output.success() {
+            let stderr = String::from_utf8_lossy(&ssh_output.stderr);
+            anyhow::bail!("SSH command failed: {}", stderr);
+        }
+        archiver.await?
+            .with_context(|| format!("Failed to archive {}", src))?;
+
+        if upload.verify {
+            self.verify_upload(host, plan).await?;
+        }
+
+        // Adjust the uploaded entries themselves, not what they replaced
+        let entries = plan.entries()?;
+        if let Some(script) = ownership_script(upload, &entries, host.user())? {
+            let output = self.inner.transport.exec(host, &script, None).await?;
+            if !output.success() {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                anyhow::bail!("Uploaded {} but failed to set mode or owner: {}", entries.join(", "), stderr.trim());
+            }
+        }
+
+        progress!(self, "Successfully uploaded {} to {}:{}", src, host, upload.dst);
+        Ok(ssh_output.bytes_sent)
+    }
+
+    /// Runs `cmd` on all `hosts` at once, returning the hosts that failed.
+    async fn handle_parallel_sessions(&self, name: &str, command: &Command, hosts: Vec<Target>, cmd: &str, input: Option<&RunInput>) -> Result<Vec<String>> {
+        let mut printer = self.output_printer().quiet(command.quiet);
+        let failed = self.run_batch(name, &hosts, cmd, command, input, &mut printer).await?;
+        printer.finish();
+        Ok(failed)
+    }
+
+    /// Runs `cmd` on all `hosts` at once, feeding their output to `printer`
+    /// as it arrives from any host. Returns the hosts that failed.
+    async fn run_batch(
+        &self,
+        name: &str,
+        hosts: &[Target],
+        cmd: &str,
+        command: &Command,
+        input: Option<&RunInput>,
+        printer: &mut OutputPrinter,
+    ) -> Result<Vec<String>> {
+        let (events, mut rx) = mpsc::channel(32);
+        let mut handles = Vec::new();
+        
+        for host in hosts {
+            let events = events.clone();
+            progress!(self, "Connecting to {}", host);
+            let host = host.clone();
+            let name = name.to_string();
+            let cmd = cmd.to_string();
+            let command = command.clone();
+            let input = input.cloned();
+            let executor = self.clone();
+            
+            let handle = tokio::spawn(async move {
+                // Forward the session's lines so the host's end can be
+                // signalled after its last line
+                let (tx, mut lines) = mpsc::channel::<OutputLine>(32);
+                // Once the printer is gone, dropping `lines` cancels the session
+                let line_events = events.clone();
+                let forward = async move {
+                    while let Some((host, stream, line)) = lines.recv().await {
+                        if line_events.send(HostEvent::Line { host, stream, line }).await.is_err() {
+                            break;
+                        }
+                    }
+                };
+                let started = Instant::now();
+                let (result, ()) = tokio::join!(executor.run_on_host(&name, &host, &cmd, &command, input.as_ref(), tx), forward);
+                let _ = events.send(HostEvent::Done {
+                    host: host.to_string(),
+                    error: result.err().map(|e| e.to_string()),
+                    elapsed: started.elapsed(),
+                }).await;
+            });
+            handles.push(handle);
+        }
+
+        // Drop the original sender so the channel can close when all tasks complete
+        drop(events);
+        
+        // Process output from all hosts
+        let mut failed = Vec::new();
+        while let Some(event) = rx.recv().await {
+            if let HostEvent::Done { host, error: Some(_), .. } = &event {
+                failed.push(host.clone());
+            }
+            printer.event(event);
+        }
+
+        // Wait for all tasks to complete
+        for handle in handles {
+            handle.await?;
+        }
+        Ok(failed)
+    }
+
+    fn output_printer(&self) -> OutputPrinter {
+        self.printer(self.inner.options.output_mode)
+    }
+
+    fn printer(&self, mode: OutputMode) -> OutputPrinter {
+        OutputPrinter::new(
+            mode,
+            self.inner.options.disable_prefix,
+            self.inner.options.combine_output,
+            self.inner.options.group_buffer.unwrap_or(output::DEFAULT_GROUP_BUFFER),
+        )
+        .style(self.style())
+        .sink(self.inner.options.sink.clone())
+    }
+
+    /// The color and banner of this executor's network.
+    pub fn style(&self) -> NetworkStyle {
+        self.inner.network.style()
+    }
+
+    /// The stdin of `command`'s `run`, with `stdin_file` expanded from the
+    /// env and resolved against the Supfile's directory.
+    fn run_input(&self, name: &str, command: &Command) -> Result<Option<RunInput>> {
+        if let Some(data) = &command.stdin_data {
+            return Ok(Some(RunInput::Data(data.as_bytes().into())));
+        }
+        let Some(file) = &command.stdin_file else {
+            return Ok(None);
+        };
+        let file = upload::expand_env(file, &self.inner.env).map_err(|missing| {
+            anyhow::anyhow!("stdin_file of command {} uses undefined variable ${}", name, missing)
+        })?;
+        let path = match self.supfile_dir() {
+            Some(dir) => dir.join(file),
+            None => PathBuf::from(file),
+        };
+        if !path.is_file() {
+            anyhow::bail!("stdin_file {} of command {} does not exist", path.display(), name);
+        }
+        Ok(Some(RunInput::File(path)))
+    }
+
+    async fn handle_interactive_session(&self, host: &Target, cmd: &str) -> Result<()> {
+        debug!("Starting interactive session to {}", host);
+        self.inner.transport.interactive(host, &self.with_exports(host, cmd)).await
+    }
+
+    /// The remote form of `cmd`: with `sudo`, the whole command runs as
+    /// root through `sudo -E`, which keeps the env exported before it. A
+    /// sudo the command invokes itself is the user's and stays as written.
+    fn prepare_remote_command(&self, cmd: &str, sudo: bool) -> String {
+        if !sudo {
+            return cmd.to_string();
+        }
+        // Quoted as one word so bash -c receives it unchanged
+        let quoted = shell_quote::sh::quote(cmd.trim());
+        format!("sudo -E bash -c {}", quoted.to_string_lossy())
+    }
+
+    async fn handle_ssh_session(
+        &self,
+        host: &Target,
+        cmd: &str,
+        stdin: Option<Box<dyn Read + Send>>,
+        tx: Option<mpsc::Sender<OutputLine>>,
+    ) -> Result<()> {
+        debug!("Starting session to {}", host);
+
+        self.inner.transport.session(host, &self.with_exports(host, cmd), stdin, tx).await
+    }
+
+    /// Prefixes a remote command with exports of the `--` arguments, sup's
+    /// variables and the exported env, secrets included, which the remote
+    /// shell does not otherwise see. Only ever logged masked.
+    fn with_exports(&self, host: &Target, cmd: &str) -> String {
+        let mut env = args_env(&self.inner.options.args);
+        env.extend(SUP_VARS.into_iter()
+            .chain(self.inner.exported.iter().map(String::as_str))
+            .filter_map(|key| Some((key.to_string(), self.inner.env.get(key)?.clone()))));
+        if let Some(index) = self.running.iter().position(|running| running == host) {
+            env.push(("SUP_HOST_INDEX".to_string(), index.to_string()));
+        }
+        let exports = env.into_iter()
+            .map(|(key, value)| format!("export {}={}; ", key, shell_quote::sh::quote(value).to_string_lossy()))
+            .collect::<String>();
+        exports + cmd
+    }
+
+    /// Replaces `{{args}}` in a command with the quoted `--` arguments.
+    fn substitute_args(&self, cmd: &str) -> String {
+        cmd.replace("{{args}}", &join_args(&self.inner.options.args))
+    }
+
+    /// Runs all steps of a command between a banner and a completion line.
+    pub async fn execute_command(&self, name: &str, command: &Command) -> Result<()> {
+        if command.require_args && self.inner.options.args.is_empty() {
+            anyhow::bail!("Command {} requires arguments; pass them after --", name);
+        }
+        let command = &self.with_overrides(name, command)?;
+        // Filters are applied once, so every step runs on the same hosts;
+        // local steps see them too
+        let resolved = self.resolve_hosts().await?;
+        let hosts = if command.is_remote() { resolved.clone() } else { Vec::new() };
+        if command.is_remote() && hosts.is_empty() {
+            let reason = self.no_hosts_reason().await?;
+            if !command.allow_empty_hosts {
+                anyhow::bail!(
+                    "No hosts to run {} on: {}; pass --allow-empty-hosts or set allow_empty_hosts: true to skip it instead",
+                    name, reason
+                );
+            }
+            warn!("No hosts to run {} on: {}", name, reason);
+        }
+        let mut executor = self.running_on(&hosts, &resolved)?;
+        executor.quiet = command.quiet;
+        let first_result = self.summary.len();
+        // Shown rather than silently dropped, so drift from the inventory is seen
+        if command.is_remote() {
+            self.summary.record_excluded(self.excluded_hosts().await?);
+        }
+        if command.quiet {
+            let started = Instant::now();
+            let result = executor.execute_steps(name, command, &hosts).await;
+            self.summary.record_timing(name, started.elapsed());
+            return result;
+        }
+
+        println!("{}", self.banner(name, command, &hosts)?);
+        let started = Instant::now();
+        let result = executor.execute_steps(name, command, &hosts).await;
+        self.summary.record_timing(name, started.elapsed());
+
+        let (ok, failed, skipped) = self.summary.command_counts_since(first_result, name);
+        let outcome = if result.is_ok() { "finished".green() } else { "failed".red() };
+        println!(
+            "{} {} {} in {}: {} succeeded, {} failed, {} skipped",
+            self.style().paint("<==", Color::Blue).bold(),
+            name.bold(),
+            outcome,
+            output::format_duration(started.elapsed()),
+            ok, failed, skipped,
+        );
+        result
+    }
+
+    /// Where the network's hosts come from and what filtered them all out,
+    /// for a command left with none.
+    async fn no_hosts_reason(&self) -> Result<String> {
+        let network = self.inner.env.get("SUP_NETWORK").map(String::as_str).unwrap_or_default();
+        let hosts = self.sourced_hosts().await?.into_iter().map(|(host, _)| host).collect::<Vec<_>>();
+        let inventories = self.sources().into_iter()
+            .filter_map(|(_, network)| network.inventory.as_deref())
+            .collect::<Vec<_>>();
+        let mut reason = match (hosts.len(), inventories.is_empty()) {
+            (0, true) => format!("network {} has no hosts and no inventory", network),
+            (0, false) => format!("network {} has no hosts", network),
+            (1..=10, _) => format!("network {} has {}", network, hosts.join(", ")),
+            (count, _) => format!("network {} has {}, and {} more", network, hosts[..10].join(", "), count - 10),
+        };
+        if !inventories.is_empty() {
+            reason += &format!(" (inventory: {})", inventories.join("; "));
+        }
+
+        let options = &self.inner.options;
+        let mut filters = Vec::new();
+        filters.extend(options.only.as_ref().map(|only| format!("--only {}", only)));
+        filters.extend(options.except.as_ref().map(|except| format!("--except {}", except)));
+        filters.extend(options.limit.map(|limit| format!("--limit {}", limit)));
+        filters.extend(self.retry.as_ref().map(|retry| format!("--retry-failed ({} hosts)", retry.len())));
+        if !self.unreachable.is_empty() {
+            filters.push(format!("{} unreachable", self.unreachable.len()));
+        }
+        let excluded = self.excluded_hosts().await?;
+        if !excluded.is_empty() {
+            filters.push(format!("{} excluded by the Supfile", excluded.len()));
+        }
+        if !filters.is_empty() {
+            reason += &format!(", filtered by {}", filters.join(", "));
+        }
+        Ok(reason)
+    }
+
+    /// This executor with the hosts a command resolved to exported as
+    /// `SUP_HOSTS` and `SUP_HOST_COUNT`; sessions to `hosts` also get their
+    /// `SUP_HOST_INDEX`.
+    fn running_on(&self, hosts: &[String], resolved: &[String]) -> Result<Self> {
+        let mut executor = self.clone();
+        let mut inner = (*self.inner).clone();
+        inner.env.insert("SUP_HOSTS".to_string(), resolved.join(","));
+        inner.env.insert("SUP_HOST_COUNT".to_string(), resolved.len().to_string());
+        executor.inner = Arc::new(inner);
+        executor.running = Arc::new(hosts.iter().map(|host| self.target(host)).collect::<Result<_>>()?);
+        Ok(executor)
+    }
+
+    fn supfile_dir(&self) -> Option<&Path> {
+        self.inner.options.supfile.as_deref().and_then(Path::parent)
+    }
+
+    /// `sup-rs` command line that tails `log` on the same Supfile, network
+    /// and filtered hosts as this run.
+    fn follow_command(&self, log: &str) -> String {
+        let options = &self.inner.options;
+        let mut words = vec!["sup-rs".to_string()];
+        if let Some(supfile) = &options.supfile {
+            words.extend(["-f".to_string(), supfile.display().to_string()]);
+        }
+        words.push(self.inner.env.get("SUP_NETWORK").cloned().unwrap_or_default());
+        if let Some(only) = &options.only {
+            words.extend(["--only".to_string(), only.clone()]);
+        }
+        if let Some(except) = &options.except {
+            words.extend(["--except".to_string(), except.clone()]);
+        }
+        if options.only_full {
+            words.push("--only-full".to_string());
+        }
+        words.extend(["--run".to_string(), format!("tail -f {}", log)]);
+        words.iter().map(|word| shell::word(word)).collect::<Vec<_>>().join(" ")
+    }
+
+    /// `command` with the command-line serial, once and parallel overrides.
+    /// Fails when an override combines with the Supfile into a command the
+    /// Supfile validation would have rejected.
+    pub fn with_overrides(&self, name: &str, command: &Command) -> Result<Command> {
+        let mut command = command.clone();
+        if self.inner.options.parallel {
+            command.serial = None;
+        }
+        if let Some(serial) = self.inner.options.serial {
+            command.serial = Some(serial);
+        }
+        if self.inner.options.once {
+            command.once = true;
+            command.serial = None;
+        }
+        command.quiet |= self.inner.options.quiet;
+        command.allow_empty_hosts |= self.inner.options.allow_empty_hosts;
+        if command.once && command.serial.is_some() {
+            anyhow::bail!(
+                "Command {} sets once, which conflicts with --serial; a once command runs on a single host",
+                name
+            );
+        }
+        Ok(command)
+    }
+
+    /// What `execute_command` would do for `command`, without connecting
+    /// to any host. Inventory commands still run unless cleared.
+    pub async fn plan_step(&self, name: &str, command: &Command) -> Result<StepPlan> {
+        let command = self.with_overrides(name, command)?;
+        let resolved = self.resolve_hosts().await?;
+        let hosts = resolved.len();
+        let mut users = Vec::new();
+        if command.is_remote() {
+            for host in &resolved {
+                if let Target::Ssh(ssh) = self.target(host)? {
+                    users.push(ssh.username);
+                }
+            }
+            users.sort();
+            users.dedup();
+        }
+        let batches = match command.serial {
+            Some(serial) if !command.once => {
+                let size = serial.batch_size(hosts).max(1);
+                (0..hosts).step_by(size).map(|start| size.min(hosts - start)).collect()
+            }
+            _ => Vec::new(),
+        };
+        let once_per_batch = command.once_per_batch && !batches.is_empty();
+        let guards = [("when", &command.when), ("creates", &command.creates), ("removes", &command.removes)]
+            .into_iter()
+            .filter_map(|(guard, check)| Some(format!("{}: {}", guard, check.as_ref()?)))
+            .collect();
+
+        let actions = command.ordered_steps().iter()
+            .map(|step| match step {
+                Step::Local(_) => ActionPlan { kind: "local", hosts: None, sudo: false },
+                Step::Script(_) => ActionPlan { kind: "script", hosts: None, sudo: false },
+                Step::Run(_) => {
+                    let run_hosts = if command.once {
+                        hosts.min(1)
+                    } else if once_per_batch {
+                        batches.len()
+                    } else {
+                        hosts
+                    };
+                    ActionPlan { kind: "run", hosts: Some(run_hosts), sudo: command.sudo }
+                }
+                Step::Upload(_) => ActionPlan { kind: "upload", hosts: Some(hosts), sudo: false },
+                Step::Template(_) => ActionPlan { kind: "template", hosts: Some(hosts), sudo: false },
+            })
+            .collect();
+
+        Ok(StepPlan {
+            command: name.to_string(),
+            network: self.inner.env.get("SUP_NETWORK").cloned().unwrap_or_default(),
+            desc: command.desc.clone(),
+            hosts,
+            users,
+            serial: command.serial.filter(|_| !command.once).map(|serial| serial.to_string()),
+            batches,
+            once: command.once,
+            once_per_batch,
+            canary: command.canary,
+            guards,
+            actions,
+            group: None,
+        })
+    }
+
+    /// `==> name (desc, 2 at a time) on 8 hosts [network]`
+    fn banner(&self, name: &str, command: &Command, hosts: &[String]) -> Result<String> {
+        let mut details = Vec::new();
+        if let Some(desc) = &command.desc {
+            details.push(desc.clone());
+        }
+        // Call out command-line overrides so they are never a surprise
+        let via = |flag: &str, set: bool| if set { format!(" via {}", flag) } else { String::new() };
+        if let Some(serial) = command.serial {
+            details.push(format!("{} at a time{}", serial, via("--serial", self.inner.options.serial.is_some())));
+            if command.once_per_batch {
+                details.push("once per batch".to_string());
+            }
+        } else if self.inner.options.parallel {
+            details.push("all at once via --parallel".to_string());
+        }
+        if command.once {
+            let mut host = String::new();
+            if command.ordered_steps().iter().any(|step| matches!(step, Step::Run(_))) {
+                if let Some(first) = self.once_candidates(name, command, hosts.to_vec())?.first() {
+                    host = format!(" on {}", first);
+                }
+            }
+            details.push(format!("once{}{}", host, via("--once", self.inner.options.once)));
+        }
+        if command.canary {
+            details.push("canary first".to_string());
+        }
+        let details = if details.is_empty() {
+            String::new()
+        } else {
+            format!(" ({})", details.join(", "))
+        };
+
+        let location = if command.is_remote() {
+            let hosts = hosts.len();
+            format!("on {} host{}", hosts, if hosts == 1 { "" } else { "s" })
+        } else {
+            "locally".to_string()
+        };
+        let network = self.inner.env.get("SUP_NETWORK").map(String::as_str).unwrap_or_default();
+
+        let style = self.style();
+        Ok(format!(
+            "{} {}{} {} [{}]{}",
+            style.paint("==>", Color::Blue).bold(), name.bold(), details, location, network, style.tag(),
+        ))
+    }
+
+    /// Runs the command's steps in order on the filtered `hosts`, stopping
+    /// at the first failure.
+    async fn execute_steps(&self, name: &str, command: &Command, hosts: &[String]) -> Result<()> {
+        for step in command.ordered_steps() {
+            match &step {
+                Step::Local(local_cmd) => match self.local_skip_reason(command).await {
+                    Some(reason) => {
+                        if !command.quiet {
+                            println!("{} {}", "LOCAL".green(), format!("SKIPPED ({})", reason).yellow());
+                        }
+                        self.summary.record(name, LOCAL_HOST, HostStatus::Skipped(reason));
+                    }
+                    None => {
+                        let started = clock::now();
+                        let result = self.execute_local(&self.substitute_args(local_cmd), command).await;
+                        self.record_result(name, LOCAL_HOST, &result, started);
+                        result?;
+                    }
+                },
+                Step::Script(script) => self.execute_script(script, command).await?,
+                Step::Run(remote_cmd) if command.detach => {
+                    let log = command.detach_log(name);
+                    let cmd = detach_script(&self.prepare_remote_command(&self.substitute_args(remote_cmd), command.sudo), &log);
+                    self.execute_ssh(name, command, &cmd, hosts).await?;
+                    if !command.quiet {
+                        println!("{} follow the output with: {}", "DETACHED".yellow(), self.follow_command(&log));
+                    }
+                }
+                Step::Run(remote_cmd) => {
+                    let cmd = self.prepare_remote_command(&self.substitute_args(remote_cmd), command.sudo);
+                    self.execute_ssh(name, command, &cmd, hosts).await?
+                }
+                Step::Upload(uploads) => self.execute_upload(name, uploads, hosts).await?,
+                Step::Template(templates) => self.execute_templates(name, templates, hosts).await?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What a command's `run` reads on stdin: `stdin_data`, or the file named
+/// by `stdin_file`, opened afresh for every host so it is streamed.
+#[derive(Debug, Clone)]
+enum RunInput {
+    Data(Arc<[u8]>),
+    File(PathBuf),
+}
+
+impl RunInput {
+    fn open(&self) -> Result<Box<dyn Read + Send>> {
+        Ok(match self {
+            RunInput::Data(data) => Box::new(std::io::Cursor::new(data.clone())),
+            RunInput::File(path) => Box::new(std::fs::File::open(path)
+                .with_context(|| format!("Failed to open stdin_file {}", path.display()))?),
+        })
+    }
+}
+
+/// Runs `true` on `host`, returning why it failed or timed out.
+async fn probe(transport: &dyn Transport, host: &Target) -> std::result::Result<(), String> {
+    match tokio::time::timeout(PREFLIGHT_TIMEOUT, transport.exec(host, "true", None)).await {
+        Ok(Ok(output)) if output.success() => Ok(()),
+        Ok(Ok(output)) => Err(String::from_utf8_lossy(&output.stderr).trim().to_string()),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err(format!("timed out after {:?}", PREFLIGHT_TIMEOUT)),
+    }
+}
+
+/// Check that `path` exists on the host, quoted so the path is taken as
+/// one word whatever it contains.
+fn exists_check(path: &str) -> String {
+    format!("test -e {}", shell_quote::sh::quote(path).to_string_lossy())
+}
+
+/// `cmd` started in a session of its own with its output going to `log`,
+/// so it outlives the ssh session. Prints the PID once the process is
+/// still running a second later; if it already exited, its status is the
+/// script's.
+fn detach_script(cmd: &str, log: &str) -> String {
+    let quote = |value: &str| shell_quote::sh::quote(value).to_string_lossy().into_owned();
+    let (cmd, log) = (quote(cmd), quote(log));
+    [
+        format!("mkdir -p \"$(dirname {log})\" || exit 1"),
+        "setsid=; command -v setsid >/dev/null 2>&1 && setsid=setsid".to_string(),
+        format!("$setsid nohup sh -c {cmd} > {log} 2>&1 < /dev/null &"),
+        "pid=$!; sleep 1".to_string(),
+        format!("if kill -0 $pid 2>/dev/null; then echo \"detached as PID $pid, logging to \"{log}; exit 0; fi"),
+        format!("wait $pid; status=$?; echo \"exited with status $status before detaching, see \"{log}; exit $status"),
+    ]
+    .join("\n")
+}
+
+fn check_mode(mode: &str, what: &str) -> Result<()> {
+    if mode.is_empty() || !mode.chars().all(|c| c.is_digit(8)) {
+        anyhow::bail!("Invalid mode {:?} for {}, expected octal like \"0644\"", mode, what);
+    }
+    Ok(())
+}
+
+/// chmod/chown commands applying an upload's mode and ownership to `paths`,
+/// run through sudo when the owner is not the user the host is reached as.
+fn ownership_script(upload: &Upload, paths: &[String], user: Option<&str>) -> Result<Option<String>> {
+    Ok(ownership_steps(upload, paths)?.map(|script| as_owner(upload, user, script)))
+}
+
+/// chmod/chown commands applying an upload's mode and ownership to `paths`.
+fn ownership_steps(upload: &Upload, paths: &[String]) -> Result<Option<String>> {
+    let quote = |value: &str| shell_quote::sh::quote(value).to_string_lossy().into_owned();
+    let flag = if upload.recursive { "-R " } else { "" };
+    let path = paths.iter().map(|path| quote(path)).collect::<Vec<_>>().join(" ");
+
+    let mut steps = Vec::new();
+    if let Some(mode) = &upload.mode {
+        check_mode(mode, &upload.source())?;
+        steps.push(format!("chmod {}{} {}", flag, mode, path));
+    }
+    match (&upload.owner, &upload.group) {
+        (Some(owner), Some(group)) => steps.push(format!("chown {}{} {}", flag, quote(&format!("{}:{}", owner, group)), path)),
+        (Some(owner), None) => steps.push(format!("chown {}{} {}", flag, quote(owner), path)),
+        (None, Some(group)) => steps.push(format!("chgrp {}{} {}", flag, quote(group), path)),
+        (None, None) => {}
+    }
+    Ok((!steps.is_empty()).then(|| steps.join(" && ")))
+}
+
+/// `script` run through sudo when the upload's owner is not `user`, the
+/// user the host is reached as.
+fn as_owner(upload: &Upload, user: Option<&str>, script: String) -> String {
+    let needs_sudo = matches!((&upload.owner, user), (Some(owner), Some(user)) if owner != user);
+    if needs_sudo { format!("sudo sh -c {}", shell_quote::sh::quote(&script).to_string_lossy()) } else { script }
+}
+
+/// Shell-quotes each argument and joins them with spaces.
+fn join_args(args: &[String]) -> String {
+    args.iter()
+        .map(|arg| shell_quote::sh::quote(arg).to_string_lossy().into_owned())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// `SUP_ARGS` plus `SUP_ARG_1..n`, or nothing when no arguments were given.
+fn args_env(args: &[String]) -> Vec<(String, String)> {
+    if args.is_empty() {
+        return Vec::new();
+    }
+    let mut env = vec![("SUP_ARGS".to_string(), join_args(args))];
+    env.extend(args.iter().enumerate().map(|(i, arg)| (format!("SUP_ARG_{}", i + 1), arg.clone())));
+    env
+}
+
+/// Runs a local process with its output captured, writing the output out
+/// only if the process fails.
+async fn quiet_status(mut process: AsyncCommand) -> Result<ExitStatus> {
+    let output = process.output().await?;
+    if !output.status.success() {
+        output::write_raw(OutputStream::Stdout, &output.stdout);
+        output::write_raw(OutputStream::Stderr, &output.stderr);
+    }
+    Ok(output.status)
+}
+
+/// Summary entry for a finished step on one host.
+fn host_result<T>(name: &str, host: &str, result: &Result<T>, started: DateTime<Local>) -> HostResult {
+    let (status, exit_code) = match result {
+        Ok(_) => (HostStatus::Success, Some(0)),
+        // Nobody read its output any more; the host did not fail
+        Err(e) if e.chain().any(|cause| cause.is::<SessionCancelled>()) => (HostStatus::Skipped("cancelled".to_string()), None),
+        Err(e) => (
+            HostStatus::Failed(e.to_string()),
+            e.downcast_ref::<ExitCodeError>().map(|e| e.code),
+        ),
+    };
+    let finished = clock::now();
+    HostResult {
+        exit_code,
+        started: Some(started),
+        finished: Some(finished),
+        elapsed: (finished - started).to_std().ok(),
+        ..HostResult::new(name, host, status)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::{ExecOutput, SubprocessTransport, TransportOptions};
+    use async_trait::async_trait;
+    use std::sync::Mutex;
+
+    /// Records every call and fails those whose command is listed for the host.
+    #[derive(Debug, Default)]
+    struct MockTransport {
+        failing: Vec<(String, String)>,
+        calls: Mutex<Vec<(String, String)>>,
+    }
+
+    impl MockTransport {
+        fn fails(&self, host: &Target, cmd: &str) -> bool {
+            // Record the command without the exports of the run's hosts
+            let mut cmd = cmd;
+            while let Some((_, rest)) = cmd.strip_prefix("export ").and_then(|cmd| cmd.split_once("; ")) {
+                cmd = rest;
+            }
+            self.calls.lock().unwrap().push((host.to_string(), cmd.to_string()));
+            self.failing.iter().any(|(h, c)| *h == host.to_string() && c == cmd)
+        }
+    }
+
+    #[async_trait]
+    impl Transport for MockTransport {
+        async fn session(
+            &self,
+            host: &Target,
+            cmd: &str,
+            _stdin: Option<Box<dyn Read + Send>>,
+            _tx: Option<mpsc::Sender<OutputLine>>,
+        ) -> Result<()> {
+            if self.fails(host, cmd) {
+                anyhow::bail!("exit status: 1");
+            }
+            Ok(())
+        }
+
+        async fn interactive(&self, host: &Target, cmd: &str) -> Result<()> {
+            self.session(host, cmd, None, None).await
+        }
+
+        async fn exec(
+            &self,
+            host: &Target,
+            script: &str,
+            stdin: Option<Box<dyn Read + Send>>,
+        ) -> Result<ExecOutput> {
+            let exit_code = if self.fails(host, script) { 1 } else { 0 };
+            let bytes_sent = match stdin {
+                Some(mut input) => std::io::copy(&mut input, &mut std::io::sink())?,
+                None => 0,
+            };
+            Ok(ExecOutput { exit_code: Some(exit_code), stdout: Vec::new(), stderr: Vec::new(), bytes_sent })
+        }
+    }
 
-        // Test sudo command with leading whitespace
-        let cmd_with_space = "   sudo apt-get install -y package";
-        let prepared = executor.prepare_remote_command(cmd_with_space);
-        assert!(prepared.starts_with("sudo -E bash -c "));
-        assert!(prepared.contains("apt-get"));
-
-        // Test sudo command with multiple spaces
-        let cmd_multi_space = "sudo   apt-get    install   -y    package";
-        let prepared = executor.prepare_remote_command(cmd_multi_space);
-        assert!(prepared.starts_with("sudo -E bash -c "));
-        assert!(prepared.contains("apt-get"));
-        assert!(prepared.contains("install"));
-        assert!(prepared.contains("package"));
-    }
-}
\ No newline at end of file
+    fn create_test_executor() -> Executor {
+        let network = Network {
+            hosts: vec!["test@localhost".into()],
+            ..Default::default()
+        };
+        let env = EnvMap::new();
+        let transport = Arc::new(SubprocessTransport::new(TransportOptions::default()));
+        Executor::new(network, transport, env, ExecutorOptions::default()).unwrap()
+    }
+
+    #[test]
+    fn test_prepare_remote_command() {
+        let executor = create_test_executor();
+
+        // Without the sudo field, commands go out as written, including a
+        // sudo of their own anywhere in them
+        for cmd in [
+            "echo 'hello world'",
+            "sudo -u deploy ./migrate.sh",
+            "cd /opt/app && sudo systemctl restart app",
+            "cat app.conf | sudo tee /etc/app.conf > /dev/null",
+        ] {
+            assert_eq!(executor.prepare_remote_command(cmd, false), cmd);
+        }
+
+        // With it, the whole command runs through sudo, which keeps the env
+        let prepared = executor.prepare_remote_command("cd /opt/app && systemctl restart app", true);
+        assert_eq!(prepared, "sudo -E bash -c 'cd /opt/app && systemctl restart app'");
+        let prepared = executor.prepare_remote_command("  sudo -u deploy ./migrate.sh", true);
+        assert_eq!(prepared, "sudo -E bash -c 'sudo -u deploy ./migrate.sh'");
+    }
+
+    #[test]
+    fn test_sudo_command_quoting() {
+        let executor = create_test_executor();
+        // Run the prepared command the way the remote sh -c would, minus sudo
+        let run = |cmd: &str| {
+            let prepared = executor.prepare_remote_command(cmd, true);
+            let unprivileged = prepared.strip_prefix("sudo -E ").unwrap();
+            let output = std::process::Command::new("sh").arg("-c").arg(unprivileged).output().unwrap();
+            String::from_utf8(output.stdout).unwrap()
+        };
+
+        assert_eq!(run(r#"echo "a  b" 'c  d'"#), "a  b c  d\n");
+        assert_eq!(run(r#"sh -c 'echo "it'\''s" $0' x"#), "it's x\n");
+        assert_eq!(run("X=1; echo \"$X\" '$X'"), "1 $X\n");
+        assert_eq!(run("echo '/*' *.nothing-matches\necho next"), "/* *.nothing-matches\nnext\n");
+        assert_eq!(run("echo a b | tr ' ' -"), "a-b\n");
+    }
+
+    #[test]
+    fn test_exists_check_quoting() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("sup-exists-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let exists = |path: &Path| {
+            std::process::Command::new("sh").arg("-c").arg(exists_check(path.to_str().unwrap())).current_dir(&dir).status().unwrap().success()
+        };
+
+        let path = dir.join("it's a file");
+        assert!(!exists(&path));
+        std::fs::write(&path, "")?;
+        assert!(exists(&path));
+        // A path cannot end the quoting and run commands of its own
+        assert!(!exists(&dir.join("x'; touch injected; echo '")));
+        assert!(!dir.join("injected").exists());
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_args_quoting() -> Result<()> {
+        let out_file = std::env::temp_dir().join(format!("sup-args-{}", std::process::id()));
+        let transport = Arc::new(SubprocessTransport::new(TransportOptions::default()));
+        let args = vec!["--service".to_string(), "api gateway".to_string(), "it's \"quoted\"".to_string()];
+        let executor = Executor::new(Network::default(), transport, EnvMap::new(), ExecutorOptions {
+            args: args.clone(),
+            ..Default::default()
+        })?;
+        let expected = args.iter().map(|arg| format!("{}\n", arg)).collect::<String>();
+
+        // Locally through {{args}} and the SUP_ARG_n variables
+        let cmd = format!("printf '%s\\n' {{{{args}}}} > {}", out_file.display());
+        executor.execute_local(&executor.substitute_args(&cmd), &Command::default()).await?;
+        assert_eq!(std::fs::read_to_string(&out_file)?, expected);
+
+        let cmd = format!("printf '%s\\n' \"$SUP_ARG_1\" \"$SUP_ARG_2\" \"$SUP_ARG_3\" > {}", out_file.display());
+        executor.execute_local(&cmd, &Command::default()).await?;
+        assert_eq!(std::fs::read_to_string(&out_file)?, expected);
+
+        // The exports prefixed to remote commands survive a second shell
+        let cmd = format!("eval \"printf '%s\\n' $SUP_ARGS\" > {}", out_file.display());
+        let status = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(executor.with_exports(&Target::parse("app@web1")?, &cmd))
+            .env_clear()
+            .status()?;
+        let written = std::fs::read_to_string(&out_file)?;
+        let _ = std::fs::remove_file(&out_file);
+        assert!(status.success());
+        assert_eq!(written, expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_templates_render_per_host() -> Result<()> {
+        let src = std::env::temp_dir().join(format!("sup-template-{}.conf", std::process::id()));
+        std::fs::write(&src, "host {{ SUP_HOST }} port {{ PORT }}\n")?;
+        let network = Network {
+            hosts: vec!["app@web1".into(), "app@web22".into()],
+            ..Default::default()
+        };
+        let transport = Arc::new(MockTransport::default());
+        let executor = Executor::new(network, transport.clone(), EnvMap::new(), ExecutorOptions::default())?;
+        let template = Template {
+            src: src.display().to_string(),
+            dst: "/etc/app/app.conf".to_string(),
+            vars: EnvMap::from([("PORT".to_string(), "8080".to_string())]),
+            mode: Some("0640".to_string()),
+        };
+
+        executor.execute_templates("config", std::slice::from_ref(&template), &executor.resolve_hosts().await?).await?;
+        let bytes = executor.summary().results().iter()
+            .map(|result| result.bytes_uploaded)
+            .collect::<Vec<_>>();
+        assert_eq!(bytes, [Some("host app@web1 port 8080\n".len() as u64), Some("host app@web22 port 8080\n".len() as u64)]);
+        let script = transport.calls.lock().unwrap()[0].1.clone();
+        assert!(script.contains("&& chmod 0640 "), "{}", script);
+
+        // A missing variable fails naming the placeholder and the file
+        let missing = Template { vars: EnvMap::new(), ..template };
+        let err = executor.execute_templates("config", &[missing], &executor.resolve_hosts().await?).await.unwrap_err().to_string();
+        std::fs::remove_file(&src)?;
+        assert!(err.contains("undefined variable {{ PORT }}") && err.contains("sup-template"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn test_ownership_script() -> Result<()> {
+        let upload = |mode: Option<&str>, owner: Option<&str>, group: Option<&str>, recursive| Upload {
+            src: vec!["./app.service".to_string()],
+            dst: "/etc/systemd/system/".to_string(),
+            mode: mode.map(str::to_string),
+            owner: owner.map(str::to_string),
+            group: group.map(str::to_string),
+            recursive,
+            ..Default::default()
+        };
+        let path = &["/etc/systemd/system/app.service".to_string()];
+        let quoted = shell_quote::sh::quote(&path[0]).to_string_lossy().into_owned();
+
+        assert_eq!(ownership_script(&upload(None, None, None, false), path, Some("deploy"))?, None);
+        assert_eq!(
+            ownership_script(&upload(Some("0644"), None, Some("www"), true), path, Some("deploy"))?,
+            Some(format!("chmod -R 0644 {0} && chgrp -R {1} {0}", quoted, shell_quote::sh::quote("www").to_string_lossy()))
+        );
+
+        // Handing the file to another owner needs sudo; keeping it does not
+        let script = ownership_script(&upload(Some("0644"), Some("root"), Some("root"), false), path, Some("deploy"))?.unwrap();
+        assert!(script.starts_with("sudo sh -c "), "{}", script);
+        assert!(script.contains("chown"), "{}", script);
+        let script = ownership_script(&upload(None, Some("deploy"), None, false), path, Some("deploy"))?.unwrap();
+        assert!(script.starts_with("chown "), "{}", script);
+
+        assert!(ownership_script(&upload(Some("rw-r--r--"), None, None, false), path, None).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_require_args() -> Result<()> {
+        let executor = create_test_executor();
+        let command = Command { require_args: true, ..Default::default() };
+        let err = executor.execute_command("restart", &command).await.unwrap_err().to_string();
+        assert!(err.contains("restart requires arguments"), "{}", err);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_host_fails_within_connect_timeout() -> Result<()> {
+        use std::time::{Duration, Instant};
+
+        // 192.0.2.0/24 is reserved for documentation and never routed
+        let network = Network {
+            hosts: vec!["sup@192.0.2.1".into()],
+            ..Default::default()
+        };
+        let options = TransportOptions {
+            connect_timeout: Some(1),
+            ..Default::default()
+        };
+        let transport = Arc::new(SubprocessTransport::new(options));
+        let executor = Executor::new(network, transport, EnvMap::new(), ExecutorOptions::default())?;
+
+        let host = Target::parse("sup@192.0.2.1")?;
+        let start = Instant::now();
+        let result = executor.handle_ssh_session(&host, "true", None, None).await;
+        assert!(result.is_err());
+        assert!(start.elapsed() < Duration::from_secs(5));
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "requires key-based ssh access to localhost"]
+    async fn test_multiplexing_speeds_up_consecutive_commands() -> Result<()> {
+        use crate::transport::ControlDir;
+        use std::time::{Duration, Instant};
+
+        let network = Network {
+            hosts: vec![format!("{}@localhost", whoami::username()).into()],
+            ..Default::default()
+        };
+
+        async fn second_run(executor: &Executor) -> Result<Duration> {
+            let host = Target::parse(&executor.inner.network.hosts[0].address())?;
+            executor.handle_ssh_session(&host, "true", None, None).await?;
+            let start = Instant::now();
+            executor.handle_ssh_session(&host, "true", None, None).await?;
+            Ok(start.elapsed())
+        }
+
+        let plain_transport = Arc::new(SubprocessTransport::new(TransportOptions::default()));
+        let plain = Executor::new(network.clone(), plain_transport, EnvMap::new(), ExecutorOptions::default())?;
+        let plain_elapsed = second_run(&plain).await?;
+
+        let control_dir = ControlDir::create()?;
+        let options = TransportOptions {
+            control_path: Some(control_dir.control_path(&TransportOptions::default())),
+            ..Default::default()
+        };
+        let transport = Arc::new(SubprocessTransport::new(options));
+        let multiplexed = Executor::new(network, transport, EnvMap::new(), ExecutorOptions::default())?;
+        let multiplexed_elapsed = second_run(&multiplexed).await;
+        drop(control_dir);
+
+        assert!(multiplexed_elapsed? < plain_elapsed);
+        Ok(())
+    }
+
+    #[cfg(feature = "docker-tests")]
+    #[tokio::test]
+    async fn test_docker_transport() -> Result<()> {
+        use std::process::Command as ProcessCommand;
+
+        let container = "sup-rs-docker-test";
+        let _ = ProcessCommand::new("docker").args(["rm", "-f", container]).output();
+        let started = ProcessCommand::new("docker")
+            .args(["run", "-d", "--name", container, "alpine", "sleep", "300"])
+            .status()?;
+        assert!(started.success());
+
+        let network = Network {
+            hosts: vec![format!("docker://{}", container).into()],
+            ..Default::default()
+        };
+        let transport = Arc::new(SubprocessTransport::new(TransportOptions::default()));
+        let executor = Executor::new(network, transport, EnvMap::new(), ExecutorOptions::default())?;
+
+        let command = Command {
+            run: Some("echo hello > /tmp/sup-run".to_string()),
+            upload: Some(vec![Upload {
+                src: vec!["./dist".to_string()],
+                dst: "/srv/".to_string(),
+                ..Default::default()
+            }]),
+            serial: Some(Serial::Count(1)),
+            ..Default::default()
+        };
+        let result = executor.execute_command("deploy", &command).await;
+
+        let check = ProcessCommand::new("docker")
+            .args(["exec", container, "sh", "-c", "cat /tmp/sup-run /srv/dist/test.txt"])
+            .output()?;
+        let _ = ProcessCommand::new("docker").args(["rm", "-f", container]).output();
+
+        result?;
+        let stdout = String::from_utf8_lossy(&check.stdout);
+        assert!(stdout.starts_with("hello"));
+        assert!(stdout.contains("test"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_when_skips_hosts() -> Result<()> {
+        let network = Network {
+            hosts: vec!["app@web1".into(), "app@web2".into()],
+            ..Default::default()
+        };
+        let transport = Arc::new(MockTransport {
+            failing: vec![("app@web2".to_string(), "test -f /etc/app.conf".to_string())],
+            ..Default::default()
+        });
+        let executor = Executor::new(network, transport.clone(), EnvMap::new(), ExecutorOptions::default())?;
+        let command = Command {
+            run: Some("systemctl restart app".to_string()),
+            when: Some("test -f /etc/app.conf".to_string()),
+            ..Default::default()
+        };
+
+        executor.execute_command("restart", &command).await?;
+
+        assert_eq!(executor.summary().counts(), (1, 0, 1));
+        let ran = transport.calls.lock().unwrap().iter()
+            .filter(|(_, cmd)| cmd == "systemctl restart app")
+            .map(|(host, _)| host.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(ran, ["app@web1"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_local_when_skip() -> Result<()> {
+        let executor = create_test_executor();
+        let command = Command {
+            local: Some("exit 1".to_string()),
+            when: Some("false".to_string()),
+            ..Default::default()
+        };
+
+        executor.execute_command("build", &command).await?;
+
+        let results = executor.summary().results();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].host, LOCAL_HOST);
+        assert_eq!(results[0].status, HostStatus::Skipped("when failed".to_string()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_steps_run_in_order() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("sup-steps-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(dir.join("compose.yml"), "services: {}\n")?;
+        let network = Network { hosts: vec!["app@web1".into()], ..Default::default() };
+        let transport = Arc::new(MockTransport::default());
+        let executor = Executor::new(network, transport.clone(), EnvMap::new(), ExecutorOptions::default())?;
+
+        let command: Command = serde_yaml::from_str(&format!(r#"
+steps:
+  - run: docker compose down
+  - upload:
+      - src: {}
+        dst: /srv/app/
+  - run: docker compose up -d
+"#, dir.join("compose.yml").display()))?;
+        executor.execute_command("deploy", &command).await?;
+
+        let calls = transport.calls.lock().unwrap().iter().map(|(_, cmd)| cmd.clone()).collect::<Vec<_>>();
+        let position = |pattern: &str| calls.iter().position(|cmd| cmd.contains(pattern));
+        let (down, upload, up) = (position("compose down"), position("tar "), position("compose up"));
+        assert!(down < upload && upload < up && down.is_some(), "{:?}", calls);
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_creates_once_falls_through() -> Result<()> {
+        let network = Network {
+            hosts: vec!["app@web1".into(), "app@web2".into(), "app@web3".into()],
+            ..Default::default()
+        };
+        // Only web2 lacks docker
+        let transport = Arc::new(MockTransport {
+            failing: vec![("app@web2".to_string(), "test -e '/usr/bin/docker'".to_string())],
+            ..Default::default()
+        });
+        let executor = Executor::new(network, transport.clone(), EnvMap::new(), ExecutorOptions::default())?;
+        let command = Command {
+            run: Some("install-docker".to_string()),
+            creates: Some("/usr/bin/docker".to_string()),
+            once: true,
+            ..Default::default()
+        };
+
+        executor.execute_command("docker", &command).await?;
+
+        let ran = transport.calls.lock().unwrap().iter()
+            .filter(|(_, cmd)| cmd == "install-docker")
+            .map(|(host, _)| host.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(ran, ["app@web2"]);
+        // web3 is never checked once web2 has run
+        assert_eq!(executor.summary().counts(), (1, 0, 1));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_once_on() -> Result<()> {
+        colored::control::set_override(false);
+        let network = Network {
+            hosts: ["app@web1", "app@web2", "app@db1", "app@db2"].map(HostEntry::from).to_vec(),
+            ..Default::default()
+        };
+        let env = EnvMap::from([("SUP_NETWORK".to_string(), "prod".to_string())]);
+        let run_once = |once_on: OnceOn, seed: u64| {
+            let network = network.clone();
+            let env = env.clone();
+            async move {
+                let transport = Arc::new(MockTransport::default());
+                let options = ExecutorOptions { seed: Some(seed), ..Default::default() };
+                let executor = Executor::new(network, transport.clone(), env, options)?;
+                let command = Command { run: Some("./migrate".to_string()), once: true, once_on, ..Default::default() };
+                let banner = executor.banner("migrate", &command, &executor.resolve_hosts().await?)?;
+                executor.execute_command("migrate", &command).await?;
+                let ran = transport.calls.lock().unwrap().iter()
+                    .map(|(host, _)| host.clone())
+                    .collect::<Vec<_>>();
+                anyhow::Ok((banner, ran))
+            }
+        };
+
+        let (banner, ran) = run_once(OnceOn::First, 1).await?;
+        assert_eq!(banner, "==> migrate (once on app@web1) on 4 hosts [prod]");
+        assert_eq!(ran, ["app@web1"]);
+
+        let (banner, ran) = run_once(OnceOn::Regex("@db".to_string()), 1).await?;
+        assert!(banner.contains("once on app@db1"), "{}", banner);
+        assert_eq!(ran, ["app@db1"]);
+
+        // The banner names the host the command then runs on, and the seed
+        // makes the pick reproducible
+        let mut picked = Vec::new();
+        for seed in 0..20 {
+            let (banner, ran) = run_once(OnceOn::Random, seed).await?;
+            assert_eq!(ran.len(), 1);
+            assert!(banner.contains(&format!("once on {}", ran[0])), "{}", banner);
+            assert_eq!(run_once(OnceOn::Random, seed).await?.1, ran);
+            picked.extend(ran);
+        }
+        picked.sort();
+        picked.dedup();
+        assert!(picked.len() > 1, "{:?}", picked);
+
+        let err = run_once(OnceOn::Regex("^cache".to_string()), 1).await.unwrap_err();
+        assert!(err.to_string().contains("No host of migrate matches once_on ^cache"), "{}", err);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_once_failure_names_host() -> Result<()> {
+        let network = Network {
+            hosts: vec!["app@web1".into(), "app@web2".into()],
+            ..Default::default()
+        };
+        let transport = Arc::new(MockTransport {
+            failing: vec![("app@web2".to_string(), "./migrate".to_string())],
+            ..Default::default()
+        });
+        let executor = Executor::new(network, transport, EnvMap::new(), ExecutorOptions::default())?;
+        let command = Command {
+            run: Some("./migrate".to_string()),
+            once: true,
+            once_on: OnceOn::Regex("web2".to_string()),
+            ..Default::default()
+        };
+
+        let err = executor.execute_command("migrate", &command).await.unwrap_err();
+        assert_eq!(format!("{:#}", err), "migrate failed on app@web2: exit status: 1");
+        assert_eq!(ExitCode::of(&err), ExitCode::HostsFailed);
+        let results = executor.summary().results();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].host, "app@web2");
+        assert!(matches!(results[0].status, HostStatus::Failed(_)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_removes_skips_missing_path() -> Result<()> {
+        let network = Network {
+            hosts: vec!["app@web1".into(), "app@web2".into()],
+            ..Default::default()
+        };
+        let transport = Arc::new(MockTransport {
+            failing: vec![("app@web1".to_string(), "test -e '/tmp/installer'".to_string())],
+            ..Default::default()
+        });
+        let executor = Executor::new(network, transport, EnvMap::new(), ExecutorOptions::default())?;
+        let command = Command {
+            run: Some("rm /tmp/installer".to_string()),
+            removes: Some("/tmp/installer".to_string()),
+            serial: Some(Serial::Count(1)),
+            ..Default::default()
+        };
+
+        executor.execute_command("cleanup", &command).await?;
+
+        let results = executor.summary().results();
+        assert_eq!(results[0].host, "app@web1");
+        assert_eq!(results[0].status, HostStatus::Skipped("/tmp/installer missing".to_string()));
+        assert_eq!(results[1].status, HostStatus::Success);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_canary_runs_first_then_rest() -> Result<()> {
+        let network = Network {
+            hosts: vec!["app@web1".into(), "app@web2".into(), "app@web3".into()],
+            ..Default::default()
+        };
+        let transport = Arc::new(MockTransport::default());
+        let options = ExecutorOptions { assume_yes: true, ..Default::default() };
+        let executor = Executor::new(network, transport.clone(), EnvMap::new(), options)?;
+        let command = Command {
+            run: Some("deploy".to_string()),
+            canary: true,
+            canary_host: Some("web2".to_string()),
+            serial: Some(Serial::Count(1)),
+            ..Default::default()
+        };
+
+        executor.execute_command("deploy", &command).await?;
+
+        let ran = transport.calls.lock().unwrap().iter()
+            .map(|(host, _)| host.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(ran, ["app@web2", "app@web1", "app@web3"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_inventory_runs_once() -> Result<()> {
+        let counter = std::env::temp_dir().join(format!("sup-inventory-{}", std::process::id()));
+        let network = Network {
+            inventory: Some(format!("echo run >> {}; printf 'app@web1\\napp@web2\\n'", counter.display())),
+            ..Default::default()
+        };
+        let transport = Arc::new(MockTransport::default());
+        let mut executor = Executor::new(network, transport.clone(), EnvMap::new(), ExecutorOptions::default())?;
+        let command = Command { run: Some("uptime".to_string()), ..Default::default() };
+
+        // Banner, host resolution and every per-host task share one inventory
+        executor.execute_command("uptime", &command).await?;
+        executor.execute_command("uptime", &command).await?;
+        assert_eq!(executor.resolve_hosts().await?, ["app@web1", "app@web2"]);
+        assert_eq!(transport.calls.lock().unwrap().len(), 4);
+        assert_eq!(std::fs::read_to_string(&counter)?, "run\n");
+
+        executor.refresh_inventory();
+        executor.resolve_hosts().await?;
+        assert_eq!(std::fs::read_to_string(&counter)?, "run\nrun\n");
+
+        std::fs::remove_file(&counter)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_inventory_gets_base_env() -> Result<()> {
+        let network = Network {
+            inventory: Some("printf '%s\\n' \"$PATH\"".to_string()),
+            ..Default::default()
+        };
+        let path = std::env::var("PATH")?;
+
+        // An env without PATH still finds the tools sup itself would
+        let executor = Executor::new(network.clone(), Arc::new(MockTransport::default()), EnvMap::new(), ExecutorOptions::default())?;
+        assert_eq!(executor.resolve_hosts().await?, [path]);
+
+        // The run's env wins over the base
+        let env = EnvMap::from([("PATH".to_string(), "/opt/tools/bin:/usr/bin".to_string())]);
+        let executor = Executor::new(network, Arc::new(MockTransport::default()), env, ExecutorOptions::default())?;
+        assert_eq!(executor.resolve_hosts().await?, ["/opt/tools/bin:/usr/bin"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ping() -> Result<()> {
+        let network = Network {
+            hosts: vec!["app@web1".into(), "app@web2".into()],
+            ..Default::default()
+        };
+        let transport = Arc::new(MockTransport {
+            failing: vec![("app@web2".to_string(), "true".to_string())],
+            ..Default::default()
+        });
+        let executor = Executor::new(network, transport.clone(), EnvMap::new(), ExecutorOptions::default())?;
+
+        let results = executor.ping(3).await?;
+        assert_eq!(results[0].host, "app@web1");
+        assert_eq!(results[0].samples.len(), 3);
+        assert!(results[0].error.is_none());
+        // A failing host is not probed again
+        assert!(results[1].samples.is_empty());
+        assert!(results[1].error.is_some());
+        assert_eq!(transport.calls.lock().unwrap().len(), 4);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reconfigure_keeps_inventory() -> Result<()> {
+        let network = Network {
+            inventory: Some("printf 'app@web1\\napp@web2\\n'".to_string()),
+            ..Default::default()
+        };
+        let mut executor = Executor::new(network, Arc::new(MockTransport::default()), EnvMap::new(), ExecutorOptions::default())?;
+        assert_eq!(executor.resolve_hosts().await?.len(), 2);
+
+        executor.reconfigure(|options, env| {
+            options.only = Some("web2".to_string());
+            env.insert("RELEASE".to_string(), "v2".to_string());
+        })?;
+        assert_eq!(executor.resolve_hosts().await?, ["app@web2"]);
+        assert_eq!(executor.inner.env["RELEASE"], "v2");
+        assert!(executor.inner.inventory.initialized());
+
+        assert!(executor.reconfigure(|options, _| options.only = Some("web[".to_string())).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_failed_host_fails_command() -> Result<()> {
+        let network = Network {
+            hosts: vec!["app@web1".into(), "app@web2".into(), "app@web3".into()],
+            ..Default::default()
+        };
+        let transport = Arc::new(MockTransport {
+            failing: vec![("app@web2".to_string(), "false".to_string())],
+            ..Default::default()
+        });
+        let options = ExecutorOptions { quiet: true, ..Default::default() };
+        let executor = Executor::new(network, transport.clone(), EnvMap::new(), options)?;
+        let mut command = Command { run: Some("false".to_string()), ..Default::default() };
+
+        let err = executor.execute_command("check", &command).await.unwrap_err().to_string();
+        assert_eq!(err, "check failed on 1 of 3 hosts: app@web2");
+        assert_eq!(executor.summary().counts(), (2, 1, 0));
+
+        // Serial runs stop after the failing batch
+        command.serial = Some(Serial::Count(2));
+        let err = executor.execute_command("check", &command).await.unwrap_err().to_string();
+        assert_eq!(err, "check failed on 1 of 3 hosts: app@web2");
+        assert_eq!(executor.summary().counts_since(3), (1, 1, 1));
+        assert_eq!(transport.calls.lock().unwrap().len(), 5);
+        Ok(())
+    }
+
+    /// Sends `lines` lines per session, after a delay on web1, and records
+    /// when each session finished.
+    #[derive(Debug, Default)]
+    struct ChattyTransport {
+        lines: usize,
+        finished: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl Transport for ChattyTransport {
+        async fn session(&self, host: &Target, _cmd: &str, _stdin: Option<Box<dyn Read + Send>>, tx: Option<mpsc::Sender<OutputLine>>) -> Result<()> {
+            if host.to_string() == "app@web1" {
+                tokio::time::sleep(Duration::from_millis(300)).await;
+            }
+            if let Some(tx) = tx {
+                for i in 0..self.lines {
+                    tx.send((host.to_string(), OutputStream::Stdout, format!("{}\n", i).into_bytes())).await?;
+                }
+            }
+            self.finished.lock().unwrap().push(host.to_string());
+            Ok(())
+        }
+
+        async fn interactive(&self, host: &Target, cmd: &str) -> Result<()> {
+            self.session(host, cmd, None, None).await
+        }
+
+        async fn exec(&self, _host: &Target, _script: &str, _stdin: Option<Box<dyn Read + Send>>) -> Result<ExecOutput> {
+            Ok(ExecOutput { exit_code: Some(0), stdout: Vec::new(), stderr: Vec::new(), bytes_sent: 0 })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_serial_batch_streams_all_hosts() -> Result<()> {
+        let network = Network {
+            hosts: vec!["app@web1".into(), "app@web2".into()],
+            ..Default::default()
+        };
+        // More lines than a channel holds, so an undrained host would block
+        let transport = Arc::new(ChattyTransport { lines: 100, ..Default::default() });
+        let options = ExecutorOptions { quiet: true, ..Default::default() };
+        let executor = Executor::new(network, transport.clone(), EnvMap::new(), options)?;
+        let command = Command {
+            run: Some("deploy".to_string()),
+            serial: Some(Serial::Count(2)),
+            ..Default::default()
+        };
+
+        executor.execute_command("deploy", &command).await?;
+        assert_eq!(*transport.finished.lock().unwrap(), ["app@web2", "app@web1"]);
+        assert_eq!(executor.summary().counts(), (2, 0, 0));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_once_per_batch() -> Result<()> {
+        let network = Network {
+            hosts: (1..=6).map(|i| format!("app@web{}", i).into()).collect(),
+            ..Default::default()
+        };
+        let transport = Arc::new(MockTransport::default());
+        let executor = Executor::new(network, transport.clone(), EnvMap::new(), ExecutorOptions::default())?;
+        let command = Command {
+            run: Some("warm-cache".to_string()),
+            serial: Some(Serial::Count(2)),
+            once_per_batch: true,
+            ..Default::default()
+        };
+
+        executor.execute_command("warm", &command).await?;
+        let ran = transport.calls.lock().unwrap().iter().map(|(host, _)| host.clone()).collect::<Vec<_>>();
+        assert_eq!(ran, ["app@web1", "app@web3", "app@web5"]);
+        let skipped = executor.summary().results().into_iter()
+            .filter(|result| result.status == HostStatus::Skipped("once per batch".to_string()))
+            .map(|result| result.host)
+            .collect::<Vec<_>>();
+        assert_eq!(skipped, ["app@web2", "app@web4", "app@web6"]);
+        assert_eq!(executor.summary().counts(), (3, 0, 3));
+
+        let plan = executor.plan_step("warm", &command).await?;
+        assert!(plan.once_per_batch);
+        assert_eq!(plan.actions[0].hosts, Some(3));
+
+        // Without batches there is nothing to run once per
+        let mut parallel = executor.clone();
+        parallel.reconfigure(|options, _| options.parallel = true)?;
+        let err = parallel.execute_command("warm", &command).await.unwrap_err();
+        assert!(err.to_string().contains("needs serial batches"), "{}", err);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_polls_until_check_passes() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("sup-wait-for-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        // An ssh that runs the remote command on this machine
+        let fake_ssh = dir.join("ssh");
+        std::fs::write(&fake_ssh, "for last; do :; done\nexec sh -c \"$last\"\n")?;
+        let transport = Arc::new(SubprocessTransport::new(TransportOptions {
+            ssh_binary: Some(format!("sh {}", fake_ssh.display())),
+            connect_timeout: None,
+            ..Default::default()
+        }));
+        let network = Network { hosts: vec!["app@localhost".into()], ..Default::default() };
+        let options = ExecutorOptions { quiet: true, ..Default::default() };
+        let executor = Executor::new(network, transport, EnvMap::new(), options)?;
+
+        // Passes on the third attempt, counted in a temp file
+        let counter = dir.join("attempts");
+        let check = format!("n=$(($(cat {0} 2>/dev/null || echo 0) + 1)); echo $n > {0}; [ $n -ge 3 ]", counter.display());
+        let wait_for = |check: &str, timeout| WaitFor {
+            command: check.to_string(),
+            timeout: Some(Duration::from_millis(timeout)),
+            interval: Some(Duration::from_millis(50)),
+        };
+        let command = Command {
+            run: Some("true".to_string()),
+            wait_for: Some(wait_for(&check, 5000)),
+            ..Default::default()
+        };
+        executor.execute_command("restart", &command).await?;
+        assert_eq!(std::fs::read_to_string(&counter)?.trim(), "3");
+
+        // A check that never passes fails the host once the timeout lapses
+        let command = Command { wait_for: Some(wait_for("false", 300)), ..command };
+        let started = Instant::now();
+        assert!(executor.execute_command("restart", &command).await.is_err());
+        assert!(started.elapsed() < Duration::from_secs(2));
+        let results = executor.summary().results();
+        assert_eq!(results[1].status, HostStatus::Failed("Timed out after 300ms waiting for: false".to_string()));
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_percentage_batches() -> Result<()> {
+        let hosts = (1..=10).map(|i| HostEntry::from(format!("app@web{}", i))).collect::<Vec<_>>();
+        let network = Network { hosts, ..Default::default() };
+        let executor = Executor::new(network, Arc::new(MockTransport::default()), EnvMap::new(), ExecutorOptions::default())?;
+
+        let serial = Serial::Percent(25);
+        let resolved = executor.resolve_hosts().await?;
+        let sizes = resolved.chunks(serial.batch_size(resolved.len()))
+            .map(|batch| batch.len())
+            .collect::<Vec<_>>();
+        assert_eq!(sizes, [3, 3, 3, 1]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_limit_and_order() -> Result<()> {
+        let network = Network {
+            hosts: vec!["app@db1", "app@web3", "app@web1", "app@web2", "app@web4"]
+                .into_iter()
+                .map(HostEntry::from)
+                .collect(),
+            ..Default::default()
+        };
+        let executor_with = |options: ExecutorOptions| {
+            Executor::new(network.clone(), Arc::new(MockTransport::default()), EnvMap::new(), options)
+        };
+
+        // Filters apply before the limit
+        let limited = executor_with(ExecutorOptions {
+            only: Some("^web".to_string()),
+            limit: Some(2),
+            ..Default::default()
+        })?;
+        assert_eq!(limited.resolve_hosts().await?, ["app@web3", "app@web1"]);
+
+        let sorted = executor_with(ExecutorOptions {
+            except: Some("db1".to_string()),
+            order: HostOrder::Sorted,
+            limit: Some(3),
+            ..Default::default()
+        })?;
+        assert_eq!(sorted.resolve_hosts().await?, ["app@web1", "app@web2", "app@web3"]);
+
+        // A fixed seed gives the same shuffle across executors and resolves
+        let shuffled = |seed| executor_with(ExecutorOptions {
+            only: Some("^web".to_string()),
+            order: HostOrder::Shuffle,
+            seed: Some(seed),
+            ..Default::default()
+        });
+        let first = shuffled(7)?;
+        let hosts = first.resolve_hosts().await?;
+        assert_eq!(hosts, first.resolve_hosts().await?);
+        assert_eq!(hosts, shuffled(7)?.resolve_hosts().await?);
+        let mut sorted_hosts = hosts.clone();
+        sorted_hosts.sort();
+        assert_eq!(sorted_hosts, ["app@web1", "app@web2", "app@web3", "app@web4"]);
+
+        // Ranges expand before filtering
+        let ranged = Network { hosts: vec!["app@web[08..11]".into()], ..Default::default() };
+        let executor = Executor::new(ranged, Arc::new(MockTransport::default()), EnvMap::new(), ExecutorOptions {
+            except: Some("web09".to_string()),
+            ..Default::default()
+        })?;
+        assert_eq!(executor.resolve_hosts().await?, ["app@web08", "app@web10", "app@web11"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_filter_hosts() -> Result<()> {
+        let hosts = ["deploy@web1:2222", "deploy@web3", "deploy@web10", "alex@db1.example.com"]
+            .map(String::from);
+        let cases: &[(Option<&str>, Option<&str>, &[&str])] = &[
+            // Plain names match the hostname or the whole entry exactly,
+            // or the numbered hosts of a name
+            (Some("web1,web3"), None, &["deploy@web1:2222", "deploy@web3"]),
+            (Some("deploy@web3"), None, &["deploy@web3"]),
+            (Some("db1.example.com"), None, &["alex@db1.example.com"]),
+            (Some("web"), None, &["deploy@web1:2222", "deploy@web3", "deploy@web10"]),
+            (Some("deploy"), None, &[]),
+            (Some("alex.*"), None, &[]),
+            // Elements with regex metacharacters stay regexes
+            (Some("web1|web3"), None, &["deploy@web1:2222", "deploy@web3", "deploy@web10"]),
+            (Some("^web[0-9]{2}$"), None, &["deploy@web10"]),
+            (Some("web[0-9]{2},db1.example.com"), None, &["deploy@web10", "alex@db1.example.com"]),
+            // --except is applied after --only
+            (None, Some("web10,db1.example.com"), &["deploy@web1:2222", "deploy@web3"]),
+            (Some("^web"), Some("web3"), &["deploy@web1:2222", "deploy@web10"]),
+        ];
+
+        for (only, except, expected) in cases {
+            let executor = Executor::new(
+                Network::default(),
+                Arc::new(MockTransport::default()),
+                EnvMap::new(),
+                ExecutorOptions {
+                    only: only.map(String::from),
+                    except: except.map(String::from),
+                    ..Default::default()
+                },
+            )?;
+            assert_eq!(executor.filter_hosts(&hosts), *expected, "only={:?} except={:?}", only, except);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_filter_hostname_or_full() -> Result<()> {
+        let hosts = ["web@db1", "deploy@web1", "deploy@db2:2222"].map(String::from);
+        let filtered = |only: &str, only_full: bool| -> Result<Vec<String>> {
+            let options = ExecutorOptions { only: Some(only.to_string()), only_full, ..Default::default() };
+            Ok(Executor::new(Network::default(), Arc::new(MockTransport::default()), EnvMap::new(), options)?.filter_hosts(&hosts))
+        };
+
+        // The user part is not matched by default
+        assert_eq!(filtered("web", false)?, ["deploy@web1"]);
+        assert_eq!(filtered("^web", false)?, ["deploy@web1"]);
+        assert_eq!(filtered("db2", false)?, ["deploy@db2:2222"]);
+
+        assert_eq!(filtered("^web", true)?, ["web@db1"]);
+        assert_eq!(filtered("deploy@db2:2222", true)?, ["deploy@db2:2222"]);
+        assert_eq!(filtered("db2", true)?, Vec::<String>::new());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_filters_apply_to_uploads() -> Result<()> {
+        let network = Network {
+            hosts: ["web@db1", "deploy@web1"].map(HostEntry::from).to_vec(),
+            ..Default::default()
+        };
+        let transport = Arc::new(MockTransport::default());
+        let options = ExecutorOptions { only: Some("web".to_string()), ..Default::default() };
+        let executor = Executor::new(network, transport.clone(), EnvMap::new(), options)?;
+
+        let src = std::env::temp_dir().join(format!("sup-filter-upload-{}", std::process::id()));
+        std::fs::write(&src, "payload")?;
+        let command = Command {
+            upload: Some(vec![Upload { src: vec![src.display().to_string()], dst: "/tmp".to_string(), ..Default::default() }]),
+            run: Some("true".to_string()),
+            ..Default::default()
+        };
+        let result = executor.execute_command("deploy", &command).await;
+        std::fs::remove_file(&src)?;
+        result?;
+
+        let hosts = transport.calls.lock().unwrap().iter()
+            .map(|(host, _)| host.clone())
+            .collect::<std::collections::BTreeSet<_>>();
+        assert_eq!(hosts.into_iter().collect::<Vec<_>>(), ["deploy@web1"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_aliased_hosts() -> Result<()> {
+        let network: Network = serde_yaml::from_str(r#"
+hosts:
+  - {host: 10.0.1.5, user: deploy, port: 2222, alias: web1}
+  - {host: 10.0.1.6, user: deploy, alias: web2}
+  - deploy@db1
+"#)?;
+        let executor_with = |only: Option<&str>, except: Option<&str>| Executor::new(
+            network.clone(),
+            Arc::new(MockTransport::default()),
+            EnvMap::new(),
+            ExecutorOptions { only: only.map(String::from), except: except.map(String::from), ..Default::default() },
+        );
+        let executor = executor_with(None, None)?;
+        assert_eq!(executor.resolve_hosts().await?, ["web1", "web2", "deploy@db1"]);
+
+        // Filters match the alias or the address's hostname
+        assert_eq!(executor_with(Some("web1,10.0.1.6"), None)?.resolve_hosts().await?, ["web1", "web2"]);
+        assert_eq!(executor_with(None, Some("10.0.1.5"))?.resolve_hosts().await?, ["web2", "deploy@db1"]);
+        assert_eq!(executor_with(None, Some("deploy@10.0.1.5:2222"))?.resolve_hosts().await?, ["web2", "deploy@db1"]);
+        assert_eq!(executor_with(None, Some("deploy@db1"))?.resolve_hosts().await?, ["web1", "web2"]);
+        assert_eq!(executor_with(Some("^10\\.0\\.1\\."), Some("web2"))?.resolve_hosts().await?, ["web1"]);
+
+        // The connection goes to the address under the alias
+        let Target::Ssh(host) = executor.target("web1")? else { panic!("not an ssh target") };
+        assert_eq!((host.username.as_str(), host.hostname.as_str()), ("deploy", "10.0.1.5:2222"));
+        assert_eq!(host.to_string(), "web1");
+        assert_eq!(executor.target("deploy@db1")?.to_string(), "deploy@db1");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_effective_user() -> Result<()> {
+        let supfile: crate::config::Supfile = serde_yaml::from_str(r#"
+version: "0.4"
+user: shared
+networks:
+  dev:
+    hosts: [web1, alex@web2, "docker://api"]
+  prod:
+    user: deploy
+    hosts: [web1, alex@web2, {host: 10.0.1.5, alias: db1}]
+commands: {}
+"#)?;
+        let executor = |name: &str, user: Option<&str>| Executor::new(
+            supfile.inherit(supfile.networks[name].clone()),
+            Arc::new(MockTransport::default()),
+            EnvMap::new(),
+            ExecutorOptions { user: user.map(String::from), ..Default::default() },
+        );
+
+        // The host string wins over the network, which wins over the Supfile
+        assert_eq!(executor("dev", None)?.resolve_hosts().await?, ["shared@web1", "alex@web2", "docker://api"]);
+        let prod = executor("prod", None)?;
+        assert_eq!(prod.resolve_hosts().await?, ["deploy@web1", "alex@web2", "db1"]);
+        let Target::Ssh(db1) = prod.target("db1")? else { panic!("not an ssh target") };
+        assert_eq!(db1.username, "deploy");
+
+        // --user wins over everything, for ssh hosts only
+        let personal = executor("dev", Some("me"))?;
+        assert_eq!(personal.resolve_hosts().await?, ["me@web1", "me@web2", "docker://api"]);
+        let plan = personal.plan_step("uptime", &Command { run: Some("uptime".to_string()), ..Default::default() }).await?;
+        assert_eq!(plan.users, ["me"]);
+
+        // Without any user the local one is used, as ssh would
+        let network = Network { hosts: vec!["web1".into()], ..Default::default() };
+        let executor = Executor::new(network, Arc::new(MockTransport::default()), EnvMap::new(), ExecutorOptions::default())?;
+        assert_eq!(executor.resolve_hosts().await?, [format!("{}@web1", whoami::username())]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_retry_hosts() -> Result<()> {
+        let network = Network {
+            hosts: vec!["app@web1".into(), "app@web2".into(), "app@web3".into()],
+            ..Default::default()
+        };
+        let mut executor = Executor::new(network, Arc::new(MockTransport::default()), EnvMap::new(), ExecutorOptions::default())?;
+        executor.retry_hosts(vec!["app@web3".to_string(), "app@old1".to_string(), "app@web1".to_string()]).await?;
+        assert_eq!(executor.resolve_hosts().await?, ["app@web1", "app@web3"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_preflight_skip_unreachable() -> Result<()> {
+        let network = Network {
+            hosts: vec!["app@web1".into(), "app@web2".into()],
+            ..Default::default()
+        };
+        let transport = Arc::new(MockTransport {
+            failing: vec![("app@web2".to_string(), "true".to_string())],
+            ..Default::default()
+        });
+
+        let mut strict = Executor::new(network.clone(), transport.clone(), EnvMap::new(), ExecutorOptions::default())?;
+        let err = strict.preflight(false).await.unwrap_err().to_string();
+        assert!(err.contains("app@web2"), "{}", err);
+
+        let mut executor = Executor::new(network, transport, EnvMap::new(), ExecutorOptions::default())?;
+        executor.preflight(true).await?;
+        assert_eq!(executor.resolve_hosts().await?, ["app@web1"]);
+        let results = executor.summary().results();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].host, "app@web2");
+        assert_eq!(results[0].status, HostStatus::Skipped("unreachable".to_string()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_excluded_hosts() -> Result<()> {
+        let network: Network = serde_yaml::from_str(
+            "hosts: [app@web1, {host: web2, user: app, disabled: true}]\ninventory: echo app@web3; echo app@web4\nexcluded_hosts: ['web3$']",
+        )?;
+        let transport = Arc::new(MockTransport::default());
+        let executor = Executor::new(network.clone(), transport.clone(), EnvMap::new(), ExecutorOptions::default())?;
+        assert_eq!(executor.excluded_hosts().await?, ["app@web2", "app@web3"]);
+        assert_eq!(executor.resolve_hosts().await?, ["app@web1", "app@web4"]);
+
+        // Listed once for the run rather than as results of each command
+        let command = Command { run: Some("uptime".to_string()), ..Default::default() };
+        executor.execute_command("uptime", &command).await?;
+        executor.execute_command("uptime", &command).await?;
+        assert_eq!(executor.summary().excluded(), ["app@web2", "app@web3"]);
+        assert_eq!(executor.summary().counts(), (4, 0, 0));
+        assert_eq!(executor.summary().host_count(), 2);
+
+        // Exclusion wins over --only, and an emptied command says why
+        let options = ExecutorOptions { only: Some("web2,web3".to_string()), ..Default::default() };
+        let executor = Executor::new(network, transport, EnvMap::new(), options)?;
+        assert!(executor.resolve_hosts().await?.is_empty());
+        let err = executor.execute_command("uptime", &command).await.unwrap_err().to_string();
+        assert!(err.contains("filtered by --only web2,web3, 2 excluded by the Supfile"), "{}", err);
+        assert!(err.contains("--allow-empty-hosts"), "{}", err);
+        let allowed = Command { allow_empty_hosts: true, ..command };
+        executor.execute_command("uptime", &allowed).await?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_input() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("sup-run-input-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("sql"))?;
+        std::fs::write(dir.join("sql/seed.sql"), "select 1;\n")?;
+        let env = EnvMap::from([("SQL_DIR".to_string(), "sql".to_string())]);
+        let options = ExecutorOptions { supfile: Some(dir.join("Supfile.yml")), ..Default::default() };
+        let executor = Executor::new(Network::default(), Arc::new(MockTransport::default()), env, options)?;
+        let input = |stdin_file: &str| {
+            let command = Command { run: Some("cat".to_string()), stdin_file: Some(stdin_file.to_string()), ..Default::default() };
+            executor.run_input("seed", &command)
+        };
+
+        // Relative to the Supfile, with the env expanded
+        assert!(matches!(input("$SQL_DIR/seed.sql")?, Some(RunInput::File(path)) if path == dir.join("sql/seed.sql")));
+        let err = input("nope.sql").unwrap_err().to_string();
+        assert!(err.contains("nope.sql of command seed does not exist"), "{}", err);
+        let err = input("$NOPE/seed.sql").unwrap_err().to_string();
+        assert!(err.contains("stdin_file of command seed uses undefined variable $NOPE"), "{}", err);
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_banner() -> Result<()> {
+        colored::control::set_override(false);
+        let network = Network {
+            hosts: vec!["app@web1".into(), "app@web2".into()],
+            ..Default::default()
+        };
+        let env = EnvMap::from([("SUP_NETWORK".to_string(), "prod-us".to_string())]);
+        let executor = Executor::new(network, Arc::new(MockTransport::default()), env, ExecutorOptions::default())?;
+
+        let command = Command {
+            desc: Some("Deploy new version".to_string()),
+            run: Some("./deploy.sh".to_string()),
+            serial: Some(Serial::Count(2)),
+            ..Default::default()
+        };
+        assert_eq!(
+            executor.banner("rolling-update", &command, &executor.resolve_hosts().await?)?,
+            "==> rolling-update (Deploy new version, 2 at a time) on 2 hosts [prod-us]"
+        );
+
+        let local = Command { local: Some("make".to_string()), ..Default::default() };
+        assert_eq!(executor.banner("build", &local, &executor.resolve_hosts().await?)?, "==> build locally [prod-us]");
+
+        // Command-line overrides show up in the banner
+        let overridden = |options: ExecutorOptions| -> Result<Executor> {
+            let env = EnvMap::from([("SUP_NETWORK".to_string(), "prod-us".to_string())]);
+            Executor::new(Network { hosts: vec!["app@web1".into()], ..Default::default() }, Arc::new(MockTransport::default()), env, options)
+        };
+        let executor = overridden(ExecutorOptions { parallel: true, ..Default::default() })?;
+        let effective = executor.with_overrides("rolling-update", &command)?;
+        assert_eq!(effective.serial, None);
+        assert_eq!(
+            executor.banner("rolling-update", &effective, &executor.resolve_hosts().await?)?,
+            "==> rolling-update (Deploy new version, all at once via --parallel) on 1 host [prod-us]"
+        );
+
+        let executor = overridden(ExecutorOptions { serial: Some(Serial::Percent(50)), ..Default::default() })?;
+        let effective = executor.with_overrides("rolling-update", &command)?;
+        assert_eq!(
+            executor.banner("rolling-update", &effective, &executor.resolve_hosts().await?)?,
+            "==> rolling-update (Deploy new version, 50% at a time via --serial) on 1 host [prod-us]"
+        );
+
+        let executor = overridden(ExecutorOptions { once: true, ..Default::default() })?;
+        let migrate = Command { run: Some("./migrate.sh".to_string()), ..Default::default() };
+        let effective = executor.with_overrides("migrate", &migrate)?;
+        assert_eq!(
+            executor.banner("migrate", &effective, &executor.resolve_hosts().await?)?,
+            "==> migrate (once on app@web1 via --once) on 1 host [prod-us]"
+        );
+
+        // --once runs a command with a Supfile serial on one host instead
+        let effective = executor.with_overrides("rolling-update", &command)?;
+        assert_eq!(effective.serial, None);
+        assert!(effective.once);
+
+        // --serial can't batch a Supfile once
+        let executor = overridden(ExecutorOptions { serial: Some(Serial::Count(2)), ..Default::default() })?;
+        let once = Command { once: true, ..migrate };
+        let err = executor.with_overrides("migrate", &once).unwrap_err().to_string();
+        assert!(err.contains("migrate sets once, which conflicts with --serial"), "{}", err);
+        Ok(())
+    }
+}
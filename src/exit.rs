@@ -0,0 +1,112 @@
+use crate::transport::ExitCodeError;
+use std::fmt;
+
+/// Exit codes of sup-rs, so scripts wrapping it can tell a Supfile problem
+/// from a host failure or a user abort.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitCode {
+    Success = 0,
+    /// Any other error, e.g. an invalid Supfile or unknown command or network
+    Error = 1,
+    /// Invalid command-line arguments
+    Usage = 2,
+    /// A command failed on one or more hosts, including this machine
+    HostsFailed = 3,
+    /// A confirmation was declined or the run was interrupted with Ctrl-C
+    Aborted = 4,
+    /// Hosts failed the reachability check of `--check-hosts` or `--ping`
+    Unreachable = 5,
+    /// The run took longer than `--max-runtime`
+    Timeout = 6,
+}
+
+/// The contract as shown at the end of `--help`.
+pub const HELP: &str = "\
+Exit codes:
+  0  success
+  1  error, e.g. an invalid Supfile or unknown command or network
+  2  invalid command-line arguments
+  3  a command failed on one or more hosts
+  4  aborted: confirmation declined or Ctrl-C
+  5  hosts unreachable (--check-hosts, --ping)
+  6  the run exceeded --max-runtime";
+
+/// An error that ends the run with a specific exit code. It displays as the
+/// error it wraps, so tagging an error does not change its message.
+#[derive(Debug)]
+pub struct ExitError {
+    code: ExitCode,
+    error: anyhow::Error,
+}
+
+impl fmt::Display for ExitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.error, f)
+    }
+}
+
+impl std::error::Error for ExitError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.error.source()
+    }
+}
+
+impl ExitCode {
+    /// A new error ending the run with this code.
+    pub fn error(self, message: impl Into<String>) -> ExitError {
+        ExitError { code: self, error: anyhow::Error::msg(message.into()) }
+    }
+
+    /// `error` ending the run with this code, unless it already has one.
+    pub fn tag(self, error: anyhow::Error) -> anyhow::Error {
+        if error.chain().any(|cause| cause.is::<ExitError>()) {
+            return error;
+        }
+        ExitError { code: self, error }.into()
+    }
+
+    /// The code `error` ends the run with: the outermost tagged code, or
+    /// `HostsFailed` for a command that exited non-zero.
+    pub fn of(error: &anyhow::Error) -> Self {
+        if let Some(tagged) = error.chain().find_map(|cause| cause.downcast_ref::<ExitError>()) {
+            return tagged.code;
+        }
+        if error.chain().any(|cause| cause.is::<ExitCodeError>()) {
+            return ExitCode::HostsFailed;
+        }
+        ExitCode::Error
+    }
+}
+
+impl From<ExitCode> for std::process::ExitCode {
+    fn from(code: ExitCode) -> Self {
+        (code as u8).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn test_exit_code_of() {
+        let untagged = anyhow::anyhow!("Failed to parse Supfile");
+        assert_eq!(ExitCode::of(&untagged), ExitCode::Error);
+
+        let aborted = anyhow::Error::from(ExitCode::Aborted.error("Aborted: deploy not confirmed"));
+        assert_eq!(aborted.to_string(), "Aborted: deploy not confirmed");
+        assert_eq!(ExitCode::of(&aborted), ExitCode::Aborted);
+
+        // Context and later tags keep the code and the message
+        let wrapped = ExitCode::HostsFailed.tag(Err::<(), _>(aborted).context("Target release failed").unwrap_err());
+        assert_eq!(ExitCode::of(&wrapped), ExitCode::Aborted);
+        assert_eq!(format!("{:#}", wrapped), "Target release failed: Aborted: deploy not confirmed");
+
+        let exited = anyhow::Error::from(ExitCodeError { what: "Local command", code: 2 });
+        assert_eq!(ExitCode::of(&exited), ExitCode::HostsFailed);
+        let tagged = ExitCode::Unreachable.tag(anyhow::anyhow!("probe failed").context("preflight"));
+        assert_eq!(ExitCode::of(&tagged), ExitCode::Unreachable);
+        assert_eq!(format!("{:#}", tagged), "preflight: probe failed");
+    }
+}
@@ -0,0 +1,144 @@
+use anyhow::{Context, Result};
+use regex::Regex;
+
+/// Characters that make a `--only`/`--except` element a regex. `.` is left
+/// out so dotted hostnames still match exactly.
+const REGEX_METACHARACTERS: &[char] = &['\\', '*', '+', '?', '(', ')', '|', '[', ']', '{', '}', '^', '$'];
+
+/// Host selector built from a `--only`/`--except` value: a comma-separated
+/// list where plain names match a host by name and elements containing
+/// regex metacharacters are matched as regexes.
+#[derive(Debug, Clone)]
+pub struct HostFilter {
+    patterns: Vec<Pattern>,
+}
+
+#[derive(Debug, Clone)]
+enum Pattern {
+    Exact(String),
+    Regex(Regex),
+}
+
+impl HostFilter {
+    pub fn parse(value: &str) -> Result<Self> {
+        let patterns = split_list(value)
+            .into_iter()
+            .map(str::trim)
+            .filter(|element| !element.is_empty())
+            .map(|element| {
+                if element.contains(REGEX_METACHARACTERS) {
+                    Regex::new(element)
+                        .map(Pattern::Regex)
+                        .with_context(|| format!("Invalid host filter regex: {}", element))
+                } else {
+                    Ok(Pattern::Exact(element.to_string()))
+                }
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self { patterns })
+    }
+
+    /// Whether any element matches `entry`, a `user@host:port` string or
+    /// alias. Plain names match the whole entry, its hostname, or with no
+    /// trailing digit the numbered hosts of that name (`web` selects `web1`
+    /// and `web10`). Regexes see only the hostname, or the whole entry with
+    /// `full` (`--only-full`), where plain names match the whole entry only.
+    pub fn is_match(&self, entry: &str, full: bool) -> bool {
+        let host = if full { entry } else { hostname(entry) };
+        self.patterns.iter().any(|pattern| match pattern {
+            Pattern::Exact(exact) => exact == entry || (!full && (exact == host || is_numbered(exact, host))),
+            Pattern::Regex(regex) => regex.is_match(host),
+        })
+    }
+}
+
+/// Whether `host` is `name` followed by a number, for names that don't end
+/// in a digit themselves, so `web1` never selects `web10`.
+fn is_numbered(name: &str, host: &str) -> bool {
+    !name.ends_with(|c: char| c.is_ascii_digit())
+        && host.strip_prefix(name).is_some_and(|number| !number.is_empty() && number.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// Splits on commas outside `{...}` so regex quantifiers stay intact.
+fn split_list(value: &str) -> Vec<&str> {
+    let mut elements = Vec::new();
+    let mut depth = 0usize;
+    let mut start = 0;
+    for (i, c) in value.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth = depth.saturating_sub(1),
+            ',' if depth == 0 => {
+                elements.push(&value[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    elements.push(&value[start..]);
+    elements
+}
+
+/// The host part of `user@host:port`, what filters match by default. The
+/// brackets of an IPv6 address are kept, and `docker://` and `kubectl://`
+/// entries are matched by what follows the scheme.
+pub fn hostname(host: &str) -> &str {
+    if let Some((_, rest)) = host.split_once("://") {
+        return rest;
+    }
+    let host = host.rsplit_once('@').map_or(host, |(_, host)| host);
+    if let Some(end) = host.strip_prefix('[').and_then(|rest| rest.find(']')) {
+        return &host[..end + 2];
+    }
+    host.rsplit_once(':').map_or(host, |(host, _)| host)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hostname() {
+        assert_eq!(hostname("deploy@web1:2222"), "web1");
+        assert_eq!(hostname("deploy@web1.example.com"), "web1.example.com");
+        assert_eq!(hostname("web1"), "web1");
+        assert_eq!(hostname("app@[2001:db8::1]:2222"), "[2001:db8::1]");
+        assert_eq!(hostname("docker://app"), "app");
+        assert_eq!(hostname("kubectl://web/api-0"), "web/api-0");
+    }
+
+    #[test]
+    fn test_split_list() {
+        assert_eq!(split_list("web1,web3"), ["web1", "web3"]);
+        assert_eq!(split_list("web[0-9]{1,2},db1"), ["web[0-9]{1,2}", "db1"]);
+        assert_eq!(split_list("web1"), ["web1"]);
+    }
+
+    #[test]
+    fn test_is_match() -> Result<()> {
+        let filter = HostFilter::parse("web,deploy@db1,10.0.1.5")?;
+        assert!(filter.is_match("deploy@web1", false));
+        assert!(filter.is_match("deploy@web10:2222", false));
+        assert!(!filter.is_match("web@db2", false));
+        assert!(!filter.is_match("deploy@webserver", false));
+        assert!(filter.is_match("deploy@db1", false));
+        assert!(!filter.is_match("alex@db1", false));
+        assert!(filter.is_match("deploy@10.0.1.5:2222", false));
+        assert!(!filter.is_match("deploy@10.0.1.50", false));
+
+        // Names ending in a digit only match that host
+        assert!(!HostFilter::parse("web1")?.is_match("deploy@web10", false));
+
+        // With --only-full plain names must match the whole entry
+        assert!(filter.is_match("deploy@db1", true));
+        assert!(!filter.is_match("deploy@web1", true));
+        assert!(HostFilter::parse("^web")?.is_match("web@db1", true));
+        assert!(!HostFilter::parse("^web")?.is_match("web@db1", false));
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_regex() {
+        assert!(HostFilter::parse("web(1").is_err());
+    }
+}
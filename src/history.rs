@@ -0,0 +1,135 @@
+use crate::config::Supfile;
+use anyhow::{Context, Result};
+use colored::*;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Env variable overriding the history file location.
+pub const HISTORY_ENV: &str = "SUP_HISTORY";
+
+/// One line of the audit log, appended per run.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    /// Matches the run's `--log-file` records and report; empty in entries
+    /// written before run ids existed
+    #[serde(default)]
+    pub run_id: String,
+    /// RFC 3339 start time of the run
+    pub timestamp: String,
+    pub user: String,
+    pub network: String,
+    /// Command or target named on the command line
+    pub target: String,
+    /// Commands the target expanded to
+    pub commands: Vec<String>,
+    pub hosts: usize,
+    pub success: bool,
+    pub error: Option<String>,
+    pub duration_ms: u64,
+}
+
+/// History file location: `$SUP_HISTORY`, then the Supfile's `history`,
+/// then `~/.local/share/sup/history.jsonl`.
+pub fn history_path(supfile: &Supfile) -> Option<PathBuf> {
+    if let Some(path) = std::env::var_os(HISTORY_ENV).filter(|path| !path.is_empty()) {
+        return Some(PathBuf::from(path));
+    }
+    if let Some(path) = &supfile.history {
+        return Some(PathBuf::from(path));
+    }
+    dirs::home_dir().map(|home| home.join(".local/share/sup/history.jsonl"))
+}
+
+/// Appends `entry` as a single write, so lines from concurrent runs never
+/// interleave.
+pub fn append(path: &Path, entry: &HistoryEntry) -> Result<()> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+    let mut line = serde_json::to_string(entry)?;
+    line.push('\n');
+
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    file.write_all(line.as_bytes())
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Reads the last `count` entries, skipping lines that do not parse.
+pub fn read_last(path: &Path, count: usize) -> Result<Vec<HistoryEntry>> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    let entries = contents.lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect::<Vec<HistoryEntry>>();
+    Ok(entries[entries.len().saturating_sub(count)..].to_vec())
+}
+
+pub fn print(entries: &[HistoryEntry]) {
+    for entry in entries {
+        let result = if entry.success { "OK    ".green() } else { "FAILED".red() };
+        let commands = if entry.commands == [entry.target.as_str()] {
+            entry.target.clone()
+        } else {
+            format!("{} ({})", entry.target, entry.commands.join(", "))
+        };
+        println!(
+            "{} {} {:<12} {:<10} {} on {} hosts in {}",
+            entry.timestamp.dimmed(),
+            result,
+            entry.user,
+            entry.network.bold(),
+            commands,
+            entry.hosts,
+            crate::output::format_duration(std::time::Duration::from_millis(entry.duration_ms)),
+        );
+        if let Some(error) = &entry.error {
+            println!("    {}", error.lines().next().unwrap_or_default());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(target: &str, success: bool) -> HistoryEntry {
+        HistoryEntry {
+            run_id: format!("run-{}", target),
+            timestamp: "2024-05-01T12:00:00+00:00".to_string(),
+            user: "deploy".to_string(),
+            network: "prod".to_string(),
+            target: target.to_string(),
+            commands: vec![target.to_string()],
+            hosts: 3,
+            success,
+            error: None,
+            duration_ms: 4200,
+        }
+    }
+
+    #[test]
+    fn test_append_and_read_last() -> Result<()> {
+        let path = std::env::temp_dir()
+            .join(format!("sup-history-{}", std::process::id()))
+            .join("history.jsonl");
+        assert!(read_last(&path, 5)?.is_empty());
+
+        append(&path, &entry("deploy", true))?;
+        append(&path, &entry("restart", false))?;
+        append(&path, &entry("status", true))?;
+
+        let last = read_last(&path, 2)?;
+        std::fs::remove_dir_all(path.parent().unwrap())?;
+        assert_eq!(last, [entry("restart", false), entry("status", true)]);
+        Ok(())
+    }
+}
@@ -0,0 +1,149 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Host key fingerprints by ssh host, `host` or `host:port`.
+pub type HostKeys = BTreeMap<String, String>;
+
+/// Fingerprint a host presented, or why it could not be read.
+pub type Observed = std::result::Result<String, String>;
+
+/// File `--learn-host-keys` writes, next to the Supfile.
+pub const SIDECAR: &str = "host_keys.yml";
+
+/// A host whose key does not pass the Supfile's `host_keys` pins.
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum HostKeyError {
+    #[error("Host key of {host} is unknown: {fingerprint} is not pinned in host_keys; pin it, e.g. with --learn-host-keys")]
+    Unknown { host: String, fingerprint: String },
+    #[error("Host key of {host} has CHANGED: pinned {pinned}, presented {presented}")]
+    Changed { host: String, pinned: String, presented: String },
+    #[error("Host key of {host} could not be read to check it against host_keys: {reason}")]
+    Unavailable { host: String, reason: String },
+}
+
+/// Checks the observed keys against `pinned`. Nothing is pinned means
+/// nothing is checked; otherwise every host must be pinned and match.
+pub fn verify(pinned: &HostKeys, observed: &BTreeMap<String, Observed>) -> Vec<HostKeyError> {
+    if pinned.is_empty() {
+        return Vec::new();
+    }
+    observed.iter()
+        .filter_map(|(host, key)| {
+            let host = host.clone();
+            match (pinned.get(&host), key) {
+                (Some(pinned), Ok(presented)) if pinned == presented => None,
+                (Some(pinned), Ok(presented)) => {
+                    Some(HostKeyError::Changed { host, pinned: pinned.clone(), presented: presented.clone() })
+                }
+                (None, Ok(fingerprint)) => Some(HostKeyError::Unknown { host, fingerprint: fingerprint.clone() }),
+                (_, Err(reason)) => Some(HostKeyError::Unavailable { host, reason: reason.clone() }),
+            }
+        })
+        .collect()
+}
+
+/// The Supfile's `host_keys` pins for a run, and the known hosts file ssh
+/// connections are restricted to. The file starts out empty: the probe
+/// that reads each host's key adds it, and since that key is checked
+/// against its pin before anything else connects, later connections accept
+/// only pinned keys. The file is removed on drop.
+#[derive(Debug)]
+pub struct PinnedKeys {
+    pub keys: HostKeys,
+    known_hosts: PathBuf,
+}
+
+impl PinnedKeys {
+    pub fn create(keys: HostKeys) -> Result<Self> {
+        let known_hosts = std::env::temp_dir().join(format!("sup-{}-known_hosts", std::process::id()));
+        std::fs::write(&known_hosts, "")
+            .with_context(|| format!("Failed to create {}", known_hosts.display()))?;
+        Ok(Self { keys, known_hosts })
+    }
+
+    pub fn known_hosts(&self) -> &Path {
+        &self.known_hosts
+    }
+}
+
+impl Drop for PinnedKeys {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.known_hosts);
+    }
+}
+
+/// Layout of the sidecar file, the same as the Supfile's `host_keys:` so
+/// its entries can be copied over.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Sidecar {
+    #[serde(default)]
+    host_keys: HostKeys,
+}
+
+/// Path of the sidecar file for the Supfile at `supfile`.
+pub fn sidecar_path(supfile: &Path) -> PathBuf {
+    supfile.parent().unwrap_or(Path::new("")).join(SIDECAR)
+}
+
+/// Adds `learned` to the keys already in the sidecar at `path`, replacing
+/// those of the same hosts.
+pub fn learn(path: &Path, learned: &HostKeys) -> Result<()> {
+    let mut sidecar = match std::fs::read_to_string(path) {
+        Ok(yaml) => serde_yaml::from_str::<Option<Sidecar>>(&yaml)
+            .with_context(|| format!("Failed to parse {}", path.display()))?
+            .unwrap_or_default(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Sidecar::default(),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    sidecar.host_keys.extend(learned.iter().map(|(host, key)| (host.clone(), key.clone())));
+    std::fs::write(path, serde_yaml::to_string(&sidecar)?)
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify() {
+        let observed = BTreeMap::from([
+            ("web1".to_string(), Ok("SHA256:aaa".to_string())),
+            ("web2:2222".to_string(), Ok("SHA256:bbb".to_string())),
+            ("web3".to_string(), Ok("SHA256:ccc".to_string())),
+            ("web4".to_string(), Err("Connection refused".to_string())),
+        ]);
+        assert!(verify(&HostKeys::new(), &observed).is_empty());
+
+        let pinned = HostKeys::from([
+            ("web1".to_string(), "SHA256:aaa".to_string()),
+            ("web2:2222".to_string(), "SHA256:old".to_string()),
+            ("web4".to_string(), "SHA256:ddd".to_string()),
+        ]);
+        assert_eq!(verify(&pinned, &observed), [
+            HostKeyError::Changed { host: "web2:2222".into(), pinned: "SHA256:old".into(), presented: "SHA256:bbb".into() },
+            HostKeyError::Unknown { host: "web3".into(), fingerprint: "SHA256:ccc".into() },
+            HostKeyError::Unavailable { host: "web4".into(), reason: "Connection refused".into() },
+        ]);
+    }
+
+    #[test]
+    fn test_learn_merges() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("sup-host-keys-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let path = sidecar_path(&dir.join("Supfile.yml"));
+        assert_eq!(path, dir.join(SIDECAR));
+
+        learn(&path, &HostKeys::from([("web1".into(), "SHA256:old".into()), ("web2".into(), "SHA256:bbb".into())]))?;
+        learn(&path, &HostKeys::from([("web1".into(), "SHA256:aaa".into())]))?;
+        let sidecar: Sidecar = serde_yaml::from_str(&std::fs::read_to_string(&path)?)?;
+        assert_eq!(sidecar.host_keys, HostKeys::from([
+            ("web1".into(), "SHA256:aaa".into()),
+            ("web2".into(), "SHA256:bbb".into()),
+        ]));
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,183 @@
+use crate::config::HostEntry;
+use anyhow::{Context, Result};
+use std::net::Ipv4Addr;
+
+/// Most hosts the entries of a network may expand to without `--yes`.
+pub const MAX_EXPANDED_HOSTS: usize = 4096;
+
+/// Expands host entries with numeric ranges (`web[01..20].example.com`) or
+/// an IPv4 CIDR block (`deploy@10.0.1.0/28`). Other entries, and those in
+/// map form, are kept as is under their name.
+/// More than `MAX_EXPANDED_HOSTS` hosts is an error unless `allow_large`.
+pub fn expand_all(entries: &[HostEntry], allow_large: bool) -> Result<Vec<String>> {
+    let mut hosts = Vec::new();
+    for entry in entries {
+        match entry {
+            HostEntry::Address(address) => {
+                hosts.extend(expand(address).with_context(|| format!("Invalid host entry {}", address))?);
+            }
+            HostEntry::Host(_) => hosts.push(entry.name()),
+        }
+        if hosts.len() > MAX_EXPANDED_HOSTS && !allow_large {
+            anyhow::bail!(
+                "Host entries expand to more than {} hosts (at {}); pass --yes to run on all of them",
+                MAX_EXPANDED_HOSTS,
+                entry.name()
+            );
+        }
+    }
+    Ok(hosts)
+}
+
+/// Hosts of a single entry.
+pub fn expand(entry: &str) -> Result<Vec<String>> {
+    // Docker and kubectl entries use `/` and `[` for other things
+    if entry.contains("://") {
+        return Ok(vec![entry.to_string()]);
+    }
+    let (user, host) = match entry.split_once('@') {
+        Some((user, host)) => (Some(user), host),
+        None => (None, entry),
+    };
+    if let Some((address, prefix)) = host.split_once('/') {
+        let with_user = |address: Ipv4Addr| match user {
+            Some(user) => format!("{}@{}", user, address),
+            None => address.to_string(),
+        };
+        return Ok(cidr(address, prefix)?.into_iter().map(with_user).collect());
+    }
+    expand_ranges(entry)
+}
+
+/// Every combination of the `[start..end]` ranges in `entry`, in order.
+/// A start with leading zeros pads all numbers of its range to its width.
+fn expand_ranges(entry: &str) -> Result<Vec<String>> {
+    let Some((open, range)) = find_range(entry) else {
+        return Ok(vec![entry.to_string()]);
+    };
+    let close = open + range.len() + 2;
+    let (start, end) = range.split_once("..").unwrap_or_default();
+    let parse = |bound: &str| bound.parse::<u64>()
+        .with_context(|| format!("Invalid range [{}]: bounds must be numbers", range));
+    let (first, last) = (parse(start)?, parse(end)?);
+    if first > last {
+        anyhow::bail!("Invalid range [{}]: start is greater than end", range);
+    }
+    if last - first >= MAX_EXPANDED_HOSTS as u64 * 16 {
+        anyhow::bail!("Range [{}] is too large", range);
+    }
+    let width = if start.len() > 1 && start.starts_with('0') { start.len() } else { 0 };
+
+    let rest = expand_ranges(&entry[close..])?;
+    let mut hosts = Vec::new();
+    for number in first..=last {
+        for tail in &rest {
+            hosts.push(format!("{}{:0width$}{}", &entry[..open], number, tail, width = width));
+        }
+    }
+    Ok(hosts)
+}
+
+/// Position of the first `[` that starts a range and the text inside it.
+/// Brackets without `..`, such as IPv6 addresses, are not ranges.
+fn find_range(entry: &str) -> Option<(usize, &str)> {
+    let mut from = 0;
+    while let Some(open) = entry[from..].find('[').map(|at| from + at) {
+        let close = open + entry[open..].find(']')?;
+        let inside = &entry[open + 1..close];
+        if inside.contains("..") {
+            return Some((open, inside));
+        }
+        from = close;
+    }
+    None
+}
+
+/// Usable addresses of an IPv4 CIDR block: all but the network and
+/// broadcast addresses, except in /31 and /32 blocks which have none.
+fn cidr(address: &str, prefix: &str) -> Result<Vec<Ipv4Addr>> {
+    let address = address.parse::<Ipv4Addr>()
+        .with_context(|| format!("Invalid CIDR address {}", address))?;
+    let prefix = prefix.parse::<u32>().ok()
+        .filter(|prefix| *prefix <= 32)
+        .with_context(|| format!("Invalid CIDR prefix /{}", prefix))?;
+    let size = 1u64 << (32 - prefix);
+    if size > MAX_EXPANDED_HOSTS as u64 * 16 {
+        anyhow::bail!("CIDR block /{} is too large", prefix);
+    }
+
+    let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+    let network = u64::from(u32::from(address) & mask);
+    let (first, last) = if prefix >= 31 {
+        (network, network + size - 1)
+    } else {
+        (network + 1, network + size - 2)
+    };
+    Ok((first..=last).map(|address| Ipv4Addr::from(address as u32)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ranges() -> Result<()> {
+        assert_eq!(expand("deploy@web[01..03].example.com")?, [
+            "deploy@web01.example.com",
+            "deploy@web02.example.com",
+            "deploy@web03.example.com",
+        ]);
+        // Padding follows the start; the end may be wider
+        assert_eq!(expand("app@web[8..10]")?, ["app@web8", "app@web9", "app@web10"]);
+        assert_eq!(expand("app@web[098..100]")?, ["app@web098", "app@web099", "app@web100"]);
+        assert_eq!(expand("app@web[0..1]")?, ["app@web0", "app@web1"]);
+
+        assert_eq!(expand("app@rack[1..2]-node[01..02]")?, [
+            "app@rack1-node01",
+            "app@rack1-node02",
+            "app@rack2-node01",
+            "app@rack2-node02",
+        ]);
+
+        // Not ranges
+        assert_eq!(expand("app@web1")?, ["app@web1"]);
+        assert_eq!(expand("app@[2001:db8::1]")?, ["app@[2001:db8::1]"]);
+        assert_eq!(expand("docker://app[1..2]")?, ["docker://app[1..2]"]);
+        assert_eq!(expand("kubectl://web/api-0")?, ["kubectl://web/api-0"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_ranges() {
+        assert!(expand("app@web[5..1]").unwrap_err().to_string().contains("start is greater than end"));
+        assert!(expand("app@web[a..c]").unwrap_err().to_string().contains("bounds must be numbers"));
+        assert!(expand("app@web[1..]").is_err());
+        assert!(expand("app@web[0..99999999]").unwrap_err().to_string().contains("too large"));
+    }
+
+    #[test]
+    fn test_cidr() -> Result<()> {
+        assert_eq!(expand("deploy@10.0.1.0/30")?, ["deploy@10.0.1.1", "deploy@10.0.1.2"]);
+        assert_eq!(expand("10.0.1.7/29")?.len(), 6);
+        assert_eq!(expand("deploy@10.0.1.7/29")?[0], "deploy@10.0.1.1");
+        assert_eq!(expand("deploy@10.0.1.4/31")?, ["deploy@10.0.1.4", "deploy@10.0.1.5"]);
+        assert_eq!(expand("deploy@10.0.1.9/32")?, ["deploy@10.0.1.9"]);
+
+        assert!(expand("deploy@10.0.1.0/33").is_err());
+        assert!(expand("deploy@10.0.1/24").is_err());
+        assert!(expand("deploy@10.0.0.0/8").unwrap_err().to_string().contains("too large"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_expansion_cap() -> Result<()> {
+        let entries = ["app@a[1..3000]".into(), "app@b[1..3000]".into()];
+        let err = expand_all(&entries, false).unwrap_err().to_string();
+        assert!(err.contains("more than 4096 hosts (at app@b[1..3000])"), "{}", err);
+        assert_eq!(expand_all(&entries, true)?.len(), 6000);
+
+        let err = expand_all(&["app@web[3..1]".into()], false).unwrap_err();
+        assert_eq!(format!("{:#}", err), "Invalid host entry app@web[3..1]: Invalid range [3..1]: start is greater than end");
+        Ok(())
+    }
+}
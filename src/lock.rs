@@ -0,0 +1,181 @@
+use crate::config::Lock;
+use crate::transport::{Target, Transport};
+use anyhow::{Context, Result};
+use chrono::Local;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tracing::{debug, warn};
+
+/// Exit code of the acquire script when the lock is already held.
+const HELD: i32 = 3;
+
+/// A held network lock, released explicitly at the end of the run.
+pub enum NetworkLock {
+    Remote {
+        transport: Arc<dyn Transport>,
+        host: Target,
+        dir: String,
+    },
+    Local {
+        dir: PathBuf,
+    },
+}
+
+/// Lock directory name for a network, limited to characters safe in paths.
+fn lock_name(network: &str) -> String {
+    let network = network.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect::<String>();
+    format!("sup-{}.lock", network)
+}
+
+fn owner() -> String {
+    format!("{} since {}", whoami::username(), Local::now().to_rfc3339())
+}
+
+/// Creates the lock directory atomically with `mkdir` and records the owner
+/// in it; if it already exists, prints the owner and exits with `HELD`.
+fn acquire_script(dir: &str, owner: &str) -> String {
+    let dir = shell_quote::sh::quote(dir).to_string_lossy().into_owned();
+    let owner = shell_quote::sh::quote(owner).to_string_lossy().into_owned();
+    format!(
+        "if mkdir {dir} 2>/dev/null; then echo {owner} > {dir}/owner; else cat {dir}/owner >&2; exit {HELD}; fi"
+    )
+}
+
+fn release_script(dir: &str) -> String {
+    format!("rm -rf {}", shell_quote::sh::quote(dir).to_string_lossy())
+}
+
+fn held_error(network: &str, holder: &str) -> anyhow::Error {
+    let holder = holder.trim();
+    anyhow::anyhow!(
+        "Network {} is locked by {}; pass --force-unlock to break a stale lock",
+        network,
+        if holder.is_empty() { "an unknown owner" } else { holder }
+    )
+}
+
+impl NetworkLock {
+    /// Takes the lock for `network`, first breaking any existing lock when
+    /// `force` is set. Remote locks live on `host`.
+    pub async fn acquire(
+        mode: Lock,
+        network: &str,
+        transport: Arc<dyn Transport>,
+        host: Option<Target>,
+        force: bool,
+    ) -> Result<Option<Self>> {
+        let lock = match mode {
+            Lock::Off => return Ok(None),
+            Lock::Remote => {
+                let host = host.with_context(|| format!("Network {} has no hosts to hold its lock", network))?;
+                NetworkLock::Remote { transport, host, dir: format!("/tmp/{}", lock_name(network)) }
+            }
+            Lock::Local => NetworkLock::Local { dir: std::env::temp_dir().join(lock_name(network)) },
+        };
+        if force {
+            warn!("Breaking lock of network {}", network);
+            lock.remove().await?;
+        }
+
+        match &lock {
+            NetworkLock::Remote { transport, host, dir } => {
+                debug!("Acquiring lock {} on {}", dir, host);
+                let output = transport.exec(host, &acquire_script(dir, &owner()), None).await?;
+                match output.exit_code {
+                    Some(0) => {}
+                    Some(HELD) => return Err(held_error(network, &String::from_utf8_lossy(&output.stderr))),
+                    _ => anyhow::bail!(
+                        "Failed to acquire lock {} on {}: {}",
+                        dir,
+                        host,
+                        String::from_utf8_lossy(&output.stderr).trim()
+                    ),
+                }
+            }
+            NetworkLock::Local { dir } => {
+                debug!("Acquiring lock {}", dir.display());
+                match std::fs::create_dir(dir) {
+                    Ok(()) => std::fs::write(dir.join("owner"), owner() + "\n")?,
+                    Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                        let holder = std::fs::read_to_string(dir.join("owner")).unwrap_or_default();
+                        return Err(held_error(network, &holder));
+                    }
+                    Err(e) => return Err(e).with_context(|| format!("Failed to create lock {}", dir.display())),
+                }
+            }
+        }
+        Ok(Some(lock))
+    }
+
+    /// Releases the lock; failures are only warned about so they never mask
+    /// the outcome of the run.
+    pub async fn release(self) {
+        if let Err(e) = self.remove().await {
+            warn!("Failed to release lock: {:#}", e);
+        }
+    }
+
+    async fn remove(&self) -> Result<()> {
+        match self {
+            NetworkLock::Remote { transport, host, dir } => {
+                let output = transport.exec(host, &release_script(dir), None).await?;
+                if !output.success() {
+                    anyhow::bail!("Failed to remove lock {} on {}", dir, host);
+                }
+            }
+            NetworkLock::Local { dir } => match std::fs::remove_dir_all(dir) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(e).with_context(|| format!("Failed to remove lock {}", dir.display()));
+                }
+                _ => {}
+            },
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::{SubprocessTransport, TransportOptions};
+
+    fn sh(script: &str) -> (Option<i32>, String) {
+        let output = std::process::Command::new("sh").arg("-c").arg(script).output().unwrap();
+        (output.status.code(), String::from_utf8_lossy(&output.stderr).into_owned())
+    }
+
+    #[test]
+    fn test_lock_scripts() {
+        assert_eq!(lock_name("prod eu/1"), "sup-prod_eu_1.lock");
+
+        let dir = std::env::temp_dir().join(format!("sup-lock-script-{}", std::process::id()));
+        let dir = dir.to_string_lossy();
+        assert_eq!(sh(&acquire_script(&dir, "alice since noon")).0, Some(0));
+        assert_eq!(sh(&acquire_script(&dir, "bob since one")), (Some(HELD), "alice since noon\n".to_string()));
+        assert_eq!(sh(&release_script(&dir)).0, Some(0));
+        assert_eq!(sh(&acquire_script(&dir, "bob's turn")).0, Some(0));
+        assert_eq!(sh(&release_script(&dir)).0, Some(0));
+    }
+
+    #[tokio::test]
+    async fn test_local_lock() -> Result<()> {
+        let network = format!("lock-test-{}", std::process::id());
+        let transport: Arc<dyn Transport> = Arc::new(SubprocessTransport::new(TransportOptions::default()));
+        let acquire = |force| NetworkLock::acquire(Lock::Local, &network, transport.clone(), None, force);
+
+        let lock = acquire(false).await?.expect("lock enabled");
+        let err = acquire(false).await.err().expect("lock held").to_string();
+        assert!(err.contains(&whoami::username()), "{}", err);
+
+        // Forcing breaks the held lock and takes it over
+        let forced = acquire(true).await?.expect("lock enabled");
+        lock.release().await;
+        forced.release().await;
+        acquire(false).await?.expect("lock released").release().await;
+
+        assert!(NetworkLock::acquire(Lock::Off, &network, transport, None, false).await?.is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,214 @@
+use crate::output::mask;
+use anyhow::{Context, Result};
+use chrono::Local;
+use serde_json::{Map, Value};
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::layer::Context as LayerContext;
+use tracing_subscriber::Layer;
+
+/// Target of the events carrying host output, which only go to the log file.
+pub const OUTPUT_TARGET: &str = "sup::output";
+
+/// Default size at which `--log-file` is rotated.
+pub const DEFAULT_MAX_BYTES: u64 = 10 * 1024 * 1024;
+
+/// Default number of rotated log files kept next to the current one.
+pub const DEFAULT_KEEP: usize = 5;
+
+/// Identifier of this run, stamped on log records, the report and the
+/// history entry so they can be matched up.
+pub fn new_run_id() -> String {
+    uuid::Uuid::new_v4().to_string()
+}
+
+/// Log file that is renamed to `<path>.1` once it reaches `max_bytes`,
+/// shifting older files up to `<path>.<keep>` and deleting the oldest.
+#[derive(Debug)]
+pub struct RotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    keep: usize,
+    file: File,
+    size: u64,
+}
+
+fn open_append(path: &Path) -> Result<File> {
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open log file {}", path.display()))
+}
+
+fn rotated(path: &Path, index: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", index));
+    PathBuf::from(name)
+}
+
+impl RotatingFile {
+    pub fn open(path: &Path, max_bytes: u64, keep: usize) -> Result<Self> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        let file = open_append(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self { path: path.to_path_buf(), max_bytes, keep, file, size })
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        if self.keep == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            for index in (1..self.keep).rev() {
+                let from = rotated(&self.path, index);
+                if from.exists() {
+                    std::fs::rename(&from, rotated(&self.path, index + 1))?;
+                }
+            }
+            std::fs::rename(&self.path, rotated(&self.path, 1))?;
+        }
+        self.file = open_append(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    /// Appends one record in a single write, rotating first if it would
+    /// push the file past its size limit.
+    pub fn write_record(&mut self, record: &[u8]) -> Result<()> {
+        if self.size > 0 && self.size + record.len() as u64 > self.max_bytes {
+            self.rotate()
+                .with_context(|| format!("Failed to rotate log file {}", self.path.display()))?;
+        }
+        self.file.write_all(record)?;
+        self.size += record.len() as u64;
+        Ok(())
+    }
+}
+
+/// Collects the fields of an event into a JSON object. Text is masked
+/// since messages may quote commands or output that contain secrets.
+struct JsonFields<'a>(&'a mut Map<String, Value>);
+
+impl Visit for JsonFields<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), Value::from(mask(value)));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.0.insert(field.name().to_string(), Value::from(mask(&format!("{:?}", value))));
+    }
+}
+
+/// Tracing layer writing every event as one JSON line to a rotating file,
+/// regardless of the console verbosity.
+pub struct JsonFileLayer {
+    run_id: String,
+    file: Mutex<RotatingFile>,
+}
+
+impl JsonFileLayer {
+    pub fn new(run_id: &str, file: RotatingFile) -> Self {
+        Self { run_id: run_id.to_string(), file: Mutex::new(file) }
+    }
+
+    fn record(&self, event: &Event<'_>) -> Vec<u8> {
+        let metadata = event.metadata();
+        let mut fields = Map::new();
+        event.record(&mut JsonFields(&mut fields));
+
+        let mut record = Map::new();
+        record.insert("timestamp".to_string(), Value::from(Local::now().to_rfc3339()));
+        record.insert("level".to_string(), Value::from(metadata.level().as_str()));
+        record.insert("target".to_string(), Value::from(metadata.target()));
+        record.insert("run_id".to_string(), Value::from(self.run_id.as_str()));
+        record.insert("fields".to_string(), Value::Object(fields));
+
+        let mut line = Value::Object(record).to_string().into_bytes();
+        line.push(b'\n');
+        line
+    }
+}
+
+impl<S: Subscriber> Layer<S> for JsonFileLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: LayerContext<'_, S>) {
+        let record = self.record(event);
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = file.write_record(&record) {
+            // Logging through tracing here would recurse into this layer
+            eprintln!("{:#}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn test_rotation_keeps_newest_files() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("sup-logfile-{}", std::process::id()));
+        let path = dir.join("sup.log");
+        let mut file = RotatingFile::open(&path, 10, 2)?;
+        for record in ["first\n", "second\n", "third\n", "fourth\n"] {
+            file.write_record(record.as_bytes())?;
+        }
+
+        assert_eq!(std::fs::read_to_string(&path)?, "fourth\n");
+        assert_eq!(std::fs::read_to_string(rotated(&path, 1))?, "third\n");
+        assert_eq!(std::fs::read_to_string(rotated(&path, 2))?, "second\n");
+        assert!(!rotated(&path, 3).exists());
+
+        // Reopening continues from the current size
+        let mut file = RotatingFile::open(&path, 10, 2)?;
+        file.write_record(b"ok\n")?;
+        assert_eq!(std::fs::read_to_string(&path)?, "fourth\nok\n");
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_records_carry_run_id() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("sup-logfile-json-{}", std::process::id()));
+        let path = dir.join("sup.log");
+        let layer = JsonFileLayer::new("run-1", RotatingFile::open(&path, DEFAULT_MAX_BYTES, DEFAULT_KEEP)?);
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!("Connecting to {}", "web1");
+            tracing::debug!(target: OUTPUT_TARGET, host = "web1", stream = "stderr", line = "disk full");
+        });
+
+        let records = std::fs::read_to_string(&path)?.lines()
+            .map(serde_json::from_str)
+            .collect::<serde_json::Result<Vec<Value>>>()?;
+        assert_eq!(records.len(), 2);
+        assert!(records.iter().all(|record| record["run_id"] == "run-1"));
+        assert_eq!(records[0]["level"], "INFO");
+        assert_eq!(records[0]["fields"]["message"], "Connecting to web1");
+        assert_eq!(records[1]["target"], OUTPUT_TARGET);
+        assert_eq!(records[1]["fields"]["line"], "disk full");
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
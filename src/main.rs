@@ -1,30 +1,185 @@
-use anyhow::Result;
-use clap::Parser;
-use std::path::PathBuf;
-use tracing::{debug, info};
+use anyhow::{Context, Result};
+use clap::{Parser, ValueEnum};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, warn};
+use tracing::Level;
+use tracing_subscriber::filter::{filter_fn, LevelFilter};
+use tracing_subscriber::prelude::*;
 use chrono::Local;
-use whoami;
+use colored::*;
+use regex::Regex;
 
+mod children;
+mod clock;
 mod config;
+mod doctor;
 mod executor;
+mod exit;
+mod filter;
+mod history;
+mod host_keys;
+mod hosts;
+mod lock;
+mod logfile;
+mod metrics;
+mod notification;
+mod output;
+mod ping;
+mod plan;
+mod prompt;
+mod repl;
+mod report;
+mod shell;
+mod sink;
+mod summary;
+mod template;
+mod transport;
+mod upload;
+mod watch;
 
-use config::Supfile;
-use executor::Executor;
+use config::{Command, EnvMap, HostEntry, HostKeyChecking, Members, Network, Notification, NotifyOn, Serial, Supfile};
+use executor::{Executor, ExecutorOptions, HostOrder};
+use exit::ExitCode;
+use host_keys::PinnedKeys;
+use output::OutputMode;
+use plan::{CommandConfig, EffectiveConfig, NetworkConfig, Plan, PlanFormat, StepPlan};
+use lock::NetworkLock;
+use report::{Report, ReportWriter};
+use transport::{AskPass, ControlDir, MockTransport, SubprocessTransport, Transport, TransportOptions};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum SshBackend {
+    /// Spawn the system ssh binary
+    Subprocess,
+    /// Use the built-in ssh client (requires the native-ssh feature)
+    Native,
+    /// Connect to no host: record what would run on each in a transcript
+    Mock,
+}
+
+/// Network name used when `--host` is given without a network.
+const ADHOC_NETWORK: &str = "adhoc";
+/// Command run when none is given.
+const DEFAULT_COMMAND: &str = "bash";
+/// Name under which a `--run` command is reported.
+const ADHOC_COMMAND: &str = "(adhoc)";
+/// Command name used for `--ping`, which runs no Supfile command.
+const PING_COMMAND: &str = "(ping)";
 
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, after_help = exit::HELP)]
+#[command(group(clap::ArgGroup::new("preview").args(["plan", "print_config"]).multiple(true)))]
 struct Args {
     /// Path to Supfile
     #[arg(short, long, default_value = "Supfile.yml")]
     file: PathBuf,
 
-    /// Network to use
-    #[arg(default_value = "dev")]
-    network: String,
+    /// Network to use (defaults to the Supfile's default_network); `a,b` runs on the hosts of both
+    network: Option<String>,
+
+    /// Command to execute (defaults to bash)
+    command: Option<String>,
+
+    /// Arguments after `--`, passed to commands as SUP_ARGS and {{args}}
+    #[arg(last = true, value_name = "ARGS")]
+    command_args: Vec<String>,
+
+    /// Run on the hosts of this network too; repeat to combine networks, and give the command as the first argument
+    #[arg(long = "network", value_name = "NAME", conflicts_with = "command")]
+    networks: Vec<String>,
+
+    /// Run this shell command on the hosts instead of a Supfile command
+    #[arg(short = 'c', long = "run", value_name = "CMD", conflicts_with = "command")]
+    run: Option<String>,
+
+    /// Run the --run command through sudo
+    #[arg(long, requires = "run", conflicts_with = "command")]
+    sudo: bool,
+
+    /// Run every command on N hosts (or a percentage like 25%) at a time
+    #[arg(long, value_name = "N")]
+    serial: Option<Serial>,
+
+    /// Run every command on one host only, ignoring serial
+    #[arg(long, conflicts_with_all = ["serial", "parallel"])]
+    once: bool,
+
+    /// Run every command on all hosts at once, ignoring serial
+    #[arg(long, conflicts_with = "serial")]
+    parallel: bool,
+
+    /// Run on this host instead of the network's hosts (repeatable)
+    #[arg(long = "host")]
+    host: Vec<String>,
+
+    /// Run on these comma-separated hosts instead of the network's hosts
+    #[arg(long = "hosts", value_delimiter = ',')]
+    hosts: Vec<String>,
+
+    /// Check that all hosts are reachable before running commands
+    #[arg(long = "check-hosts")]
+    check_hosts: bool,
+
+    /// Drop unreachable hosts found by the pre-flight check instead of aborting
+    #[arg(long = "skip-unreachable")]
+    skip_unreachable: bool,
+
+    /// Warn and carry on when a command is left with no hosts, instead of failing
+    #[arg(long = "allow-empty-hosts")]
+    allow_empty_hosts: bool,
+
+    /// List networks, commands and targets, then exit
+    #[arg(long)]
+    list: bool,
+
+    /// Open an interactive prompt running each line on this network's hosts
+    #[arg(long, value_name = "NETWORK", conflicts_with_all = ["network", "command", "run", "plan", "watch"])]
+    repl: Option<String>,
+
+    /// Measure connect+execute latency of every host of this network
+    #[arg(long, value_name = "NETWORK", conflicts_with_all = ["network", "command", "run", "plan", "watch", "repl"])]
+    ping: Option<String>,
+
+    /// Probes per host for --ping, averaged
+    #[arg(long = "ping-count", value_name = "N", default_value_t = 1, requires = "ping",
+        value_parser = clap::value_parser!(u16).range(1..))]
+    ping_count: u16,
 
-    /// Command to execute
-    #[arg(default_value = "bash")]
-    command: String,
+    /// Choose the command (and network, if none is set) from an interactive list
+    #[arg(long, conflicts_with_all = ["command", "run", "plan", "repl", "ping"])]
+    pick: bool,
+
+    /// Print the steps of a target or command without connecting to any host
+    #[arg(long, value_name = "TARGET", conflicts_with_all = ["command", "run"])]
+    plan: Option<String>,
+
+    /// Output format of --plan
+    #[arg(long = "plan-format", value_enum, default_value = "human", requires = "plan")]
+    plan_format: PlanFormat,
+
+    /// Leave out inventory commands when counting hosts for --plan, or listing them for --print-config
+    #[arg(long = "plan-skip-inventory", requires = "preview")]
+    plan_skip_inventory: bool,
+
+    /// Print the merged env, hosts, ssh invocation and commands of the selected network and command as YAML, without running anything
+    #[arg(long = "print-config", conflicts_with_all = ["run", "plan", "repl", "ping", "watch", "pick"])]
+    print_config: bool,
+
+    /// Read the host keys of the network's ssh hosts and add them to host_keys.yml next to the Supfile, for pinning
+    #[arg(long = "learn-host-keys", conflicts_with_all = ["command", "run", "plan", "repl", "ping", "watch", "pick", "print_config"])]
+    learn_host_keys: bool,
+
+    /// After the run, rerun the commands whenever these local paths change
+    #[arg(long, value_name = "PATH", value_delimiter = ',', conflicts_with = "plan")]
+    watch: Vec<PathBuf>,
+
+    /// Rerun inventory commands on every --watch iteration
+    #[arg(long = "refresh-inventory", requires = "watch")]
+    refresh_inventory: bool,
 
     /// Enable debug output
     #[arg(short = 'D', long)]
@@ -34,88 +189,1533 @@ struct Args {
     #[arg(short, long = "env", value_delimiter = ',')]
     env_vars: Vec<String>,
 
-    /// Filter hosts matching regexp
+    /// Set a secret environment variable, masked in all output
+    #[arg(long = "secret")]
+    secrets: Vec<String>,
+
+    /// Filter hosts: comma-separated host names or a regexp
     #[arg(long)]
     only: Option<String>,
 
-    /// Filter out hosts matching regexp
+    /// Filter out hosts: comma-separated host names or a regexp
     #[arg(long)]
     except: Option<String>,
 
+    /// Match --only and --except against the whole user@host:port entry instead of the hostname
+    #[arg(long = "only-full")]
+    only_full: bool,
+
+    /// Start the env from only the Supfile's inherit_env_only variables instead of the whole local environment
+    #[arg(long = "no-env-inherit")]
+    no_env_inherit: bool,
+
+    /// Reach every ssh host as this user, whatever the host entries say
+    #[arg(long, value_name = "NAME")]
+    user: Option<String>,
+
+    /// Run on at most N hosts after filtering
+    #[arg(long)]
+    limit: Option<usize>,
+
+    /// Order hosts run in
+    #[arg(long, value_enum, default_value = "inventory")]
+    order: HostOrder,
+
+    /// Seed for --order shuffle, for a reproducible order
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Show only errors and the final summary unless a command fails
+    #[arg(short, long)]
+    quiet: bool,
+
+    /// Print each distinct host output once, followed by the hosts that produced it
+    #[arg(long = "group-identical")]
+    group_identical: bool,
+
+    /// Print each host's output as one block when the host finishes
+    #[arg(long, conflicts_with = "group_identical")]
+    group: bool,
+
+    /// Print only failed hosts' output, and one OK line per successful host
+    #[arg(long = "failures-only", conflicts_with_all = ["group", "group_identical"])]
+    failures_only: bool,
+
+    /// Bytes of output kept in memory per host with --group or --failures-only before spilling to a temp file
+    #[arg(long = "group-buffer")]
+    group_buffer: Option<usize>,
+
+    /// Write Prometheus textfile metrics of the run to this path
+    #[arg(long = "metrics-file", value_name = "PATH")]
+    metrics_file: Option<PathBuf>,
+
+    /// Write a JSON report of the run to this path, even if the run fails
+    #[arg(long)]
+    report: Option<PathBuf>,
+
+    /// Remove an existing lock on the network before taking it
+    #[arg(long = "force-unlock")]
+    force_unlock: bool,
+
+    /// Print the last N runs from the history log and exit
+    #[arg(long, value_name = "N", num_args = 0..=1, default_missing_value = "20")]
+    history: Option<usize>,
+
+    /// Run only on the hosts that failed or were skipped in this earlier report
+    #[arg(long = "retry-failed")]
+    retry_failed: Option<PathBuf>,
+
+    /// Upload directories whole, ignoring .supignore files
+    #[arg(long = "no-supignore")]
+    no_supignore: bool,
+
     /// Disable hostname prefix in output
     #[arg(long = "disable-prefix")]
     disable_prefix: bool,
+
+    /// Print remote stderr undecorated, merged with stdout
+    #[arg(long = "combine-output")]
+    combine_output: bool,
+
+    /// Disable ssh host key verification for this run
+    #[arg(long = "insecure-host-keys")]
+    insecure_host_keys: bool,
+
+    /// Prompt once for an ssh password, tried on every host after keys
+    #[arg(long = "ask-pass")]
+    ask_pass: bool,
+
+    /// Seconds to wait when connecting to a host
+    #[arg(long = "connect-timeout")]
+    connect_timeout: Option<u64>,
+
+    /// Stop the run once it has taken this long, e.g. 30m
+    #[arg(long = "max-runtime", value_name = "DURATION", value_parser = config::parse_duration)]
+    max_runtime: Option<Duration>,
+
+    /// Skip confirmation prompts of protected networks and canary runs
+    #[arg(short = 'y', long)]
+    yes: bool,
+
+    /// SSH implementation used to reach hosts
+    #[arg(long = "ssh-backend", value_enum, default_value = "subprocess")]
+    ssh_backend: SshBackend,
+
+    /// Write the mock backend's transcript to this file instead of stdout
+    #[arg(long, value_name = "PATH")]
+    transcript: Option<PathBuf>,
+
+    /// Canned outputs and exit codes for the mock backend to answer with
+    #[arg(long = "mock-fixtures", value_name = "PATH")]
+    mock_fixtures: Option<PathBuf>,
+
+    /// Print sup's version and check its environment: ssh, tar, the Supfile, the ssh agent and identity files
+    #[arg(long)]
+    doctor: bool,
+
+    /// When to color output
+    #[arg(long, value_enum, default_value = "auto")]
+    color: output::ColorChoice,
+
+    /// Never color output, the same as --color never
+    #[arg(long = "no-color", conflicts_with = "color")]
+    no_color: bool,
+
+    /// Run this local command when the run ends, instead of the Supfile's notify entries
+    #[arg(long = "notify-command", value_name = "CMD")]
+    notify_command: Option<String>,
+
+    /// Cut output lines longer than this many bytes short on the console; 0 shows them in full
+    #[arg(long = "max-line-length", value_name = "BYTES", default_value_t = output::DEFAULT_MAX_LINE)]
+    max_line_length: usize,
+
+    /// Also write each host's output to DIR/<host>.log, timestamped and without colors
+    #[arg(long = "output-dir", value_name = "DIR")]
+    output_dir: Option<PathBuf>,
+
+    /// Also write all hosts' output to this file as JSON lines
+    #[arg(long = "output-json", value_name = "PATH")]
+    output_json: Option<PathBuf>,
+
+    /// Also write debug-level JSON logs, including host output, to this file
+    #[arg(long = "log-file")]
+    log_file: Option<PathBuf>,
+
+    /// Size in bytes at which --log-file is rotated
+    #[arg(long = "log-max-bytes", default_value_t = logfile::DEFAULT_MAX_BYTES)]
+    log_max_bytes: u64,
+
+    /// Number of rotated log files to keep
+    #[arg(long = "log-keep", default_value_t = logfile::DEFAULT_KEEP)]
+    log_keep: usize,
+}
+
+fn build_transport(backend: SshBackend, options: TransportOptions, mock: Option<&MockTransport>) -> Result<Arc<dyn Transport>> {
+    match backend {
+        SshBackend::Mock => Ok(Arc::new(mock.cloned().unwrap_or_default())),
+        SshBackend::Subprocess => Ok(Arc::new(SubprocessTransport::new(options))),
+        #[cfg(feature = "native-ssh")]
+        SshBackend::Native => Ok(Arc::new(transport::NativeTransport::new(options))),
+        #[cfg(not(feature = "native-ssh"))]
+        SshBackend::Native => anyhow::bail!("sup-rs was built without the native-ssh feature"),
+    }
+}
+
+/// Resolves the network and command names from the positional arguments.
+/// A single positional that names a command or target rather than a
+/// network is taken as the command (`sup deploy`). Hosts given with
+/// `--host`/`--hosts` replace the network's hosts and inventory; without an
+/// explicit network an empty ad-hoc network is used.
+fn select_network(supfile: &Supfile, args: &Args) -> Result<(String, String, Network, Members)> {
+    let override_hosts = args.host.iter()
+        .chain(args.hosts.iter())
+        .map(|host| host.trim())
+        .filter(|host| !host.is_empty())
+        .map(HostEntry::from)
+        .collect::<Vec<_>>();
+
+    let is_command = |name: &str| supfile.find_command(name).is_some() || supfile.targets.contains_key(name);
+    let (network_arg, command_name) = match (&args.network, &args.command) {
+        (network, _) if args.run.is_some() => (network.clone(), ADHOC_COMMAND.to_string()),
+        (_, _) if args.repl.is_some() => (args.repl.clone(), repl::REPL_COMMAND.to_string()),
+        (_, _) if args.ping.is_some() => (args.ping.clone(), PING_COMMAND.to_string()),
+        (network, _) if args.plan.is_some() => (network.clone(), args.plan.clone().unwrap_or_default()),
+        (Some(name), None) if !is_network(supfile, name) && is_command(name) => {
+            (None, name.clone())
+        }
+        (network, command) => {
+            (network.clone(), command.clone().unwrap_or_else(|| DEFAULT_COMMAND.to_string()))
+        }
+    };
+
+    let mut members = Vec::new();
+    let (network_name, mut network) = match network_arg {
+        // Several networks, or one including others, run on all their hosts
+        Some(name) if name.contains(',') || supfile.networks.get(&name).is_some_and(|network| !network.include.is_empty()) => {
+            let names = name.split(',').map(str::trim).filter(|name| !name.is_empty()).collect::<Vec<_>>();
+            for name in &names {
+                if !supfile.networks.contains_key(*name) {
+                    return Err(not_found("Network", name, supfile.networks.keys()));
+                }
+                for (member, network) in supfile.network_members(name)? {
+                    if !members.iter().any(|(known, _)| *known == member) {
+                        members.push((member, network));
+                    }
+                }
+            }
+            (names.join(","), Supfile::union_network(&members)?)
+        }
+        Some(name) => {
+            let network = match supfile.networks.get(&name) {
+                Some(network) => network,
+                // A lone mistyped argument may have been meant as a command
+                None if args.command.is_none() => {
+                    return Err(not_found("Network or command", &name, supfile.networks.keys()
+                        .chain(supfile.commands.keys())
+                        .chain(supfile.aliases.keys())
+                        .chain(supfile.targets.keys())));
+                }
+                None => return Err(not_found("Network", &name, supfile.networks.keys())),
+            };
+            (name, network.clone())
+        }
+        None if !override_hosts.is_empty() => (ADHOC_NETWORK.to_string(), Network::default()),
+        None => {
+            let name = supfile.default_network_name()?;
+            let network = supfile.networks.get(&name)
+                .ok_or_else(|| anyhow::anyhow!("Default network {} not found", name))?;
+            (name, network.clone())
+        }
+    };
+
+    if !override_hosts.is_empty() {
+        network.hosts = override_hosts;
+        network.inventory = None;
+        members.clear();
+    }
+    // An alias runs, and is reported as, the command it stands for
+    let command_name = match supfile.find_command(&command_name) {
+        Some((name, _)) => name.to_string(),
+        None => command_name,
+    };
+    Ok((network_name, command_name, supfile.inherit(network), members))
+}
+
+/// Whether `name` names a network of the Supfile, or several as `a,b`.
+fn is_network(supfile: &Supfile, name: &str) -> bool {
+    name.split(',').all(|member| supfile.networks.contains_key(member.trim()))
+}
+
+/// Builds a "not found" error with close matches among `candidates`.
+fn not_found<'a>(kind: &str, name: &str, candidates: impl IntoIterator<Item = &'a String>) -> anyhow::Error {
+    let suggestions = config::suggest(name, candidates.into_iter().map(String::as_str));
+    if suggestions.is_empty() {
+        return anyhow::anyhow!("{} {} not found; run with --list to see what is available", kind, name);
+    }
+    let suggestions = suggestions.iter()
+        .map(|suggestion| format!("`{}`", suggestion))
+        .collect::<Vec<_>>();
+    anyhow::anyhow!(
+        "{} {} not found; did you mean {}? Run with --list to see what is available",
+        kind, name, suggestions.join(", ")
+    )
+}
+
+fn sorted_keys<V>(map: &HashMap<String, V>) -> Vec<&String> {
+    let mut keys = map.keys().collect::<Vec<_>>();
+    keys.sort();
+    keys
+}
+
+/// Commands run together: a single step, or a parallel group of a target.
+type Stage<'a> = Vec<(&'a str, &'a Command, Option<&'a str>)>;
+
+/// Resolves the commands of a target stage by stage, each with the network
+/// it runs on when a step overrides `network_name`.
+fn target_steps<'a>(supfile: &'a Supfile, target: &str, network_name: &str) -> Result<Vec<Stage<'a>>> {
+    let resolve = |step: &'a config::TargetStep| {
+        let (name, command) = supfile.find_command(step.command())
+            .ok_or_else(|| not_found(&format!("Target {} step", target), step.command(), supfile.commands.keys().chain(supfile.aliases.keys())))?;
+        let network = match step.network() {
+            Some(name) if !supfile.networks.contains_key(name) => {
+                let kind = format!("Target {} step {} network", target, step.command());
+                return Err(not_found(&kind, name, supfile.networks.keys()));
+            }
+            Some(name) if name != network_name => Some(name),
+            _ => None,
+        };
+        Ok((name, command, network))
+    };
+    supfile.targets[target].steps.iter()
+        .map(|stage| stage.steps().iter().map(resolve).collect())
+        .collect()
+}
+
+/// Runs the stages in order, each command on the executor of its network.
+/// The commands of a group run concurrently and all finish before the
+/// group's first error, if any, stops the run.
+async fn run_steps(
+    stages: &[Stage<'_>],
+    executor: &Executor,
+    step_executors: &BTreeMap<&str, (Network, Executor)>,
+) -> Result<()> {
+    let executor_of = |step_network: Option<&str>| match step_network {
+        Some(step_network) => &step_executors[step_network].1,
+        None => executor,
+    };
+    for stage in stages {
+        match stage.as_slice() {
+            [(name, command, step_network)] => executor_of(*step_network).execute_command(name, command).await?,
+            steps => {
+                let group = steps.iter().map(|(name, command, step_network)| executor_of(*step_network).execute_command(name, command));
+                futures::future::join_all(group).await.into_iter().collect::<Result<Vec<_>>>()?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Marks the hosts a run stopped by `--max-runtime` did not finish the
+/// commands on.
+async fn record_deadline(
+    commands: &[(&str, &Command, Option<&str>)],
+    executor: &Executor,
+    step_executors: &BTreeMap<&str, (Network, Executor)>,
+) {
+    for (name, command, step_network) in commands {
+        let executor = match step_network {
+            Some(step_network) => &step_executors[step_network].1,
+            None => executor,
+        };
+        let hosts = match command.is_remote() {
+            true => executor.resolve_hosts().await.unwrap_or_default(),
+            false => vec![summary::LOCAL_HOST.to_string()],
+        };
+        executor.summary().record_unfinished(name, &hosts, "deadline exceeded");
+    }
+}
+
+/// Aborts the run if any of its commands may not run against the network
+/// it would use, or restricts itself by a network that does not exist.
+fn check_networks(supfile: &Supfile, command_name: &str, commands: &[(&str, &Command, Option<&str>)], network_name: &str) -> Result<()> {
+    for (name, command, step_network) in commands {
+        let unknown = command.networks.iter().flatten()
+            .chain(&command.forbidden_networks)
+            .find(|listed| !supfile.networks.contains_key(*listed));
+        if let Some(unknown) = unknown {
+            return Err(not_found(&format!("Command {} lists network", name), unknown, supfile.networks.keys()));
+        }
+
+        for reached in reached_networks(supfile, step_network.unwrap_or(network_name))? {
+            let check = command.check_network(name, &reached);
+            if *name != command_name {
+                check.with_context(|| format!("Target {} cannot run", command_name))?;
+            } else {
+                check?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Networks whose hosts a run against `network_name` reaches: each network
+/// of a union, and for a network including others, those networks and
+/// itself if it has hosts of its own.
+fn reached_networks(supfile: &Supfile, network_name: &str) -> Result<Vec<String>> {
+    let mut reached = Vec::new();
+    for name in network_name.split(',') {
+        let members = match supfile.networks.get(name) {
+            Some(network) if !network.include.is_empty() => supfile.network_members(name)?.into_iter()
+                .filter(|(_, member)| member.include.is_empty() || !member.hosts.is_empty() || member.inventory.is_some())
+                .map(|(member, _)| member)
+                .collect(),
+            _ => vec![name.to_string()],
+        };
+        for member in members {
+            if !reached.contains(&member) {
+                reached.push(member);
+            }
+        }
+    }
+    Ok(reached)
+}
+
+/// Env of commands run against a network: the local environment, the
+/// `SUP_*` variables, Supfile and network env, then `-e` and `--secret`.
+fn network_env(supfile: &Supfile, args: &Args, overrides: &[(String, String)], network_name: &str, network: &Network, time: &str) -> Result<EnvMap> {
+    let mut base = supfile.inherited_env(inherits_env(supfile, args), std::env::vars());
+
+    // Add Sup-specific environment variables
+    base.insert("SUP_TIME".to_string(), time.to_string());
+    base.insert("SUP_USER".to_string(), whoami::username());
+    base.insert("SUP_NETWORK".to_string(), network_name.to_string());
+
+    supfile.network_env(network, base, overrides)
+}
+
+/// Whether the env starts from the whole local environment.
+fn inherits_env(supfile: &Supfile, args: &Args) -> bool {
+    !args.no_env_inherit && supfile.inherit_env.unwrap_or(true)
+}
+
+/// Env variables remote commands on `network` are given: those the
+/// Supfile, the network and the command line set, and the secrets.
+fn exported_env<'a>(supfile: &'a Supfile, network: &'a Network, overrides: &'a [(String, String)]) -> BTreeSet<&'a str> {
+    let mut names = supfile.declared_env(network, overrides);
+    names.extend(supfile.secrets.iter().map(String::as_str));
+    names
+}
+
+/// `--print-config`'s account of the env layers, lowest precedence first.
+fn env_sources(supfile: &Supfile, inherit: bool, network_name: &str) -> Vec<String> {
+    let inherited = if inherit {
+        "local environment".to_string()
+    } else if supfile.inherit_env_only.is_empty() {
+        "local environment: nothing (inheritance off)".to_string()
+    } else {
+        format!("local environment: {} only", supfile.inherit_env_only.join(", "))
+    };
+    vec![
+        inherited,
+        "SUP_* variables".to_string(),
+        "Supfile env".to_string(),
+        format!("network {} env", network_name),
+        "-e and --secret".to_string(),
+    ]
+}
+
+/// Env variables given with `-e` and `--secret`, in that order.
+fn env_overrides(args: &Args) -> Result<Vec<(String, String)>> {
+    let mut overrides = args.env_vars.iter()
+        .filter_map(|var| var.split_once('='))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect::<Vec<_>>();
+    for var in &args.secrets {
+        let (key, value) = var.split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Secret must be in format KEY=VALUE"))?;
+        overrides.push((key.to_string(), value.to_string()));
+    }
+    Ok(overrides)
+}
+
+/// Transport settings of a network with the command-line overrides applied.
+fn transport_options(supfile: &Supfile, args: &Args, network_name: &str, network: &Network) -> TransportOptions {
+    let mut transport = TransportOptions::new(supfile, network);
+    if let Ok(ssh_binary) = std::env::var("SUP_SSH") {
+        transport.ssh_binary = Some(ssh_binary);
+    }
+    if let Some(timeout) = args.connect_timeout {
+        transport.connect_timeout = Some(timeout);
+    }
+    if args.insecure_host_keys {
+        transport.host_key_checking = HostKeyChecking::Off;
+    }
+    if transport.host_key_checking == HostKeyChecking::Off {
+        warn!("HOST KEY CHECKING IS DISABLED for network {}: connections are open to man-in-the-middle attacks", network_name);
+    }
+    transport
+}
+
+/// Reads the keys of the ssh hosts of every network of the run and checks
+/// them against the Supfile's `host_keys`, if any. With pins, this is also
+/// what records the keys the run's connections accept.
+async fn check_host_keys(
+    pinned_keys: Option<&PinnedKeys>,
+    executor: &Executor,
+    step_executors: &BTreeMap<&str, (Network, Executor)>,
+) -> Result<BTreeMap<String, host_keys::Observed>> {
+    let mut observed = executor.host_keys().await?;
+    for (_, step_executor) in step_executors.values() {
+        observed.extend(step_executor.host_keys().await?);
+    }
+    let Some(pinned_keys) = pinned_keys else {
+        for (host, reason) in observed.iter().filter_map(|(host, key)| Some((host, key.as_ref().err()?))) {
+            debug!("No host key of {} for the report: {}", host, reason);
+        }
+        return Ok(observed);
+    };
+    let mut problems = host_keys::verify(&pinned_keys.keys, &observed);
+    if problems.len() == 1 {
+        return Err(problems.remove(0).into());
+    }
+    if !problems.is_empty() {
+        let lines = problems.iter().map(|problem| format!("  {}", problem)).collect::<Vec<_>>();
+        anyhow::bail!("{} hosts failed host key verification:\n{}", problems.len(), lines.join("\n"));
+    }
+    Ok(observed)
+}
+
+/// Takes the network's lock, if it has one; remote locks live on its
+/// first host.
+async fn acquire_lock(network_name: &str, network: &Network, executor: &Executor, force: bool) -> Result<Option<NetworkLock>> {
+    let lock_host = match network.lock {
+        config::Lock::Remote => {
+            let mut hosts = executor.member_hosts(network_name).await?;
+            // A network that only includes others has no hosts of its own
+            if hosts.is_empty() {
+                hosts = executor.resolve_hosts().await?;
+            }
+            hosts.first().map(|host| executor.target(host)).transpose()?
+        }
+        _ => None,
+    };
+    NetworkLock::acquire(network.lock, network_name, executor.transport(), lock_host, force).await
+}
+
+/// Builds the command run by `--run`.
+fn adhoc(cmd: &str, args: &Args) -> Command {
+    Command {
+        run: Some(cmd.to_string()),
+        sudo: args.sudo,
+        ..Default::default()
+    }
+}
+
+/// Whether the command was left out where no default command exists, as
+/// in `sup prod`, so it should be picked interactively.
+fn wants_picker(supfile: &Supfile, args: &Args) -> bool {
+    args.command.is_none()
+        && args.run.is_none()
+        && args.plan.is_none()
+        && args.repl.is_none()
+        && args.ping.is_none()
+        && !args.learn_host_keys
+        && args.network.as_ref().is_none_or(|name| is_network(supfile, name))
+        && !supfile.commands.contains_key(DEFAULT_COMMAND)
+}
+
+/// Commands then targets as picker entries with their descriptions.
+fn picker_commands(supfile: &Supfile) -> Vec<(String, String)> {
+    let commands = sorted_keys(&supfile.commands).into_iter()
+        .map(|name| (name.clone(), supfile.commands[name].desc.clone().unwrap_or_default()));
+    let targets = sorted_keys(&supfile.targets).into_iter()
+        .map(|name| {
+            let target = &supfile.targets[name];
+            let desc = target.desc.clone().unwrap_or_else(|| {
+                let steps = target.steps.iter().map(ToString::to_string).collect::<Vec<_>>();
+                format!("target: {}", steps.join(", "))
+            });
+            (name.clone(), desc)
+        });
+    commands.chain(targets).collect()
+}
+
+/// Fills in the command, and the network when none is given or configured,
+/// from interactive pickers. Returns false if the user cancelled.
+fn pick_command(supfile: &Supfile, args: &mut Args) -> Result<bool> {
+    let no_hosts = args.host.is_empty() && args.hosts.is_empty();
+    if args.network.is_none() && no_hosts && supfile.default_network_name().is_err() {
+        let networks = sorted_keys(&supfile.networks).into_iter()
+            .map(|name| {
+                let network = &supfile.networks[name];
+                let inventory = if network.inventory.is_some() { " + inventory" } else { "" };
+                let desc = network.desc.as_ref().map(|desc| format!("  {}", desc)).unwrap_or_default();
+                (name.clone(), format!("{} hosts{}{}", network.hosts.len(), inventory, desc))
+            })
+            .collect::<Vec<_>>();
+        let Some(network) = prompt::pick("Network", &networks)? else {
+            return Ok(false);
+        };
+        args.network = Some(network);
+    }
+
+    let Some(command) = prompt::pick("Command", &picker_commands(supfile))? else {
+        return Ok(false);
+    };
+    args.command = Some(command);
+    Ok(true)
+}
+
+/// Prints the networks, commands and targets defined in the Supfile.
+fn print_list(supfile: &Supfile) {
+    println!("{}", "Networks:".bold());
+    for name in sorted_keys(&supfile.networks) {
+        let network = &supfile.networks[name];
+        let inventory = if network.inventory.is_some() { " + inventory" } else { "" };
+        let includes = network.include.iter().map(|name| format!(" + {}", name)).collect::<String>();
+        let hosts = format!("{} hosts{}{}", network.hosts.len(), inventory, includes);
+        let line = format!("  {:<20} {:<20} {}", name, hosts, network.desc.as_deref().unwrap_or(""));
+        println!("{}", line.trim_end());
+    }
+
+    println!("{}", "Commands:".bold());
+    for name in sorted_keys(&supfile.commands) {
+        let command = &supfile.commands[name];
+        let label = match command.aliases.as_deref() {
+            Some(aliases) if !aliases.is_empty() => format!("{} ({})", name, aliases.join(", ")),
+            _ => name.clone(),
+        };
+        println!("  {:<20} {}", label, command.desc.as_deref().unwrap_or(""));
+    }
+
+    if !supfile.targets.is_empty() {
+        println!("{}", "Targets:".bold());
+        for name in sorted_keys(&supfile.targets) {
+            let target = &supfile.targets[name];
+            let steps = target.steps.iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>();
+            match &target.desc {
+                Some(desc) => println!("  {:<20} {} ({})", name, desc, steps.join(", ")),
+                None => println!("  {:<20} {}", name, steps.join(", ")),
+            }
+        }
+    }
 }
 
 #[tokio::main]
-async fn main() -> Result<()> {
-    let args = Args::parse();
+async fn main() -> std::process::ExitCode {
+    // ssh runs sup as its askpass program, pointed at the run's socket
+    if let Some(socket) = std::env::var_os(transport::askpass::SOCKET_ENV) {
+        return answer_askpass(Path::new(&socket));
+    }
+    let args = match Args::try_parse() {
+        Ok(args) => args,
+        Err(e) => {
+            // --help and --version end up here too
+            let _ = e.print();
+            return if e.use_stderr() { ExitCode::Usage } else { ExitCode::Success }.into();
+        }
+    };
+
+    let result = run(args).await;
+    // No ssh or local command may outlive the run
+    children::reap_all();
+    match result {
+        Ok(()) => ExitCode::Success.into(),
+        Err(e) => {
+            // Errors may quote commands or output that contain secrets
+            eprintln!("Error: {}", output::mask(&format!("{:?}", e)));
+            ExitCode::of(&e).into()
+        }
+    }
+}
+
+/// Prints the password for the prompt ssh passes, if the run serving
+/// `socket` answers it.
+#[cfg(unix)]
+fn answer_askpass(socket: &Path) -> std::process::ExitCode {
+    let prompt = std::env::args().nth(1).unwrap_or_default();
+    match transport::askpass::ask(socket, &prompt) {
+        Ok(Some(password)) => {
+            print!("{}", password);
+            ExitCode::Success.into()
+        }
+        Ok(None) => ExitCode::Error.into(),
+        Err(e) => {
+            eprintln!("Failed to ask sup for the password: {}", e);
+            ExitCode::Error.into()
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn answer_askpass(_: &Path) -> std::process::ExitCode {
+    ExitCode::Error.into()
+}
+
+async fn run(mut args: Args) -> Result<()> {
+    let color = output::configure_color(if args.no_color { output::ColorChoice::Never } else { args.color });
 
-    // Initialize logging
-    tracing_subscriber::fmt()
-        .with_max_level(if args.debug { tracing::Level::DEBUG } else { tracing::Level::INFO })
+    let run_id = logfile::new_run_id();
+    output::set_max_line_length(args.max_line_length);
+
+    // Initialize logging; host output only ever goes to the log file, and
+    // quiet runs only log warnings unless debugging
+    let debug = args.debug;
+    let quiet = args.quiet;
+    let console = tracing_subscriber::fmt::layer()
+        .with_ansi(color)
         .with_target(false)
         .with_thread_ids(true)
         .with_file(true)
         .with_line_number(true)
-        .init();
+        .with_filter(filter_fn(move |metadata| {
+            let level = match (debug, quiet) {
+                (true, _) => Level::DEBUG,
+                (false, true) => Level::WARN,
+                (false, false) => Level::INFO,
+            };
+            metadata.target() != logfile::OUTPUT_TARGET && *metadata.level() <= level
+        }));
+    let log_file = match &args.log_file {
+        Some(path) => {
+            let file = logfile::RotatingFile::open(path, args.log_max_bytes, args.log_keep)?;
+            Some(logfile::JsonFileLayer::new(&run_id, file).with_filter(LevelFilter::DEBUG))
+        }
+        None => None,
+    };
+    tracing_subscriber::registry().with(console).with(log_file).init();
+    let mut sinks: Vec<Box<dyn sink::Sink>> = Vec::new();
+    if let Some(dir) = &args.output_dir {
+        sinks.push(Box::new(sink::HostFileSink::new(dir)?));
+    }
+    if let Some(path) = &args.output_json {
+        sinks.push(Box::new(sink::JsonSink::new(path)?));
+    }
+    // Written out until the run returns
+    let (console_sink, _sink_writer) = sink::ConsoleSink::spawn(sinks);
+    debug!("Starting run {}", run_id);
+
+    // `--network a --network b cmd` is `a,b cmd`
+    if !args.networks.is_empty() {
+        args.command = args.network.take();
+        args.network = Some(args.networks.join(","));
+    }
+
+    // Checks the Supfile itself, so it runs before loading it
+    if args.doctor {
+        let checks = doctor::run(&args.file, args.network.as_deref(), std::env::var("SUP_SSH").ok());
+        for line in doctor::report(&checks) {
+            println!("{}", line);
+        }
+        let problems = checks.iter().filter(|check| check.status == doctor::Status::Problem).count();
+        if problems > 0 {
+            anyhow::bail!("{} problem{} found", problems, if problems == 1 { "" } else { "s" });
+        }
+        return Ok(());
+    }
 
     debug!("Loading Supfile from {}", args.file.display());
     let supfile = Supfile::from_file(&args.file)?;
 
-    let network = supfile.networks.get(&args.network)
-        .ok_or_else(|| anyhow::anyhow!("Network {} not found", args.network))?;
+    if args.list {
+        print_list(&supfile);
+        return Ok(());
+    }
+
+    // Without a command, offer the Supfile's commands in a picker
+    let picked = args.pick || wants_picker(&supfile, &args);
+    if picked {
+        if !std::io::stdin().is_terminal() {
+            print_list(&supfile);
+            return Ok(());
+        }
+        if !pick_command(&supfile, &mut args)? {
+            return Ok(());
+        }
+    }
+
+    let history_path = history::history_path(&supfile);
+    if let Some(count) = args.history {
+        let path = history_path.context("Cannot locate home directory for the history log")?;
+        history::print(&history::read_last(&path, count)?);
+        return Ok(());
+    }
+
+    let (network_name, command_name, mut network, mut members) = select_network(&supfile, &args)?;
+    let skipped_inventory = if args.plan_skip_inventory { network.inventory.take() } else { None };
+    if args.plan_skip_inventory {
+        for (_, member) in &mut members {
+            member.inventory = None;
+        }
+    }
 
-    // Check if this is a target or a command
-    let commands = if let Some(target) = supfile.targets.get(&args.command) {
+    // Check if this is an ad-hoc command, a target or a command
+    let adhoc_command;
+    let stages = if let Some(cmd) = &args.run {
+        adhoc_command = adhoc(cmd, &args);
+        vec![vec![(ADHOC_COMMAND, &adhoc_command, None)]]
+    } else if args.repl.is_some() || args.ping.is_some() || args.learn_host_keys {
+        Vec::new()
+    } else if supfile.targets.contains_key(&command_name) {
         // For targets, we need to run multiple commands in sequence
-        target.iter()
-            .map(|cmd| supfile.commands.get(cmd)
-                .ok_or_else(|| anyhow::anyhow!("Command {} not found in target {}", cmd, args.command)))
-            .collect::<Result<Vec<_>>>()?
+        target_steps(&supfile, &command_name, &network_name)?
     } else {
         // For single commands, just get that command
-        vec![supfile.commands.get(&args.command)
-            .ok_or_else(|| anyhow::anyhow!("Command {} not found", args.command))?]
+        vec![vec![supfile.commands.get_key_value(&command_name)
+            .map(|(name, command)| (name.as_str(), command, None))
+            .ok_or_else(|| not_found("Command", &command_name, supfile.commands.keys().chain(supfile.aliases.keys()).chain(supfile.targets.keys())))?]]
     };
+    let commands = stages.concat();
 
-    // Setup environment variables
-    let mut env = std::env::vars().collect::<std::collections::HashMap<_, _>>();
-    
-    // Add Sup-specific environment variables
-    env.insert("SUP_TIME".to_string(), Local::now().to_rfc3339());
-    env.insert("SUP_USER".to_string(), whoami::username());
-    env.insert("SUP_NETWORK".to_string(), args.network.clone());
-    
-    // Add global environment variables from Supfile
-    if let Some(vars) = &supfile.env {
-        env.extend(vars.clone());
-    }
-    
-    // Add network-specific environment variables
-    if let Some(net_env) = &network.env {
-        env.extend(net_env.clone());
-    }
-    
-    // Add command-line environment variables
-    for var in &args.env_vars {
-        if let Some((key, value)) = var.split_once('=') {
-            env.insert(key.to_string(), value.to_string());
+    check_networks(&supfile, &command_name, &commands, &network_name)?;
+
+    // Shown before any inventory or env command runs, so it is seen even if
+    // one of them hangs; previews keep their output machine-readable
+    if !args.print_config && args.plan.is_none() {
+        if let Some(banner) = network.style().banner_line(&network_name) {
+            println!("{}", banner);
         }
     }
 
-    let executor = Executor::new(
+    // Target steps on other networks get that network's env and transport
+    let time = clock::now().to_rfc3339();
+    let inherit_env = inherits_env(&supfile, &args);
+    let overrides = env_overrides(&args)?;
+    let env = network_env(&supfile, &args, &overrides, &network_name, &network, &time)?;
+    let mut step_network_names = commands.iter()
+        .filter_map(|(_, _, network)| *network)
+        .collect::<Vec<_>>();
+    step_network_names.sort();
+    step_network_names.dedup();
+    let step_networks = step_network_names.into_iter()
+        .map(|name| {
+            let mut step_members = Members::new();
+            let mut step_network = if supfile.networks[name].include.is_empty() {
+                supfile.inherit(supfile.networks[name].clone())
+            } else {
+                step_members = supfile.network_members(name)?;
+                Supfile::union_network(&step_members)?
+            };
+            if args.plan_skip_inventory {
+                step_network.inventory = None;
+                for (_, member) in &mut step_members {
+                    member.inventory = None;
+                }
+            }
+            let step_env = network_env(&supfile, &args, &overrides, name, &step_network, &time)?;
+            Ok((name, step_network, step_members, step_env))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    // Secrets behave like env vars but their values never reach the output
+    let secret_names = supfile.secrets.iter()
+        .map(String::as_str)
+        .chain(args.secrets.iter().filter_map(|var| var.split_once('=').map(|(key, _)| key)))
+        .collect::<Vec<_>>();
+    let secret_values = std::iter::once(&env)
+        .chain(step_networks.iter().map(|(_, _, _, step_env)| step_env))
+        .flat_map(|env| secret_names.iter().filter_map(|name| env.get(*name).cloned()))
+        .collect::<Vec<_>>();
+    let redact = supfile.redact.iter()
+        .map(|pattern| Regex::new(pattern).with_context(|| format!("Invalid redact regex: {}", pattern)))
+        .collect::<Result<Vec<_>>>()?;
+    output::set_redacted(std::iter::once(&env)
+        .chain(step_networks.iter().map(|(_, _, _, step_env)| step_env))
+        .flat_map(|env| output::redacted_values(env, &redact, &secret_values))
+        .collect::<Vec<_>>());
+    output::set_secrets(secret_values);
+
+    // Asked once for the whole run; previews never connect
+    let ask_pass = if args.ask_pass && !args.print_config && args.plan.is_none() {
+        Some(Arc::new(AskPass::create(prompt::password("SSH password")?)?))
+    } else {
+        None
+    };
+    let ask_pass_cleanup = ask_pass.as_ref().map(AskPass::cleanup_on_drop);
+
+    // Pinned hosts only get to present the keys that matched their pins;
+    // previews and the mock backend never connect, and learning reads keys
+    // the pins may not know
+    let connects = !args.print_config && args.plan.is_none() && !matches!(args.ssh_backend, SshBackend::Mock);
+    let pinned_keys = if !supfile.host_keys.is_empty() && !args.learn_host_keys && connects {
+        Some(Arc::new(PinnedKeys::create(supfile.host_keys.clone())?))
+    } else {
+        None
+    };
+
+    // Share one ssh connection per host across all commands of this run
+    let multiplex = |network: &Network| network.multiplex.unwrap_or(true);
+    let control_dir = if multiplex(&network) || step_networks.iter().any(|(_, step_network, _, _)| multiplex(step_network)) {
+        let dir = ControlDir::create()?;
+        debug!("Using ssh control directory {}", dir.path().display());
+        Some(dir)
+    } else {
+        None
+    };
+    let control_path = |network: &Network, options: &TransportOptions| {
+        control_dir.as_ref().filter(|_| multiplex(network)).map(|dir| dir.control_path(options))
+    };
+    let mut transport = transport_options(&supfile, &args, &network_name, &network);
+    transport.control_path = control_path(&network, &transport);
+    transport.ask_pass = ask_pass.clone();
+    transport.pinned_keys = pinned_keys.clone();
+    let step_networks = step_networks.into_iter()
+        .map(|(name, step_network, step_members, step_env)| {
+            let mut step_transport = transport_options(&supfile, &args, name, &step_network);
+            step_transport.control_path = control_path(&step_network, &step_transport);
+            step_transport.ask_pass = ask_pass.clone();
+            step_transport.pinned_keys = pinned_keys.clone();
+            (name, step_network, step_members, step_env, step_transport)
+        })
+        .collect::<Vec<_>>();
+
+    // Every network of the run records into the one transcript
+    let mock = match args.ssh_backend {
+        SshBackend::Mock => {
+            let fixtures = args.mock_fixtures.as_deref().map(transport::load_fixtures).transpose()?;
+            Some(MockTransport::new(fixtures.unwrap_or_default()))
+        }
+        _ if args.transcript.is_some() || args.mock_fixtures.is_some() => {
+            anyhow::bail!(ExitCode::Usage.error("--transcript and --mock-fixtures need --ssh-backend mock"));
+        }
+        _ => None,
+    };
+
+    let mut executor = Executor::new(
         network.clone(),
+        build_transport(args.ssh_backend, transport.clone(), mock.as_ref())?,
         env,
-        args.only,
-        args.except,
-        args.disable_prefix,
-    )?;
+        ExecutorOptions {
+            only: args.only,
+            except: args.except,
+            only_full: args.only_full,
+            user: args.user,
+            disable_prefix: args.disable_prefix,
+            combine_output: args.combine_output,
+            assume_yes: args.yes,
+            limit: args.limit,
+            order: args.order,
+            seed: args.seed,
+            quiet: args.quiet,
+            output_mode: if args.group_identical {
+                OutputMode::GroupIdentical
+            } else if args.group {
+                OutputMode::Group
+            } else if args.failures_only {
+                OutputMode::FailuresOnly
+            } else {
+                OutputMode::Stream
+            },
+            group_buffer: args.group_buffer,
+            serial: args.serial,
+            once: args.once,
+            parallel: args.parallel,
+            args: args.command_args,
+            no_supignore: args.no_supignore,
+            supfile: Some(args.file.clone()),
+            no_env_inherit: !inherit_env,
+            allow_empty_hosts: args.allow_empty_hosts,
+            sink: console_sink,
+        },
+    )?
+    .union_of(members.clone())
+    .exporting(exported_env(&supfile, &network, &overrides));
+    let mut step_executors = BTreeMap::new();
+    let mut step_transports = BTreeMap::new();
+    for (name, step_network, step_members, step_env, step_transport) in step_networks {
+        let step_executor = executor.for_network(
+            step_network.clone(),
+            build_transport(args.ssh_backend, step_transport.clone(), mock.as_ref())?,
+            step_env,
+        )?
+        .union_of(step_members)
+        .exporting(exported_env(&supfile, &step_network, &overrides));
+        step_executors.insert(name, (step_network, step_executor));
+        step_transports.insert(name, step_transport);
+    }
 
-    // Execute all commands in sequence
-    for command in commands {
-        executor.execute_command(command).await?;
+    // The merged view comes from the executors a run would use
+    if args.print_config {
+        let mut networks = Vec::new();
+        let all = std::iter::once((network_name.as_str(), &network, &executor, &transport, skipped_inventory))
+            .chain(step_executors.iter().map(|(name, (step_network, step_executor))| {
+                let skipped = supfile.networks[*name].inventory.clone().filter(|_| args.plan_skip_inventory);
+                (*name, step_network, step_executor, &step_transports[name], skipped)
+            }));
+        for (name, config_network, config_executor, config_transport, inventory_pending) in all {
+            // Without inheritance the env is small and shown whole
+            let declared = supfile.declared_env(config_network, &overrides);
+            let env = config_executor.env().iter()
+                .filter(|(key, _)| !inherit_env || key.starts_with("SUP_") || declared.contains(key.as_str()))
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect();
+            // The control directory is named after this process; show its
+            // pattern so the output is the same on every run
+            let stable_transport = TransportOptions {
+                control_path: config_transport.control_path.as_ref()
+                    .map(|path| std::env::temp_dir().join("sup-<pid>").join(path.file_name().unwrap_or_default())),
+                ..config_transport.clone()
+            };
+            let ssh = stable_transport.ssh_command(false);
+            networks.push(NetworkConfig {
+                name: name.to_string(),
+                desc: config_network.desc.clone(),
+                hosts: config_executor.resolve_hosts().await?,
+                inventory_pending,
+                env_sources: env_sources(&supfile, inherit_env, name),
+                env,
+                ssh: std::iter::once(ssh.get_program())
+                    .chain(ssh.get_args())
+                    .map(|arg| arg.to_string_lossy().into_owned())
+                    .collect(),
+            });
+        }
+        let commands = commands.iter()
+            .map(|(name, command, step_network)| {
+                let (network, command_executor) = match step_network {
+                    Some(step_network) => (*step_network, &step_executors[step_network].1),
+                    None => (network_name.as_str(), &executor),
+                };
+                Ok(CommandConfig {
+                    name: name.to_string(),
+                    network: network.to_string(),
+                    command: command_executor.with_overrides(name, command)?,
+                })
+            })
+            .collect::<Result<_>>()?;
+        let config = EffectiveConfig { networks, commands };
+        print!("{}", config.to_yaml()?);
+        return Ok(());
     }
 
-    Ok(())
+    // The plan expands steps through the same executors a run would use
+    if args.plan.is_some() {
+        let mut steps = Vec::new();
+        let mut groups = 0;
+        for stage in &stages {
+            let group = (stage.len() > 1).then(|| {
+                groups += 1;
+                groups
+            });
+            for (name, command, step_network) in stage {
+                let step = match step_network {
+                    Some(step_network) => step_executors[step_network].1.plan_step(name, command).await?,
+                    None => executor.plan_step(name, command).await?,
+                };
+                steps.push(StepPlan { group, ..step });
+            }
+        }
+        let mut excluded = executor.excluded_hosts().await?;
+        for (_, step_executor) in step_executors.values() {
+            for host in step_executor.excluded_hosts().await? {
+                if !excluded.contains(&host) {
+                    excluded.push(host);
+                }
+            }
+        }
+        let plan = Plan {
+            desc: supfile.targets.get(&command_name).and_then(|target| target.desc.clone()),
+            target: command_name,
+            network: network_name,
+            inventory_skipped: args.plan_skip_inventory,
+            excluded,
+            steps,
+        };
+        match args.plan_format {
+            PlanFormat::Human => println!("{}", plan.render()),
+            PlanFormat::Json => println!("{}", serde_json::to_string_pretty(&plan)?),
+        }
+        return Ok(());
+    }
+
+    // Probes go through the same transport and multiplexing as a real run
+    if args.ping.is_some() {
+        if pinned_keys.is_some() {
+            check_host_keys(pinned_keys.as_deref(), &executor, &step_executors).await?;
+        }
+        let results = executor.ping(args.ping_count.into()).await?;
+        for line in ping::report(&results, transport.control_path.is_some()) {
+            println!("{}", line);
+        }
+        let failed = results.iter().filter(|result| result.error.is_some()).count();
+        if failed > 0 {
+            anyhow::bail!(ExitCode::Unreachable.error(format!("{} of {} hosts failed", failed, results.len())));
+        }
+        return Ok(());
+    }
+
+    if args.learn_host_keys {
+        let keys = executor.host_keys().await?;
+        let mut learned = BTreeMap::new();
+        let mut failed = Vec::new();
+        for (host, key) in keys {
+            match key {
+                Ok(fingerprint) => {
+                    if supfile.host_keys.get(&host).is_some_and(|pinned| *pinned != fingerprint) {
+                        warn!("Host key of {} differs from the one pinned in the Supfile", host);
+                    }
+                    println!("{} {}", host, fingerprint);
+                    learned.insert(host, fingerprint);
+                }
+                Err(reason) => {
+                    println!("{} {}: {}", "FAILED".red(), host, reason);
+                    failed.push(host);
+                }
+            }
+        }
+        let path = host_keys::sidecar_path(&args.file);
+        host_keys::learn(&path, &learned)?;
+        println!("Wrote {} host keys to {}", learned.len(), path.display());
+        if !failed.is_empty() {
+            anyhow::bail!(ExitCode::Unreachable.error(format!(
+                "Could not read the host keys of {} hosts: {}",
+                failed.len(),
+                failed.join(", ")
+            )));
+        }
+        return Ok(());
+    }
+
+    // Rerunning on every save must not sneak past a protected network
+    let mut watcher = None;
+    if !args.watch.is_empty() {
+        let protected = std::iter::once((network_name.as_str(), &network))
+            .chain(step_executors.iter().map(|(name, (step_network, _))| (*name, step_network)))
+            .find(|(_, network)| network.confirm);
+        if let (Some((name, _)), false) = (protected, args.yes) {
+            anyhow::bail!("Network {} requires confirmation; pass --yes to use it with --watch", name);
+        }
+        watcher = Some(watch::Watcher::new(&args.watch)?);
+    }
+
+    if let Some(path) = &args.retry_failed {
+        let failed = Report::load(path)?.failed_hosts();
+        if failed.is_empty() {
+            println!("No failed hosts in {}; nothing to retry", path.display());
+            return Ok(());
+        }
+        executor.retry_hosts(failed).await?;
+    }
+
+    // Dropping the writer on an early return still records what ran
+    let mut report = args.report.map(|path| ReportWriter::new(
+        path,
+        Report::new(&args.file, &network_name, &run_id),
+        executor.summary().clone(),
+    ));
+    if let Some(report) = &mut report {
+        report.set_hosts(executor.resolve_hosts().await?);
+    }
+    executor.summary().set_networks(executor.host_networks().await?);
+
+    // Pinned keys are checked before anything runs; the report records the
+    // keys either way
+    if pinned_keys.is_some() || report.is_some() {
+        let observed = check_host_keys(pinned_keys.as_deref(), &executor, &step_executors).await?;
+        if let Some(report) = &mut report {
+            report.set_host_keys(observed.iter()
+                .filter_map(|(host, key)| Some((host.clone(), key.clone().ok()?)))
+                .collect());
+        }
+    }
+
+    // Protected networks confirm with their own prompt below
+    if picked && !network.confirm {
+        prompt::confirm_hosts(&command_name, &network_name, &executor.resolve_hosts().await?, args.yes)?;
+    }
+
+    // Only one run at a time may hold a locked network
+    let mut locks = Vec::new();
+    // A union run takes the lock of each of its networks
+    let main_networks = if members.is_empty() {
+        vec![(network_name.as_str(), &network)]
+    } else {
+        members.iter().map(|(name, member)| (name.as_str(), member)).collect()
+    };
+    let lock_networks = main_networks.into_iter()
+        .map(|(name, lock_network)| (name, lock_network, &executor))
+        .chain(step_executors.iter().map(|(name, (step_network, step_executor))| (*name, step_network, step_executor)));
+    for (name, lock_network, lock_executor) in lock_networks {
+        match acquire_lock(name, lock_network, lock_executor, args.force_unlock).await {
+            Ok(lock) => locks.extend(lock),
+            Err(e) => {
+                for lock in locks {
+                    lock.release().await;
+                }
+                return Err(e);
+            }
+        }
+    }
+
+    let started = Local::now();
+    let deadline = tokio::time::Instant::now() + args.max_runtime.unwrap_or_default();
+    let names = commands.iter().map(|(name, _, _)| name.to_string()).collect::<Vec<_>>();
+    let watching = watcher.is_some();
+    let repl = args.repl.is_some();
+
+    // Execute all commands in sequence, tearing down master connections
+    // whether the run finishes or is interrupted
+    let result = tokio::select! {
+        result = async {
+            if args.check_hosts || network.preflight {
+                executor.preflight(args.skip_unreachable).await?;
+            }
+
+            for (step_network, step_executor) in step_executors.values_mut() {
+                if args.check_hosts || step_network.preflight {
+                    step_executor.preflight(args.skip_unreachable).await?;
+                }
+            }
+
+            // Protected networks need explicit confirmation with the real host list
+            let networks = std::iter::once((None, &network, &executor))
+                .chain(step_executors.iter().map(|(name, (step_network, step_executor))| (Some(*name), step_network, step_executor)));
+            for (step_name, confirm_network, confirm_executor) in networks.filter(|(_, network, _)| network.confirm) {
+                let mut names = commands.iter()
+                    .filter(|(_, _, network)| *network == step_name)
+                    .map(|(name, _, _)| *name)
+                    .collect::<Vec<_>>();
+                if repl && step_name.is_none() {
+                    names.push(repl::REPL_COMMAND);
+                }
+                if names.is_empty() {
+                    continue;
+                }
+                let hosts = confirm_executor.resolve_hosts().await?;
+                let name = step_name.unwrap_or(&network_name);
+                prompt::confirm_network(name, &confirm_network.style(), confirm_network.desc.as_deref(), &hosts, &names, args.yes)?;
+            }
+
+            if repl {
+                return repl::run(&mut executor, &network_name).await;
+            }
+
+            let mut result = run_steps(&stages, &executor, &step_executors).await;
+            let Some(watcher) = &mut watcher else {
+                return result;
+            };
+
+            // Keep going after failures until interrupted
+            loop {
+                if let Err(e) = &result {
+                    warn!("{}", output::mask(&format!("{:#}", e)));
+                }
+                let changed = watcher.changed().await?;
+                let what = match changed.as_slice() {
+                    [path] => path.display().to_string(),
+                    paths => format!("{} files", paths.len()),
+                };
+                println!("{}", format!("--- {} changed, running {} again ---", what, command_name).dimmed());
+
+                if args.refresh_inventory {
+                    executor.refresh_inventory();
+                    for (_, step_executor) in step_executors.values_mut() {
+                        step_executor.refresh_inventory();
+                    }
+                }
+                result = run_steps(&stages, &executor, &step_executors).await;
+            }
+        } => result,
+        // Watch mode only ends this way, which is not a failure
+        // The REPL handles Ctrl-C itself, per command
+        _ = tokio::signal::ctrl_c(), if !repl => if watching { Ok(()) } else { Err(ExitCode::Aborted.error("Interrupted").into()) },
+        // Stops the run the way Ctrl-C does, but leaves a trace in the summary
+        _ = tokio::time::sleep_until(deadline), if args.max_runtime.is_some() => {
+            let limit = output::format_duration(args.max_runtime.unwrap_or_default());
+            Err(ExitCode::Timeout.error(format!("Run exceeded --max-runtime of {}", limit)).into())
+        }
+    };
+
+    if result.as_ref().is_err_and(|e| ExitCode::of(e) == ExitCode::Timeout) {
+        record_deadline(&commands, &executor, &step_executors).await;
+    }
+
+    for lock in locks {
+        lock.release().await;
+    }
+    drop(control_dir);
+    drop(ask_pass_cleanup);
+
+    executor.summary().print(&executor.style());
+    let result = match &mock {
+        Some(mock) => result.and(mock.write_transcript(args.transcript.as_deref())),
+        None => result,
+    };
+    if let Some(report) = report {
+        report.finish(&result);
+    }
+
+    // Notifications are best-effort too; a REPL session is not a run to report
+    if !repl {
+        let notifications = match &args.notify_command {
+            Some(command) => vec![Notification {
+                on: vec![NotifyOn::Success, NotifyOn::Failure],
+                command: command.clone(),
+                timeout: None,
+            }],
+            None => supfile.notify.clone(),
+        };
+        let duration = (Local::now() - started).to_std().unwrap_or_default();
+        let outcome = notification::Outcome::new(result.is_ok(), executor.summary(), duration, &network_name, &names);
+        notification::send(&notifications, &outcome).await;
+    }
+
+    // Metrics are best-effort like the history
+    if let Some(path) = &args.metrics_file {
+        let duration = (Local::now() - started).to_std().unwrap_or_default();
+        let text = metrics::render(&network_name, duration, executor.summary());
+        if let Err(e) = metrics::write(path, &text) {
+            warn!("Failed to write metrics: {:#}", e);
+        }
+    }
+
+    // The audit log is best-effort and never changes the outcome
+    if let Some(path) = history_path {
+        let entry = history::HistoryEntry {
+            run_id,
+            timestamp: started.to_rfc3339(),
+            user: whoami::username(),
+            network: network_name,
+            target: command_name,
+            commands: names,
+            hosts: executor.summary().host_count(),
+            success: result.is_ok(),
+            error: result.as_ref().err().map(|e| output::mask(&e.to_string())),
+            duration_ms: (Local::now() - started).num_milliseconds().max(0) as u64,
+        };
+        if let Err(e) = history::append(&path, &entry) {
+            warn!("Failed to write history to {}: {:#}", path.display(), e);
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SUPFILE: &str = r#"
+version: "0.4"
+networks:
+  prod:
+    hosts:
+      - deploy@web1
+    inventory: echo deploy@web2
+    connect_timeout: 3
+commands:
+  setup:
+    run: ./setup.sh
+"#;
+
+    #[test]
+    fn test_host_override() -> Result<()> {
+        let supfile: Supfile = serde_yaml::from_str(SUPFILE)?;
+
+        let args = Args::parse_from(["sup", "--host", "deploy@newbox", "--hosts", "a,b", "prod", "setup"]);
+        let (network_name, command_name, network, _) = select_network(&supfile, &args)?;
+        assert_eq!((network_name.as_str(), command_name.as_str()), ("prod", "setup"));
+        assert_eq!(network.hosts, ["deploy@newbox", "a", "b"].map(HostEntry::from));
+        assert!(network.inventory.is_none());
+        assert_eq!(network.connect_timeout, Some(3));
+
+        // Without a network the first positional is the command
+        let args = Args::parse_from(["sup", "--host", "deploy@newbox", "setup"]);
+        let (network_name, command_name, network, _) = select_network(&supfile, &args)?;
+        assert_eq!((network_name.as_str(), command_name.as_str()), (ADHOC_NETWORK, "setup"));
+        assert_eq!(network.hosts, [HostEntry::from("deploy@newbox")]);
+
+        let args = Args::parse_from(["sup", "staging", "setup"]);
+        assert!(select_network(&supfile, &args).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_default_network() -> Result<()> {
+        let supfile: Supfile = serde_yaml::from_str(SUPFILE)?;
+
+        // The only network is used when none is given
+        let args = Args::parse_from(["sup", "setup"]);
+        let (network_name, command_name, network, _) = select_network(&supfile, &args)?;
+        assert_eq!((network_name.as_str(), command_name.as_str()), ("prod", "setup"));
+        assert_eq!(network.hosts, [HostEntry::from("deploy@web1")]);
+
+        let args = Args::parse_from(["sup", "prod"]);
+        let (_, command_name, _, _) = select_network(&supfile, &args)?;
+        assert_eq!(command_name, DEFAULT_COMMAND);
+        Ok(())
+    }
+
+    #[test]
+    fn test_adhoc_run() -> Result<()> {
+        let supfile: Supfile = serde_yaml::from_str(SUPFILE)?;
+
+        let args = Args::parse_from(["sup", "prod", "--run", "uptime", "--sudo"]);
+        let (network_name, command_name, _, _) = select_network(&supfile, &args)?;
+        assert_eq!((network_name.as_str(), command_name.as_str()), ("prod", ADHOC_COMMAND));
+        let command = adhoc(args.run.as_deref().unwrap(), &args);
+        assert_eq!(command.run.as_deref(), Some("uptime"));
+        assert!(command.sudo);
+
+        // A lone positional is the network, even if it names a command
+        let args = Args::parse_from(["sup", "-c", "uptime", "setup"]);
+        assert!(select_network(&supfile, &args).is_err());
+        let args = Args::parse_from(["sup", "-c", "uptime"]);
+        let (network_name, _, _, _) = select_network(&supfile, &args)?;
+        assert_eq!(network_name, "prod");
+
+        assert!(Args::try_parse_from(["sup", "prod", "setup", "--run", "uptime"]).is_err());
+        assert!(Args::try_parse_from(["sup", "prod", "setup", "--sudo"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_target_step_networks() -> Result<()> {
+        let supfile: Supfile = serde_yaml::from_str(r#"
+version: "0.4"
+networks:
+  local:
+    hosts: [localhost]
+  prod:
+    hosts: [deploy@web1]
+commands:
+  build:
+    local: make
+  restart:
+    run: systemctl restart app
+targets:
+  deploy:
+    - command: build
+      network: local
+    - restart
+  broken:
+    - command: build
+      network: lcoal
+"#)?;
+
+        let steps = target_steps(&supfile, "deploy", "prod")?.concat();
+        let steps = steps.iter().map(|(name, _, network)| (*name, *network)).collect::<Vec<_>>();
+        assert_eq!(steps, [("build", Some("local")), ("restart", None)]);
+
+        // A step on the selected network needs no executor of its own
+        let steps = target_steps(&supfile, "deploy", "local")?.concat();
+        assert!(steps.iter().all(|(_, _, network)| network.is_none()));
+
+        let err = target_steps(&supfile, "broken", "prod").unwrap_err().to_string();
+        assert!(err.contains("Target broken step build network lcoal not found; did you mean `local`?"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn test_command_networks() -> Result<()> {
+        let supfile: Supfile = serde_yaml::from_str(r#"
+version: "0.4"
+networks:
+  dev:
+    hosts: [deploy@dev1]
+  prod:
+    hosts: [deploy@web1]
+  all:
+    include: [dev, prod]
+  dev-plus:
+    include: [dev]
+commands:
+  drop-database:
+    run: dropdb app
+    networks: [dev]
+  wipe-cache:
+    run: rm -rf /var/cache/app
+    forbidden_networks: [prod]
+  typo:
+    run: "true"
+    forbidden_networks: [prd]
+targets:
+  reset:
+    - wipe-cache
+    - drop-database
+"#)?;
+        let commands = |name: &'static str| vec![(name, &supfile.commands[name], None)];
+
+        // Direct invocation
+        check_networks(&supfile, "drop-database", &commands("drop-database"), "dev")?;
+        let err = check_networks(&supfile, "drop-database", &commands("drop-database"), "prod").unwrap_err();
+        assert_eq!(err.to_string(), "Command drop-database may not run against network prod; allowed networks: dev");
+        let err = check_networks(&supfile, "wipe-cache", &commands("wipe-cache"), "prod").unwrap_err();
+        assert_eq!(err.to_string(), "Command wipe-cache must never run against network prod");
+
+        // Every network a union or include reaches is checked, but not an
+        // including network without hosts of its own
+        let err = check_networks(&supfile, "wipe-cache", &commands("wipe-cache"), "dev,prod").unwrap_err();
+        assert_eq!(err.to_string(), "Command wipe-cache must never run against network prod");
+        let err = check_networks(&supfile, "drop-database", &commands("drop-database"), "all").unwrap_err();
+        assert_eq!(err.to_string(), "Command drop-database may not run against network prod; allowed networks: dev");
+        check_networks(&supfile, "drop-database", &commands("drop-database"), "dev-plus")?;
+
+        // Via a target, naming the target and the offending step
+        check_networks(&supfile, "reset", &target_steps(&supfile, "reset", "dev")?.concat(), "dev")?;
+        let err = check_networks(&supfile, "reset", &target_steps(&supfile, "reset", "prod")?.concat(), "prod").unwrap_err();
+        assert_eq!(format!("{:#}", err), "Target reset cannot run: Command wipe-cache must never run against network prod");
+
+        let err = check_networks(&supfile, "typo", &commands("typo"), "dev").unwrap_err().to_string();
+        assert!(err.contains("Command typo lists network prd not found; did you mean `prod`?"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn test_picker() -> Result<()> {
+        let mut supfile: Supfile = serde_yaml::from_str(SUPFILE)?;
+        supfile.commands.get_mut("setup").unwrap().desc = Some("Install the app".to_string());
+        supfile.targets.insert("all".to_string(), vec![config::TargetStep::Command("setup".to_string())].into());
+        assert_eq!(picker_commands(&supfile), [
+            ("setup".to_string(), "Install the app".to_string()),
+            ("all".to_string(), "target: setup".to_string()),
+        ]);
+
+        assert!(wants_picker(&supfile, &Args::parse_from(["sup", "prod"])));
+        assert!(wants_picker(&supfile, &Args::parse_from(["sup"])));
+        assert!(!wants_picker(&supfile, &Args::parse_from(["sup", "prod", "setup"])));
+        assert!(!wants_picker(&supfile, &Args::parse_from(["sup", "setup"])));
+        assert!(!wants_picker(&supfile, &Args::parse_from(["sup", "prod", "--run", "uptime"])));
+
+        // A Supfile with a bash command keeps it as the default
+        supfile.commands.insert(DEFAULT_COMMAND.to_string(), Command::default());
+        assert!(!wants_picker(&supfile, &Args::parse_from(["sup", "prod"])));
+        Ok(())
+    }
+
+    #[test]
+    fn test_trailing_args() {
+        let args = Args::parse_from(["sup", "prod", "restart", "--", "--service", "api gateway"]);
+        assert_eq!(args.command.as_deref(), Some("restart"));
+        assert_eq!(args.command_args, ["--service", "api gateway"]);
+    }
+
+    #[test]
+    fn test_override_flags_conflict() {
+        let args = Args::parse_from(["sup", "prod", "setup", "--serial", "25%"]);
+        assert_eq!(args.serial, Some(Serial::Percent(25)));
+        assert!(Args::try_parse_from(["sup", "prod", "setup", "--serial", "2", "--parallel"]).is_err());
+        assert!(Args::try_parse_from(["sup", "prod", "setup", "--once", "--serial", "2"]).is_err());
+        assert!(Args::try_parse_from(["sup", "prod", "setup", "--once", "--parallel"]).is_err());
+        assert!(Args::try_parse_from(["sup", "prod", "setup", "--serial", "0"]).is_err());
+    }
+
+    #[test]
+    fn test_not_found_suggestions() -> Result<()> {
+        let supfile: Supfile = serde_yaml::from_str(SUPFILE)?;
+
+        let args = Args::parse_from(["sup", "prd", "setup"]);
+        let err = select_network(&supfile, &args).unwrap_err().to_string();
+        assert!(err.contains("Network prd not found; did you mean `prod`?"), "{}", err);
+
+        let args = Args::parse_from(["sup", "setpu"]);
+        let err = select_network(&supfile, &args).unwrap_err().to_string();
+        assert!(err.contains("did you mean `setup`?"), "{}", err);
+
+        let err = not_found("Command", "xyz", supfile.commands.keys()).to_string();
+        assert_eq!(err, "Command xyz not found; run with --list to see what is available");
+        Ok(())
+    }
 }
@@ -0,0 +1,101 @@
+use crate::summary::{HostResult, HostStatus, Summary, LOCAL_HOST};
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::Path;
+use std::time::Duration;
+
+/// A label value escaped for the Prometheus text format.
+fn label(value: &str) -> String {
+    value.replace('\\', r"\\").replace('"', r#"\""#).replace('\n', r"\n")
+}
+
+/// A command name as a label value: anything but letters, digits and
+/// `_.:-` becomes `_`, so names with quotes, spaces or control characters
+/// cannot make new series that look alike or break scrapers.
+fn command_label(command: &str) -> String {
+    command.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || "_.:-".contains(c) { c } else { '_' })
+        .collect()
+}
+
+fn header(text: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(text, "# HELP {} {}", name, help);
+    let _ = writeln!(text, "# TYPE {} {}", name, kind);
+}
+
+/// Renders the run's results and timings, as also written to `--report`,
+/// in the Prometheus text format.
+pub fn render(network: &str, duration: Duration, summary: &Summary) -> String {
+    let results = summary.results();
+    let network = label(network);
+    // Whether each remote host failed, and what it was sent if it had
+    // uploads; hosts that were only skipped never ran
+    let mut hosts: BTreeMap<&str, (bool, Option<u64>)> = BTreeMap::new();
+    let ran = |result: &&HostResult| result.host != LOCAL_HOST && !matches!(result.status, HostStatus::Skipped(_));
+    for result in results.iter().filter(ran) {
+        let (failed, bytes) = hosts.entry(&result.host).or_default();
+        *failed |= matches!(result.status, HostStatus::Failed(_));
+        if let Some(uploaded) = result.bytes_uploaded {
+            *bytes = Some(bytes.unwrap_or(0) + uploaded);
+        }
+    }
+
+    let mut text = String::new();
+    header(&mut text, "sup_run_duration_seconds", "gauge", "Wall-clock time of the run.");
+    let _ = writeln!(text, "sup_run_duration_seconds{{network=\"{}\"}} {:.3}", network, duration.as_secs_f64());
+    header(&mut text, "sup_hosts_total", "gauge", "Remote hosts that ran a command.");
+    let _ = writeln!(text, "sup_hosts_total{{network=\"{}\"}} {}", network, hosts.len());
+    header(&mut text, "sup_hosts_failed", "gauge", "Remote hosts that failed any command.");
+    let failed = hosts.values().filter(|(failed, _)| *failed).count();
+    let _ = writeln!(text, "sup_hosts_failed{{network=\"{}\"}} {}", network, failed);
+    header(&mut text, "sup_command_duration_seconds", "gauge", "Wall-clock time of each command.");
+    for timing in summary.timings() {
+        let (command, seconds) = (command_label(&timing.command), timing.elapsed.as_secs_f64());
+        let _ = writeln!(text, "sup_command_duration_seconds{{network=\"{}\",command=\"{}\"}} {:.3}", network, command, seconds);
+    }
+    header(&mut text, "sup_upload_bytes_total", "gauge", "Bytes uploaded to each host.");
+    for (host, bytes) in hosts.iter().filter_map(|(host, (_, bytes))| Some((host, (*bytes)?))) {
+        let _ = writeln!(text, "sup_upload_bytes_total{{network=\"{}\",host=\"{}\"}} {}", network, label(host), bytes);
+    }
+    text
+}
+
+/// Writes `text` next to `path` and renames it into place, so a scraper
+/// never reads half a file.
+pub fn write(path: &Path, text: &str) -> Result<()> {
+    let name = path.file_name().with_context(|| format!("{} is not a file path", path.display()))?;
+    let mut temp = name.to_os_string();
+    temp.push(format!(".{}.tmp", std::process::id()));
+    let temp = path.with_file_name(temp);
+    std::fs::write(&temp, text).with_context(|| format!("Failed to write {}", temp.display()))?;
+    std::fs::rename(&temp, path).with_context(|| {
+        let _ = std::fs::remove_file(&temp);
+        format!("Failed to move metrics into place at {}", path.display())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let summary = Summary::default();
+        summary.push(HostResult { bytes_uploaded: Some(2048), ..HostResult::new("upload", "web1", HostStatus::Success) });
+        summary.push(HostResult { bytes_uploaded: Some(0), ..HostResult::new("upload", "web2", HostStatus::Failed("exit code 1".into())) });
+        summary.record("build", LOCAL_HOST, HostStatus::Success);
+        summary.record("upload", "web3", HostStatus::Skipped("creates exists".into()));
+        summary.record_timing("upload", Duration::from_millis(1250));
+        summary.record_timing("say \"hi\"", Duration::from_millis(5));
+
+        let text = render("prod", Duration::from_secs(3), &summary);
+        assert!(text.contains("sup_run_duration_seconds{network=\"prod\"} 3.000\n"), "{}", text);
+        assert!(text.contains("sup_hosts_total{network=\"prod\"} 2\n"), "{}", text);
+        assert!(text.contains("sup_hosts_failed{network=\"prod\"} 1\n"), "{}", text);
+        assert!(text.contains("sup_command_duration_seconds{network=\"prod\",command=\"upload\"} 1.250\n"), "{}", text);
+        assert!(text.contains("sup_command_duration_seconds{network=\"prod\",command=\"say__hi_\"} 0.005\n"), "{}", text);
+        assert!(text.contains("# TYPE sup_upload_bytes_total gauge\n"), "{}", text);
+        assert!(text.contains("sup_upload_bytes_total{network=\"prod\",host=\"web1\"} 2048\n"), "{}", text);
+    }
+}
@@ -0,0 +1,138 @@
+use crate::config::{Notification, NotifyOn};
+use crate::output;
+use crate::shell;
+use crate::summary::{HostStatus, Summary};
+use std::process::Stdio;
+use std::time::Duration;
+use tracing::{debug, warn};
+
+/// How long a notification may take before it is killed, so a dead
+/// webhook cannot hang sup after the run.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How a run ended, as passed to notification commands.
+#[derive(Debug, Clone)]
+pub struct Outcome {
+    pub success: bool,
+    pub failed_hosts: Vec<String>,
+    pub duration: Duration,
+    pub network: String,
+    pub commands: Vec<String>,
+}
+
+impl Outcome {
+    pub fn new(success: bool, summary: &Summary, duration: Duration, network: &str, commands: &[String]) -> Self {
+        let mut failed_hosts = Vec::new();
+        for result in summary.results() {
+            if matches!(result.status, HostStatus::Failed(_)) && !failed_hosts.contains(&result.host) {
+                failed_hosts.push(result.host);
+            }
+        }
+        Self {
+            success,
+            failed_hosts,
+            duration,
+            network: network.to_string(),
+            commands: commands.to_vec(),
+        }
+    }
+
+    fn on(&self) -> NotifyOn {
+        if self.success { NotifyOn::Success } else { NotifyOn::Failure }
+    }
+
+    /// `SUP_RESULT` (`success` or `failure`), `SUP_FAILED_HOSTS` and
+    /// `SUP_COMMANDS` (comma-separated), `SUP_DURATION` (whole seconds) and
+    /// `SUP_NETWORK`.
+    pub fn env(&self) -> Vec<(&'static str, String)> {
+        let result = match self.on() {
+            NotifyOn::Success => "success",
+            NotifyOn::Failure => "failure",
+        };
+        vec![
+            ("SUP_RESULT", result.to_string()),
+            ("SUP_FAILED_HOSTS", self.failed_hosts.join(",")),
+            ("SUP_DURATION", self.duration.as_secs().to_string()),
+            ("SUP_NETWORK", self.network.clone()),
+            ("SUP_COMMANDS", self.commands.join(",")),
+        ]
+    }
+}
+
+/// Runs the notifications matching the outcome, one after another, each
+/// killed once its timeout passes. They are best-effort: failures are
+/// logged and never change the run's result.
+pub async fn send(notifications: &[Notification], outcome: &Outcome) {
+    let on = outcome.on();
+    for notification in notifications.iter().filter(|notification| notification.on.contains(&on)) {
+        debug!("Running notification: {}", output::redact(&notification.command));
+        let mut command = tokio::process::Command::from(shell::command(&notification.command));
+        command.envs(outcome.env()).stdin(Stdio::null()).kill_on_drop(true);
+        let timeout = notification.timeout.unwrap_or(DEFAULT_TIMEOUT);
+        let result = match tokio::time::timeout(timeout, command.output()).await {
+            Ok(result) => result,
+            Err(_) => {
+                warn!("Notification `{}` killed after {:?}", output::mask(&notification.command), timeout);
+                continue;
+            }
+        };
+        match result {
+            Ok(result) if result.status.success() => {}
+            Ok(result) => warn!(
+                "Notification `{}` failed with {}: {}",
+                output::mask(&notification.command),
+                result.status,
+                output::mask(String::from_utf8_lossy(&result.stderr).trim()),
+            ),
+            Err(e) => warn!("Failed to run notification `{}`: {}", output::mask(&notification.command), e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_notification_env() -> anyhow::Result<()> {
+        let out = std::env::temp_dir().join(format!("sup-notify-{}", std::process::id()));
+        let notifications = [
+            Notification {
+                on: vec![NotifyOn::Success, NotifyOn::Failure],
+                command: format!(
+                    "echo \"$SUP_RESULT|$SUP_FAILED_HOSTS|$SUP_DURATION|$SUP_NETWORK|$SUP_COMMANDS\" >> {}",
+                    out.display()
+                ),
+                timeout: None,
+            },
+            Notification { on: vec![NotifyOn::Failure], command: format!("echo paged >> {}", out.display()), timeout: None },
+            // Failing and hanging notifications are only logged
+            Notification { on: vec![NotifyOn::Success, NotifyOn::Failure], command: "exit 3".to_string(), timeout: None },
+            Notification {
+                on: vec![NotifyOn::Failure],
+                command: "sleep 30".to_string(),
+                timeout: Some(Duration::from_millis(200)),
+            },
+        ];
+        let commands = ["build".to_string(), "rolling-update".to_string()];
+
+        let summary = Summary::default();
+        summary.record("build", "local", HostStatus::Success);
+        send(&notifications, &Outcome::new(true, &summary, Duration::from_millis(1500), "prod", &commands)).await;
+
+        summary.record("rolling-update", "app@web2", HostStatus::Failed("exit status: 1".to_string()));
+        summary.record("rolling-update", "app@web7", HostStatus::Failed("timed out".to_string()));
+        summary.record("status", "app@web2", HostStatus::Failed("exit status: 1".to_string()));
+        let started = std::time::Instant::now();
+        send(&notifications, &Outcome::new(false, &summary, Duration::from_secs(1260), "prod", &commands)).await;
+        assert!(started.elapsed() < Duration::from_secs(10));
+
+        assert_eq!(std::fs::read_to_string(&out)?, "\
+success||1|prod|build,rolling-update
+failure|app@web2,app@web7|1260|prod|build,rolling-update
+paged
+");
+        std::fs::remove_file(&out)?;
+        Ok(())
+    }
+}
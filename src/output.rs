@@ -0,0 +1,850 @@
+use crate::sink::ConsoleSink;
+use colored::*;
+use indexmap::IndexMap;
+use regex::Regex;
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{BufRead, BufReader, IsTerminal, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{LazyLock, RwLock};
+use std::time::Duration;
+use tracing::warn;
+
+const MASK: &str = "*****";
+const REDACTED: &str = "[redacted]";
+
+/// Values of credential-like env variables shorter than this are left in
+/// debug logs, so `USE_TOKEN=1` does not redact every `1`.
+const MIN_REDACTED_LEN: usize = 4;
+
+/// Replaces secret values with a fixed mask in text shown to the user.
+#[derive(Debug, Clone)]
+pub struct Masker {
+    secrets: Vec<String>,
+    mask: &'static str,
+}
+
+impl Default for Masker {
+    fn default() -> Self {
+        Self::new(Vec::new())
+    }
+}
+
+impl Masker {
+    pub fn new(secrets: impl IntoIterator<Item = String>) -> Self {
+        let mut secrets = secrets.into_iter()
+            .filter(|secret| !secret.is_empty())
+            .collect::<Vec<_>>();
+        // Longest first so a secret containing another is masked whole
+        secrets.sort_by_key(|secret| std::cmp::Reverse(secret.len()));
+        secrets.dedup();
+        Self { secrets, mask: MASK }
+    }
+
+    /// Replaces `values` with `[redacted]`, for commands in debug logs.
+    pub fn redacting(values: impl IntoIterator<Item = String>) -> Self {
+        Self { mask: REDACTED, ..Self::new(values) }
+    }
+
+    pub fn mask<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        match self.replace(text.as_bytes()) {
+            // Whole UTF-8 values replaced by UTF-8 keep the text valid
+            Some(replaced) => Cow::Owned(String::from_utf8_lossy(&replaced).into_owned()),
+            None => Cow::Borrowed(text),
+        }
+    }
+
+    /// Like [`Masker::mask`], for output that may not be valid UTF-8.
+    pub fn mask_bytes<'a>(&self, bytes: &'a [u8]) -> Cow<'a, [u8]> {
+        match self.replace(bytes) {
+            Some(replaced) => Cow::Owned(replaced),
+            None => Cow::Borrowed(bytes),
+        }
+    }
+
+    /// `haystack` with every secret replaced in a single left-to-right pass,
+    /// so a secret is never found inside an earlier replacement; `None` if
+    /// there is nothing to replace.
+    fn replace(&self, haystack: &[u8]) -> Option<Vec<u8>> {
+        let mut replaced = Vec::new();
+        let mut copied = 0;
+        let mut at = 0;
+        while at < haystack.len() {
+            match self.secrets.iter().find(|secret| haystack[at..].starts_with(secret.as_bytes())) {
+                Some(secret) => {
+                    replaced.extend_from_slice(&haystack[copied..at]);
+                    replaced.extend_from_slice(self.mask.as_bytes());
+                    at += secret.len();
+                    copied = at;
+                }
+                None => at += 1,
+            }
+        }
+        if copied == 0 {
+            return None;
+        }
+        replaced.extend_from_slice(&haystack[copied..]);
+        Some(replaced)
+    }
+}
+
+static MASKER: RwLock<Option<Masker>> = RwLock::new(None);
+
+/// Registers the secret values to hide from all sup-produced output.
+pub fn set_secrets(secrets: impl IntoIterator<Item = String>) {
+    let masker = Masker::new(secrets);
+    *MASKER.write().unwrap_or_else(|e| e.into_inner()) = Some(masker);
+}
+
+/// Masks registered secrets in `text`.
+pub fn mask(text: &str) -> String {
+    match MASKER.read().unwrap_or_else(|e| e.into_inner()).as_ref() {
+        Some(masker) => masker.mask(text).into_owned(),
+        None => text.to_string(),
+    }
+}
+
+/// Masks registered secrets in raw output bytes.
+pub fn mask_bytes(bytes: &[u8]) -> Vec<u8> {
+    match MASKER.read().unwrap_or_else(|e| e.into_inner()).as_ref() {
+        Some(masker) => masker.mask_bytes(bytes).into_owned(),
+        None => bytes.to_vec(),
+    }
+}
+
+static REDACTOR: RwLock<Option<Masker>> = RwLock::new(None);
+
+/// Values to redact from logged commands: the secrets, and the values of
+/// env variables whose names match one of `patterns`.
+pub fn redacted_values<'a>(
+    env: impl IntoIterator<Item = (&'a String, &'a String)>,
+    patterns: &[Regex],
+    secrets: &[String],
+) -> Vec<String> {
+    env.into_iter()
+        .filter(|(key, value)| value.len() >= MIN_REDACTED_LEN && patterns.iter().any(|pattern| pattern.is_match(key)))
+        .map(|(_, value)| value.clone())
+        .chain(secrets.iter().cloned())
+        .collect()
+}
+
+/// Registers the values to redact from commands in debug logs.
+pub fn set_redacted(values: impl IntoIterator<Item = String>) {
+    *REDACTOR.write().unwrap_or_else(|e| e.into_inner()) = Some(Masker::redacting(values));
+}
+
+/// `text` for a debug log: redacted values replaced with `[redacted]` and
+/// registered secrets masked. Only ever applied to what is logged, never to
+/// what runs.
+pub fn redact(text: &str) -> String {
+    let redacted = match REDACTOR.read().unwrap_or_else(|e| e.into_inner()).as_ref() {
+        Some(redactor) => redactor.mask(text).into_owned(),
+        None => text.to_string(),
+    };
+    mask(&redacted)
+}
+
+/// Stream of a remote command that a line of output was written to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+/// Writes raw output to the local stream of the same kind as is, so
+/// redirected output is not altered.
+pub fn write_raw(stream: OutputStream, bytes: &[u8]) {
+    let bytes = mask_bytes(bytes);
+    let result = match stream {
+        OutputStream::Stdout => {
+            let mut stdout = std::io::stdout().lock();
+            stdout.write_all(&bytes).and_then(|()| stdout.flush())
+        }
+        OutputStream::Stderr => std::io::stderr().lock().write_all(&bytes),
+    };
+    if let Err(e) = result {
+        warn!("Failed to write output: {}", e);
+    }
+}
+
+/// Default of `--max-line-length`: longer lines are cut short on the
+/// console.
+pub const DEFAULT_MAX_LINE: usize = 256 * 1024;
+
+/// Longest line kept in memory and passed to output files; the rest of a
+/// longer line is dropped as it is read.
+pub const MAX_CAPTURED_LINE: usize = 16 * 1024 * 1024;
+
+static MAX_LINE: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_LINE);
+
+/// Sets the longest line shown on the console; 0 shows lines in full.
+pub fn set_max_line_length(max: usize) {
+    MAX_LINE.store(max, Ordering::Relaxed);
+}
+
+static TRUNCATED: LazyLock<regex::bytes::Regex> =
+    LazyLock::new(|| regex::bytes::Regex::new(r"\[\.\.\. truncated (\d+) bytes\]$").unwrap());
+
+/// The largest length up to `max` that does not split a UTF-8 code point
+/// of `bytes`, judged from the bytes before the cut alone.
+fn char_boundary(bytes: &[u8], max: usize) -> usize {
+    let end = max.min(bytes.len());
+    // A code point has at most 3 continuation bytes; past those it is not
+    // UTF-8 and any cut will do
+    for start in (end.saturating_sub(3)..end).rev() {
+        let width = match bytes[start] {
+            0x80..=0xBF => continue,
+            0xC0..=0xDF => 2,
+            0xE0..=0xEF => 3,
+            0xF0..=0xF7 => 4,
+            _ => 1,
+        };
+        return if start + width > end { start } else { end };
+    }
+    end
+}
+
+/// Cuts `line` to at most `max` bytes on a code point boundary, ending it
+/// with a marker of how many bytes were left out. A line cut short as it
+/// was read counts what was dropped then too.
+pub fn truncate_line(line: &[u8], max: usize) -> Cow<'_, [u8]> {
+    let content = line.strip_suffix(b"\n").unwrap_or(line);
+    if content.len() <= max {
+        return Cow::Borrowed(line);
+    }
+    let tail = content.len().saturating_sub(48);
+    let (content, earlier) = match TRUNCATED.captures(&content[tail..]) {
+        Some(marker) => {
+            let dropped = std::str::from_utf8(&marker[1]).ok().and_then(|n| n.parse::<u64>().ok()).unwrap_or(0);
+            (&content[..tail + marker.get(0).map_or(0, |m| m.start())], dropped)
+        }
+        None => (content, 0),
+    };
+    let end = char_boundary(content, max);
+    let mut cut = content[..end].to_vec();
+    mark_truncated(&mut cut, (content.len() - end) as u64 + earlier);
+    Cow::Owned(cut)
+}
+
+/// Ends a line that had `dropped` bytes cut off with the truncation
+/// marker, moving a code point the cut split to the dropped bytes.
+pub fn mark_truncated(line: &mut Vec<u8>, dropped: u64) {
+    if line.ends_with(b"\n") {
+        line.pop();
+    }
+    let end = char_boundary(line, line.len());
+    let dropped = dropped + (line.len() - end) as u64;
+    line.truncate(end);
+    line.extend_from_slice(format!("[... truncated {} bytes]\n", dropped).as_bytes());
+}
+
+/// `line` as shown on the console, cut to `--max-line-length`.
+fn console_line(line: &[u8]) -> Cow<'_, [u8]> {
+    match MAX_LINE.load(Ordering::Relaxed) {
+        0 => Cow::Borrowed(line),
+        max => truncate_line(line, max),
+    }
+}
+
+/// Writes a line of host output to the local stream of the same kind,
+/// cut to `--max-line-length` only if that stream is a terminal, so
+/// redirected output stays verbatim.
+pub fn write_line(stream: OutputStream, line: &[u8]) {
+    let terminal = match stream {
+        OutputStream::Stdout => std::io::stdout().is_terminal(),
+        OutputStream::Stderr => std::io::stderr().is_terminal(),
+    };
+    if terminal {
+        write_raw(stream, &console_line(line));
+    } else {
+        write_raw(stream, line);
+    }
+}
+
+/// A line of output decoded for display, always ending in a newline even
+/// if the stream ended without one. Invalid UTF-8 is replaced, not dropped.
+pub fn display_line(line: &[u8]) -> String {
+    let mut text = String::from_utf8_lossy(line).into_owned();
+    if !text.ends_with('\n') {
+        text.push('\n');
+    }
+    text
+}
+
+/// A line decoded for display, in red if it came from stderr.
+fn styled_line(stream: OutputStream, line: &[u8]) -> String {
+    let text = display_line(&console_line(line));
+    match stream {
+        OutputStream::Stdout => text,
+        OutputStream::Stderr => format!("{}\n", text.trim_end_matches('\n').red()),
+    }
+}
+
+/// Prints a line of host output, prefixed with the host unless disabled.
+/// Without the prefix the bytes are passed through verbatim to the local
+/// stream matching `stream`, cut short only on a terminal.
+fn print_host_line(style: &NetworkStyle, host: &str, stream: OutputStream, line: &[u8], disable_prefix: bool) {
+    if disable_prefix {
+        write_line(stream, line);
+    } else {
+        print!("{} {}", style.paint(host, Color::Blue), mask(&styled_line(stream, line)));
+    }
+}
+
+/// How output about one network stands out: the Supfile's `color` and
+/// `banner` for it. Without them output looks as it always has.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NetworkStyle {
+    pub color: Option<Color>,
+    pub banner: Option<String>,
+}
+
+impl NetworkStyle {
+    /// `text` in the network's color, or in `default` without one.
+    pub fn paint(&self, text: &str, default: Color) -> ColoredString {
+        text.color(self.color.unwrap_or(default))
+    }
+
+    /// A line announcing a run against `network`, if it has a banner.
+    pub fn banner_line(&self, network: &str) -> Option<String> {
+        let banner = self.banner.as_ref()?;
+        let line = format!("*** {} *** network {}", banner, network);
+        Some(self.paint(&line, Color::Yellow).bold().to_string())
+    }
+
+    /// The banner to append to a heading, with a leading space, or nothing.
+    pub fn tag(&self) -> String {
+        match &self.banner {
+            Some(banner) => format!(" {}", self.paint(banner, Color::Yellow).bold()),
+            None => String::new(),
+        }
+    }
+}
+
+/// When sup-rs colors its output.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ColorChoice {
+    /// Color when stdout is a terminal and NO_COLOR is not set
+    #[default]
+    Auto,
+    /// Always color, e.g. under CI systems that render ANSI
+    Always,
+    /// Never color
+    Never,
+}
+
+impl ColorChoice {
+    fn enabled(self, no_color: bool, terminal: bool) -> bool {
+        match self {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => terminal && !no_color,
+        }
+    }
+}
+
+/// Decides once per run whether output is colored and applies it to every
+/// `colored` string. Returns the decision for other sinks such as logging.
+pub fn configure_color(choice: ColorChoice) -> bool {
+    let no_color = std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+    let enabled = choice.enabled(no_color, std::io::stdout().is_terminal());
+    colored::control::set_override(enabled);
+    enabled
+}
+
+/// How host output of a command is presented.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputMode {
+    /// Print lines as they arrive, prefixed with their host
+    #[default]
+    Stream,
+    /// Buffer each host's output and print every distinct output once,
+    /// followed by the hosts that produced it
+    GroupIdentical,
+    /// Buffer each host's output and print it as one block when the host
+    /// finishes
+    Group,
+    /// Print one OK line per successful host and the full output of failed
+    /// hosts once the command completes
+    FailuresOnly,
+}
+
+/// Output of a running host as seen by the printer.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HostEvent {
+    /// Raw bytes of one line, ending in a newline unless it was the
+    /// unterminated end of the stream.
+    Line { host: String, stream: OutputStream, line: Vec<u8> },
+    /// The host finished, with its error if it failed.
+    Done { host: String, error: Option<String>, elapsed: Duration },
+}
+
+/// Default in-memory output kept per host in `OutputMode::Group` before
+/// spilling to a temporary file.
+pub const DEFAULT_GROUP_BUFFER: usize = 1024 * 1024;
+
+/// Output of one host, kept in memory up to a cap and spilled to a
+/// temporary file beyond it.
+#[derive(Debug, Default)]
+struct HostBuffer {
+    memory: String,
+    spill: Option<(PathBuf, File)>,
+}
+
+impl HostBuffer {
+    fn push(&mut self, line: &str, cap: usize) -> std::io::Result<()> {
+        if self.spill.is_none() && self.memory.len() + line.len() > cap {
+            static SPILLS: AtomicUsize = AtomicUsize::new(0);
+            let path = std::env::temp_dir().join(format!(
+                "sup-output-{}-{}",
+                std::process::id(),
+                SPILLS.fetch_add(1, Ordering::Relaxed)
+            ));
+            let mut file = File::options().read(true).write(true).create_new(true).open(&path)?;
+            file.write_all(self.memory.as_bytes())?;
+            self.memory.clear();
+            self.spill = Some((path, file));
+        }
+        match &mut self.spill {
+            Some((_, file)) => file.write_all(line.as_bytes()),
+            None => {
+                self.memory.push_str(line);
+                Ok(())
+            }
+        }
+    }
+
+    /// Prints the buffered output.
+    fn print(&mut self) -> std::io::Result<()> {
+        print!("{}", mask(&self.memory));
+        if let Some((_, file)) = &mut self.spill {
+            file.seek(SeekFrom::Start(0))?;
+            for line in BufReader::new(&*file).lines() {
+                println!("{}", mask(&line?));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for HostBuffer {
+    fn drop(&mut self) {
+        if let Some((path, _)) = &self.spill {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Consumes the output lines of one command's hosts according to the mode.
+#[derive(Debug)]
+pub struct OutputPrinter {
+    mode: OutputMode,
+    disable_prefix: bool,
+    /// Treat stderr lines like stdout ones instead of styling them apart.
+    combine_output: bool,
+    /// Hold all output back and show it only if a host fails.
+    quiet: bool,
+    any_failed: bool,
+    buffers: IndexMap<String, String>,
+    group_buffer: usize,
+    groups: IndexMap<String, HostBuffer>,
+    failures: Vec<(String, HostBuffer, String)>,
+    style: NetworkStyle,
+    sink: ConsoleSink,
+}
+
+impl OutputPrinter {
+    pub fn new(mode: OutputMode, disable_prefix: bool, combine_output: bool, group_buffer: usize) -> Self {
+        Self {
+            mode,
+            disable_prefix,
+            combine_output,
+            quiet: false,
+            any_failed: false,
+            buffers: IndexMap::new(),
+            group_buffer,
+            groups: IndexMap::new(),
+            failures: Vec::new(),
+            style: NetworkStyle::default(),
+            sink: ConsoleSink::default(),
+        }
+    }
+
+    /// Colors host prefixes and headings like the rest of the network's
+    /// output.
+    pub fn style(mut self, style: NetworkStyle) -> Self {
+        self.style = style;
+        self
+    }
+
+    /// Tees every line and host result to the `--output-dir` and
+    /// `--output-json` sinks.
+    pub fn sink(mut self, sink: ConsoleSink) -> Self {
+        self.sink = sink;
+        self
+    }
+
+    /// Prints host lines without the host prefix.
+    pub fn disable_prefix(mut self, disable_prefix: bool) -> Self {
+        self.disable_prefix = disable_prefix;
+        self
+    }
+
+    /// Holds back output until `finish`, which prints it only if a host
+    /// failed. With `OutputMode::FailuresOnly` the OK lines are dropped too.
+    pub fn quiet(mut self, quiet: bool) -> Self {
+        self.quiet = quiet;
+        self
+    }
+
+    /// Mode lines are handled in; quiet printers buffer like `Group`.
+    fn effective_mode(&self) -> OutputMode {
+        match self.mode {
+            OutputMode::FailuresOnly => OutputMode::FailuresOnly,
+            _ if self.quiet => OutputMode::Group,
+            mode => mode,
+        }
+    }
+
+    pub fn event(&mut self, event: HostEvent) {
+        match event {
+            HostEvent::Line { host, stream, line } => self.line(&host, stream, &line),
+            HostEvent::Done { host, error, elapsed } => self.host_done(&host, error, elapsed),
+        }
+    }
+
+    pub fn line(&mut self, host: &str, stream: OutputStream, line: &[u8]) {
+        self.sink.line(host, stream, line);
+        tracing::debug!(
+            target: crate::logfile::OUTPUT_TARGET,
+            host,
+            stream = ?stream,
+            line = display_line(line).trim_end_matches('\n'),
+        );
+        let stream = if self.combine_output { OutputStream::Stdout } else { stream };
+        match self.effective_mode() {
+            OutputMode::Stream => print_host_line(&self.style, host, stream, line, self.disable_prefix),
+            OutputMode::GroupIdentical => {
+                self.buffers.entry(host.to_string()).or_default().push_str(&styled_line(stream, line));
+            }
+            OutputMode::Group | OutputMode::FailuresOnly => {
+                let buffer = self.groups.entry(host.to_string()).or_default();
+                if let Err(e) = buffer.push(&styled_line(stream, line), self.group_buffer) {
+                    // Never lose output: fall back to streaming the line
+                    warn!("Failed to buffer output of {}: {}", host, e);
+                    print_host_line(&self.style, host, stream, line, self.disable_prefix);
+                }
+            }
+        }
+    }
+
+    fn host_done(&mut self, host: &str, error: Option<String>, elapsed: Duration) {
+        self.sink.event(&HostEvent::Done { host: host.to_string(), error: error.clone(), elapsed });
+        self.any_failed |= error.is_some();
+        if self.quiet && self.mode != OutputMode::FailuresOnly {
+            // Output stays buffered until finish
+            if let Some(error) = error {
+                eprintln!("Error on host {}: {}", host, mask(&error));
+            }
+            return;
+        }
+
+        let mut buffer = self.groups.shift_remove(host).unwrap_or_default();
+        match (self.mode, error) {
+            (OutputMode::FailuresOnly, None) if self.quiet => {}
+            (OutputMode::FailuresOnly, None) => {
+                println!("{} {} ({})", self.style.paint(host, Color::Blue), "OK".green(), format_duration(elapsed));
+            }
+            (OutputMode::FailuresOnly, Some(error)) => {
+                println!("{} {} ({})", self.style.paint(host, Color::Blue), "FAILED".red(), format_duration(elapsed));
+                self.failures.push((host.to_string(), buffer, error));
+            }
+            (mode, error) => {
+                if mode == OutputMode::Group {
+                    println!("{}", self.style.paint(&format!("==== {}", host), Color::Blue));
+                    if let Err(e) = buffer.print() {
+                        warn!("Failed to print buffered output of {}: {}", host, e);
+                    }
+                }
+                if let Some(error) = error {
+                    eprintln!("Error on host {}: {}", host, mask(&error));
+                }
+            }
+        }
+    }
+
+    /// Prints whatever was buffered, or for a quiet printer whose hosts
+    /// all succeeded, nothing.
+    pub fn finish(mut self) {
+        if self.quiet && !self.any_failed {
+            return;
+        }
+        for (host, mut buffer) in std::mem::take(&mut self.groups) {
+            println!("{}", self.style.paint(&format!("==== {}", host), Color::Blue));
+            if let Err(e) = buffer.print() {
+                warn!("Failed to print buffered output of {}: {}", host, e);
+            }
+        }
+        for (host, mut buffer, error) in std::mem::take(&mut self.failures) {
+            println!("{}", format!("==== {} failed: {}", host, mask(&error)).red());
+            if let Err(e) = buffer.print() {
+                warn!("Failed to print buffered output of {}: {}", host, e);
+            }
+        }
+        for (output, hosts) in group_identical(&self.buffers) {
+            let count = if hosts.len() > 1 { format!(" ({} hosts)", hosts.len()) } else { String::new() };
+            println!("{}{}", self.style.paint(&format!("==== {}", hosts.join(", ")), Color::Blue), count);
+            print!("{}", mask(output));
+        }
+    }
+}
+
+/// Groups hosts by identical output, in order of first appearance.
+fn group_identical(buffers: &IndexMap<String, String>) -> Vec<(&str, Vec<&str>)> {
+    let mut groups: IndexMap<&str, Vec<&str>> = IndexMap::new();
+    for (host, output) in buffers {
+        groups.entry(output.as_str()).or_default().push(host.as_str());
+    }
+    groups.into_iter().collect()
+}
+
+/// Formats a duration for humans: `850ms`, `4.2s`, `1m 4s`, `2h 5m`.
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs == 0 {
+        format!("{}ms", duration.as_millis())
+    } else if secs < 60 {
+        format!("{:.1}s", duration.as_secs_f64())
+    } else if secs < 3600 {
+        format!("{}m {}s", secs / 60, secs % 60)
+    } else {
+        format!("{}h {}m", secs / 3600, secs % 3600 / 60)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mask_secrets() {
+        let masker = Masker::new(vec![
+            "hunter2".to_string(),
+            "hunter2-extended".to_string(),
+            String::new(),
+        ]);
+
+        assert_eq!(masker.mask("docker login -p hunter2"), "docker login -p *****");
+        assert_eq!(masker.mask("token=hunter2-extended;"), "token=*****;");
+        assert_eq!(masker.mask("hunter2hunter2"), "**********");
+        assert_eq!(masker.mask("nothing to hide"), "nothing to hide");
+        assert!(matches!(masker.mask("nothing to hide"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn test_mask_bytes() {
+        let masker = Masker::new(vec!["hunter2".to_string()]);
+        assert_eq!(masker.mask_bytes(b"\xffpass=hunter2\xfe hunter2"), &b"\xffpass=*****\xfe *****"[..]);
+        assert!(matches!(masker.mask_bytes(b"\xff\xfe"), Cow::Borrowed(_)));
+        assert_eq!(display_line(b"caf\xe9"), "caf\u{fffd}\n");
+    }
+
+    #[test]
+    fn test_redact() {
+        let env = [
+            ("API_TOKEN", "tok_abc123"),
+            ("DB_PASSWORD", "p@ss$(word)"),
+            ("SSH_KEY_SUFFIX", "tok_abc123-old"),
+            ("USE_TOKEN", "1"),
+            ("RELEASE", "tok_abc123-v2"),
+            ("Secret_Name", "S3cr3t"),
+        ].map(|(key, value)| (key.to_string(), value.to_string()));
+        let patterns = [Regex::new(crate::config::DEFAULT_REDACT).unwrap()];
+        let secrets = ["hunter2".to_string()];
+        let mut values = redacted_values(env.iter().map(|(key, value)| (key, value)), &patterns, &secrets);
+        values.sort();
+        // Short values and names that don't look like credentials stay
+        assert_eq!(values, ["S3cr3t", "hunter2", "p@ss$(word)", "tok_abc123", "tok_abc123-old"]);
+
+        let redactor = Masker::redacting(values);
+        // The longer value wins over the one it contains; names stay readable
+        assert_eq!(
+            redactor.mask("API_TOKEN=tok_abc123 OLD=tok_abc123-old RELEASE=tok_abc123-v2"),
+            "API_TOKEN=[redacted] OLD=[redacted] RELEASE=[redacted]-v2",
+        );
+        // Regex and shell metacharacters in values are matched literally
+        assert_eq!(
+            redactor.mask(r#"curl -u "admin:p@ss$(word)" -H 'X-Key: hunter2hunter2'"#),
+            r#"curl -u "admin:[redacted]" -H 'X-Key: [redacted][redacted]'"#,
+        );
+        assert_eq!(redactor.mask("echo $USE_TOKEN 1"), "echo $USE_TOKEN 1");
+        assert_eq!(redactor.mask("S3CR3T"), "S3CR3T");
+        // A value is never found inside the marker of an earlier one
+        let redactor = Masker::redacting(vec!["dact".to_string(), "hunter2".to_string()]);
+        assert_eq!(redactor.mask("hunter2 redacted"), "[redacted] re[redacted]ed");
+    }
+
+    #[test]
+    fn test_empty_masker_is_passthrough() {
+        let masker = Masker::default();
+        assert_eq!(masker.mask("hunter2"), "hunter2");
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::from_millis(850)), "850ms");
+        assert_eq!(format_duration(Duration::from_millis(4200)), "4.2s");
+        assert_eq!(format_duration(Duration::from_secs(64)), "1m 4s");
+        assert_eq!(format_duration(Duration::from_secs(7500)), "2h 5m");
+    }
+
+    #[test]
+    fn test_truncate_line() {
+        // At the limit a line is left alone, newline or not
+        assert_eq!(truncate_line(b"12345\n", 5), &b"12345\n"[..]);
+        assert_eq!(truncate_line(b"12345", 5), &b"12345"[..]);
+        assert_eq!(truncate_line(b"123456\n", 5), &b"12345[... truncated 1 bytes]\n"[..]);
+
+        // "€" is 3 bytes; cutting into it leaves all of it out
+        let line = "1234€5\n".as_bytes();
+        assert_eq!(truncate_line(line, 6), "1234[... truncated 4 bytes]\n".as_bytes());
+        assert_eq!(truncate_line(line, 7), "1234€[... truncated 1 bytes]\n".as_bytes());
+        let line = "ab😀".as_bytes();
+        for max in 2..=5 {
+            assert_eq!(truncate_line(line, max), "ab[... truncated 4 bytes]\n".as_bytes(), "{}", max);
+        }
+
+        // Bytes dropped when the line was read are counted too
+        let mut read = b"0123456789".to_vec();
+        mark_truncated(&mut read, 90);
+        assert_eq!(truncate_line(&read, 4), &b"0123[... truncated 96 bytes]\n"[..]);
+    }
+
+    #[test]
+    fn test_group_identical() {
+        let mut printer = OutputPrinter::new(OutputMode::GroupIdentical, false, false, DEFAULT_GROUP_BUFFER);
+        for host in ["web1", "web2", "web3"] {
+            printer.line(host, OutputStream::Stdout, b"Container Status:\n");
+        }
+        printer.line("web1", OutputStream::Stdout, b"running\n");
+        printer.line("web3", OutputStream::Stdout, b"exited");
+        printer.line("web2", OutputStream::Stdout, b"running\n");
+
+        let groups = group_identical(&printer.buffers);
+        assert_eq!(groups, [
+            ("Container Status:\nrunning\n", vec!["web1", "web2"]),
+            ("Container Status:\nexited\n", vec!["web3"]),
+        ]);
+    }
+
+    #[test]
+    fn test_stderr_styling() {
+        let mut printer = OutputPrinter::new(OutputMode::Group, false, false, DEFAULT_GROUP_BUFFER);
+        printer.line("web1", OutputStream::Stdout, b"copying\n");
+        printer.line("web1", OutputStream::Stderr, b"50%\n");
+        assert_eq!(printer.groups["web1"].memory, format!("copying\n{}\n", "50%".red()));
+
+        let mut combined = OutputPrinter::new(OutputMode::Group, false, true, DEFAULT_GROUP_BUFFER);
+        combined.line("web1", OutputStream::Stderr, b"50%\n");
+        assert_eq!(combined.groups["web1"].memory, "50%\n");
+    }
+
+    #[test]
+    fn test_color_choice() {
+        assert!(ColorChoice::Auto.enabled(false, true));
+        assert!(!ColorChoice::Auto.enabled(true, true));
+        assert!(!ColorChoice::Auto.enabled(false, false));
+        assert!(ColorChoice::Always.enabled(true, false));
+        assert!(!ColorChoice::Never.enabled(false, true));
+
+        assert!(!configure_color(ColorChoice::Never));
+        let line = styled_line(OutputStream::Stderr, b"failed\n");
+        assert_eq!(line, "failed\n");
+        assert!(!format!("{} {}", "web1".blue(), "OK".green().bold()).contains('\x1b'));
+    }
+
+    #[test]
+    fn test_network_style_without_color() {
+        colored::control::set_override(false);
+        let style = NetworkStyle { color: Some(Color::Red), banner: Some("⚠ PRODUCTION".to_string()) };
+        assert_eq!(style.banner_line("prod").as_deref(), Some("*** ⚠ PRODUCTION *** network prod"));
+        assert_eq!(style.tag(), " ⚠ PRODUCTION");
+        assert_eq!(style.paint("web1", Color::Blue).to_string(), "web1");
+
+        let plain = NetworkStyle::default();
+        assert_eq!(plain.banner_line("dev"), None);
+        assert_eq!(plain.tag(), "");
+    }
+
+    #[test]
+    fn test_quiet_holds_output_until_failure() {
+        let done = |host: &str, error: Option<&str>| HostEvent::Done {
+            host: host.to_string(),
+            error: error.map(str::to_string),
+            elapsed: Duration::from_secs(1),
+        };
+
+        let mut printer = OutputPrinter::new(OutputMode::Stream, false, false, DEFAULT_GROUP_BUFFER).quiet(true);
+        printer.line("web1", OutputStream::Stdout, b"pulled\n");
+        printer.event(done("web1", None));
+        assert_eq!(printer.groups["web1"].memory, "pulled\n");
+        assert!(!printer.any_failed);
+
+        // A failed host keeps every host's output for finish to dump
+        printer.line("web2", OutputStream::Stderr, b"no space\n");
+        printer.event(done("web2", Some("exit status: 1")));
+        assert!(printer.any_failed);
+        assert_eq!(printer.groups.keys().collect::<Vec<_>>(), ["web1", "web2"]);
+
+        // With --failures-only only failed hosts' output is kept
+        let mut printer = OutputPrinter::new(OutputMode::FailuresOnly, false, false, DEFAULT_GROUP_BUFFER).quiet(true);
+        printer.line("web1", OutputStream::Stdout, b"pulled\n");
+        printer.line("web2", OutputStream::Stdout, b"no space\n");
+        printer.event(done("web1", None));
+        printer.event(done("web2", Some("exit status: 1")));
+        assert!(printer.groups.is_empty());
+        assert_eq!(printer.failures.iter().map(|(host, _, _)| host.as_str()).collect::<Vec<_>>(), ["web2"]);
+    }
+
+    #[test]
+    fn test_host_buffer_spills_past_cap() -> std::io::Result<()> {
+        let mut buffer = HostBuffer::default();
+        buffer.push("short\n", 16)?;
+        assert!(buffer.spill.is_none());
+        buffer.push("this line passes the cap\n", 16)?;
+        buffer.push("and keeps going\n", 16)?;
+
+        let (path, file) = buffer.spill.as_mut().unwrap();
+        assert!(buffer.memory.is_empty());
+        let mut contents = String::new();
+        file.seek(SeekFrom::Start(0))?;
+        std::io::Read::read_to_string(file, &mut contents)?;
+        assert_eq!(contents, "short\nthis line passes the cap\nand keeps going\n");
+
+        let path = path.clone();
+        buffer.print()?;
+        assert!(path.exists());
+        drop(buffer);
+        assert!(!path.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_failures_only_keeps_failed_output() {
+        let mut printer = OutputPrinter::new(OutputMode::FailuresOnly, false, false, DEFAULT_GROUP_BUFFER);
+        printer.line("web1", OutputStream::Stdout, b"all good\n");
+        printer.line("web2", OutputStream::Stdout, b"disk full \xff\xfe\n");
+        printer.event(HostEvent::Done { host: "web1".to_string(), error: None, elapsed: Duration::from_secs(1) });
+        printer.event(HostEvent::Done {
+            host: "web2".to_string(),
+            error: Some("exit status: 1".to_string()),
+            elapsed: Duration::from_secs(2),
+        });
+
+        assert!(printer.groups.is_empty());
+        assert_eq!(printer.failures.len(), 1);
+        let (host, buffer, error) = &printer.failures[0];
+        assert_eq!((host.as_str(), buffer.memory.as_str(), error.as_str()), ("web2", "disk full \u{fffd}\u{fffd}\n", "exit status: 1"));
+    }
+}
@@ -0,0 +1,96 @@
+use crate::output::{self, format_duration};
+use colored::*;
+use std::time::Duration;
+
+/// Measured connect+execute times of one host for `--ping`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PingResult {
+    pub host: String,
+    /// Time of each successful probe, in order
+    pub samples: Vec<Duration>,
+    /// Why the host could not be probed; later probes are not attempted
+    pub error: Option<String>,
+}
+
+impl PingResult {
+    pub fn average(&self) -> Option<Duration> {
+        let count = u32::try_from(self.samples.len()).ok().filter(|count| *count > 0)?;
+        Some(self.samples.iter().sum::<Duration>() / count)
+    }
+
+    /// Average of the probes after the first, which reuse the ssh master
+    /// connection when multiplexing is on.
+    pub fn reused_average(&self) -> Option<Duration> {
+        let rest = self.samples.get(1..)?;
+        let count = u32::try_from(rest.len()).ok().filter(|count| *count > 0)?;
+        Some(rest.iter().sum::<Duration>() / count)
+    }
+}
+
+/// Formats the results slowest first, followed by the failed hosts. With
+/// several probes per host the first (new connection) is shown apart from
+/// the rest, which ride on the master connection when `multiplexed`.
+pub fn report(results: &[PingResult], multiplexed: bool) -> Vec<String> {
+    let mut reachable = results.iter()
+        .filter_map(|result| Some((result, result.average()?)))
+        .filter(|(result, _)| result.error.is_none())
+        .collect::<Vec<_>>();
+    reachable.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.host.cmp(&b.0.host)));
+
+    let width = results.iter().map(|result| result.host.len()).max().unwrap_or(0);
+    let mut lines = Vec::new();
+    for (result, average) in reachable {
+        let detail = match (result.samples.first(), result.reused_average()) {
+            (Some(first), Some(rest)) => {
+                let rest_label = if multiplexed { "multiplexed" } else { "reconnecting" };
+                format!("  (first {}, {} {})", format_duration(*first), rest_label, format_duration(rest))
+            }
+            _ => String::new(),
+        };
+        lines.push(format!(
+            "{} {:<width$} {:>8}{}",
+            "OK    ".green(), result.host, format_duration(average), detail, width = width,
+        ));
+    }
+    for result in results.iter().filter(|result| result.error.is_some()) {
+        let error = result.error.as_deref().unwrap_or_default();
+        lines.push(format!("{} {:<width$} {}", "FAILED".red(), result.host, output::mask(error), width = width));
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(host: &str, millis: &[u64], error: Option<&str>) -> PingResult {
+        PingResult {
+            host: host.to_string(),
+            samples: millis.iter().copied().map(Duration::from_millis).collect(),
+            error: error.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn test_report_sorts_slowest_first() {
+        colored::control::set_override(false);
+        let results = [
+            result("app@web1", &[300, 20, 40], None),
+            result("app@web7", &[900, 700, 800], None),
+            result("app@db1", &[], Some("Connection refused")),
+        ];
+        assert_eq!(results[0].average(), Some(Duration::from_millis(120)));
+        assert_eq!(results[0].reused_average(), Some(Duration::from_millis(30)));
+        assert_eq!(results[2].average(), None);
+
+        let lines = report(&results, true);
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("OK     app@web7"), "{}", lines[0]);
+        assert!(lines[1].contains("app@web1") && lines[1].contains("multiplexed"), "{}", lines[1]);
+        assert_eq!(lines[2], "FAILED app@db1  Connection refused");
+
+        // A single probe has nothing to compare
+        let lines = report(&[result("app@web1", &[250], None)], false);
+        assert!(!lines[0].contains("first"), "{}", lines[0]);
+    }
+}
@@ -0,0 +1,328 @@
+use crate::config::Command;
+use crate::output;
+use colored::*;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// How `--plan` prints the expanded target.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum PlanFormat {
+    /// Indented tree for reading in a terminal or pasting into a PR
+    #[default]
+    Human,
+    /// Machine-readable JSON
+    Json,
+}
+
+/// What one action of a step does and where.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ActionPlan {
+    /// `local`, `script`, `run`, `upload` or `template`
+    pub kind: &'static str,
+    /// Hosts the action runs on; `None` for actions run locally
+    pub hosts: Option<usize>,
+    /// Remote command runs through sudo
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub sudo: bool,
+}
+
+/// A command of the plan with the attributes it will actually run with,
+/// command-line overrides included.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StepPlan {
+    pub command: String,
+    pub network: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub desc: Option<String>,
+    /// Resolved hosts of the step's network
+    pub hosts: usize,
+    /// Users the step's ssh hosts are reached as
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub users: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub serial: Option<String>,
+    /// Host counts of the serial batches, in order
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub batches: Vec<usize>,
+    pub once: bool,
+    /// `run` goes to the first host of each batch only
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub once_per_batch: bool,
+    pub canary: bool,
+    /// Shell guards (`when`, `creates`, `removes`) that may skip hosts
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub guards: Vec<String>,
+    pub actions: Vec<ActionPlan>,
+    /// Parallel group of the target the step runs in, numbered from 1
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group: Option<usize>,
+}
+
+/// A target or command expanded into the steps a run would execute.
+#[derive(Debug, Clone, Serialize)]
+pub struct Plan {
+    pub target: String,
+    /// Description of the target, if it has one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub desc: Option<String>,
+    pub network: String,
+    /// Host counts leave out the networks' inventory commands
+    pub inventory_skipped: bool,
+    /// Hosts the Supfile leaves out with `disabled` or `excluded_hosts`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub excluded: Vec<String>,
+    pub steps: Vec<StepPlan>,
+}
+
+fn hosts(count: usize) -> String {
+    format!("{} host{}", count, if count == 1 { "" } else { "s" })
+}
+
+impl ActionPlan {
+    fn describe(&self, step: &StepPlan) -> String {
+        let location = match self.hosts {
+            None => "on this machine".to_string(),
+            Some(count) if count < step.hosts => format!("on {} of {}", count, hosts(step.hosts)),
+            Some(count) => format!("on {}", hosts(count)),
+        };
+        let sudo = if self.sudo { " (sudo)" } else { "" };
+        format!("{} {}{}", self.kind, location, sudo)
+    }
+}
+
+impl StepPlan {
+    fn details(&self) -> String {
+        let mut details = self.desc.iter().cloned().collect::<Vec<_>>();
+        if !self.users.is_empty() {
+            details.push(format!("as {}", self.users.join(", ")));
+        }
+        if let Some(serial) = &self.serial {
+            let batches = self.batches.iter().map(ToString::to_string).collect::<Vec<_>>();
+            details.push(format!("{} at a time: {}", serial, batches.join(", ")));
+        }
+        if self.once_per_batch {
+            details.push("once per batch".to_string());
+        }
+        if self.once {
+            details.push("once".to_string());
+        }
+        if self.canary {
+            details.push("canary first".to_string());
+        }
+        details.extend(self.guards.iter().cloned());
+        if details.is_empty() {
+            String::new()
+        } else {
+            format!(" ({})", details.join("; "))
+        }
+    }
+}
+
+/// A network as a run would use it, for `--print-config`.
+#[derive(Debug, Clone, Serialize)]
+pub struct NetworkConfig {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub desc: Option<String>,
+    /// Resolved hosts, after filters, ordering and `--limit`
+    pub hosts: Vec<String>,
+    /// Inventory command left unrun, whose hosts `hosts` leaves out
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub inventory_pending: Option<String>,
+    /// Where the env comes from, later sources overriding earlier ones
+    pub env_sources: Vec<String>,
+    /// Env set by sup, the Supfile, the network and the command line, and
+    /// the allow-listed variables when inheritance is off; the rest of the
+    /// inherited local environment is left out
+    pub env: BTreeMap<String, String>,
+    /// The ssh invocation hosts are reached with, up to the destination
+    pub ssh: Vec<String>,
+}
+
+/// A command with its fields after defaults and command-line overrides.
+#[derive(Debug, Clone, Serialize)]
+pub struct CommandConfig {
+    pub name: String,
+    pub network: String,
+    #[serde(flatten)]
+    pub command: Command,
+}
+
+/// The merged configuration of a run, printed as YAML by `--print-config`.
+#[derive(Debug, Clone, Serialize)]
+pub struct EffectiveConfig {
+    pub networks: Vec<NetworkConfig>,
+    pub commands: Vec<CommandConfig>,
+}
+
+impl EffectiveConfig {
+    /// The config as YAML, with secrets masked in every value.
+    pub fn to_yaml(&self) -> Result<String, serde_yaml::Error> {
+        let mut value = serde_yaml::to_value(self)?;
+        mask_strings(&mut value);
+        serde_yaml::to_string(&value)
+    }
+}
+
+/// Masks secrets in the strings of `value`, so a masked value still
+/// serializes as a quoted scalar.
+fn mask_strings(value: &mut serde_yaml::Value) {
+    match value {
+        serde_yaml::Value::String(text) => *text = output::mask(text),
+        serde_yaml::Value::Sequence(items) => items.iter_mut().for_each(mask_strings),
+        serde_yaml::Value::Mapping(map) => map.iter_mut().for_each(|(_, item)| mask_strings(item)),
+        _ => {}
+    }
+}
+
+impl StepPlan {
+    /// The step and its actions as tree lines, under `branch` and `indent`.
+    fn render(&self, branch: &str, indent: &str, lines: &mut Vec<String>) {
+        lines.push(format!("{}{} [{}]{}", branch, self.command.bold(), self.network, self.details()));
+        for (index, action) in self.actions.iter().enumerate() {
+            let branch = if index + 1 == self.actions.len() { "└── " } else { "├── " };
+            lines.push(format!("{}{}{}", indent, branch, action.describe(self)));
+        }
+    }
+}
+
+impl Plan {
+    /// The plan as an indented tree: target, steps, then their actions.
+    /// The steps of a parallel group hang off a `parallel` node.
+    pub fn render(&self) -> String {
+        let desc = self.desc.as_ref().map(|desc| format!(" ({})", desc)).unwrap_or_default();
+        let mut lines = vec![format!("{} [{}]{}", self.target.bold(), self.network, desc)];
+        let stages = self.steps.chunk_by(|a, b| a.group.is_some() && a.group == b.group).collect::<Vec<_>>();
+        for (index, stage) in stages.iter().enumerate() {
+            let (branch, indent) = if index + 1 == stages.len() { ("└── ", "    ") } else { ("├── ", "│   ") };
+            match stage {
+                [step] if step.group.is_none() => step.render(branch, indent, &mut lines),
+                steps => {
+                    lines.push(format!("{}{}", branch, "parallel".dimmed()));
+                    for (index, step) in steps.iter().enumerate() {
+                        let (branch, inner) = if index + 1 == steps.len() { ("└── ", "    ") } else { ("├── ", "│   ") };
+                        step.render(&format!("{}{}", indent, branch), &format!("{}{}", indent, inner), &mut lines);
+                    }
+                }
+            }
+        }
+        if !self.excluded.is_empty() {
+            lines.push(format!("(excluded (supfile): {})", self.excluded.join(", ")).dimmed().to_string());
+        }
+        if self.inventory_skipped {
+            lines.push("(inventory skipped; host counts cover static hosts only)".dimmed().to_string());
+        }
+        lines.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_tree() {
+        colored::control::set_override(false);
+        let step = |command: &str, network: &str, actions: Vec<ActionPlan>| StepPlan {
+            command: command.to_string(),
+            network: network.to_string(),
+            desc: None,
+            hosts: 8,
+            users: Vec::new(),
+            serial: None,
+            batches: Vec::new(),
+            once: false,
+            once_per_batch: false,
+            canary: false,
+            guards: Vec::new(),
+            actions,
+            group: None,
+        };
+        let plan = Plan {
+            target: "deploy".to_string(),
+            desc: Some("Build and roll out".to_string()),
+            network: "prod".to_string(),
+            inventory_skipped: false,
+            excluded: vec!["deploy@web9".to_string()],
+            steps: vec![
+                step("build", "local", vec![ActionPlan { kind: "local", hosts: None, sudo: false }]),
+                StepPlan {
+                    serial: Some("25%".to_string()),
+                    users: vec!["deploy".to_string()],
+                    batches: vec![2, 2, 2, 2],
+                    guards: vec!["when: test -f /etc/app".to_string()],
+                    ..step("rolling-update", "prod", vec![
+                        ActionPlan { kind: "run", hosts: Some(8), sudo: true },
+                        ActionPlan { kind: "upload", hosts: Some(8), sudo: false },
+                    ])
+                },
+                StepPlan {
+                    once: true,
+                    ..step("migrate", "prod", vec![ActionPlan { kind: "run", hosts: Some(1), sudo: false }])
+                },
+            ],
+        };
+
+        assert_eq!(plan.render(), "\
+deploy [prod] (Build and roll out)
+├── build [local]
+│   └── local on this machine
+├── rolling-update [prod] (as deploy; 25% at a time: 2, 2, 2, 2; when: test -f /etc/app)
+│   ├── run on 8 hosts (sudo)
+│   └── upload on 8 hosts
+└── migrate [prod] (once)
+    └── run on 1 of 8 hosts
+(excluded (supfile): deploy@web9)");
+
+        let json = serde_json::to_value(&plan).unwrap();
+        assert_eq!(json["steps"][1]["batches"], serde_json::json!([2, 2, 2, 2]));
+        assert_eq!(json["steps"][1]["actions"][0]["sudo"], true);
+        assert!(json["steps"][0].get("serial").is_none());
+        assert_eq!(json["desc"], "Build and roll out");
+        assert_eq!(json["excluded"], serde_json::json!(["deploy@web9"]));
+    }
+
+    #[test]
+    fn test_render_parallel_group() {
+        colored::control::set_override(false);
+        let step = |command: &str, group: Option<usize>| StepPlan {
+            command: command.to_string(),
+            network: "prod".to_string(),
+            desc: None,
+            hosts: 2,
+            users: Vec::new(),
+            serial: None,
+            batches: Vec::new(),
+            once: false,
+            once_per_batch: false,
+            canary: false,
+            guards: Vec::new(),
+            actions: vec![ActionPlan { kind: "run", hosts: Some(2), sudo: false }],
+            group,
+        };
+        let plan = Plan {
+            target: "deploy".to_string(),
+            desc: None,
+            network: "prod".to_string(),
+            inventory_skipped: false,
+            excluded: Vec::new(),
+            steps: vec![step("build", None), step("push", Some(1)), step("upload-config", Some(1)), step("restart", None)],
+        };
+
+        assert_eq!(plan.render(), "\
+deploy [prod]
+├── build [prod]
+│   └── run on 2 hosts
+├── parallel
+│   ├── push [prod]
+│   │   └── run on 2 hosts
+│   └── upload-config [prod]
+│       └── run on 2 hosts
+└── restart [prod]
+    └── run on 2 hosts");
+
+        let json = serde_json::to_value(&plan).unwrap();
+        assert_eq!(json["steps"][2]["group"], 1);
+        assert!(json["steps"][3].get("group").is_none());
+    }
+}
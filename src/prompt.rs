@@ -0,0 +1,157 @@
+use crate::exit::ExitCode;
+use crate::output::NetworkStyle;
+use anyhow::{Context, Result};
+use colored::*;
+use std::io::{BufRead, IsTerminal, Write};
+
+/// Asks the user to confirm a run against a protected network by typing
+/// its name (or `yes`). Non-interactive runs must pass `--yes` instead.
+pub fn confirm_network(
+    network: &str,
+    style: &NetworkStyle,
+    desc: Option<&str>,
+    hosts: &[String],
+    commands: &[&str],
+    assume_yes: bool,
+) -> Result<()> {
+    if assume_yes {
+        return Ok(());
+    }
+
+    let stdin = std::io::stdin();
+    if !stdin.is_terminal() {
+        anyhow::bail!(
+            "Network {} requires confirmation but stdin is not a terminal; pass --yes to proceed",
+            network
+        );
+    }
+
+    if let Some(banner) = style.banner_line(network) {
+        println!("{}", banner);
+    }
+    let label = style.paint("Network:", Color::Yellow).bold();
+    match desc {
+        Some(desc) => println!("{} {} ({})", label, network.bold(), desc),
+        None => println!("{} {}", label, network.bold()),
+    }
+    println!("{} ({})", "Hosts:".yellow().bold(), hosts.len());
+    for host in hosts {
+        println!("  {}", host);
+    }
+    println!("{} {}", "Commands:".yellow().bold(), commands.join(", "));
+    print!("Type the network name or 'yes' to continue: ");
+    std::io::stdout().flush()?;
+
+    let mut answer = String::new();
+    stdin.lock().read_line(&mut answer)
+        .context("Failed to read confirmation")?;
+
+    if !is_confirmed(&answer, network) {
+        anyhow::bail!(ExitCode::Aborted.error(format!("Aborted: confirmation for network {} not given", network)));
+    }
+    Ok(())
+}
+
+/// Asks a yes/no question, defaulting to no. `assume_yes` answers yes
+/// without asking; a non-interactive stdin answers no.
+pub fn confirm_continue(question: &str, assume_yes: bool) -> Result<bool> {
+    if assume_yes {
+        return Ok(true);
+    }
+
+    let stdin = std::io::stdin();
+    if !stdin.is_terminal() {
+        println!("{}no (stdin is not a terminal)", question);
+        return Ok(false);
+    }
+
+    print!("{}", question);
+    std::io::stdout().flush()?;
+
+    let mut answer = String::new();
+    stdin.lock().read_line(&mut answer)
+        .context("Failed to read answer")?;
+    Ok(is_yes(&answer))
+}
+
+/// Asks the user to confirm the hosts a picked command is about to run on.
+pub fn confirm_hosts(command: &str, network: &str, hosts: &[String], assume_yes: bool) -> Result<()> {
+    if !assume_yes {
+        println!("{} {}", "Hosts:".yellow().bold(), hosts.len());
+        for host in hosts {
+            println!("  {}", host);
+        }
+    }
+    let question = format!("Run {} on {} [{}]? [y/N] ", command, network, hosts.len());
+    if !confirm_continue(&question, assume_yes)? {
+        anyhow::bail!(ExitCode::Aborted.error(format!("Aborted: {} not confirmed", command)));
+    }
+    Ok(())
+}
+
+/// Reads a password from the terminal without echoing it.
+pub fn password(prompt: &str) -> Result<String> {
+    if !std::io::stdin().is_terminal() {
+        anyhow::bail!(ExitCode::Usage.error("--ask-pass needs a terminal to read the password from"));
+    }
+    dialoguer::Password::new()
+        .with_prompt(prompt)
+        .interact()
+        .context("Failed to read password")
+}
+
+/// Lets the user choose one of `items` (name and description) from a list
+/// filtered as they type. Returns the chosen name, or `None` if cancelled.
+pub fn pick(prompt: &str, items: &[(String, String)]) -> Result<Option<String>> {
+    let width = items.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+    let labels = items.iter()
+        .map(|(name, desc)| format!("{:<width$}  {}", name, desc.dimmed(), width = width))
+        .collect::<Vec<_>>();
+    let choice = dialoguer::FuzzySelect::new()
+        .with_prompt(prompt)
+        .items(&labels)
+        .default(0)
+        .interact_opt()
+        .context("Failed to read selection")?;
+    Ok(choice.map(|index| items[index].0.clone()))
+}
+
+fn is_yes(answer: &str) -> bool {
+    let answer = answer.trim();
+    answer.eq_ignore_ascii_case("y") || answer.eq_ignore_ascii_case("yes")
+}
+
+fn is_confirmed(answer: &str, network: &str) -> bool {
+    let answer = answer.trim();
+    answer == network || answer.eq_ignore_ascii_case("yes")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_confirmed() {
+        assert!(is_confirmed("prod\n", "prod"));
+        assert!(is_confirmed("  yes ", "prod"));
+        assert!(is_confirmed("YES", "prod"));
+        assert!(!is_confirmed("y", "prod"));
+        assert!(!is_confirmed("dev", "prod"));
+        assert!(!is_confirmed("", "prod"));
+    }
+
+    #[test]
+    fn test_is_yes() {
+        assert!(is_yes("y\n"));
+        assert!(is_yes(" YES "));
+        assert!(!is_yes(""));
+        assert!(!is_yes("n"));
+        assert!(!is_yes("sure"));
+    }
+
+    #[test]
+    fn test_assume_yes_skips_prompt() {
+        assert!(confirm_network("prod", &NetworkStyle::default(), None, &[], &["deploy"], true).is_ok());
+        assert!(confirm_continue("continue? [y/N] ", true).unwrap());
+    }
+}
@@ -0,0 +1,171 @@
+use crate::config::{Command, Serial};
+use crate::executor::Executor;
+use crate::output;
+use anyhow::{Context, Result};
+use colored::*;
+use indexmap::IndexMap;
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use std::path::PathBuf;
+
+/// Name under which REPL commands are reported.
+pub const REPL_COMMAND: &str = "(repl)";
+
+const HELP: &str = "\
+Lines are run as shell commands on every host. Meta-commands:
+  :hosts           list the hosts commands run on
+  :only [REGEX]    run only on matching hosts; without REGEX, on all hosts
+  :serial [N]      run on N hosts (or N%) at a time; without N, all at once
+  :env KEY=VALUE   set an env variable for later commands
+  :help            show this help
+  :quit            leave (also Ctrl-D)";
+
+/// One line of REPL input.
+#[derive(Debug, PartialEq)]
+enum Input {
+    Empty,
+    Run(String),
+    Hosts,
+    Only(Option<String>),
+    Serial(Option<Serial>),
+    Env(String, String),
+    Help,
+    Quit,
+}
+
+fn parse(line: &str) -> Result<Input> {
+    let line = line.trim();
+    let Some(meta) = line.strip_prefix(':') else {
+        return Ok(if line.is_empty() { Input::Empty } else { Input::Run(line.to_string()) });
+    };
+
+    let (name, arg) = meta.split_once(char::is_whitespace).unwrap_or((meta, ""));
+    let arg = Some(arg.trim()).filter(|arg| !arg.is_empty());
+    Ok(match (name, arg) {
+        ("hosts", None) => Input::Hosts,
+        ("only", regex) => Input::Only(regex.map(str::to_string)),
+        ("serial", serial) => Input::Serial(serial.map(str::parse).transpose().map_err(anyhow::Error::msg)?),
+        ("env", Some(var)) => {
+            let (key, value) = var.split_once('=')
+                .context("Usage: :env KEY=VALUE")?;
+            Input::Env(key.trim().to_string(), value.to_string())
+        }
+        ("help", None) => Input::Help,
+        ("quit" | "q" | "exit", None) => Input::Quit,
+        _ => anyhow::bail!("Unknown meta-command :{}; type :help for the list", meta),
+    })
+}
+
+/// `cmd` prefixed with `export KEY=VALUE;` for each variable set by `:env`.
+fn with_exports(exports: &IndexMap<String, String>, cmd: &str) -> String {
+    let exports = exports.iter()
+        .map(|(key, value)| format!("export {}={}; ", key, shell_quote::sh::quote(value).to_string_lossy()))
+        .collect::<String>();
+    exports + cmd
+}
+
+fn history_path() -> Option<PathBuf> {
+    dirs::home_dir().map(|home| home.join(".sup_repl_history"))
+}
+
+/// Reads lines until `:quit` or Ctrl-D and runs each on the network's hosts
+/// through `executor`, whose ssh master connections are reused throughout.
+/// A failed or interrupted command only ends that command.
+pub async fn run(executor: &mut Executor, network_name: &str) -> Result<()> {
+    let mut editor = DefaultEditor::new()?;
+    let history = history_path();
+    if let Some(path) = &history {
+        // Missing on first use
+        let _ = editor.load_history(path);
+    }
+    println!("Connected to network {}; type :help for meta-commands", network_name.bold());
+
+    let prompt = format!("sup {}> ", network_name);
+    // Remote shells only see these through exports prefixed to each line
+    let mut exports = IndexMap::new();
+    loop {
+        let line = match tokio::task::block_in_place(|| editor.readline(&prompt)) {
+            Ok(line) => line,
+            // Ctrl-C discards the line being typed
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e.into()),
+        };
+        if !line.trim().is_empty() {
+            editor.add_history_entry(line.as_str())?;
+        }
+
+        let result = match parse(&line) {
+            Ok(Input::Empty) => Ok(()),
+            Ok(Input::Run(cmd)) => {
+                let command = Command { run: Some(with_exports(&exports, &cmd)), ..Default::default() };
+                tokio::select! {
+                    result = executor.execute_command(REPL_COMMAND, &command) => result,
+                    _ = tokio::signal::ctrl_c() => Err(anyhow::anyhow!("Interrupted")),
+                }
+            }
+            Ok(Input::Hosts) => executor.resolve_hosts().await.map(|hosts| {
+                for host in hosts {
+                    println!("  {}", host);
+                }
+            }),
+            Ok(Input::Only(only)) => executor.reconfigure(|options, _| options.only = only),
+            Ok(Input::Serial(serial)) => executor.reconfigure(|options, _| options.serial = serial),
+            Ok(Input::Env(key, value)) => {
+                exports.insert(key.clone(), value.clone());
+                executor.reconfigure(|_, env| {
+                    env.insert(key, value);
+                })
+            }
+            Ok(Input::Help) => {
+                println!("{}", HELP);
+                Ok(())
+            }
+            Ok(Input::Quit) => break,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            eprintln!("{} {}", "Error:".red(), output::mask(&format!("{:#}", e)));
+        }
+    }
+
+    if let Some(path) = &history {
+        if let Err(e) = editor.save_history(path) {
+            tracing::warn!("Failed to save REPL history to {}: {}", path.display(), e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        assert_eq!(parse("  ")?, Input::Empty);
+        assert_eq!(parse(" uptime -p ")?, Input::Run("uptime -p".to_string()));
+        assert_eq!(parse(":hosts")?, Input::Hosts);
+        assert_eq!(parse(":only web[12]")?, Input::Only(Some("web[12]".to_string())));
+        assert_eq!(parse(":only")?, Input::Only(None));
+        assert_eq!(parse(":serial 25%")?, Input::Serial(Some(Serial::Percent(25))));
+        assert_eq!(parse(":serial")?, Input::Serial(None));
+        assert_eq!(parse(":env RELEASE=v1=2")?, Input::Env("RELEASE".to_string(), "v1=2".to_string()));
+        assert_eq!(parse(":q")?, Input::Quit);
+
+        assert!(parse(":serial many").is_err());
+        assert!(parse(":env RELEASE").is_err());
+        assert!(parse(":hosts web1").is_err());
+        assert!(parse(":reboot").unwrap_err().to_string().contains(":help"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_with_exports() {
+        let mut exports = IndexMap::new();
+        assert_eq!(with_exports(&exports, "uptime"), "uptime");
+        exports.insert("RELEASE".to_string(), "v2".to_string());
+        exports.insert("NOTE".to_string(), "it's".to_string());
+        assert_eq!(with_exports(&exports, "echo $RELEASE"), "export RELEASE='v2'; export NOTE='it'\\''s'; echo $RELEASE");
+    }
+}
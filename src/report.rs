@@ -0,0 +1,299 @@
+use crate::summary::{CommandTiming, HostResult, HostStatus, Summary, LOCAL_HOST};
+use anyhow::{Context, Result};
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Bumped whenever the report layout changes incompatibly.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Structured record of a run, written by `--report`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Report {
+    pub schema_version: u32,
+    pub sup_version: String,
+    /// Matches the run's `--log-file` records and history entry
+    #[serde(default)]
+    pub run_id: String,
+    pub supfile: String,
+    pub network: String,
+    pub hosts: Vec<String>,
+    /// Network each host came from, for runs on several networks
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub host_networks: BTreeMap<String, String>,
+    pub success: bool,
+    pub error: Option<String>,
+    pub commands: Vec<CommandReport>,
+    /// Hosts the Supfile left out with `disabled` or `excluded_hosts`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub excluded: Vec<String>,
+    /// Fingerprints of the keys ssh hosts presented, by `host[:port]`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub host_keys: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CommandReport {
+    pub name: String,
+    /// Wall-clock time of the whole command, all batches included
+    #[serde(default)]
+    pub duration_ms: Option<u64>,
+    pub hosts: Vec<HostReport>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportStatus {
+    Success,
+    Failed,
+    Skipped,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HostReport {
+    pub host: String,
+    pub status: ReportStatus,
+    pub exit_code: Option<i32>,
+    /// RFC 3339 timestamps
+    pub started: Option<String>,
+    pub finished: Option<String>,
+    #[serde(default)]
+    pub duration_ms: Option<u64>,
+    pub bytes_uploaded: Option<u64>,
+    pub skipped_reason: Option<String>,
+    pub error: Option<String>,
+}
+
+impl From<&HostResult> for HostReport {
+    fn from(result: &HostResult) -> Self {
+        let (status, skipped_reason, error) = match &result.status {
+            HostStatus::Success => (ReportStatus::Success, None, None),
+            HostStatus::Failed(error) => (ReportStatus::Failed, None, Some(error.clone())),
+            HostStatus::Skipped(reason) => (ReportStatus::Skipped, Some(reason.clone()), None),
+        };
+        Self {
+            host: result.host.clone(),
+            status,
+            exit_code: result.exit_code,
+            started: result.started.map(|time| time.to_rfc3339()),
+            finished: result.finished.map(|time| time.to_rfc3339()),
+            duration_ms: result.elapsed.map(|elapsed| elapsed.as_millis() as u64),
+            bytes_uploaded: result.bytes_uploaded,
+            skipped_reason,
+            error,
+        }
+    }
+}
+
+impl Report {
+    pub fn new(supfile: &Path, network: &str, run_id: &str) -> Self {
+        Self {
+            schema_version: SCHEMA_VERSION,
+            sup_version: env!("CARGO_PKG_VERSION").to_string(),
+            run_id: run_id.to_string(),
+            supfile: supfile.display().to_string(),
+            network: network.to_string(),
+            hosts: Vec::new(),
+            host_networks: BTreeMap::new(),
+            success: false,
+            error: None,
+            commands: Vec::new(),
+            excluded: Vec::new(),
+            host_keys: BTreeMap::new(),
+        }
+    }
+
+    /// Groups per-host results by command, in the order commands ran, and
+    /// orders each command's hosts as in `hosts` rather than by which
+    /// finished first, so reruns produce the same report.
+    pub fn set_results(&mut self, results: &[HostResult]) {
+        let mut commands: IndexMap<&str, Vec<HostReport>> = IndexMap::new();
+        for result in results {
+            commands.entry(result.command.as_str()).or_default().push(result.into());
+        }
+        for hosts in commands.values_mut() {
+            hosts.sort_by_key(|host| self.hosts.iter().position(|resolved| *resolved == host.host).unwrap_or(usize::MAX));
+        }
+        self.commands = commands.into_iter()
+            .map(|(name, hosts)| CommandReport { name: name.to_string(), duration_ms: None, hosts })
+            .collect();
+    }
+
+    /// Adds each command's wall-clock time to its results.
+    pub fn set_timings(&mut self, timings: &[CommandTiming]) {
+        for timing in timings {
+            if let Some(command) = self.commands.iter_mut().find(|command| command.name == timing.command) {
+                command.duration_ms = Some(timing.elapsed.as_millis() as u64);
+            }
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let json = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read report {}", path.display()))?;
+        let value: serde_json::Value = serde_json::from_str(&json)
+            .with_context(|| format!("Failed to parse report {}", path.display()))?;
+        // Check the version first so older layouts get a clear error
+        let version = value["schema_version"].as_u64();
+        if version != Some(SCHEMA_VERSION.into()) {
+            anyhow::bail!(
+                "Report {} has schema version {}, expected {}",
+                path.display(),
+                version.map_or("none".to_string(), |v| v.to_string()),
+                SCHEMA_VERSION
+            );
+        }
+        serde_json::from_value(value)
+            .with_context(|| format!("Failed to parse report {}", path.display()))
+    }
+
+    /// Remote hosts that failed or were skipped by any command, in the
+    /// order they first appear.
+    pub fn failed_hosts(&self) -> Vec<String> {
+        let mut hosts: Vec<String> = Vec::new();
+        let failed = self.commands.iter()
+            .flat_map(|command| &command.hosts)
+            .filter(|host| host.status != ReportStatus::Success && host.host != LOCAL_HOST);
+        for host in failed {
+            if !hosts.contains(&host.host) {
+                hosts.push(host.host.clone());
+            }
+        }
+        hosts
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        std::fs::write(path, json + "\n")
+            .with_context(|| format!("Failed to write report {}", path.display()))
+    }
+}
+
+/// Writes the report when dropped, so a run that fails or is interrupted
+/// still leaves a report of what happened so far.
+#[derive(Debug)]
+pub struct ReportWriter {
+    path: PathBuf,
+    report: Report,
+    summary: Summary,
+}
+
+impl ReportWriter {
+    pub fn new(path: PathBuf, report: Report, summary: Summary) -> Self {
+        Self { path, report, summary }
+    }
+
+    pub fn set_hosts(&mut self, hosts: Vec<String>) {
+        self.report.hosts = hosts;
+    }
+
+    pub fn set_host_keys(&mut self, host_keys: BTreeMap<String, String>) {
+        self.report.host_keys = host_keys;
+    }
+
+    /// Records the outcome of the run and writes the report.
+    pub fn finish(mut self, result: &Result<()>) {
+        self.report.success = result.is_ok();
+        self.report.error = result.as_ref().err().map(|e| crate::output::mask(&e.to_string()));
+    }
+}
+
+impl Drop for ReportWriter {
+    fn drop(&mut self) {
+        if !self.report.success && self.report.error.is_none() {
+            self.report.error = Some("run did not complete".to_string());
+        }
+        self.report.set_results(&self.summary.results());
+        self.report.host_networks = self.summary.networks();
+        self.report.excluded = self.summary.excluded();
+        self.report.set_timings(&self.summary.timings());
+        if let Err(e) = self.report.write(&self.path) {
+            eprintln!("{:#}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Local;
+    use std::time::Duration;
+
+    #[test]
+    fn test_report_groups_results_by_command() -> Result<()> {
+        let summary = Summary::default();
+        summary.push(HostResult {
+            exit_code: Some(0),
+            started: Some(Local::now()),
+            finished: Some(Local::now()),
+            elapsed: Some(Duration::from_millis(850)),
+            bytes_uploaded: Some(2048),
+            ..HostResult::new("upload", "web1", HostStatus::Success)
+        });
+        // Hosts are reported in inventory order, whichever finished first
+        summary.record("restart", "web2", HostStatus::Skipped("when failed".to_string()));
+        summary.push(HostResult {
+            exit_code: Some(2),
+            ..HostResult::new("restart", "web1", HostStatus::Failed("exit code 2".to_string()))
+        });
+
+        summary.record_timing("upload", Duration::from_secs(64));
+
+        let mut report = Report::new(Path::new("Supfile.yml"), "prod", "run-1");
+        report.hosts = vec!["web1".to_string(), "web2".to_string()];
+        report.set_results(&summary.results());
+        report.set_timings(&summary.timings());
+
+        let names = report.commands.iter().map(|command| command.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["upload", "restart"]);
+        assert_eq!(report.commands[0].hosts[0].bytes_uploaded, Some(2048));
+        assert_eq!(report.commands[0].hosts[0].duration_ms, Some(850));
+        assert_eq!((report.commands[0].duration_ms, report.commands[1].duration_ms), (Some(64_000), None));
+        let restart = &report.commands[1].hosts;
+        assert_eq!(restart[0].status, ReportStatus::Failed);
+        assert_eq!(restart[0].exit_code, Some(2));
+        assert_eq!(restart[1].skipped_reason.as_deref(), Some("when failed"));
+
+        let json: serde_json::Value = serde_json::to_value(&report)?;
+        assert_eq!(json["schema_version"], SCHEMA_VERSION);
+        assert_eq!(json["run_id"], "run-1");
+        assert_eq!(json["commands"][1]["hosts"][1]["status"], "skipped");
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_hosts() {
+        let summary = Summary::default();
+        summary.record("preflight", "web3", HostStatus::Skipped("unreachable".to_string()));
+        summary.record("build", LOCAL_HOST, HostStatus::Failed("exit code 1".to_string()));
+        summary.record("deploy", "web1", HostStatus::Success);
+        summary.record("deploy", "web2", HostStatus::Failed("exit code 1".to_string()));
+        summary.record("restart", "web2", HostStatus::Skipped("not run (canary aborted)".to_string()));
+
+        let mut report = Report::new(Path::new("Supfile.yml"), "prod", "run-1");
+        report.set_results(&summary.results());
+        assert_eq!(report.failed_hosts(), ["web3", "web2"]);
+    }
+
+    #[test]
+    fn test_writer_writes_on_drop() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("sup-report-{}.json", std::process::id()));
+        let summary = Summary::default();
+        summary.record("deploy", "web1", HostStatus::Success);
+
+        drop(ReportWriter::new(path.clone(), Report::new(Path::new("Supfile.yml"), "prod", "run-1"), summary));
+
+        let report: Report = serde_json::from_str(&std::fs::read_to_string(&path)?)?;
+        std::fs::remove_file(&path)?;
+        assert!(!report.success);
+        assert_eq!(report.error.as_deref(), Some("run did not complete"));
+        assert_eq!(report.commands[0].hosts[0].host, "web1");
+
+        std::fs::write(&path, r#"{"schema_version": 99}"#)?;
+        let err = Report::load(&path).unwrap_err().to_string();
+        std::fs::remove_file(&path)?;
+        assert!(err.contains("schema version 99"), "{}", err);
+        Ok(())
+    }
+}
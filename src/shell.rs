@@ -0,0 +1,79 @@
+use std::process::Command as ProcessCommand;
+
+/// Program and flag that run a command line through the local shell:
+/// `sh -c` on Unix, `cmd /C` on Windows.
+fn invocation(windows: bool) -> (&'static str, &'static str) {
+    if windows {
+        ("cmd", "/C")
+    } else {
+        ("sh", "-c")
+    }
+}
+
+fn build(windows: bool, cmd: &str) -> ProcessCommand {
+    let (program, flag) = invocation(windows);
+    let mut shell = ProcessCommand::new(program);
+    shell.arg(flag).arg(cmd);
+    shell
+}
+
+fn build_script(windows: bool, path: &str) -> ProcessCommand {
+    if windows {
+        build(true, path)
+    } else {
+        let mut shell = ProcessCommand::new("sh");
+        shell.arg(path);
+        shell
+    }
+}
+
+/// Runs `cmd` through the local shell.
+pub fn command(cmd: &str) -> ProcessCommand {
+    build(cfg!(windows), cmd)
+}
+
+/// Runs the script file at `path` with the local shell.
+pub fn script(path: &str) -> ProcessCommand {
+    build_script(cfg!(windows), path)
+}
+
+/// `word` as a POSIX shell word, quoted only when it has to be, for
+/// command lines shown to users.
+pub fn word(word: &str) -> String {
+    let plain = !word.is_empty() && word.chars().all(|c| c.is_ascii_alphanumeric() || "@%+=:,./_-".contains(c));
+    if plain {
+        word.to_string()
+    } else {
+        shell_quote::sh::quote(word).to_string_lossy().into_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(cmd: &ProcessCommand) -> Vec<String> {
+        cmd.get_args().map(|arg| arg.to_string_lossy().into_owned()).collect()
+    }
+
+    #[test]
+    fn test_word() {
+        assert_eq!(word("deploy@web1:2222"), "deploy@web1:2222");
+        assert_eq!(word("tail -f app.log"), "'tail -f app.log'");
+        assert_eq!(word(""), "''");
+    }
+
+    #[test]
+    fn test_invocation_per_platform() {
+        let unix = build(false, "echo hi && ls");
+        assert_eq!(unix.get_program(), "sh");
+        assert_eq!(args(&unix), ["-c", "echo hi && ls"]);
+        assert_eq!(args(&build_script(false, "./deploy.sh")), ["./deploy.sh"]);
+
+        let windows = build(true, "echo hi && dir");
+        assert_eq!(windows.get_program(), "cmd");
+        assert_eq!(args(&windows), ["/C", "echo hi && dir"]);
+        let script = build_script(true, r"scripts\deploy.bat");
+        assert_eq!((script.get_program().to_str(), args(&script)), (Some("cmd"), vec!["/C".to_string(), r"scripts\deploy.bat".to_string()]));
+    }
+}
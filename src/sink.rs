@@ -0,0 +1,229 @@
+use crate::clock;
+use crate::output::{self, HostEvent, OutputStream};
+use anyhow::{Context, Result};
+use chrono::SecondsFormat;
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, LazyLock};
+use std::thread::JoinHandle;
+use tracing::warn;
+
+/// A destination host output is copied to besides the console, each with
+/// its own format.
+pub trait Sink: Send {
+    fn event(&mut self, event: &HostEvent) -> std::io::Result<()>;
+}
+
+/// The side of the sinks printers hold: every host event printed on the
+/// console is sent to a writer thread, so async tasks never wait on the
+/// sinks' files. Without sinks it drops everything.
+#[derive(Debug, Clone, Default)]
+pub struct ConsoleSink {
+    tx: Option<mpsc::Sender<Option<HostEvent>>>,
+}
+
+impl ConsoleSink {
+    /// Starts the thread writing to `sinks`. Events are written until the
+    /// returned writer is dropped.
+    pub fn spawn(mut sinks: Vec<Box<dyn Sink>>) -> (Self, SinkWriter) {
+        if sinks.is_empty() {
+            return (Self::default(), SinkWriter::default());
+        }
+        let (tx, rx) = mpsc::channel::<Option<HostEvent>>();
+        let thread = std::thread::spawn(move || {
+            // `None` is the writer's request to stop
+            while let Ok(Some(event)) = rx.recv() {
+                for sink in &mut sinks {
+                    // A sink that fails to write is reported and the others
+                    // still get the event
+                    if let Err(e) = sink.event(&event) {
+                        warn!("Failed to write host output: {}", e);
+                    }
+                }
+            }
+        });
+        (Self { tx: Some(tx.clone()) }, SinkWriter { stop: Some((tx, thread)) })
+    }
+
+    pub fn event(&self, event: &HostEvent) {
+        if let Some(tx) = &self.tx {
+            let _ = tx.send(Some(event.clone()));
+        }
+    }
+
+    pub fn line(&self, host: &str, stream: OutputStream, line: &[u8]) {
+        if self.tx.is_some() {
+            self.event(&HostEvent::Line { host: host.to_string(), stream, line: line.to_vec() });
+        }
+    }
+}
+
+/// Owns the sinks' writer thread; dropping it writes what is still queued
+/// and stops the thread. Later events are dropped.
+#[derive(Default)]
+pub struct SinkWriter {
+    stop: Option<(mpsc::Sender<Option<HostEvent>>, JoinHandle<()>)>,
+}
+
+impl Drop for SinkWriter {
+    fn drop(&mut self) {
+        if let Some((tx, thread)) = self.stop.take() {
+            let _ = tx.send(None);
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Escape sequences of terminal colors and cursor movement.
+static ANSI: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\x1b\[[0-9;?]*[ -/]*[@-~]").unwrap());
+
+/// `line` as plain text: decoded, masked and without terminal escapes or
+/// the trailing newline.
+fn plain(line: &[u8]) -> String {
+    let text = output::mask(&output::display_line(line));
+    ANSI.replace_all(text.trim_end_matches('\n'), "").into_owned()
+}
+
+fn timestamp() -> String {
+    clock::now().to_rfc3339_opts(SecondsFormat::Millis, false)
+}
+
+fn stream_name(stream: OutputStream) -> &'static str {
+    match stream {
+        OutputStream::Stdout => "stdout",
+        OutputStream::Stderr => "stderr",
+    }
+}
+
+/// File name for `host`'s output: the host with anything but letters,
+/// digits and `.@_-` replaced, so ports and schemes make no directories.
+fn host_file_name(host: &str) -> String {
+    let name = host.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || ".@_-".contains(c) { c } else { '_' })
+        .collect::<String>();
+    format!("{}.log", name)
+}
+
+/// One plain-text file per host in a directory, each line timestamped and
+/// marked with its stream.
+pub struct HostFileSink {
+    dir: PathBuf,
+    files: BTreeMap<String, BufWriter<File>>,
+}
+
+impl HostFileSink {
+    pub fn new(dir: &Path) -> Result<Self> {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create output directory {}", dir.display()))?;
+        Ok(Self { dir: dir.to_path_buf(), files: BTreeMap::new() })
+    }
+
+    /// The host's file, appended to so reruns keep earlier output.
+    fn file(&mut self, host: &str) -> std::io::Result<&mut BufWriter<File>> {
+        if !self.files.contains_key(host) {
+            let path = self.dir.join(host_file_name(host));
+            let file = File::options().create(true).append(true).open(path)?;
+            self.files.insert(host.to_string(), BufWriter::new(file));
+        }
+        Ok(self.files.get_mut(host).expect("inserted above"))
+    }
+}
+
+impl Sink for HostFileSink {
+    fn event(&mut self, event: &HostEvent) -> std::io::Result<()> {
+        let (host, text) = match event {
+            HostEvent::Line { host, stream, line } => (host, format!("{} [{}] {}", timestamp(), stream_name(*stream), plain(line))),
+            HostEvent::Done { host, error: None, elapsed } => {
+                (host, format!("{} [done] ok in {}", timestamp(), output::format_duration(*elapsed)))
+            }
+            HostEvent::Done { host, error: Some(error), elapsed } => {
+                let error = output::mask(error);
+                (host, format!("{} [done] failed in {}: {}", timestamp(), output::format_duration(*elapsed), error))
+            }
+        };
+        let file = self.file(host)?;
+        writeln!(file, "{}", text)?;
+        file.flush()
+    }
+}
+
+/// All hosts' output in one file of JSON lines.
+pub struct JsonSink {
+    file: BufWriter<File>,
+}
+
+impl JsonSink {
+    pub fn new(path: &Path) -> Result<Self> {
+        let file = File::create(path)
+            .with_context(|| format!("Failed to create output file {}", path.display()))?;
+        Ok(Self { file: BufWriter::new(file) })
+    }
+}
+
+impl Sink for JsonSink {
+    fn event(&mut self, event: &HostEvent) -> std::io::Result<()> {
+        let record = match event {
+            HostEvent::Line { host, stream, line } => serde_json::json!({
+                "time": timestamp(),
+                "host": host,
+                "stream": stream_name(*stream),
+                "line": plain(line),
+            }),
+            HostEvent::Done { host, error, elapsed } => serde_json::json!({
+                "time": timestamp(),
+                "host": host,
+                "done": true,
+                "error": error.as_deref().map(output::mask),
+                "duration_ms": elapsed.as_millis(),
+            }),
+        };
+        writeln!(self.file, "{}", record)?;
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_host_file_sink() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("sup-sink-{}", std::process::id()));
+        let mut sink = HostFileSink::new(&dir)?;
+        let line = |host: &str, stream, line: &[u8]| HostEvent::Line { host: host.to_string(), stream, line: line.to_vec() };
+        sink.event(&line("app@web1:2222", OutputStream::Stdout, b"\x1b[32mready\x1b[0m\n"))?;
+        sink.event(&line("app@web1:2222", OutputStream::Stderr, b"warning"))?;
+        sink.event(&HostEvent::Done { host: "app@web1:2222".to_string(), error: None, elapsed: Duration::from_millis(1500) })?;
+
+        let text = std::fs::read_to_string(dir.join("app@web1_2222.log"))?;
+        let lines = text.lines().map(|line| line.split_once(' ').unwrap().1).collect::<Vec<_>>();
+        assert_eq!(lines, ["[stdout] ready", "[stderr] warning", "[done] ok in 1.5s"]);
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_console_sink_writes_before_writer_drops() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("sup-sink-{}.json", std::process::id()));
+        let (sink, writer) = ConsoleSink::spawn(vec![Box::new(JsonSink::new(&path)?)]);
+        sink.clone().line("web1", OutputStream::Stdout, b"ready\n");
+        drop(writer);
+        sink.line("web1", OutputStream::Stdout, b"dropped\n");
+
+        let text = std::fs::read_to_string(&path)?;
+        assert_eq!(text.lines().count(), 1, "{}", text);
+        assert!(text.contains(r#""line":"ready""#), "{}", text);
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_host_file_name() {
+        assert_eq!(host_file_name("docker://app@api"), "docker___app@api.log");
+        assert_eq!(host_file_name("../etc"), ".._etc.log");
+    }
+}
@@ -0,0 +1,283 @@
+use crate::output::{self, NetworkStyle};
+use chrono::{DateTime, Local};
+use colored::*;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Host name used for results of commands that run on the local machine.
+pub const LOCAL_HOST: &str = "local";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HostStatus {
+    Success,
+    Failed(String),
+    Skipped(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HostResult {
+    pub command: String,
+    pub host: String,
+    pub status: HostStatus,
+    pub exit_code: Option<i32>,
+    pub started: Option<DateTime<Local>>,
+    pub finished: Option<DateTime<Local>>,
+    /// Time from connecting to the host until the step finished on it
+    pub elapsed: Option<Duration>,
+    pub bytes_uploaded: Option<u64>,
+}
+
+impl HostResult {
+    pub fn new(command: &str, host: &str, status: HostStatus) -> Self {
+        Self {
+            command: command.to_string(),
+            host: host.to_string(),
+            status,
+            exit_code: None,
+            started: None,
+            finished: None,
+            elapsed: None,
+            bytes_uploaded: None,
+        }
+    }
+}
+
+/// Wall-clock time of one command across all its hosts, batches included.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandTiming {
+    pub command: String,
+    pub elapsed: Duration,
+}
+
+/// Per-host outcomes collected over a run. Clones share the same records.
+#[derive(Debug, Clone, Default)]
+pub struct Summary {
+    results: Arc<Mutex<Vec<HostResult>>>,
+    timings: Arc<Mutex<Vec<CommandTiming>>>,
+    /// Network each host came from, in runs on several networks
+    networks: Arc<Mutex<BTreeMap<String, String>>>,
+    /// Hosts the Supfile left out of remote commands, kept apart from the
+    /// results since they never ran
+    excluded: Arc<Mutex<Vec<String>>>,
+}
+
+impl Summary {
+    pub fn record(&self, command: &str, host: &str, status: HostStatus) {
+        self.push(HostResult::new(command, host, status));
+    }
+
+    pub fn push(&self, result: HostResult) {
+        self.results.lock().unwrap_or_else(|e| e.into_inner()).push(result);
+    }
+
+    pub fn results(&self) -> Vec<HostResult> {
+        self.results.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Records `reason` as the outcome of `command` on those of `hosts`
+    /// it has no result for yet, e.g. hosts a stopped run never finished.
+    pub fn record_unfinished(&self, command: &str, hosts: &[String], reason: &str) {
+        let results = self.results();
+        for host in hosts {
+            if !results.iter().any(|result| result.command == command && &result.host == host) {
+                self.record(command, host, HostStatus::Skipped(reason.to_string()));
+            }
+        }
+    }
+
+    pub fn record_timing(&self, command: &str, elapsed: Duration) {
+        let timing = CommandTiming { command: command.to_string(), elapsed };
+        self.timings.lock().unwrap_or_else(|e| e.into_inner()).push(timing);
+    }
+
+    pub fn timings(&self) -> Vec<CommandTiming> {
+        self.timings.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Adds those of `hosts` not yet listed to the excluded hosts.
+    pub fn record_excluded(&self, hosts: Vec<String>) {
+        let mut excluded = self.excluded.lock().unwrap_or_else(|e| e.into_inner());
+        for host in hosts {
+            if !excluded.contains(&host) {
+                excluded.push(host);
+            }
+        }
+    }
+
+    pub fn excluded(&self) -> Vec<String> {
+        self.excluded.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    pub fn set_networks(&self, networks: BTreeMap<String, String>) {
+        *self.networks.lock().unwrap_or_else(|e| e.into_inner()) = networks;
+    }
+
+    pub fn networks(&self) -> BTreeMap<String, String> {
+        self.networks.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// The remote host that took longest to run `command`, with its time,
+    /// if it ran on more than one host.
+    pub fn slowest_host(&self, command: &str) -> Option<(String, Duration)> {
+        let timed = self.results().into_iter()
+            .filter(|result| result.command == command && result.host != LOCAL_HOST)
+            .filter_map(|result| Some((result.host, result.elapsed?)))
+            .collect::<Vec<_>>();
+        if timed.len() < 2 {
+            return None;
+        }
+        timed.into_iter().max_by_key(|(_, elapsed)| *elapsed)
+    }
+
+    pub fn len(&self) -> usize {
+        self.results.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    /// Number of distinct remote hosts with a recorded result.
+    pub fn host_count(&self) -> usize {
+        let mut hosts = self.results().into_iter()
+            .map(|result| result.host)
+            .filter(|host| host != LOCAL_HOST)
+            .collect::<Vec<_>>();
+        hosts.sort();
+        hosts.dedup();
+        hosts.len()
+    }
+
+    /// Returns (succeeded, failed, skipped) counts.
+    pub fn counts(&self) -> (usize, usize, usize) {
+        self.counts_since(0)
+    }
+
+    /// Counts of the results recorded after the first `start` ones.
+    pub fn counts_since(&self, start: usize) -> (usize, usize, usize) {
+        tally(self.results().iter().skip(start))
+    }
+
+    /// Counts of the results of `command` recorded after the first `start`
+    /// ones, leaving out those of commands running alongside it.
+    pub fn command_counts_since(&self, start: usize, command: &str) -> (usize, usize, usize) {
+        tally(self.results().iter().skip(start).filter(|result| result.command == command))
+    }
+
+    /// Prints the outcome under a header in the color and with the banner
+    /// of the run's network.
+    pub fn print(&self, style: &NetworkStyle) {
+        let results = self.results();
+        if results.is_empty() {
+            return;
+        }
+
+        let (ok, failed, skipped) = self.counts();
+        let header = match style.color {
+            Some(color) => "Summary:".color(color).bold(),
+            None => "Summary:".bold(),
+        };
+        println!(
+            "{} {} succeeded, {} failed, {} skipped{}",
+            header,
+            ok.to_string().green(),
+            failed.to_string().red(),
+            skipped.to_string().yellow(),
+            style.tag(),
+        );
+        let excluded = self.excluded();
+        if !excluded.is_empty() {
+            println!("  {} {}: excluded (supfile)", "EXCLUDED".yellow(), excluded.join(", "));
+        }
+        for timing in self.timings() {
+            let slowest = match self.slowest_host(&timing.command) {
+                Some((host, elapsed)) => format!(", slowest {} ({})", host, output::format_duration(elapsed)),
+                None => String::new(),
+            };
+            println!(
+                "  {} {} in {}{}",
+                "TIME   ".dimmed(), timing.command, output::format_duration(timing.elapsed), slowest,
+            );
+        }
+        let networks = self.networks();
+        for result in &results {
+            let host = match networks.get(&result.host) {
+                Some(network) => format!("{} [{}]", result.host, network),
+                None => result.host.clone(),
+            };
+            let elapsed = result.elapsed
+                .map(|elapsed| format!(" after {}", output::format_duration(elapsed)))
+                .unwrap_or_default();
+            match &result.status {
+                HostStatus::Success => {}
+                HostStatus::Failed(reason) => println!(
+                    "  {} {} ({}{}): {}",
+                    "FAILED ".red(), host, result.command, elapsed, output::mask(reason),
+                ),
+                HostStatus::Skipped(reason) => println!(
+                    "  {} {} ({}): {}",
+                    "SKIPPED".yellow(), host, result.command, reason,
+                ),
+            }
+        }
+    }
+}
+
+/// (succeeded, failed, skipped) counts of `results`.
+fn tally<'a>(results: impl Iterator<Item = &'a HostResult>) -> (usize, usize, usize) {
+    results.fold((0, 0, 0), |(ok, failed, skipped), result| {
+        match result.status {
+            HostStatus::Success => (ok + 1, failed, skipped),
+            HostStatus::Failed(_) => (ok, failed + 1, skipped),
+            HostStatus::Skipped(_) => (ok, failed, skipped + 1),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counts() {
+        let summary = Summary::default();
+        summary.record("deploy", "web1", HostStatus::Success);
+        summary.record("deploy", "web2", HostStatus::Failed("exit status: 1".to_string()));
+
+        // Clones record into the same run
+        let shared = summary.clone();
+        shared.record("restart", "web1", HostStatus::Skipped("when failed".to_string()));
+        shared.record("restart", "web2", HostStatus::Skipped("when failed".to_string()));
+
+        assert_eq!(summary.counts(), (1, 1, 2));
+        assert_eq!(summary.counts_since(2), (0, 0, 2));
+        assert_eq!(summary.command_counts_since(0, "deploy"), (1, 1, 0));
+        assert_eq!(summary.len(), 4);
+        assert_eq!(summary.host_count(), 2);
+        assert_eq!(summary.results()[1].host, "web2");
+
+        summary.record_unfinished("deploy", &["web1".to_string(), "web3".to_string()], "deadline exceeded");
+        assert_eq!(summary.len(), 5);
+        assert_eq!(summary.results()[4], HostResult::new("deploy", "web3", HostStatus::Skipped("deadline exceeded".to_string())));
+
+        // Excluded hosts are listed once and never count as results
+        shared.record_excluded(vec!["db1".to_string(), "db2".to_string()]);
+        shared.record_excluded(vec!["db1".to_string()]);
+        assert_eq!(summary.excluded(), ["db1", "db2"]);
+        assert_eq!((summary.len(), summary.host_count()), (5, 3));
+    }
+
+    #[test]
+    fn test_timings() {
+        let summary = Summary::default();
+        for (host, secs) in [("web1", 3), ("web7", 110), ("web2", 40)] {
+            summary.push(HostResult {
+                elapsed: Some(Duration::from_secs(secs)),
+                ..HostResult::new("rolling-update", host, HostStatus::Success)
+            });
+        }
+        summary.record("rolling-update", "web3", HostStatus::Skipped("when failed".to_string()));
+        summary.record_timing("rolling-update", Duration::from_secs(252));
+
+        assert_eq!(summary.slowest_host("rolling-update"), Some(("web7".to_string(), Duration::from_secs(110))));
+        assert_eq!(summary.slowest_host("restart"), None);
+        assert_eq!(summary.timings(), [CommandTiming { command: "rolling-update".to_string(), elapsed: Duration::from_secs(252) }]);
+    }
+}
@@ -0,0 +1,42 @@
+use anyhow::Result;
+use regex::{Captures, Regex};
+use crate::config::EnvMap;
+use std::sync::OnceLock;
+
+fn placeholder() -> &'static Regex {
+    static PLACEHOLDER: OnceLock<Regex> = OnceLock::new();
+    PLACEHOLDER.get_or_init(|| Regex::new(r"\{\{\s*([A-Za-z_][A-Za-z0-9_]*)\s*\}\}").unwrap())
+}
+
+/// Replaces every `{{ VAR }}` in `template` with its value from `vars`.
+/// `file` names the template in the error for a missing variable.
+pub fn render(template: &str, vars: &EnvMap, file: &str) -> Result<String> {
+    if let Some(missing) = placeholder().captures_iter(template)
+        .map(|captures| captures[1].to_string())
+        .find(|name| !vars.contains_key(name))
+    {
+        anyhow::bail!("Template {} uses undefined variable {{{{ {} }}}}", file, missing);
+    }
+    Ok(placeholder()
+        .replace_all(template, |captures: &Captures| vars[&captures[1]].clone())
+        .into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() -> Result<()> {
+        let vars = EnvMap::from([
+            ("SERVER_NAME".to_string(), "example.com".to_string()),
+            ("PORT".to_string(), "8080".to_string()),
+        ]);
+        let rendered = render("server_name {{ SERVER_NAME }};\nlisten {{PORT}}; # {{  PORT  }}\n${HOME} {{ lower-case }}", &vars, "nginx.conf")?;
+        assert_eq!(rendered, "server_name example.com;\nlisten 8080; # 8080\n${HOME} {{ lower-case }}");
+
+        let err = render("root {{ DOC_ROOT }};", &vars, "nginx.conf").unwrap_err().to_string();
+        assert_eq!(err, "Template nginx.conf uses undefined variable {{ DOC_ROOT }}");
+        Ok(())
+    }
+}
@@ -0,0 +1,241 @@
+use anyhow::{Context, Result};
+use std::fmt;
+#[cfg(unix)]
+use std::io::{Read, Write};
+#[cfg(unix)]
+use std::net::Shutdown;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process::Command as ProcessCommand;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use tracing::warn;
+
+const SOCKET: &str = "socket";
+/// Set on ssh, and so on the askpass program it runs, to the socket the
+/// password is served on.
+pub const SOCKET_ENV: &str = "SUP_ASKPASS_SOCKET";
+
+/// Password given with `--ask-pass`, handed to ssh through `SSH_ASKPASS`:
+/// ssh runs sup itself as the askpass program, which asks this run for the
+/// password over a socket in a private per-run directory. The password is
+/// never written to disk, and neither ssh's arguments nor its environment
+/// carry it. The directory is removed by `cleanup`, or when the last copy
+/// is dropped.
+pub struct AskPass {
+    dir: PathBuf,
+    program: PathBuf,
+    /// The native backend authenticates with the password itself
+    #[cfg(feature = "native-ssh")]
+    password: Arc<String>,
+    stop: Arc<AtomicBool>,
+    server: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl AskPass {
+    pub fn create(password: String) -> Result<Self> {
+        let program = std::env::current_exe().context("Failed to find the sup executable for SSH_ASKPASS")?;
+        let dir = std::env::temp_dir().join(format!("sup-askpass-{}-{}", std::process::id(), fastrand::u32(..)));
+        let mut builder = std::fs::DirBuilder::new();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::DirBuilderExt;
+            builder.mode(0o700);
+        }
+        // A fresh directory, so only this user can reach the socket
+        builder.create(&dir)
+            .with_context(|| format!("Failed to create askpass directory {}", dir.display()))?;
+        let (password, stop) = (Arc::new(password), Arc::new(AtomicBool::new(false)));
+        let server = match listen(&dir.join(SOCKET), password.clone(), stop.clone()) {
+            Ok(server) => server,
+            Err(e) => {
+                let _ = std::fs::remove_dir_all(&dir);
+                return Err(e);
+            }
+        };
+        Ok(Self {
+            dir,
+            program,
+            #[cfg(feature = "native-ssh")]
+            password,
+            stop,
+            server: Mutex::new(Some(server)),
+        })
+    }
+
+    /// The program ssh runs for each prompt, with the prompt as argument.
+    pub fn program(&self) -> &Path {
+        &self.program
+    }
+
+    fn socket(&self) -> PathBuf {
+        self.dir.join(SOCKET)
+    }
+
+    #[cfg(feature = "native-ssh")]
+    pub(super) fn password(&self) -> &str {
+        &self.password
+    }
+
+    /// Points `ssh_cmd` at the askpass program, even when a terminal is
+    /// attached; this needs OpenSSH 8.4 or later.
+    pub fn apply(&self, ssh_cmd: &mut ProcessCommand) {
+        ssh_cmd
+            .arg("-o").arg("NumberOfPasswordPrompts=1")
+            .env("SSH_ASKPASS", self.program())
+            .env("SSH_ASKPASS_REQUIRE", "force")
+            .env(SOCKET_ENV, self.socket());
+    }
+
+    /// Stops answering and removes the directory. Later calls do nothing.
+    pub fn cleanup(&self) {
+        let Some(server) = self.server.lock().unwrap_or_else(|e| e.into_inner()).take() else {
+            return;
+        };
+        self.stop.store(true, Ordering::SeqCst);
+        if wake(&self.socket()) {
+            let _ = server.join();
+        }
+        if let Err(e) = std::fs::remove_dir_all(&self.dir) {
+            warn!("Failed to remove {}: {}", self.dir.display(), e);
+        }
+    }
+}
+
+/// Answers askpass requests on `socket` until `stop` is set and a
+/// connection wakes the server.
+#[cfg(unix)]
+fn listen(socket: &Path, password: Arc<String>, stop: Arc<AtomicBool>) -> Result<JoinHandle<()>> {
+    let listener = UnixListener::bind(socket)
+        .with_context(|| format!("Failed to listen on {}", socket.display()))?;
+    Ok(std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            if stop.load(Ordering::SeqCst) {
+                break;
+            }
+            if let Err(e) = stream.and_then(|stream| serve(stream, &password)) {
+                warn!("Failed to answer an askpass request: {}", e);
+            }
+        }
+    }))
+}
+
+#[cfg(not(unix))]
+fn listen(_: &Path, _: Arc<String>, _: Arc<AtomicBool>) -> Result<JoinHandle<()>> {
+    anyhow::bail!("--ask-pass needs Unix domain sockets, which this system lacks")
+}
+
+/// Connects to the server so it sees the stop; false if it is gone.
+#[cfg(unix)]
+fn wake(socket: &Path) -> bool {
+    UnixStream::connect(socket).is_ok()
+}
+
+#[cfg(not(unix))]
+fn wake(_: &Path) -> bool {
+    false
+}
+
+/// Whether ssh's prompt asks for a password. Host key confirmations and
+/// key passphrases are refused, as BatchMode would.
+#[cfg(unix)]
+fn is_password_prompt(prompt: &str) -> bool {
+    prompt.contains("assword")
+}
+
+/// Reads one prompt and answers `+` and the password if it asks for one;
+/// anything else gets an empty reply.
+#[cfg(unix)]
+fn serve(mut stream: UnixStream, password: &str) -> std::io::Result<()> {
+    let mut prompt = String::new();
+    stream.read_to_string(&mut prompt)?;
+    if is_password_prompt(&prompt) {
+        stream.write_all(format!("+{}", password).as_bytes())?;
+    }
+    Ok(())
+}
+
+/// The askpass side, run by sup started as ssh's askpass program: sends
+/// `prompt` to the run serving `socket` and returns the password, or
+/// `None` if the prompt was refused.
+#[cfg(unix)]
+pub fn ask(socket: &Path, prompt: &str) -> std::io::Result<Option<String>> {
+    let mut stream = UnixStream::connect(socket)?;
+    stream.write_all(prompt.as_bytes())?;
+    stream.shutdown(Shutdown::Write)?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    Ok(reply.strip_prefix('+').map(str::to_string))
+}
+
+/// Cleans up the run's `AskPass` when dropped, whatever copies interrupted
+/// sessions still hold.
+#[derive(Debug)]
+pub struct AskPassCleanup(Arc<AskPass>);
+
+impl AskPass {
+    pub fn cleanup_on_drop(self: &Arc<Self>) -> AskPassCleanup {
+        AskPassCleanup(self.clone())
+    }
+}
+
+impl Drop for AskPassCleanup {
+    fn drop(&mut self) {
+        self.0.cleanup();
+    }
+}
+
+impl Drop for AskPass {
+    fn drop(&mut self) {
+        self.cleanup();
+    }
+}
+
+// Keeps the password out of `{:?}` output such as debug logs
+impl fmt::Debug for AskPass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AskPass").field("dir", &self.dir).finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    #[test]
+    fn test_askpass_socket() -> Result<()> {
+        let ask_pass = AskPass::create("s3cret 'quoted'".to_string())?;
+        let (dir, socket) = (ask_pass.dir.clone(), ask_pass.socket());
+        let answer = |prompt: &str| ask(&socket, prompt).unwrap();
+        assert_eq!(answer("deploy@web1's password: ").as_deref(), Some("s3cret 'quoted'"));
+        assert_eq!(answer("(deploy@web1) Password: ").as_deref(), Some("s3cret 'quoted'"));
+        // Anything else is refused rather than answered with the password
+        assert_eq!(answer("Enter passphrase for key '/root/.ssh/id_rsa': "), None);
+        assert_eq!(answer("Are you sure you want to continue connecting (yes/no)? "), None);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(std::fs::metadata(&dir)?.permissions().mode() & 0o777, 0o700);
+        }
+        // Nothing but the socket is on disk
+        let entries = std::fs::read_dir(&dir)?.map(|entry| entry.map(|entry| entry.file_name())).collect::<std::io::Result<Vec<_>>>()?;
+        assert_eq!(entries, [SOCKET]);
+        assert!(!format!("{:?}", ask_pass).contains("s3cret"));
+
+        let mut ssh_cmd = Command::new("ssh");
+        ask_pass.apply(&mut ssh_cmd);
+        let args = ssh_cmd.get_args().map(|arg| arg.to_string_lossy().into_owned()).collect::<Vec<_>>();
+        assert_eq!(args, ["-o", "NumberOfPasswordPrompts=1"]);
+        assert!(ssh_cmd.get_envs().all(|(_, value)| !value.unwrap().to_string_lossy().contains("s3cret")));
+
+        // Cleaning up on an abort leaves nothing for the drop to do
+        ask_pass.cleanup();
+        assert!(!dir.exists());
+        assert!(ask(&socket, "password: ").is_err());
+        drop(ask_pass);
+        Ok(())
+    }
+}
@@ -0,0 +1,239 @@
+use super::{forward_lines, ExecOutput, ExitCodeError, OutputLine, Target, Transport};
+use crate::output;
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+/// Layout version of the transcript; bumped on incompatible changes.
+pub const TRANSCRIPT_VERSION: u32 = 1;
+
+/// Canned outcome of the calls whose host and command match. Calls no
+/// fixture matches succeed without output.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(try_from = "FixtureRepr")]
+pub struct Fixture {
+    /// Regex the host must match; any host when unset
+    pub host: Option<Regex>,
+    /// Regex the command or helper script must match; any when unset
+    pub command: Option<Regex>,
+    pub exit_code: i32,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FixtureRepr {
+    #[serde(default)]
+    host: Option<String>,
+    #[serde(default)]
+    command: Option<String>,
+    #[serde(default)]
+    exit_code: i32,
+    #[serde(default)]
+    stdout: String,
+    #[serde(default)]
+    stderr: String,
+}
+
+impl TryFrom<FixtureRepr> for Fixture {
+    type Error = String;
+
+    fn try_from(repr: FixtureRepr) -> Result<Self, String> {
+        let regex = |pattern: Option<String>| {
+            pattern.map(|pattern| Regex::new(&pattern).map_err(|e| format!("invalid regex {}: {}", pattern, e))).transpose()
+        };
+        Ok(Self {
+            host: regex(repr.host)?,
+            command: regex(repr.command)?,
+            exit_code: repr.exit_code,
+            stdout: repr.stdout,
+            stderr: repr.stderr,
+        })
+    }
+}
+
+impl Fixture {
+    fn matches(&self, host: &str, cmd: &str) -> bool {
+        self.host.as_ref().is_none_or(|host_re| host_re.is_match(host))
+            && self.command.as_ref().is_none_or(|command_re| command_re.is_match(cmd))
+    }
+}
+
+/// Loads fixtures from a YAML list; the first one matching a call wins.
+pub fn load_fixtures(path: &Path) -> Result<Vec<Fixture>> {
+    let yaml = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read mock fixtures {}", path.display()))?;
+    serde_yaml::from_str::<Option<Vec<Fixture>>>(&yaml)
+        .with_context(|| format!("Failed to parse mock fixtures {}", path.display()))
+        .map(Option::unwrap_or_default)
+}
+
+/// How a call reached the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CallKind {
+    /// A command's `run`
+    Session,
+    /// A command run attached to the terminal
+    Interactive,
+    /// A helper script: guards, uploads, locks, readiness checks
+    Exec,
+}
+
+/// One call the mock backend answered instead of a host.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Call {
+    pub kind: CallKind,
+    pub command: String,
+    /// Bytes the call was fed on stdin, e.g. an upload's archive
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stdin_bytes: Option<u64>,
+    pub exit_code: i32,
+}
+
+/// Every call of a run by host, each host's in the order they were made.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Transcript {
+    pub version: u32,
+    pub hosts: BTreeMap<String, Vec<Call>>,
+}
+
+/// Transport that connects to nothing: it records what would run on each
+/// host and answers from fixtures. Clones share the same transcript.
+#[derive(Debug, Clone, Default)]
+pub struct MockTransport {
+    fixtures: Arc<Vec<Fixture>>,
+    calls: Arc<Mutex<BTreeMap<String, Vec<Call>>>>,
+}
+
+impl MockTransport {
+    pub fn new(fixtures: Vec<Fixture>) -> Self {
+        Self { fixtures: Arc::new(fixtures), ..Default::default() }
+    }
+
+    pub fn transcript(&self) -> Transcript {
+        let hosts = self.calls.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        Transcript { version: TRANSCRIPT_VERSION, hosts }
+    }
+
+    /// Writes the transcript as YAML to `path`, or to stdout without one.
+    pub fn write_transcript(&self, path: Option<&Path>) -> Result<()> {
+        let yaml = serde_yaml::to_string(&self.transcript())?;
+        match path {
+            Some(path) => std::fs::write(path, yaml)
+                .with_context(|| format!("Failed to write transcript {}", path.display())),
+            None => {
+                print!("{}", yaml);
+                Ok(())
+            }
+        }
+    }
+
+    /// Records the call and returns the fixture answering it, with the
+    /// bytes read from `stdin`.
+    fn answer(&self, kind: CallKind, host: &Target, cmd: &str, stdin: Option<Box<dyn Read + Send>>) -> Result<(Fixture, u64)> {
+        let host = host.to_string();
+        let fixture = self.fixtures.iter()
+            .find(|fixture| fixture.matches(&host, cmd))
+            .cloned()
+            .unwrap_or_default();
+        let stdin_bytes = match stdin {
+            Some(mut input) => Some(std::io::copy(&mut input, &mut std::io::sink())?),
+            None => None,
+        };
+        // The transcript is output too, so secrets stay masked
+        let call = Call { kind, command: output::redact(cmd), stdin_bytes, exit_code: fixture.exit_code };
+        self.calls.lock().unwrap_or_else(|e| e.into_inner()).entry(host).or_default().push(call);
+        Ok((fixture, stdin_bytes.unwrap_or(0)))
+    }
+}
+
+fn check_exit(code: i32) -> Result<()> {
+    if code != 0 {
+        return Err(ExitCodeError { what: "SSH command", code }.into());
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl Transport for MockTransport {
+    async fn session(
+        &self,
+        host: &Target,
+        cmd: &str,
+        stdin: Option<Box<dyn Read + Send>>,
+        tx: Option<mpsc::Sender<OutputLine>>,
+    ) -> Result<()> {
+        let (fixture, _) = self.answer(CallKind::Session, host, cmd, stdin)?;
+        forward_lines(&host.to_string(), fixture.stdout.as_bytes(), fixture.stderr.as_bytes(), tx).await?;
+        check_exit(fixture.exit_code)
+    }
+
+    async fn interactive(&self, host: &Target, cmd: &str) -> Result<()> {
+        let (fixture, _) = self.answer(CallKind::Interactive, host, cmd, None)?;
+        forward_lines(&host.to_string(), fixture.stdout.as_bytes(), fixture.stderr.as_bytes(), None).await?;
+        check_exit(fixture.exit_code)
+    }
+
+    async fn exec(
+        &self,
+        host: &Target,
+        script: &str,
+        stdin: Option<Box<dyn Read + Send>>,
+    ) -> Result<ExecOutput> {
+        let (fixture, bytes_sent) = self.answer(CallKind::Exec, host, script, stdin)?;
+        Ok(ExecOutput {
+            exit_code: Some(fixture.exit_code),
+            stdout: fixture.stdout.into_bytes(),
+            stderr: fixture.stderr.into_bytes(),
+            bytes_sent,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_mock_transport() -> Result<()> {
+        let fixtures: Vec<Fixture> = serde_yaml::from_str(r#"
+- host: web2
+  command: restart
+  exit_code: 1
+  stderr: "unit not found\n"
+- command: ^test -e
+  exit_code: 1
+"#)?;
+        let mock = MockTransport::new(fixtures);
+        let (web1, web2) = (Target::parse("app@web1")?, Target::parse("app@web2")?);
+
+        mock.session(&web1, "systemctl restart app", None, None).await?;
+        let err = mock.session(&web2, "systemctl restart app", None, None).await.unwrap_err();
+        assert!(err.is::<ExitCodeError>(), "{}", err);
+        let input: Box<dyn Read + Send> = Box::new(&b"archive"[..]);
+        let output = mock.exec(&web1, "tar -xf -", Some(input)).await?;
+        assert_eq!((output.exit_code, output.bytes_sent), (Some(0), 7));
+        assert_eq!(mock.exec(&web1, "test -e '/usr/bin/docker'", None).await?.exit_code, Some(1));
+
+        // Clones record into the same transcript, grouped by host
+        let transcript = mock.clone().transcript();
+        assert_eq!(transcript.hosts["app@web1"].len(), 3);
+        assert_eq!(transcript.hosts["app@web2"], [Call {
+            kind: CallKind::Session,
+            command: "systemctl restart app".to_string(),
+            stdin_bytes: None,
+            exit_code: 1,
+        }]);
+        let yaml = serde_yaml::to_string(&transcript)?;
+        assert!(yaml.starts_with("version: 1\nhosts:\n  app@web1:\n  - kind: session\n"), "{}", yaml);
+        Ok(())
+    }
+}
@@ -0,0 +1,852 @@
+use crate::config::{HostKeyChecking, Network, Supfile};
+use crate::host_keys::PinnedKeys;
+use crate::output::OutputStream;
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use std::fmt;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Command as ProcessCommand, Stdio};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+
+pub mod askpass;
+mod mock;
+#[cfg(feature = "native-ssh")]
+mod native;
+mod subprocess;
+
+pub use askpass::AskPass;
+#[cfg(feature = "native-ssh")]
+pub use native::NativeTransport;
+pub use mock::{load_fixtures, MockTransport};
+pub(crate) use subprocess::forward_lines;
+pub use subprocess::SubprocessTransport;
+
+const DEFAULT_SSH_BINARY: &str = "ssh";
+/// Seconds to wait for an ssh connection before giving up on a host.
+pub const DEFAULT_CONNECT_TIMEOUT: u64 = 10;
+const DOCKER_SCHEME: &str = "docker://";
+const KUBECTL_SCHEME: &str = "kubectl://";
+
+/// Captured result of a helper command run on a target.
+#[derive(Debug, Clone)]
+pub struct ExecOutput {
+    pub exit_code: Option<i32>,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    /// Bytes fed to the command's stdin.
+    pub bytes_sent: u64,
+}
+
+/// One line of session output sent to the executor: the host, the stream
+/// it was written to and the raw bytes of the line.
+pub type OutputLine = (String, OutputStream, Vec<u8>);
+
+/// Moves `available` up to and including its first newline into `line`,
+/// keeping at most `max` bytes of the line besides the newline and
+/// counting the rest in `dropped`. Returns the bytes taken and whether the
+/// line is complete.
+pub(crate) fn take_line(available: &[u8], line: &mut Vec<u8>, dropped: &mut u64, max: usize) -> (usize, bool) {
+    let (taken, complete) = match available.iter().position(|&byte| byte == b'\n') {
+        Some(newline) => (newline + 1, true),
+        None => (available.len(), false),
+    };
+    let content = &available[..taken - usize::from(complete)];
+    let kept = content.len().min(max.saturating_sub(line.len()));
+    line.extend_from_slice(&content[..kept]);
+    *dropped += (content.len() - kept) as u64;
+    if complete {
+        line.push(b'\n');
+    }
+    (taken, complete)
+}
+
+/// Hands over a line read with `take_line`, marked if it was cut short.
+pub(crate) fn finish_line(line: &mut Vec<u8>, dropped: &mut u64) -> Vec<u8> {
+    if *dropped > 0 {
+        crate::output::mark_truncated(line, std::mem::take(dropped));
+    }
+    std::mem::take(line)
+}
+
+/// A command that ran to completion but exited non-zero.
+#[derive(Debug, thiserror::Error)]
+#[error("{what} failed with exit code: {code}")]
+pub struct ExitCodeError {
+    pub what: &'static str,
+    pub code: i32,
+}
+
+/// A session stopped because nothing reads its output any more, e.g. the
+/// run was interrupted. The host did not fail; its command was cut short.
+#[derive(Debug, thiserror::Error)]
+#[error("Session on {host} cancelled: its output is no longer read")]
+pub struct SessionCancelled {
+    pub host: String,
+}
+
+/// Single string for an ssh server to hand to the remote login shell, which
+/// runs `cmd` under `sh -c` exactly as written. ssh joins separate arguments
+/// with spaces, so `cmd` has to be quoted as one word.
+pub fn remote_shell_command(cmd: &str) -> String {
+    format!("sh -c {}", shell_quote::sh::quote(cmd).to_string_lossy())
+}
+
+impl ExecOutput {
+    pub fn success(&self) -> bool {
+        self.exit_code == Some(0)
+    }
+}
+
+/// Backend used by the executor to run commands and stream data to targets.
+#[async_trait]
+pub trait Transport: fmt::Debug + Send + Sync {
+    /// Runs `cmd` on the target, feeding it `stdin` if given and forwarding
+    /// output lines over `tx` (or printing them when no channel is given).
+    /// Fails on non-zero exit.
+    async fn session(
+        &self,
+        host: &Target,
+        cmd: &str,
+        stdin: Option<Box<dyn Read + Send>>,
+        tx: Option<mpsc::Sender<OutputLine>>,
+    ) -> Result<()>;
+
+    /// Runs `cmd` attached to the local terminal.
+    async fn interactive(&self, host: &Target, cmd: &str) -> Result<()>;
+
+    /// Runs a helper script, optionally feeding `stdin`, and captures its output.
+    async fn exec(
+        &self,
+        host: &Target,
+        script: &str,
+        stdin: Option<Box<dyn Read + Send>>,
+    ) -> Result<ExecOutput>;
+
+    /// `SHA256:` fingerprint of the key the host presents, read over a new
+    /// connection; `None` for targets without host keys.
+    async fn host_key(&self, _host: &Target) -> Result<Option<String>> {
+        Ok(None)
+    }
+}
+
+/// Network-level settings that shape how targets are reached.
+#[derive(Debug, Clone, Default)]
+pub struct TransportOptions {
+    pub kube_context: Option<String>,
+    /// ControlPath template for ssh connection multiplexing, if enabled.
+    pub control_path: Option<PathBuf>,
+    pub host_key_checking: HostKeyChecking,
+    /// Replacement for the `ssh` binary; may include leading arguments
+    /// (e.g. `tsh ssh`).
+    pub ssh_binary: Option<String>,
+    /// Extra arguments appended to every ssh invocation.
+    pub ssh_options: Vec<String>,
+    /// Seconds before an unreachable host fails.
+    pub connect_timeout: Option<u64>,
+    /// Password of `--ask-pass`, tried after keys on every ssh host.
+    pub ask_pass: Option<Arc<AskPass>>,
+    /// The Supfile's `host_keys`, which replace `host_key_checking` when set.
+    pub pinned_keys: Option<Arc<PinnedKeys>>,
+}
+
+impl TransportOptions {
+    pub fn new(supfile: &Supfile, network: &Network) -> Self {
+        // ssh keeps the first value it sees for an option, so network
+        // options go first to take precedence over Supfile-wide ones
+        let ssh_options = network.ssh_options.iter()
+            .chain(supfile.ssh_options.iter())
+            .cloned()
+            .collect();
+
+        Self {
+            kube_context: network.kube_context.clone(),
+            control_path: None,
+            host_key_checking: network.host_key_checking,
+            ssh_binary: network.ssh_binary.clone().or_else(|| supfile.ssh_binary.clone()),
+            ssh_options,
+            connect_timeout: Some(network.connect_timeout.unwrap_or(DEFAULT_CONNECT_TIMEOUT)),
+            ask_pass: None,
+            pinned_keys: None,
+        }
+    }
+
+    /// The configured ssh program without any per-run options.
+    pub fn ssh_program(&self) -> ProcessCommand {
+        let binary = self.ssh_binary.as_deref().unwrap_or(DEFAULT_SSH_BINARY);
+        let mut words = binary.split_whitespace();
+        let mut ssh_cmd = ProcessCommand::new(words.next().unwrap_or(DEFAULT_SSH_BINARY));
+        ssh_cmd.args(words);
+        ssh_cmd
+    }
+
+    /// Base `ssh` invocation shared by sessions, uploads and helpers.
+    /// Non-interactive invocations run in BatchMode so password or
+    /// passphrase prompts fail the host instead of hanging the run. With
+    /// `--ask-pass` the askpass script answers password prompts instead,
+    /// and refuses all others.
+    pub fn ssh_command(&self, interactive: bool) -> ProcessCommand {
+        self.checked_ssh_command(interactive, false)
+    }
+
+    /// `ssh_command`, where pinned hosts either must present the key in
+    /// the pinned known hosts file or, while `recording` the key the pins
+    /// are checked against, are added to it.
+    fn checked_ssh_command(&self, interactive: bool, recording: bool) -> ProcessCommand {
+        let mut ssh_cmd = self.ssh_program();
+        // ssh keeps the first value of an option, so pins go first to stay
+        // out of reach of ssh_options, and user options go before the
+        // defaults below to override them
+        if let Some(pinned) = &self.pinned_keys {
+            ssh_cmd
+                .arg("-o").arg(format!("UserKnownHostsFile={}", pinned.known_hosts().display()))
+                .arg("-o").arg("GlobalKnownHostsFile=/dev/null")
+                .arg("-o").arg(if recording { "StrictHostKeyChecking=accept-new" } else { "StrictHostKeyChecking=yes" });
+        }
+        ssh_cmd.args(&self.ssh_options);
+        match &self.ask_pass {
+            Some(ask_pass) => ask_pass.apply(&mut ssh_cmd),
+            None if !interactive => {
+                ssh_cmd.arg("-o").arg("BatchMode=yes");
+            }
+            None => {}
+        }
+        if let Some(timeout) = self.connect_timeout {
+            ssh_cmd.arg("-o").arg(format!("ConnectTimeout={}", timeout));
+        }
+        if let Some(control_path) = &self.control_path {
+            ssh_cmd
+                .arg("-o").arg("ControlMaster=auto")
+                .arg("-o").arg(format!("ControlPath={}", control_path.display()))
+                .arg("-o").arg("ControlPersist=60s");
+        }
+        match self.host_key_checking {
+            _ if self.pinned_keys.is_some() => {}
+            HostKeyChecking::Strict => {}
+            HostKeyChecking::AcceptNew => {
+                ssh_cmd.arg("-o").arg("StrictHostKeyChecking=accept-new");
+            }
+            HostKeyChecking::Off => {
+                ssh_cmd
+                    .arg("-o").arg("StrictHostKeyChecking=no")
+                    .arg("-o").arg("UserKnownHostsFile=/dev/null");
+            }
+        }
+        ssh_cmd
+    }
+}
+
+/// Per-run directory holding ssh ControlMaster sockets. Dropping it stops
+/// every master connection opened during the run and removes the
+/// directory, however the run ends.
+#[derive(Debug)]
+pub struct ControlDir {
+    path: PathBuf,
+    /// The ssh program of each network's masters, by the index their
+    /// socket names start with
+    programs: Mutex<Vec<Option<String>>>,
+}
+
+impl ControlDir {
+    pub fn create() -> Result<Self> {
+        let path = std::env::temp_dir().join(format!("sup-{}", std::process::id()));
+        std::fs::create_dir_all(&path)
+            .context("Failed to create ssh control socket directory")?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o700))?;
+        }
+        Ok(Self { path, programs: Mutex::default() })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// ControlPath template for connections made with `options`; ssh
+    /// expands %r, %h and %p per connection. Their masters are stopped with
+    /// the same ssh program.
+    pub fn control_path(&self, options: &TransportOptions) -> PathBuf {
+        let mut programs = self.programs.lock().unwrap_or_else(|e| e.into_inner());
+        let index = match programs.iter().position(|program| *program == options.ssh_binary) {
+            Some(index) => index,
+            None => {
+                programs.push(options.ssh_binary.clone());
+                programs.len() - 1
+            }
+        };
+        self.path.join(format!("sup-{}-%r@%h:%p", index))
+    }
+}
+
+impl Drop for ControlDir {
+    fn drop(&mut self) {
+        let programs = self.programs.lock().unwrap_or_else(|e| e.into_inner());
+        if let Ok(entries) = std::fs::read_dir(&self.path) {
+            for entry in entries.flatten() {
+                let socket = entry.path();
+                let name = entry.file_name().to_string_lossy().into_owned();
+                // Socket names look like sup-index-user@host:port
+                let Some((index, destination)) = name.strip_prefix("sup-")
+                    .and_then(|name| name.split_once('-'))
+                    .and_then(|(index, rest)| Some((index.parse::<usize>().ok()?, rest.rsplit_once(':')?.0)))
+                else {
+                    continue;
+                };
+                let options = TransportOptions {
+                    ssh_binary: programs.get(index).cloned().flatten(),
+                    ..Default::default()
+                };
+                debug!("Closing ssh master connection {}", socket.display());
+                let status = options.ssh_program()
+                    .arg("-o").arg(format!("ControlPath={}", socket.display()))
+                    .arg("-O").arg("exit")
+                    .arg(destination)
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::null())
+                    .status();
+                if let Err(e) = status {
+                    warn!("Failed to close ssh master connection {}: {}", socket.display(), e);
+                }
+            }
+        }
+        if let Err(e) = std::fs::remove_dir_all(&self.path) {
+            warn!("Failed to remove {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SshHost {
+    pub username: String,
+    pub hostname: String,
+    /// Name shown in place of `user@host` in output
+    pub alias: Option<String>,
+}
+
+impl SshHost {
+    pub fn parse(host_str: &str) -> Result<Self> {
+        // Parse user@host
+        let (username, hostname) = host_str.split_once('@')
+            .context("Host must be in format user@host")?;
+
+        Ok(Self {
+            username: username.to_string(),
+            hostname: hostname.to_string(),
+            alias: None,
+        })
+    }
+
+    /// Adds the destination to an ssh command: `-p` with the hostname's
+    /// port, if it has one, and `user@host`.
+    fn add_destination(&self, ssh_cmd: &mut ProcessCommand) {
+        match self.hostname.rsplit_once(':') {
+            Some((hostname, port)) if !hostname.contains(':') => {
+                ssh_cmd.arg("-p").arg(port).arg(format!("{}@{}", self.username, hostname));
+            }
+            _ => {
+                ssh_cmd.arg(format!("{}@{}", self.username, self.hostname));
+            }
+        }
+    }
+}
+
+impl fmt::Display for SshHost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.alias {
+            Some(alias) => write!(f, "{}", alias),
+            None => write!(f, "{}@{}", self.username, self.hostname),
+        }
+    }
+}
+
+/// A host entry resolved to the mechanism used to reach it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Target {
+    Ssh(SshHost),
+    Docker {
+        container: String,
+        user: Option<String>,
+    },
+    Kubectl {
+        namespace: String,
+        pod: String,
+        container: Option<String>,
+    },
+}
+
+impl Target {
+    pub fn parse(host_str: &str) -> Result<Self> {
+        if let Some(rest) = host_str.strip_prefix(DOCKER_SCHEME) {
+            let (user, container) = match rest.split_once('@') {
+                Some((user, container)) => (Some(user.to_string()), container),
+                None => (None, rest),
+            };
+            if container.is_empty() {
+                anyhow::bail!("Docker host must be in format docker://[user@]container");
+            }
+            return Ok(Target::Docker {
+                container: container.to_string(),
+                user,
+            });
+        }
+
+        if let Some(rest) = host_str.strip_prefix(KUBECTL_SCHEME) {
+            let (path, query) = match rest.split_once('?') {
+                Some((path, query)) => (path, Some(query)),
+                None => (rest, None),
+            };
+            let (namespace, pod) = path.split_once('/')
+                .filter(|(ns, pod)| !ns.is_empty() && !pod.is_empty() && !pod.contains('/'))
+                .context("Kubectl host must be in format kubectl://namespace/pod[?container=name]")?;
+
+            let mut container = None;
+            for param in query.into_iter().flat_map(|q| q.split('&')) {
+                match param.split_once('=') {
+                    Some(("container", name)) if !name.is_empty() => {
+                        container = Some(name.to_string());
+                    }
+                    _ => anyhow::bail!("Unsupported kubectl host parameter: {}", param),
+                }
+            }
+
+            return Ok(Target::Kubectl {
+                namespace: namespace.to_string(),
+                pod: pod.to_string(),
+                container,
+            });
+        }
+
+        Ok(Target::Ssh(SshHost::parse(host_str)?))
+    }
+
+    /// User commands run as, when known.
+    pub fn user(&self) -> Option<&str> {
+        match self {
+            Target::Ssh(host) => Some(&host.username),
+            Target::Docker { user, .. } => user.as_deref(),
+            Target::Kubectl { .. } => None,
+        }
+    }
+
+    /// Command that runs `cmd` non-interactively on the target.
+    pub fn session_command(&self, cmd: &str, options: &TransportOptions) -> ProcessCommand {
+        match self {
+            Target::Ssh(host) => {
+                let mut ssh_cmd = options.ssh_command(false);
+                host.add_destination(&mut ssh_cmd);
+                ssh_cmd.arg(remote_shell_command(cmd));
+                ssh_cmd
+            }
+            Target::Docker { .. } => self.docker_exec(cmd, false, false),
+            Target::Kubectl { .. } => self.kubectl_exec(cmd, false, false, options),
+        }
+    }
+
+    /// Command that runs a helper script on the target, optionally with
+    /// stdin attached (used for directory creation and tar extraction).
+    pub fn exec_command(&self, script: &str, stdin: bool, options: &TransportOptions) -> ProcessCommand {
+        match self {
+            Target::Ssh(host) => {
+                let mut ssh_cmd = options.ssh_command(false);
+                host.add_destination(&mut ssh_cmd);
+                ssh_cmd.arg(script);
+                ssh_cmd
+            }
+            Target::Docker { .. } => self.docker_exec(script, stdin, false),
+            Target::Kubectl { .. } => self.kubectl_exec(script, stdin, false, options),
+        }
+    }
+
+    /// Verbose ssh connection running `true` outside any shared master
+    /// connection, so its output shows the host key; `None` for docker and
+    /// kubectl targets. With pinned keys it records the key for the run's
+    /// later connections.
+    pub fn host_key_command(&self, options: &TransportOptions) -> Option<ProcessCommand> {
+        let Target::Ssh(host) = self else {
+            return None;
+        };
+        let options = TransportOptions { control_path: None, ..options.clone() };
+        let mut ssh_cmd = options.checked_ssh_command(false, true);
+        ssh_cmd.arg("-v");
+        host.add_destination(&mut ssh_cmd);
+        ssh_cmd.arg("true");
+        Some(ssh_cmd)
+    }
+
+    /// Command that runs `cmd` with a TTY attached to the local terminal.
+    pub fn interactive_command(&self, cmd: &str, options: &TransportOptions) -> ProcessCommand {
+        match self {
+            Target::Ssh(host) => {
+                let mut ssh_cmd = options.ssh_command(true);
+                ssh_cmd.arg("-tt"); // Force TTY allocation
+                host.add_destination(&mut ssh_cmd);
+                ssh_cmd.arg(cmd);
+                ssh_cmd
+            }
+            Target::Docker { .. } => self.docker_exec(cmd, true, true),
+            Target::Kubectl { .. } => self.kubectl_exec(cmd, true, true, options),
+        }
+    }
+
+    fn docker_exec(&self, cmd: &str, stdin: bool, tty: bool) -> ProcessCommand {
+        let mut docker_cmd = ProcessCommand::new("docker");
+        docker_cmd.arg("exec");
+        if stdin {
+            docker_cmd.arg("-i");
+        }
+        if tty {
+            docker_cmd.arg("-t");
+        }
+        if let Target::Docker { container, user } = self {
+            if let Some(user) = user {
+                docker_cmd.arg("-u").arg(user);
+            }
+            docker_cmd.arg(container);
+        }
+        docker_cmd.arg("sh").arg("-c").arg(cmd);
+        docker_cmd
+    }
+
+    fn kubectl_exec(&self, cmd: &str, stdin: bool, tty: bool, options: &TransportOptions) -> ProcessCommand {
+        let mut kubectl_cmd = ProcessCommand::new("kubectl");
+        if let Some(context) = &options.kube_context {
+            kubectl_cmd.arg("--context").arg(context);
+        }
+        kubectl_cmd.arg("exec");
+        if stdin {
+            kubectl_cmd.arg("-i");
+        }
+        if tty {
+            kubectl_cmd.arg("-t");
+        }
+        if let Target::Kubectl { namespace, pod, container } = self {
+            kubectl_cmd.arg("-n").arg(namespace).arg(pod);
+            if let Some(container) = container {
+                kubectl_cmd.arg("-c").arg(container);
+            }
+        }
+        kubectl_cmd.arg("--").arg("sh").arg("-c").arg(cmd);
+        kubectl_cmd
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Ssh(host) => write!(f, "{}", host),
+            Target::Docker { container, user: Some(user) } => {
+                write!(f, "{}{}@{}", DOCKER_SCHEME, user, container)
+            }
+            Target::Docker { container, user: None } => {
+                write!(f, "{}{}", DOCKER_SCHEME, container)
+            }
+            // Pods are identified by name in output prefixes
+            Target::Kubectl { pod, .. } => write!(f, "{}", pod),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host_keys::HostKeys;
+
+    fn args(cmd: &ProcessCommand) -> Vec<String> {
+        cmd.get_args().map(|a| a.to_string_lossy().into_owned()).collect()
+    }
+
+    #[test]
+    fn test_parse_targets() {
+        let ssh = Target::parse("deploy@web1").unwrap();
+        assert_eq!(ssh, Target::Ssh(SshHost {
+            username: "deploy".to_string(),
+            hostname: "web1".to_string(),
+            alias: None,
+        }));
+
+        let docker = Target::parse("docker://app").unwrap();
+        assert_eq!(docker, Target::Docker { container: "app".to_string(), user: None });
+
+        let docker_user = Target::parse("docker://root@app").unwrap();
+        assert_eq!(docker_user, Target::Docker {
+            container: "app".to_string(),
+            user: Some("root".to_string()),
+        });
+        assert_eq!(docker_user.to_string(), "docker://root@app");
+
+        let pod = Target::parse("kubectl://web/api-0?container=app").unwrap();
+        assert_eq!(pod, Target::Kubectl {
+            namespace: "web".to_string(),
+            pod: "api-0".to_string(),
+            container: Some("app".to_string()),
+        });
+        assert_eq!(pod.to_string(), "api-0");
+
+        assert!(Target::parse("docker://").is_err());
+        assert!(Target::parse("kubectl://api-0").is_err());
+        assert!(Target::parse("kubectl://web/api-0?image=x").is_err());
+        assert!(Target::parse("no-user-host").is_err());
+    }
+
+    #[test]
+    fn test_docker_commands() {
+        let target = Target::parse("docker://root@app").unwrap();
+
+        let session = target.session_command("echo hi", &TransportOptions::default());
+        assert_eq!(session.get_program(), "docker");
+        assert_eq!(args(&session), ["exec", "-u", "root", "app", "sh", "-c", "echo hi"]);
+
+        let upload = target.exec_command("cd '/tmp' && tar xzf -", true, &TransportOptions::default());
+        assert_eq!(args(&upload), ["exec", "-i", "-u", "root", "app", "sh", "-c", "cd '/tmp' && tar xzf -"]);
+
+        let interactive = target.interactive_command("bash", &TransportOptions::default());
+        assert_eq!(args(&interactive), ["exec", "-i", "-t", "-u", "root", "app", "sh", "-c", "bash"]);
+    }
+
+    #[test]
+    fn test_kubectl_commands() {
+        let options = TransportOptions {
+            kube_context: Some("staging".to_string()),
+            ..Default::default()
+        };
+        let target = Target::parse("kubectl://web/api-0?container=app").unwrap();
+
+        let session = target.session_command("echo hi", &options);
+        assert_eq!(session.get_program(), "kubectl");
+        assert_eq!(args(&session), [
+            "--context", "staging", "exec", "-n", "web", "api-0", "-c", "app", "--", "sh", "-c", "echo hi",
+        ]);
+
+        let upload = Target::parse("kubectl://web/api-0").unwrap()
+            .exec_command("tar xzf -", true, &TransportOptions::default());
+        assert_eq!(args(&upload), ["exec", "-i", "-n", "web", "api-0", "--", "sh", "-c", "tar xzf -"]);
+    }
+
+    #[test]
+    fn test_ssh_commands() {
+        let target = Target::parse("deploy@web1").unwrap();
+
+        let session = target.session_command("uptime", &TransportOptions::default());
+        assert_eq!(session.get_program(), "ssh");
+        assert_eq!(args(&session), ["-o", "BatchMode=yes", "deploy@web1", "sh -c 'uptime'"]);
+
+        let interactive = target.interactive_command("bash", &TransportOptions::default());
+        assert_eq!(args(&interactive), ["-tt", "deploy@web1", "bash"]);
+
+        // An aliased host is shown by its alias but reached at its address
+        let Target::Ssh(mut host) = Target::parse("deploy@10.0.1.5:2222").unwrap() else { unreachable!() };
+        host.alias = Some("web2".to_string());
+        let target = Target::Ssh(host);
+        assert_eq!(target.to_string(), "web2");
+        let session = target.session_command("uptime", &TransportOptions::default());
+        assert_eq!(args(&session), ["-o", "BatchMode=yes", "-p", "2222", "deploy@10.0.1.5", "sh -c 'uptime'"]);
+
+        // Host keys are read over a connection of their own
+        let options = TransportOptions { control_path: Some("/tmp/sup-1/%h".into()), ..Default::default() };
+        let probe = target.host_key_command(&options).unwrap();
+        assert_eq!(args(&probe), ["-o", "BatchMode=yes", "-v", "-p", "2222", "deploy@10.0.1.5", "true"]);
+        assert!(Target::parse("docker://app").unwrap().host_key_command(&options).is_none());
+    }
+
+    #[test]
+    fn test_remote_shell_command_round_trip() {
+        // The remote login shell parses the string once, then sh -c runs cmd
+        let login_shell = |remote: &str| {
+            let output = ProcessCommand::new("sh").arg("-c").arg(remote).output().unwrap();
+            String::from_utf8(output.stdout).unwrap()
+        };
+        let dir = std::env::temp_dir().join(format!("sup-remote-shell-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.log"), "").unwrap();
+
+        let cases = [
+            (r#"echo "a  b""#, "a  b\n".to_string()),
+            (r#"echo 'x y' | awk '{print $1}'"#, "x\n".to_string()),
+            ("X=1; echo \"$X\" '$X' \\$X", "1 $X $X\n".to_string()),
+            (&*format!("cd {} && echo *.log '*.log'", dir.display()), "a.log *.log\n".to_string()),
+            ("printf '%s\\n' first\necho 'second\nline'", "first\nsecond\nline\n".to_string()),
+        ];
+        for (cmd, expected) in cases {
+            assert_eq!(login_shell(&remote_shell_command(cmd)), expected, "{}", cmd);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_host_key_checking_options() {
+        let target = Target::parse("deploy@web1").unwrap();
+        let with_policy = |policy| TransportOptions {
+            host_key_checking: policy,
+            ..Default::default()
+        };
+
+        let strict = target.interactive_command("true", &with_policy(HostKeyChecking::Strict));
+        assert_eq!(args(&strict), ["-tt", "deploy@web1", "true"]);
+
+        let accept_new = target.interactive_command("true", &with_policy(HostKeyChecking::AcceptNew));
+        assert_eq!(args(&accept_new), ["-o", "StrictHostKeyChecking=accept-new", "-tt", "deploy@web1", "true"]);
+
+        let off = target.interactive_command("bash", &with_policy(HostKeyChecking::Off));
+        assert_eq!(args(&off), [
+            "-o", "StrictHostKeyChecking=no", "-o", "UserKnownHostsFile=/dev/null", "-tt", "deploy@web1", "bash",
+        ]);
+    }
+
+    #[test]
+    fn test_pinned_keys_options() -> Result<()> {
+        let target = Target::parse("deploy@web1").unwrap();
+        let pinned = Arc::new(PinnedKeys::create(HostKeys::from([("web1".into(), "SHA256:aaa".into())]))?);
+        let known_hosts = format!("UserKnownHostsFile={}", pinned.known_hosts().display());
+        let options = TransportOptions {
+            host_key_checking: HostKeyChecking::Off,
+            ssh_options: vec!["-o".into(), "StrictHostKeyChecking=no".into()],
+            pinned_keys: Some(pinned.clone()),
+            ..Default::default()
+        };
+
+        // Pins win over both ssh_options and host_key_checking
+        let session = target.session_command("true", &options);
+        assert_eq!(args(&session)[..8], [
+            "-o", &*known_hosts, "-o", "GlobalKnownHostsFile=/dev/null", "-o", "StrictHostKeyChecking=yes",
+            "-o", "StrictHostKeyChecking=no",
+        ]);
+        assert!(!args(&session).contains(&"UserKnownHostsFile=/dev/null".to_string()));
+
+        // The probe adds the key it reads to the pinned known hosts
+        let probe = target.host_key_command(&options).unwrap();
+        assert_eq!(args(&probe)[..6], ["-o", &*known_hosts, "-o", "GlobalKnownHostsFile=/dev/null", "-o", "StrictHostKeyChecking=accept-new"]);
+
+        let path = pinned.known_hosts().to_path_buf();
+        assert!(path.exists());
+        drop((options, pinned));
+        assert!(!path.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_ssh_binary_and_options() -> Result<()> {
+        let supfile: Supfile = serde_yaml::from_str(r#"
+version: "0.4"
+ssh_binary: tsh ssh
+ssh_options: ["-o", "ConnectTimeout=10"]
+networks:
+  prod:
+    ssh_options: ["-o", "ConnectTimeout=5"]
+  bastion:
+    ssh_binary: /opt/homebrew/bin/ssh
+commands: {}
+"#)?;
+        let target = Target::parse("deploy@web1")?;
+
+        let prod = TransportOptions::new(&supfile, &supfile.networks["prod"]);
+        let session = target.exec_command("true", false, &prod);
+        assert_eq!(session.get_program(), "tsh");
+        // The network's and Supfile's options come before the defaults, so
+        // ConnectTimeout=5 wins
+        assert_eq!(args(&session), [
+            "ssh", "-o", "ConnectTimeout=5", "-o", "ConnectTimeout=10",
+            "-o", "BatchMode=yes", "-o", "ConnectTimeout=10", "deploy@web1", "true",
+        ]);
+
+        let bastion = TransportOptions::new(&supfile, &supfile.networks["bastion"]);
+        let session = target.session_command("true", &bastion);
+        assert_eq!(session.get_program(), "/opt/homebrew/bin/ssh");
+        assert_eq!(args(&session), [
+            "-o", "ConnectTimeout=10", "-o", "BatchMode=yes",
+            "-o", "ConnectTimeout=10", "deploy@web1", "sh -c 'true'",
+        ]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_connect_timeout_and_batch_mode() {
+        let options = TransportOptions {
+            connect_timeout: Some(3),
+            ..Default::default()
+        };
+        let target = Target::parse("deploy@web1").unwrap();
+
+        let upload = target.exec_command("tar xzf -", true, &options);
+        assert_eq!(args(&upload), [
+            "-o", "BatchMode=yes", "-o", "ConnectTimeout=3", "deploy@web1", "tar xzf -",
+        ]);
+
+        // Interactive sessions may still prompt for passwords
+        let interactive = target.interactive_command("bash", &options);
+        assert_eq!(args(&interactive), ["-o", "ConnectTimeout=3", "-tt", "deploy@web1", "bash"]);
+
+        // BatchMode would keep ssh from asking the askpass script
+        let ask_pass = Arc::new(AskPass::create("s3cret".to_string()).unwrap());
+        let options = TransportOptions { ask_pass: Some(ask_pass.clone()), ..options };
+        let session = target.session_command("uptime", &options);
+        assert_eq!(args(&session), [
+            "-o", "NumberOfPasswordPrompts=1", "-o", "ConnectTimeout=3", "deploy@web1", "sh -c 'uptime'",
+        ]);
+        let askpass = session.get_envs().find(|(key, _)| *key == "SSH_ASKPASS").and_then(|(_, value)| value);
+        assert_eq!(askpass, Some(ask_pass.program().as_os_str()));
+    }
+
+    #[test]
+    fn test_ssh_multiplexing_options() {
+        let control_dir = ControlDir::create().unwrap();
+        let options = TransportOptions {
+            control_path: Some(control_dir.control_path(&TransportOptions::default())),
+            ..Default::default()
+        };
+        let target = Target::parse("deploy@web1").unwrap();
+
+        let session = target.session_command("uptime", &options);
+        let session_args = args(&session);
+        assert_eq!(session_args[2..8], [
+            "-o".to_string(),
+            "ControlMaster=auto".to_string(),
+            "-o".to_string(),
+            format!("ControlPath={}/sup-0-%r@%h:%p", control_dir.path().display()),
+            "-o".to_string(),
+            "ControlPersist=60s".to_string(),
+        ]);
+
+        // Helper invocations share the same master connection
+        let upload = target.exec_command("tar xzf -", true, &options);
+        assert_eq!(args(&upload)[..8], session_args[..8]);
+
+        // Networks with another ssh program get masters of their own, which
+        // that program stops
+        let log = control_dir.path().with_extension("log");
+        let script = control_dir.path().with_extension("sh");
+        std::fs::write(&script, format!("echo \"$*\" >> {}\n", log.display())).unwrap();
+        let tsh = TransportOptions { ssh_binary: Some(format!("sh {}", script.display())), ..Default::default() };
+        assert!(control_dir.control_path(&tsh).ends_with("sup-1-%r@%h:%p"));
+        assert!(control_dir.control_path(&options).ends_with("sup-0-%r@%h:%p"));
+        // Stands in for the socket of a master ssh opened
+        let socket = control_dir.path().join("sup-1-deploy@web1:22");
+        std::fs::write(&socket, "").unwrap();
+
+        let path = control_dir.path().to_path_buf();
+        drop(control_dir);
+        let closed = std::fs::read_to_string(&log).unwrap();
+        let _ = std::fs::remove_file(&log);
+        let _ = std::fs::remove_file(&script);
+        assert_eq!(closed, format!("-o ControlPath={} -O exit deploy@web1\n", socket.display()));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_take_line_caps_long_lines() {
+        let (mut line, mut dropped) = (Vec::new(), 0);
+        assert_eq!(take_line(b"abc", &mut line, &mut dropped, 5), (3, false));
+        assert_eq!(take_line("dé€\nnext".as_bytes(), &mut line, &mut dropped, 5), (7, true));
+        // "abcd" plus the first byte of "é" are kept; the split code point
+        // goes with the dropped bytes
+        assert_eq!(finish_line(&mut line, &mut dropped), "abcd[... truncated 5 bytes]\n".as_bytes());
+        assert_eq!((line.len(), dropped), (0, 0));
+
+        // A line of exactly the limit keeps its newline and is not marked
+        take_line(b"12345\n", &mut line, &mut dropped, 5);
+        assert_eq!(finish_line(&mut line, &mut dropped), b"12345\n");
+    }
+}
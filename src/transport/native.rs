@@ -0,0 +1,377 @@
+use super::{finish_line, remote_shell_command, take_line, ExecOutput, ExitCodeError, OutputLine, SessionCancelled, SshHost, SubprocessTransport, Target, Transport, TransportOptions};
+use crate::config::HostKeyChecking;
+use crate::output::{self, OutputStream};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use ssh2::{CheckResult, HashType, KnownHostFileKind, Session};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::debug;
+
+const DEFAULT_PORT: u16 = 22;
+const IDENTITY_FILES: &[&str] = &["id_ed25519", "id_ecdsa", "id_rsa"];
+
+/// In-process ssh backend built on libssh2. Docker and kubectl targets, as
+/// well as interactive sessions that need a local TTY, are delegated to the
+/// subprocess backend.
+#[derive(Debug, Clone)]
+pub struct NativeTransport {
+    options: TransportOptions,
+    fallback: SubprocessTransport,
+}
+
+impl NativeTransport {
+    pub fn new(options: TransportOptions) -> Self {
+        Self {
+            fallback: SubprocessTransport::new(options.clone()),
+            options,
+        }
+    }
+}
+
+fn split_port(hostname: &str) -> Result<(&str, u16)> {
+    match hostname.rsplit_once(':') {
+        Some((name, port)) => Ok((name, port.parse().context("Invalid ssh port")?)),
+        None => Ok((hostname, DEFAULT_PORT)),
+    }
+}
+
+fn connect(host: &SshHost, options: &TransportOptions) -> Result<Session> {
+    let session = handshake(host, options)?;
+    verify_host_key(&session, host, options)?;
+    authenticate(&session, &host.username, options)?;
+    Ok(session)
+}
+
+/// A session that has exchanged keys with the host but not checked them.
+fn handshake(host: &SshHost, options: &TransportOptions) -> Result<Session> {
+    let (hostname, port) = split_port(&host.hostname)?;
+    debug!("Opening native ssh connection to {}:{}", hostname, port);
+
+    let tcp = match options.connect_timeout {
+        Some(timeout) => {
+            let addr = (hostname, port).to_socket_addrs()?
+                .next()
+                .with_context(|| format!("Failed to resolve {}", hostname))?;
+            TcpStream::connect_timeout(&addr, Duration::from_secs(timeout))
+        }
+        None => TcpStream::connect((hostname, port)),
+    }
+    .with_context(|| format!("Failed to connect to {}", host))?;
+    let mut session = Session::new()?;
+    session.set_tcp_stream(tcp);
+    session.handshake()
+        .with_context(|| format!("SSH handshake with {} failed", host))?;
+    Ok(session)
+}
+
+/// `SHA256:` fingerprint of the session's host key, as ssh prints it.
+fn fingerprint(session: &Session) -> Option<String> {
+    session.host_key_hash(HashType::Sha256).map(|hash| format!("SHA256:{}", base64_unpadded(hash)))
+}
+
+/// Standard base64 without the trailing `=` padding.
+fn base64_unpadded(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::new();
+    for chunk in bytes.chunks(3) {
+        let word = chunk.iter().enumerate().fold(0u32, |word, (i, byte)| word | u32::from(*byte) << (16 - 8 * i));
+        for i in 0..=chunk.len() {
+            encoded.push(ALPHABET[(word >> (18 - 6 * i) & 0x3f) as usize] as char);
+        }
+    }
+    encoded
+}
+
+/// Checks the session's host key against the host's pin, when the Supfile
+/// pins keys, or else known_hosts as `host_key_checking` says.
+fn verify_host_key(session: &Session, host: &SshHost, options: &TransportOptions) -> Result<()> {
+    if let Some(pinned) = &options.pinned_keys {
+        let presented = fingerprint(session).context("Server did not present a host key")?;
+        return match pinned.keys.get(&host.hostname) {
+            Some(key) if *key == presented => Ok(()),
+            Some(key) => anyhow::bail!("Host key of {} has CHANGED: pinned {}, presented {}", host.hostname, key, presented),
+            None => anyhow::bail!("Host key of {} is not pinned in host_keys", host.hostname),
+        };
+    }
+    let policy = options.host_key_checking;
+    if policy == HostKeyChecking::Off {
+        return Ok(());
+    }
+    let (hostname, port) = split_port(&host.hostname)?;
+
+    let path = dirs::home_dir()
+        .context("Cannot locate home directory for known_hosts")?
+        .join(".ssh")
+        .join("known_hosts");
+    let mut known_hosts = session.known_hosts()?;
+    if path.exists() {
+        known_hosts.read_file(&path, KnownHostFileKind::OpenSSH)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+    }
+
+    let (key, key_type) = session.host_key()
+        .context("Server did not present a host key")?;
+    match known_hosts.check_port(hostname, port, key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::Mismatch => {
+            anyhow::bail!("Host key for {} does not match {}", hostname, path.display())
+        }
+        CheckResult::NotFound if policy == HostKeyChecking::AcceptNew => {
+            let entry = if port == DEFAULT_PORT {
+                hostname.to_string()
+            } else {
+                format!("[{}]:{}", hostname, port)
+            };
+            known_hosts.add(&entry, key, "added by sup-rs", key_type.into())?;
+            known_hosts.write_file(&path, KnownHostFileKind::OpenSSH)
+                .with_context(|| format!("Failed to update {}", path.display()))?;
+            Ok(())
+        }
+        CheckResult::NotFound => {
+            anyhow::bail!("Host key for {} is not in {}", hostname, path.display())
+        }
+        CheckResult::Failure => anyhow::bail!("Failed to check host key for {}", hostname),
+    }
+}
+
+fn authenticate(session: &Session, username: &str, options: &TransportOptions) -> Result<()> {
+    if let Err(e) = session.userauth_agent(username) {
+        debug!("ssh-agent authentication failed: {}", e);
+    }
+
+    let ssh_dir = dirs::home_dir().map(|home| home.join(".ssh"));
+    let identities = IDENTITY_FILES.iter()
+        .filter_map(|name| ssh_dir.as_ref().map(|dir| dir.join(name)))
+        .filter(|path: &PathBuf| path.exists());
+    for identity in identities {
+        if session.authenticated() {
+            break;
+        }
+        if let Err(e) = session.userauth_pubkey_file(username, None, &identity, None) {
+            debug!("Identity {} rejected: {}", identity.display(), e);
+        }
+    }
+
+    // Keys first, so hosts that accept them never see the password
+    if let Some(ask_pass) = options.ask_pass.as_ref().filter(|_| !session.authenticated()) {
+        if let Err(e) = session.userauth_password(username, ask_pass.password()) {
+            anyhow::bail!("Password authentication failed for {}: {}", username, e);
+        }
+    }
+
+    if !session.authenticated() {
+        anyhow::bail!("Authentication failed for {}: no usable key in ssh-agent or ~/.ssh", username);
+    }
+    Ok(())
+}
+
+/// Lines of `reader` as raw bytes, keeping their newline; the last one may
+/// lack it. Lines longer than `MAX_CAPTURED_LINE` are cut short.
+fn raw_lines(mut reader: impl BufRead) -> impl Iterator<Item = std::io::Result<Vec<u8>>> {
+    let (mut line, mut dropped) = (Vec::new(), 0);
+    std::iter::from_fn(move || {
+        loop {
+            let available = match reader.fill_buf() {
+                Ok(available) => available,
+                Err(e) => return Some(Err(e)),
+            };
+            if available.is_empty() {
+                let line = finish_line(&mut line, &mut dropped);
+                return (!line.is_empty()).then_some(Ok(line));
+            }
+            let (taken, complete) = take_line(available, &mut line, &mut dropped, output::MAX_CAPTURED_LINE);
+            reader.consume(taken);
+            if complete {
+                return Some(Ok(finish_line(&mut line, &mut dropped)));
+            }
+        }
+    })
+}
+
+fn run_session(
+    host: &SshHost,
+    cmd: &str,
+    options: &TransportOptions,
+    stdin: Option<Box<dyn Read + Send>>,
+    tx: Option<mpsc::Sender<OutputLine>>,
+) -> Result<()> {
+    let session = connect(host, options)?;
+    let mut channel = session.channel_session()?;
+    channel.exec(&remote_shell_command(cmd))?;
+    if let Some(mut input) = stdin {
+        let bytes_sent = std::io::copy(&mut input, &mut channel)?;
+        debug!("Transferred {} bytes", bytes_sent);
+        channel.flush()?;
+        channel.send_eof()?;
+    }
+
+    let stdout_reader = BufReader::new(channel.stream(0));
+    let stderr_reader = BufReader::new(channel.stderr());
+    for (reader, stream) in [(stdout_reader, OutputStream::Stdout), (stderr_reader, OutputStream::Stderr)] {
+        for line in raw_lines(reader) {
+            let line = line?;
+            match &tx {
+                Some(tx) => tx.blocking_send((host.to_string(), stream, line))
+                    .map_err(|_| SessionCancelled { host: host.to_string() })?,
+                None => output::write_line(stream, &line),
+            }
+        }
+    }
+
+    channel.wait_close()?;
+    let exit_code = channel.exit_status()?;
+    if exit_code != 0 {
+        return Err(ExitCodeError { what: "SSH command", code: exit_code }.into());
+    }
+    Ok(())
+}
+
+fn run_exec(
+    host: &SshHost,
+    script: &str,
+    options: &TransportOptions,
+    stdin: Option<Box<dyn Read + Send>>,
+) -> Result<ExecOutput> {
+    let session = connect(host, options)?;
+    let mut channel = session.channel_session()?;
+    channel.exec(script)?;
+
+    let mut bytes_sent = 0;
+    if let Some(mut input) = stdin {
+        bytes_sent = std::io::copy(&mut input, &mut channel)?;
+        debug!("Transferred {} bytes", bytes_sent);
+        channel.flush()?;
+    }
+    channel.send_eof()?;
+
+    // Read stdout fully so the remote side never blocks on a full window
+    let mut stdout = Vec::new();
+    channel.read_to_end(&mut stdout)?;
+    let mut stderr = Vec::new();
+    channel.stderr().read_to_end(&mut stderr)?;
+
+    channel.wait_close()?;
+    Ok(ExecOutput {
+        exit_code: Some(channel.exit_status()?),
+        stdout,
+        stderr,
+        bytes_sent,
+    })
+}
+
+#[async_trait]
+impl Transport for NativeTransport {
+    async fn session(
+        &self,
+        host: &Target,
+        cmd: &str,
+        stdin: Option<Box<dyn Read + Send>>,
+        tx: Option<mpsc::Sender<OutputLine>>,
+    ) -> Result<()> {
+        let Target::Ssh(ssh_host) = host else {
+            return self.fallback.session(host, cmd, stdin, tx).await;
+        };
+
+        debug!("Running command on {}: {}", ssh_host, output::redact(cmd));
+        let ssh_host = ssh_host.clone();
+        let cmd = cmd.to_string();
+        let options = self.options.clone();
+        tokio::task::spawn_blocking(move || run_session(&ssh_host, &cmd, &options, stdin, tx)).await?
+    }
+
+    async fn interactive(&self, host: &Target, cmd: &str) -> Result<()> {
+        self.fallback.interactive(host, cmd).await
+    }
+
+    async fn exec(
+        &self,
+        host: &Target,
+        script: &str,
+        stdin: Option<Box<dyn Read + Send>>,
+    ) -> Result<ExecOutput> {
+        let Target::Ssh(ssh_host) = host else {
+            return self.fallback.exec(host, script, stdin).await;
+        };
+
+        debug!("Running command on {}: {}", ssh_host, output::redact(script));
+        let ssh_host = ssh_host.clone();
+        let script = script.to_string();
+        let options = self.options.clone();
+        tokio::task::spawn_blocking(move || run_exec(&ssh_host, &script, &options, stdin)).await?
+    }
+
+    async fn host_key(&self, host: &Target) -> Result<Option<String>> {
+        let Target::Ssh(ssh_host) = host else {
+            return self.fallback.host_key(host).await;
+        };
+        let ssh_host = ssh_host.clone();
+        let options = self.options.clone();
+        tokio::task::spawn_blocking(move || Ok(fingerprint(&handshake(&ssh_host, &options)?))).await?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host_keys::{HostKeys, PinnedKeys};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_base64_unpadded() {
+        assert_eq!(base64_unpadded(b""), "");
+        assert_eq!(base64_unpadded(b"f"), "Zg");
+        assert_eq!(base64_unpadded(b"fo"), "Zm8");
+        assert_eq!(base64_unpadded(b"foo"), "Zm9v");
+        assert_eq!(base64_unpadded(b"foob"), "Zm9vYg");
+        assert_eq!(base64_unpadded(&[0xfb, 0xff]), "+/8");
+    }
+
+    #[test]
+    fn test_raw_lines() {
+        let lines = raw_lines(&b"one\ncaf\xe9\nend"[..]).collect::<std::io::Result<Vec<_>>>().unwrap();
+        assert_eq!(lines, [&b"one\n"[..], b"caf\xe9\n", b"end"]);
+    }
+
+    #[test]
+    fn test_split_port() {
+        assert_eq!(split_port("web1").unwrap(), ("web1", 22));
+        assert_eq!(split_port("web1:2222").unwrap(), ("web1", 2222));
+        assert!(split_port("web1:ssh").is_err());
+    }
+
+    /// Point SUP_NATIVE_TEST_HOST at a dockerized sshd (user@host:port) that
+    /// accepts the local agent or default identity.
+    #[tokio::test]
+    #[ignore = "requires an sshd reachable via SUP_NATIVE_TEST_HOST"]
+    async fn test_native_session_and_upload() -> Result<()> {
+        let host = Target::parse(&std::env::var("SUP_NATIVE_TEST_HOST")?)?;
+        let transport = NativeTransport::new(TransportOptions {
+            host_key_checking: HostKeyChecking::Off,
+            ..Default::default()
+        });
+
+        let (tx, mut rx) = mpsc::channel(32);
+        transport.session(&host, "echo out; echo err >&2", None, Some(tx)).await?;
+        let mut lines = Vec::new();
+        while let Some((_, stream, line)) = rx.recv().await {
+            lines.push((stream, line));
+        }
+        assert_eq!(lines, [(OutputStream::Stdout, b"out\n".to_vec()), (OutputStream::Stderr, b"err\n".to_vec())]);
+
+        let payload: Box<dyn Read + Send> = Box::new(std::io::Cursor::new(b"payload".to_vec()));
+        let output = transport.exec(&host, "test \"$(cat)\" = payload", Some(payload)).await?;
+        assert!(output.success());
+
+        // A pin the host's key doesn't match refuses the session
+        let Target::Ssh(ssh_host) = &host else { panic!("not an ssh target") };
+        let pinned = PinnedKeys::create(HostKeys::from([(ssh_host.hostname.clone(), "SHA256:wrong".to_string())]))?;
+        let pinned = NativeTransport::new(TransportOptions { pinned_keys: Some(Arc::new(pinned)), ..Default::default() });
+        let err = pinned.session(&host, "true", None, None).await.unwrap_err();
+        assert!(format!("{:#}", err).contains("has CHANGED"), "{:#}", err);
+        Ok(())
+    }
+}
@@ -0,0 +1,428 @@
+use super::{finish_line, take_line, ExecOutput, ExitCodeError, OutputLine, SessionCancelled, Target, Transport, TransportOptions};
+use crate::children;
+use crate::output::{self, OutputStream};
+use crate::shell;
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use std::io::Read;
+use std::process::{ExitStatus, Stdio};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader};
+use tokio::process::{ChildStdin, Command as AsyncCommand};
+use tokio::sync::mpsc;
+use tracing::debug;
+
+/// Size of the chunks copied from an upload stream to the child's stdin.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Reaches targets by spawning the local `ssh`, `docker` or `kubectl` binary.
+#[derive(Debug, Clone)]
+pub struct SubprocessTransport {
+    options: TransportOptions,
+}
+
+impl SubprocessTransport {
+    pub fn new(options: TransportOptions) -> Self {
+        Self { options }
+    }
+}
+
+/// Converts a command built by [`Target`] into a tokio child that is
+/// killed when the future awaiting it is dropped (timeouts, Ctrl-C).
+fn spawnable(cmd: std::process::Command) -> AsyncCommand {
+    let mut cmd = AsyncCommand::from(cmd);
+    cmd.kill_on_drop(true);
+    cmd
+}
+
+/// `cmd` as a shell command line for debug logs. Each argument is redacted
+/// before it is quoted, since quoting could escape a secret's characters
+/// and hide it from the redactor.
+fn loggable(cmd: &AsyncCommand) -> String {
+    let cmd = cmd.as_std();
+    std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(|arg| shell::word(&output::redact(&arg.to_string_lossy())))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn check_status(status: ExitStatus) -> Result<()> {
+    if let Some(code) = status.code().filter(|code| *code != 0) {
+        return Err(ExitCodeError { what: "SSH command", code }.into());
+    }
+    if !status.success() {
+        anyhow::bail!("SSH command failed with status: {}", status);
+    }
+    Ok(())
+}
+
+/// Forwards stdout and stderr lines of `host` as raw bytes in the order
+/// they arrive, including invalid UTF-8 and a final line without a
+/// newline. Without a channel they are written to the local stdout and
+/// stderr. A closed channel ends forwarding with `SessionCancelled`.
+pub(crate) async fn forward_lines(
+    host: &str,
+    stdout: impl AsyncRead + Unpin,
+    stderr: impl AsyncRead + Unpin,
+    tx: Option<mpsc::Sender<OutputLine>>,
+) -> Result<()> {
+    let mut stdout = BufReader::new(stdout);
+    let mut stderr = BufReader::new(stderr);
+    // Partial reads stay in these buffers if select! drops a read
+    let (mut stdout_line, mut stderr_line) = ((Vec::new(), 0), (Vec::new(), 0));
+    let (mut stdout_open, mut stderr_open) = (true, true);
+
+    while stdout_open || stderr_open {
+        let (read, stream) = tokio::select! {
+            read = read_line(&mut stdout, &mut stdout_line), if stdout_open => (read?, OutputStream::Stdout),
+            read = read_line(&mut stderr, &mut stderr_line), if stderr_open => (read?, OutputStream::Stderr),
+        };
+        let (open, (buffer, dropped)) = match stream {
+            OutputStream::Stdout => (&mut stdout_open, &mut stdout_line),
+            OutputStream::Stderr => (&mut stderr_open, &mut stderr_line),
+        };
+        *open = read > 0;
+
+        // A read that hit EOF returns 0 even if an earlier, dropped read
+        // left the start of an unterminated line behind
+        let line = finish_line(buffer, dropped);
+        if line.is_empty() {
+            continue;
+        }
+        match &tx {
+            Some(tx) => tx.send((host.to_string(), stream, line)).await
+                .map_err(|_| SessionCancelled { host: host.to_string() })?,
+            None => output::write_line(stream, &line),
+        }
+    }
+    Ok(())
+}
+
+/// Reads the next line into `line` like `read_until`, but keeps only the
+/// first `MAX_CAPTURED_LINE` bytes of it so one huge line cannot exhaust
+/// memory. Returns the bytes read, 0 at the end of the stream.
+async fn read_line(reader: &mut (impl AsyncBufRead + Unpin), (line, dropped): &mut (Vec<u8>, u64)) -> std::io::Result<usize> {
+    let mut read = 0;
+    loop {
+        let available = reader.fill_buf().await?;
+        if available.is_empty() {
+            return Ok(read);
+        }
+        let (taken, complete) = take_line(available, line, dropped, output::MAX_CAPTURED_LINE);
+        reader.consume(taken);
+        read += taken;
+        if complete {
+            return Ok(read);
+        }
+    }
+}
+
+/// Copies `input` into the child's stdin and closes it, returning the
+/// bytes written. The blocking reader runs off the async worker threads.
+async fn feed_stdin(input: Box<dyn Read + Send>, mut stdin: ChildStdin) -> Result<u64> {
+    let (tx, mut rx) = mpsc::channel::<std::io::Result<Vec<u8>>>(4);
+    tokio::task::spawn_blocking(move || {
+        let mut input = input;
+        loop {
+            let mut chunk = vec![0; CHUNK_SIZE];
+            match input.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(n) => {
+                    chunk.truncate(n);
+                    if tx.blocking_send(Ok(chunk)).is_err() {
+                        break;
+                    }
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    let _ = tx.blocking_send(Err(e));
+                    break;
+                }
+            }
+        }
+    });
+
+    let mut bytes_sent = 0;
+    while let Some(chunk) = rx.recv().await {
+        let chunk = chunk?;
+        stdin.write_all(&chunk).await?;
+        bytes_sent += chunk.len() as u64;
+    }
+    stdin.shutdown().await?;
+    debug!("Transferred {} bytes", bytes_sent);
+    Ok(bytes_sent)
+}
+
+#[async_trait]
+impl Transport for SubprocessTransport {
+    async fn session(
+        &self,
+        host: &Target,
+        cmd: &str,
+        stdin: Option<Box<dyn Read + Send>>,
+        tx: Option<mpsc::Sender<OutputLine>>,
+    ) -> Result<()> {
+        let mut ssh_cmd = spawnable(host.session_command(cmd, &self.options));
+        ssh_cmd
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        if stdin.is_some() {
+            ssh_cmd.stdin(Stdio::piped());
+        }
+
+        debug!("Running command: {}", loggable(&ssh_cmd));
+        let mut child = children::spawn(&mut ssh_cmd)?;
+        let pid = child.id();
+
+        let stdout = child.stdout.take()
+            .context("Failed to capture stdout")?;
+        let stderr = child.stderr.take()
+            .context("Failed to capture stderr")?;
+        let feed = match stdin {
+            Some(input) => Some(feed_stdin(input, child.stdin.take().context("Failed to get SSH stdin")?)),
+            None => None,
+        };
+        let host_name = host.to_string();
+        let (forwarded, fed) = tokio::join!(forward_lines(&host_name, stdout, stderr, tx), async {
+            match feed {
+                Some(feed) => feed.await.map(|_| ()),
+                None => Ok(()),
+            }
+        });
+        // A command may stop reading before the end of its input
+        let fed = fed.or_else(|e| match e.downcast_ref::<std::io::Error>() {
+            Some(io) if io.kind() == std::io::ErrorKind::BrokenPipe => Ok(()),
+            _ => Err(e),
+        });
+        if let Err(e) = forwarded.and(fed) {
+            // Nobody reads the output any more: stop ssh instead of leaving
+            // it running unattended
+            debug!("Killing session on {}: {}", host, e);
+            child.kill().await?;
+            children::reaped(pid);
+            return Err(e);
+        }
+
+        let status = child.wait().await?;
+        children::reaped(pid);
+        check_status(status)
+    }
+
+    async fn interactive(&self, host: &Target, cmd: &str) -> Result<()> {
+        let mut ssh_cmd = spawnable(host.interactive_command(cmd, &self.options));
+        ssh_cmd
+            .stdin(Stdio::inherit())
+            .stdout(Stdio::inherit())
+            .stderr(Stdio::inherit());
+
+        debug!("Running command: {}", loggable(&ssh_cmd));
+        check_status(ssh_cmd.status().await?)
+    }
+
+    async fn exec(
+        &self,
+        host: &Target,
+        script: &str,
+        stdin: Option<Box<dyn Read + Send>>,
+    ) -> Result<ExecOutput> {
+        let mut ssh_cmd = spawnable(host.exec_command(script, stdin.is_some(), &self.options));
+        ssh_cmd
+            .stdin(if stdin.is_some() { Stdio::piped() } else { Stdio::null() })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+
+        debug!("Running command: {}", loggable(&ssh_cmd));
+        let mut child = children::spawn(&mut ssh_cmd)?;
+        let pid = child.id();
+
+        // Feed stdin while collecting output, so neither side can stall
+        let feed = match stdin {
+            Some(input) => {
+                let child_stdin = child.stdin.take()
+                    .context("Failed to get SSH stdin")?;
+                Some(feed_stdin(input, child_stdin))
+            }
+            None => None,
+        };
+        let (bytes_sent, output) = tokio::try_join!(
+            async {
+                match feed {
+                    Some(feed) => feed.await,
+                    None => Ok(0),
+                }
+            },
+            async { Ok(child.wait_with_output().await?) },
+        )?;
+        children::reaped(pid);
+
+        Ok(ExecOutput {
+            exit_code: output.status.code(),
+            stdout: output.stdout,
+            stderr: output.stderr,
+            bytes_sent,
+        })
+    }
+
+    async fn host_key(&self, host: &Target) -> Result<Option<String>> {
+        let Some(probe) = host.host_key_command(&self.options) else {
+            return Ok(None);
+        };
+        let mut probe = spawnable(probe);
+        probe.stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::piped());
+        debug!("Reading host key: {}", loggable(&probe));
+        let child = children::spawn(&mut probe)?;
+        let pid = child.id();
+        let output = child.wait_with_output().await?;
+        children::reaped(pid);
+
+        // ssh shows the key before it verifies it, so a rejected key is
+        // still reported for the caller to compare
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        match parse_host_key(&stderr) {
+            Some(fingerprint) => Ok(Some(fingerprint)),
+            None if output.status.success() => Ok(None),
+            None => {
+                let reason = stderr.lines().rfind(|line| !line.starts_with("debug")).unwrap_or_default();
+                anyhow::bail!("ssh to {} failed before showing a host key: {}", host, reason.trim())
+            }
+        }
+    }
+}
+
+/// Fingerprint of the host key in `ssh -v` output, from the line
+/// `debug1: Server host key: ssh-ed25519 SHA256:...`.
+fn parse_host_key(stderr: &str) -> Option<String> {
+    stderr.lines()
+        .find_map(|line| line.split_once("Server host key: "))
+        .and_then(|(_, key)| key.split_whitespace().nth(1))
+        .map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    /// Transport whose `ssh` runs the remote command locally, so the
+    /// subprocess plumbing can be tested without an sshd.
+    fn local_transport(name: &str) -> (SubprocessTransport, Target, std::path::PathBuf) {
+        let fake_ssh = std::env::temp_dir().join(format!("sup-fake-ssh-{}-{}", name, std::process::id()));
+        std::fs::write(&fake_ssh, "for last; do :; done\nexec sh -c \"$last\"\n").unwrap();
+        let transport = SubprocessTransport::new(TransportOptions {
+            ssh_binary: Some(format!("sh {}", fake_ssh.display())),
+            connect_timeout: None,
+            ..Default::default()
+        });
+        (transport, Target::parse("deploy@web1").unwrap(), fake_ssh)
+    }
+
+    #[test]
+    fn test_loggable_redacts_each_argument() {
+        // Debug formatting would escape the quote and backslash
+        let secret = r#"pa"ss\w0rd-loggable"#;
+        output::set_redacted([secret.to_string()]);
+        let options = TransportOptions { connect_timeout: None, ..Default::default() };
+        let cmd = spawnable(Target::parse("deploy@web1").unwrap().session_command(&format!("login {}", secret), &options));
+        let logged = loggable(&cmd);
+        assert!(!logged.contains("w0rd-loggable"), "{}", logged);
+        assert!(logged.contains("[redacted]"), "{}", logged);
+        assert!(logged.starts_with("ssh -o BatchMode=yes deploy@web1 "), "{}", logged);
+    }
+
+    #[tokio::test]
+    async fn test_session_output_order_and_exit_code() -> Result<()> {
+        let (transport, host, fake_ssh) = local_transport("session");
+
+        let (tx, mut rx) = mpsc::channel(32);
+        let script = "echo one; sleep 0.2; echo two >&2; sleep 0.2; printf 'caf\\351\\nend'; exit 3";
+        let err = transport.session(&host, script, None, Some(tx)).await.unwrap_err();
+        assert_eq!(err.downcast_ref::<ExitCodeError>().map(|e| e.code), Some(3));
+
+        let mut lines = Vec::new();
+        while let Some((sender, stream, line)) = rx.recv().await {
+            assert_eq!(sender, "deploy@web1");
+            lines.push((stream, line));
+        }
+        assert_eq!(lines, [
+            (OutputStream::Stdout, b"one\n".to_vec()),
+            (OutputStream::Stderr, b"two\n".to_vec()),
+            (OutputStream::Stdout, b"caf\xe9\n".to_vec()),
+            (OutputStream::Stdout, b"end".to_vec()),
+        ]);
+
+        transport.session(&host, "true", None, None).await?;
+        std::fs::remove_file(fake_ssh)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_exec_stdin_and_cancellation() -> Result<()> {
+        let (transport, host, fake_ssh) = local_transport("exec");
+
+        let payload = vec![b'x'; 3 * CHUNK_SIZE + 5];
+        let input: Box<dyn Read + Send> = Box::new(std::io::Cursor::new(payload.clone()));
+        let output = transport.exec(&host, "wc -c; echo done >&2; exit 4", Some(input)).await?;
+        assert_eq!(output.exit_code, Some(4));
+        assert_eq!(output.bytes_sent, payload.len() as u64);
+        assert_eq!(String::from_utf8(output.stdout)?.trim(), payload.len().to_string());
+        assert_eq!(output.stderr, b"done\n");
+
+        // A timed-out session is killed instead of holding a worker
+        let started = Instant::now();
+        let session = transport.session(&host, "sleep 5", None, None);
+        assert!(tokio::time::timeout(Duration::from_millis(200), session).await.is_err());
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        std::fs::remove_file(fake_ssh)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_host_key() {
+        let stderr = "debug1: Connecting to web1 [10.0.1.5] port 22.\n\
+            debug1: Server host key: ssh-ed25519 SHA256:47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU\n\
+            debug1: Host 'web1' is known and matches the ED25519 host key.\n";
+        assert_eq!(parse_host_key(stderr).as_deref(), Some("SHA256:47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU"));
+        assert_eq!(parse_host_key("ssh: connect to host web1 port 22: Connection refused\n"), None);
+    }
+
+    /// Whether a process with exactly this command line is running.
+    #[cfg(target_os = "linux")]
+    fn running(cmdline: &str) -> bool {
+        std::fs::read_dir("/proc").unwrap().flatten().any(|entry| {
+            std::fs::read(entry.path().join("cmdline"))
+                .is_ok_and(|found| found.split(|b| *b == 0).filter(|arg| !arg.is_empty()).eq(cmdline.split(' ').map(str::as_bytes)))
+        })
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_dropped_receiver_cancels_session() -> Result<()> {
+        // An ssh that keeps running after its output: the remote side of
+        // a real one would go with the connection, but not a local stand-in
+        let fake_ssh = std::env::temp_dir().join(format!("sup-fake-ssh-cancel-{}", std::process::id()));
+        std::fs::write(&fake_ssh, "echo first; echo second; exec sleep 37\n")?;
+        let transport = SubprocessTransport::new(TransportOptions {
+            ssh_binary: Some(format!("sh {}", fake_ssh.display())),
+            connect_timeout: None,
+            ..Default::default()
+        });
+        let host = Target::parse("deploy@web1")?;
+
+        let (tx, mut rx) = mpsc::channel(1);
+        let started = Instant::now();
+        let session = transport.session(&host, "true", None, Some(tx));
+        let reader = async move {
+            rx.recv().await;
+        };
+        let (result, ()) = tokio::join!(session, reader);
+        let err = result.unwrap_err();
+        assert!(err.is::<SessionCancelled>(), "{:#}", err);
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        // The ssh process was killed and reaped, not left sleeping
+        assert!(!running("sleep 37"));
+        std::fs::remove_file(fake_ssh)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,776 @@
+use crate::config::{Compression, EnvMap, Upload};
+use anyhow::{Context, Result};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use sha2::{Digest, Sha256};
+use regex::{Captures, Regex};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use tracing::debug;
+
+/// Gitignore-style file listing paths left out of directory uploads.
+pub const SUPIGNORE: &str = ".supignore";
+
+/// Comma-separated hosts whose first transfer is aborted, so tests can
+/// exercise `retries` deterministically.
+pub const FAIL_ONCE_ENV: &str = "SUP_UPLOAD_FAIL_ONCE";
+
+/// Whether `FAIL_ONCE_ENV` asks to abort this transfer to `host`; true at
+/// most once per host and run.
+pub fn injected_failure(host: &str) -> bool {
+    static FAILED: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+    std::env::var(FAIL_ONCE_ENV).is_ok_and(|hosts| hosts.split(',').any(|name| name.trim() == host))
+        && FAILED.lock().unwrap_or_else(|e| e.into_inner()).insert(host.to_string())
+}
+
+/// Where uploaded entries land on the host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Destination {
+    /// `dst` ending in `/`, or a directory source: every source is placed
+    /// inside this directory
+    Dir(String),
+    /// Any other `dst` for a single file: the file is written under this name
+    Path(String),
+}
+
+/// What an upload entry sends to each host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Payload {
+    /// Local files, sent as a tar stream
+    Files(UploadPlan),
+    /// The output of `from_command`, written to `dst` as is
+    Generated(Vec<u8>),
+}
+
+impl Payload {
+    /// Whether there is nothing to send: an `optional` entry whose sources
+    /// matched nothing.
+    pub fn is_empty(&self) -> bool {
+        matches!(self, Payload::Files(plan) if plan.is_empty())
+    }
+}
+
+/// An upload entry with its sources expanded on the local machine.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UploadPlan {
+    pub sources: Vec<PathBuf>,
+    pub dst: Destination,
+    pub compression: Compression,
+    pub compression_level: Option<u32>,
+    pub follow_symlinks: bool,
+    pub preserve_permissions: bool,
+    /// Explicit archive members per source parent once a `.supignore` has
+    /// filtered the sources; `None` archives every source whole.
+    members: Option<Vec<(PathBuf, Vec<PathBuf>)>>,
+}
+
+fn env_reference() -> &'static Regex {
+    static ENV_REFERENCE: OnceLock<Regex> = OnceLock::new();
+    ENV_REFERENCE.get_or_init(|| {
+        Regex::new(r"\$(?:\{([A-Za-z_][A-Za-z0-9_]*)\}|([A-Za-z_][A-Za-z0-9_]*))").unwrap()
+    })
+}
+
+/// Replaces `$VAR` and `${VAR}` in `value` from `env`, returning the name of
+/// the first undefined variable as the error.
+pub fn expand_env(value: &str, env: &EnvMap) -> std::result::Result<String, String> {
+    let name = |captures: &Captures| captures.get(1).or(captures.get(2)).unwrap().as_str().to_string();
+    if let Some(missing) = env_reference().captures_iter(value)
+        .map(|captures| name(&captures))
+        .find(|name| !env.contains_key(name))
+    {
+        return Err(missing);
+    }
+    Ok(env_reference()
+        .replace_all(value, |captures: &Captures| env[&name(captures)].clone())
+        .into_owned())
+}
+
+/// `upload` with env references in its `src` and `dst` expanded. `index`
+/// is the entry's position in the command's upload list, counted from 1.
+pub fn with_env(upload: &Upload, index: usize, env: &EnvMap) -> Result<Upload> {
+    let expand = |value: &str| expand_env(value, env).map_err(|missing| {
+        anyhow::anyhow!("Upload #{} ({}) uses undefined variable ${}", index, value, missing)
+    });
+    Ok(Upload {
+        src: upload.src.iter().map(|src| expand(src)).collect::<Result<_>>()?,
+        dst: expand(&upload.dst)?,
+        ..upload.clone()
+    })
+}
+
+fn is_pattern(src: &str) -> bool {
+    src.contains(['*', '?', '['])
+}
+
+/// Expands one `src` element: glob patterns to their sorted matches, plain
+/// paths to themselves if they exist.
+pub fn expand(src: &str) -> Result<Vec<PathBuf>> {
+    if !is_pattern(src) {
+        let path = PathBuf::from(src);
+        return Ok(if path.exists() { vec![path] } else { Vec::new() });
+    }
+    glob::glob(src)
+        .with_context(|| format!("Invalid upload pattern {}", src))?
+        .map(|path| path.with_context(|| format!("Failed to expand {}", src)))
+        .collect()
+}
+
+/// `path` with `/` separators, as used on the remote host whatever the
+/// local platform.
+fn slash_path(path: &Path) -> String {
+    path.components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn parent(path: &Path) -> &Path {
+    path.parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."))
+}
+
+/// The `.supignore` in `dir`, or else the one in `fallback_dir`.
+fn load_supignore(dir: &Path, fallback_dir: Option<&Path>) -> Result<Option<Gitignore>> {
+    let Some(file) = std::iter::once(dir).chain(fallback_dir)
+        .map(|dir| dir.join(SUPIGNORE))
+        .find(|file| file.is_file())
+    else {
+        return Ok(None);
+    };
+    debug!("Filtering {} with {}", dir.display(), file.display());
+
+    // Patterns are matched relative to the uploaded directory
+    let mut builder = GitignoreBuilder::new(dir);
+    if let Some(e) = builder.add(&file) {
+        return Err(e).with_context(|| format!("Invalid {}", file.display()));
+    }
+    Ok(Some(builder.build()?))
+}
+
+/// Appends `dir` and everything under it not ignored, relative to `base`.
+/// Symlinks to directories are descended into only when `follow` is set.
+fn walk(dir: &Path, base: &Path, ignore: Option<&Gitignore>, follow: bool, members: &mut Vec<PathBuf>) -> Result<()> {
+    members.push(dir.strip_prefix(base)?.to_path_buf());
+    let mut entries = std::fs::read_dir(dir)
+        .with_context(|| format!("Failed to read {}", dir.display()))?
+        .collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let path = entry.path();
+        let is_dir = if follow { path.is_dir() } else { entry.file_type()?.is_dir() };
+        if ignore.is_some_and(|ignore| ignore.matched(&path, is_dir).is_ignore()) {
+            continue;
+        }
+        if is_dir {
+            walk(&path, base, ignore, follow, members)?;
+        } else {
+            members.push(path.strip_prefix(base)?.to_path_buf());
+        }
+    }
+    Ok(())
+}
+
+fn sha256(path: &Path) -> Result<String> {
+    let mut file = std::fs::File::open(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Remote script printing `sha256sum` lines for the NUL-separated paths it
+/// reads on stdin, falling back to `shasum` where sha256sum is missing.
+pub const CHECKSUM_SCRIPT: &str = "if command -v sha256sum >/dev/null 2>&1; then xargs -0 sha256sum --; \
+                                   else xargs -0 shasum -a 256 --; fi";
+
+/// Remote check that tar can read zstd streams.
+pub const ZSTD_PROBE: &str = "command -v zstd >/dev/null 2>&1 && tar --zstd -cf - -T /dev/null >/dev/null 2>&1";
+
+/// Parses `<hash>  <path>` lines as printed by sha256sum.
+pub fn parse_checksums(output: &str) -> BTreeMap<String, String> {
+    output.lines()
+        .filter_map(|line| line.split_once("  "))
+        .map(|(hash, path)| (path.to_string(), hash.to_string()))
+        .collect()
+}
+
+/// Lists every file whose remote checksum is missing or differs.
+pub fn compare_manifests(local: &BTreeMap<String, String>, remote: &BTreeMap<String, String>) -> Vec<String> {
+    local.iter()
+        .filter(|(path, hash)| remote.get(*path) != Some(hash))
+        .map(|(path, hash)| format!(
+            "{}: local {}, remote {}",
+            path,
+            hash,
+            remote.get(path).map(String::as_str).unwrap_or("missing"),
+        ))
+        .collect()
+}
+
+fn file_name(path: &Path) -> Result<String> {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .with_context(|| format!("Cannot upload {}: it has no file name", path.display()))
+}
+
+impl UploadPlan {
+    /// Expands the sources of `upload` and checks them against its `dst`.
+    /// A source that matches nothing is an error unless the entry is `optional`.
+    pub fn new(upload: &Upload) -> Result<Self> {
+        let mut sources = Vec::new();
+        for src in &upload.src {
+            let matches = expand(src)?;
+            if matches.is_empty() && !upload.optional {
+                anyhow::bail!("Upload source {} matched nothing", src);
+            }
+            sources.extend(matches);
+        }
+
+        let into_dir = upload.dst.ends_with('/')
+            || sources.iter().any(|source| source.is_dir() && (upload.follow_symlinks || !source.is_symlink()));
+        let dst = if into_dir && (upload.dst.ends_with('/') || sources.len() == 1) {
+            let mut names = Vec::new();
+            for source in &sources {
+                let name = file_name(source)?;
+                if names.contains(&name) {
+                    anyhow::bail!("Upload sources for {} include {} more than once", upload.dst, name);
+                }
+                names.push(name);
+            }
+            Destination::Dir(upload.dst.clone())
+        } else {
+            if sources.len() > 1 {
+                anyhow::bail!(
+                    "{} sources match {} but dst {} names a single file; end it with / to upload into a directory",
+                    sources.len(),
+                    upload.src.join(", "),
+                    upload.dst
+                );
+            }
+            Destination::Path(upload.dst.clone())
+        };
+        Ok(Self {
+            sources,
+            dst,
+            compression: upload.compression,
+            compression_level: upload.compression_level,
+            follow_symlinks: upload.follow_symlinks,
+            preserve_permissions: upload.preserve_permissions,
+            members: None,
+        })
+    }
+
+    /// Leaves out of directory sources whatever their `.supignore` (or the
+    /// one in `fallback_dir`) ignores.
+    pub fn with_supignore(mut self, fallback_dir: Option<&Path>) -> Result<Self> {
+        let ignores = self.sources.iter()
+            .map(|source| match source.is_dir() {
+                true => load_supignore(source, fallback_dir),
+                false => Ok(None),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if ignores.iter().all(Option::is_none) {
+            return Ok(self);
+        }
+
+        // Once any source is filtered, every member is listed explicitly
+        self.members = Some(self.list_members(&ignores)?);
+        Ok(self)
+    }
+
+    /// Archive members of each source relative to its parent, skipping
+    /// whatever the matching ignore in `ignores` excludes.
+    fn list_members(&self, ignores: &[Option<Gitignore>]) -> Result<Vec<(PathBuf, Vec<PathBuf>)>> {
+        let mut members = Vec::new();
+        for (i, source) in self.sources.iter().enumerate() {
+            let base = parent(source);
+            let mut names = Vec::new();
+            if source.is_dir() && (self.follow_symlinks || !source.is_symlink()) {
+                walk(source, base, ignores.get(i).and_then(Option::as_ref), self.follow_symlinks, &mut names)?;
+            } else {
+                names.push(PathBuf::from(file_name(source)?));
+            }
+            members.push((base.to_path_buf(), names));
+        }
+        Ok(members)
+    }
+
+    /// SHA-256 of every uploaded regular file, keyed by its remote path.
+    pub fn manifest(&self) -> Result<BTreeMap<String, String>> {
+        let members = match &self.members {
+            Some(members) => members.clone(),
+            None => self.list_members(&[])?,
+        };
+
+        let mut manifest = BTreeMap::new();
+        for (base, names) in members {
+            for name in names {
+                let local = base.join(&name);
+                let metadata = if self.follow_symlinks {
+                    std::fs::metadata(&local)
+                } else {
+                    std::fs::symlink_metadata(&local)
+                };
+                let metadata = metadata.with_context(|| format!("Failed to read {}", local.display()))?;
+                if !metadata.is_file() {
+                    continue;
+                }
+                manifest.insert(self.remote_path(&name), sha256(&local)?);
+            }
+        }
+        Ok(manifest)
+    }
+
+    /// Remote path of an archive member named relative to its source's parent.
+    fn remote_path(&self, name: &Path) -> String {
+        match &self.dst {
+            Destination::Dir(dir) => format!("{}/{}", dir.trim_end_matches('/'), slash_path(name)),
+            Destination::Path(path) => path.clone(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sources.is_empty()
+    }
+
+    /// Remote paths of the uploaded top-level entries.
+    pub fn entries(&self) -> Result<Vec<String>> {
+        match &self.dst {
+            Destination::Dir(dir) => self.sources.iter()
+                .map(|source| Ok(format!("{}/{}", dir.trim_end_matches('/'), file_name(source)?)))
+                .collect(),
+            Destination::Path(path) => Ok(vec![path.clone()]),
+        }
+    }
+
+    /// The same plan sent with `compression` instead.
+    pub fn with_compression(&self, compression: Compression) -> Self {
+        Self { compression, ..self.clone() }
+    }
+
+    /// Writes all sources to `out` as one tar stream, compressed as planned.
+    /// Entries are read and written one at a time, so memory use does not
+    /// grow with the size of the upload.
+    pub fn write_archive(&self, out: impl Write) -> Result<()> {
+        match self.compression {
+            Compression::None => {
+                self.write_tar(out)?;
+            }
+            Compression::Gzip => {
+                // gzip has no levels past 9, e.g. for a zstd level after fallback
+                let level = self.compression_level
+                    .map_or_else(flate2::Compression::default, |level| flate2::Compression::new(level.min(9)));
+                self.write_tar(flate2::write::GzEncoder::new(out, level))?.finish()?;
+            }
+            Compression::Zstd => {
+                // Level 0 is zstd's default
+                let level = self.compression_level.map_or(0, |level| level as i32);
+                self.write_tar(zstd::Encoder::new(out, level)?)?.finish()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Appends every archive member to a tar stream written to `out`,
+    /// keeping modes and mtimes and storing symlinks as links unless
+    /// following them.
+    fn write_tar<W: Write>(&self, out: W) -> Result<W> {
+        let members = match &self.members {
+            Some(members) => members.clone(),
+            None => self.list_members(&[])?,
+        };
+
+        let mut tar = tar::Builder::new(out);
+        tar.follow_symlinks(self.follow_symlinks);
+        for (base, names) in members {
+            for name in names {
+                let path = base.join(&name);
+                tar.append_path_with_name(&path, &name)
+                    .with_context(|| format!("Failed to archive {}", path.display()))?;
+            }
+        }
+        Ok(tar.into_inner()?)
+    }
+
+    /// Remote tar invocation reading the stream from stdin.
+    fn untar(&self) -> String {
+        let permissions = if self.preserve_permissions { "p" } else { "" };
+        match self.compression {
+            Compression::None => format!("tar -x{}f -", permissions),
+            Compression::Gzip => format!("tar -x{}zf -", permissions),
+            Compression::Zstd => format!("tar --zstd -x{}f -", permissions),
+        }
+    }
+
+    /// Remote script extracting the stream read on stdin.
+    pub fn extract_script(&self) -> Result<String> {
+        let quote = |value: &str| shell_quote::sh::quote(value).to_string_lossy().into_owned();
+        match &self.dst {
+            Destination::Dir(dir) => Ok(format!("mkdir -p {0} && {1} -C {0}", quote(dir), self.untar())),
+            Destination::Path(path) => {
+                // Extract next to the target, then atomically replace it
+                let name = quote(&file_name(&self.sources[0])?);
+                Ok(format!(
+                    "dir=$(dirname {path}) && mkdir -p \"$dir\" && tmp=$(mktemp -d \"$dir/.sup-upload.XXXXXX\") \
+                     && {untar} -C \"$tmp\" && mv -f -T \"$tmp\"/{name} {path}; \
+                     status=$?; [ -n \"$tmp\" ] && rm -rf \"$tmp\"; exit $status",
+                    path = quote(path),
+                    name = name,
+                    untar = self.untar(),
+                ))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upload(src: &[&str], dst: &str) -> Upload {
+        Upload {
+            src: src.iter().map(|src| src.to_string()).collect(),
+            dst: dst.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_expand_and_destinations() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("sup-upload-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("dist"))?;
+        for name in ["b.tar.gz", "a.tar.gz", "notes.txt"] {
+            std::fs::write(dir.join("dist").join(name), name)?;
+        }
+        let root = dir.display().to_string();
+
+        let releases = UploadPlan::new(&upload(&[&format!("{}/dist/*.tar.gz", root)], "/srv/releases/"))?;
+        assert_eq!(releases.sources, [dir.join("dist/a.tar.gz"), dir.join("dist/b.tar.gz")]);
+        assert_eq!(releases.entries()?, ["/srv/releases/a.tar.gz", "/srv/releases/b.tar.gz"]);
+
+        // A list of sources is bundled into one plan
+        let mixed = UploadPlan::new(&upload(&[&format!("{}/dist/notes.txt", root), &format!("{}/dist/a*", root)], "/srv/"))?;
+        assert_eq!(mixed.sources.len(), 2);
+
+        // Without a trailing slash dst names the single uploaded file
+        let single = UploadPlan::new(&upload(&[&format!("{}/dist/notes.txt", root)], "/etc/app/NOTES"))?;
+        assert_eq!(single.dst, Destination::Path("/etc/app/NOTES".to_string()));
+        assert_eq!(single.entries()?, ["/etc/app/NOTES"]);
+        let into_tmp = UploadPlan::new(&upload(&[&format!("{}/dist/notes.txt", root)], "/tmp/"))?;
+        assert_eq!(into_tmp.dst, Destination::Dir("/tmp/".to_string()));
+        assert_eq!(into_tmp.entries()?, ["/tmp/notes.txt"]);
+
+        // A directory source always goes into dst, slash or not
+        let dist = UploadPlan::new(&upload(&[&format!("{}/dist", root)], "/srv/app"))?;
+        assert_eq!(dist.dst, Destination::Dir("/srv/app".to_string()));
+        assert_eq!(dist.entries()?, ["/srv/app/dist"]);
+        let err = UploadPlan::new(&upload(&[&format!("{}/dist/*.tar.gz", root)], "/srv/release")).unwrap_err();
+        assert!(err.to_string().contains("end it with /"), "{}", err);
+
+        // Patterns matching nothing fail unless optional
+        let missing = upload(&[&format!("{}/dist/*.zip", root)], "/srv/");
+        assert!(UploadPlan::new(&missing).unwrap_err().to_string().contains("matched nothing"));
+        assert!(UploadPlan::new(&upload(&[&format!("{}/nope", root)], "/srv/")).is_err());
+        assert!(UploadPlan::new(&Upload { optional: true, ..missing })?.is_empty());
+
+        let duplicate = upload(&[&format!("{}/dist/notes.txt", root), &format!("{}/dist/../dist/notes.txt", root)], "/srv/");
+        assert!(UploadPlan::new(&duplicate).is_err());
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    /// Archives `plan` and runs its extract script, and so the system tar,
+    /// locally in place of a host.
+    fn deliver(plan: &UploadPlan) -> Result<()> {
+        let mut archive = Vec::new();
+        plan.write_archive(&mut archive)?;
+        let mut extract = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(plan.extract_script()?)
+            .stdin(std::process::Stdio::piped())
+            .spawn()?;
+        extract.stdin.take().unwrap().write_all(&archive)?;
+        assert!(extract.wait()?.success());
+        Ok(())
+    }
+
+    fn sh_ok(script: &str) -> bool {
+        std::process::Command::new("sh").arg("-c").arg(script).status().is_ok_and(|status| status.success())
+    }
+
+    fn tree(root: &Path) -> Vec<String> {
+        let mut files = Vec::new();
+        let mut dirs = vec![root.to_path_buf()];
+        while let Some(dir) = dirs.pop() {
+            for entry in std::fs::read_dir(dir).unwrap().map(Result::unwrap) {
+                if entry.path().is_dir() {
+                    dirs.push(entry.path());
+                } else {
+                    files.push(entry.path().strip_prefix(root).unwrap().display().to_string());
+                }
+            }
+        }
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn test_supignore() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("sup-supignore-{}", std::process::id()));
+        let app = dir.join("app");
+        for file in ["main.js", "debug.log", "keep.log", "node_modules/dep/index.js", "src/lib.js", "src/lib.log"] {
+            std::fs::create_dir_all(app.join(file).parent().unwrap())?;
+            std::fs::write(app.join(file), file)?;
+        }
+        std::fs::write(app.join(SUPIGNORE), "*.log\n!keep.log\nnode_modules/\n")?;
+
+        let remote = dir.join("remote");
+        let plan = UploadPlan::new(&upload(&[&app.display().to_string()], &format!("{}/", remote.display())))?
+            .with_supignore(None)?;
+        deliver(&plan)?;
+        assert_eq!(tree(&remote.join("app")), [SUPIGNORE, "keep.log", "main.js", "src/lib.js"]);
+
+        // The Supfile directory's .supignore applies when the source has none
+        std::fs::remove_file(app.join(SUPIGNORE))?;
+        std::fs::write(dir.join(SUPIGNORE), "src/\n")?;
+        std::fs::remove_dir_all(&remote)?;
+        let plan = UploadPlan::new(&upload(&[&app.display().to_string()], &format!("{}/", remote.display())))?;
+        deliver(&plan.clone().with_supignore(Some(&dir))?)?;
+        assert_eq!(tree(&remote.join("app")), ["debug.log", "keep.log", "main.js", "node_modules/dep/index.js"]);
+
+        // Without it (--no-supignore) everything is sent
+        std::fs::remove_dir_all(&remote)?;
+        deliver(&plan)?;
+        assert_eq!(tree(&remote.join("app")).len(), 6);
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_env_expansion() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("sup-upload-env-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("build/1.4.2"))?;
+        std::fs::write(dir.join("build/1.4.2/app.tar"), "app")?;
+        let env = EnvMap::from([
+            ("BUILD".to_string(), dir.join("build").display().to_string()),
+            ("VERSION".to_string(), "1.4.2".to_string()),
+            ("NAME".to_string(), "app".to_string()),
+        ]);
+
+        // The source only exists once expanded
+        let expanded = with_env(&upload(&["$BUILD/${VERSION}/app.tar"], "/srv/$NAME/releases/${VERSION}/"), 1, &env)?;
+        assert_eq!(expanded.dst, "/srv/app/releases/1.4.2/");
+        let plan = UploadPlan::new(&expanded)?;
+        assert_eq!(plan.sources, [dir.join("build/1.4.2/app.tar")]);
+        assert_eq!(plan.dst, Destination::Dir("/srv/app/releases/1.4.2/".to_string()));
+
+        assert_eq!(with_env(&upload(&["%VERSION%.tar", "cost$"], "/srv/"), 1, &env)?.src, ["%VERSION%.tar", "cost$"]);
+        let err = with_env(&upload(&["./dist"], "/srv/${RELEASE}/"), 2, &env).unwrap_err().to_string();
+        assert_eq!(err, "Upload #2 (/srv/${RELEASE}/) uses undefined variable $RELEASE");
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_compression_round_trip() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("sup-compression-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("build"))?;
+        std::fs::write(dir.join("build/app.js"), "console.log(1)".repeat(100))?;
+
+        let zstd = sh_ok(ZSTD_PROBE);
+        for (compression, level) in [(Compression::None, None), (Compression::Gzip, None), (Compression::Gzip, Some(1)), (Compression::Zstd, Some(3))] {
+            if compression == Compression::Zstd && !zstd {
+                continue;
+            }
+            let remote = dir.join(format!("remote-{:?}-{:?}", compression, level));
+            let plan = UploadPlan::new(&Upload {
+                compression,
+                compression_level: level,
+                ..upload(&[&dir.join("build").display().to_string()], &format!("{}/", remote.display()))
+            })?;
+            deliver(&plan)?;
+            assert_eq!(tree(&remote), ["build/app.js"], "{:?}", compression);
+        }
+
+        let plan = UploadPlan::new(&upload(&[&dir.join("build").display().to_string()], "/srv/"))?;
+        let magic = |plan: &UploadPlan| {
+            let mut archive = Vec::new();
+            plan.write_archive(&mut archive).unwrap();
+            archive[..4].to_vec()
+        };
+        assert_eq!(magic(&plan)[..2], [0x1f, 0x8b]);
+        assert_eq!(magic(&plan.with_compression(Compression::Zstd)), [0x28, 0xb5, 0x2f, 0xfd]);
+        assert_eq!(magic(&plan.with_compression(Compression::None)), *b"buil");
+        assert!(plan.with_compression(Compression::Zstd).extract_script()?.contains("tar --zstd -xpf -"));
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_archive_matches_system_tar() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("sup-archive-{}", std::process::id()));
+        let app = dir.join("app");
+        std::fs::create_dir_all(app.join("bin"))?;
+        std::fs::create_dir_all(app.join("empty"))?;
+        std::fs::write(app.join("bin/run"), "#!/bin/sh\necho run\n")?;
+        std::fs::set_permissions(app.join("bin/run"), std::fs::Permissions::from_mode(0o755))?;
+        std::fs::write(app.join("config.yml"), vec![b'x'; 100_000])?;
+        std::fs::set_permissions(app.join("config.yml"), std::fs::Permissions::from_mode(0o600))?;
+        std::os::unix::fs::symlink("bin/run", app.join("start"))?;
+
+        let remote = dir.join("remote");
+        deliver(&UploadPlan::new(&upload(&[&app.display().to_string()], &format!("{}/", remote.display())))?)?;
+
+        let extracted = remote.join("app");
+        assert_eq!(tree(&extracted), ["bin/run", "config.yml", "start"]);
+        assert!(extracted.join("empty").is_dir());
+        let mode = |path: &str| std::fs::metadata(extracted.join(path)).unwrap().permissions().mode() & 0o777;
+        assert_eq!((mode("bin/run"), mode("config.yml")), (0o755, 0o600));
+        assert_eq!(std::fs::read_link(extracted.join("start"))?, Path::new("bin/run"));
+        assert_eq!(std::fs::read(extracted.join("config.yml"))?.len(), 100_000);
+
+        // The system tar lists the same members
+        let mut archive = Vec::new();
+        UploadPlan::new(&upload(&[&app.display().to_string()], "/srv/"))?
+            .with_compression(Compression::None)
+            .write_archive(&mut archive)?;
+        std::fs::write(dir.join("app.tar"), &archive)?;
+        let listing = std::process::Command::new("tar").arg("-tf").arg(dir.join("app.tar")).output()?;
+        let mut members = String::from_utf8(listing.stdout)?.lines()
+            .map(|member| member.trim_end_matches('/').to_string())
+            .collect::<Vec<_>>();
+        members.sort();
+        assert_eq!(members, ["app", "app/bin", "app/bin/run", "app/config.yml", "app/empty", "app/start"]);
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_symlinks_and_permissions() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("sup-symlinks-{}", std::process::id()));
+        let app = dir.join("app");
+        std::fs::create_dir_all(app.join("releases/123"))?;
+        std::fs::write(app.join("releases/123/run"), "#!/bin/sh\n")?;
+        std::fs::set_permissions(app.join("releases/123/run"), std::fs::Permissions::from_mode(0o755))?;
+        std::os::unix::fs::symlink("releases/123", app.join("current"))?;
+        std::os::unix::fs::symlink("releases/122", app.join("previous"))?;
+        let src = app.display().to_string();
+
+        // By default links, dangling or not, arrive as links and modes are kept
+        let remote = dir.join("remote");
+        let plan = UploadPlan::new(&upload(&[&src], &format!("{}/", remote.display())))?;
+        assert!(plan.extract_script()?.contains("tar -xpzf -"));
+        deliver(&plan)?;
+        let extracted = remote.join("app");
+        assert_eq!(std::fs::read_link(extracted.join("current"))?, Path::new("releases/123"));
+        assert_eq!(std::fs::read_link(extracted.join("previous"))?, Path::new("releases/122"));
+        assert_eq!(std::fs::metadata(extracted.join("releases/123/run"))?.permissions().mode() & 0o777, 0o755);
+        assert_eq!(plan.manifest()?.len(), 1);
+
+        // Following them uploads what they point to, which must exist
+        std::fs::remove_file(app.join("previous"))?;
+        let followed = dir.join("followed");
+        let plan = UploadPlan::new(&Upload { follow_symlinks: true, ..upload(&[&src], &format!("{}/", followed.display())) })?;
+        deliver(&plan)?;
+        assert!(!followed.join("app/current").is_symlink());
+        assert_eq!(tree(&followed.join("app")), ["current/run", "releases/123/run"]);
+        assert_eq!(plan.manifest()?.len(), 2);
+        std::os::unix::fs::symlink("releases/122", app.join("previous"))?;
+        assert!(plan.write_archive(std::io::sink()).is_err());
+
+        let plan = UploadPlan::new(&Upload { preserve_permissions: false, ..upload(&[&src], "/srv/") })?;
+        assert!(plan.extract_script()?.contains("tar -xzf -"));
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_manifest() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("sup-manifest-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("site/css"))?;
+        std::fs::write(dir.join("site/index.html"), "hello")?;
+        std::fs::write(dir.join("site/css/app.css"), "")?;
+        std::fs::write(dir.join("app.conf"), "hello")?;
+        let hello = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        let empty = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+        let site = UploadPlan::new(&upload(&[&dir.join("site").display().to_string()], "/srv/www/"))?;
+        let manifest = site.manifest()?;
+        assert_eq!(manifest.into_iter().collect::<Vec<_>>(), [
+            ("/srv/www/site/css/app.css".to_string(), empty.to_string()),
+            ("/srv/www/site/index.html".to_string(), hello.to_string()),
+        ]);
+        let no_slash = UploadPlan::new(&upload(&[&dir.join("site").display().to_string()], "/srv/www"))?;
+        assert!(no_slash.manifest()?.contains_key("/srv/www/site/index.html"));
+        let file = UploadPlan::new(&upload(&[&dir.join("app.conf").display().to_string()], "/etc/app.conf"))?;
+        assert_eq!(file.manifest()?["/etc/app.conf"], hello);
+
+        // Hashes computed the way the remote side does agree with the manifest
+        let mut checksum = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(CHECKSUM_SCRIPT)
+            .current_dir(&dir)
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .spawn()?;
+        std::io::Write::write_all(&mut checksum.stdin.take().unwrap(), b"app.conf\0site/css/app.css\0")?;
+        let remote = parse_checksums(&String::from_utf8(checksum.wait_with_output()?.stdout)?);
+        assert_eq!(remote["app.conf"], hello);
+        assert_eq!(remote["site/css/app.css"], empty);
+
+        let local = BTreeMap::from([("app.conf".to_string(), hello.to_string()), ("gone".to_string(), empty.to_string())]);
+        let mismatches = compare_manifests(&local, &BTreeMap::from([("app.conf".to_string(), empty.to_string())]));
+        assert_eq!(mismatches, [
+            format!("app.conf: local {}, remote {}", hello, empty),
+            format!("gone: local {}, remote missing", empty),
+        ]);
+        assert!(compare_manifests(&local, &local).is_empty());
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_extract_into_named_path() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("sup-upload-extract-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("src"))?;
+        std::fs::write(dir.join("src/app.conf"), "new")?;
+        std::fs::create_dir_all(dir.join("remote/etc"))?;
+        std::fs::write(dir.join("remote/etc/app.conf"), "old")?;
+
+        // Run the plan's tar and extract script locally in place of a host
+        let target = dir.join("remote/etc/app.conf").display().to_string();
+        let plan = UploadPlan::new(&upload(&[&dir.join("src/app.conf").display().to_string()], &target))?;
+        deliver(&plan)?;
+
+        assert_eq!(std::fs::read_to_string(&target)?, "new");
+        assert_eq!(std::fs::read_dir(dir.join("remote/etc"))?.count(), 1);
+
+        // The same file sent to a directory dst lands inside it, and a
+        // missing parent of a file dst is created
+        let into = format!("{}/", dir.join("remote/tmp").display());
+        deliver(&UploadPlan::new(&upload(&[&dir.join("src/app.conf").display().to_string()], &into))?)?;
+        assert_eq!(std::fs::read_to_string(dir.join("remote/tmp/app.conf"))?, "new");
+        let nested = dir.join("remote/new/app.conf").display().to_string();
+        deliver(&UploadPlan::new(&upload(&[&dir.join("src/app.conf").display().to_string()], &nested))?)?;
+        assert!(std::path::Path::new(&nested).is_file());
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
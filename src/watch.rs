@@ -0,0 +1,89 @@
+use anyhow::{Context, Result};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher as _};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// Quiet time after a change before rerunning, so an editor's burst of
+/// writes triggers a single run.
+pub const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Watches local paths for `--watch`, recursively for directories.
+pub struct Watcher {
+    // Dropping the watcher stops the events
+    _watcher: RecommendedWatcher,
+    events: mpsc::UnboundedReceiver<notify::Result<Event>>,
+}
+
+impl Watcher {
+    pub fn new(paths: &[PathBuf]) -> Result<Self> {
+        let (tx, events) = mpsc::unbounded_channel();
+        let mut watcher = notify::recommended_watcher(move |event| {
+            let _ = tx.send(event);
+        })?;
+        for path in paths {
+            watcher.watch(path, RecursiveMode::Recursive)
+                .with_context(|| format!("Failed to watch {}", path.display()))?;
+        }
+        Ok(Self { _watcher: watcher, events })
+    }
+
+    /// Waits for a change, then until no further change arrives for
+    /// `DEBOUNCE`, and returns the changed paths.
+    pub async fn changed(&mut self) -> Result<Vec<PathBuf>> {
+        let mut changed = Vec::new();
+        while changed.is_empty() {
+            let event = self.events.recv().await
+                .context("File watcher stopped")?;
+            collect(&mut changed, event?);
+        }
+        while let Ok(Some(event)) = tokio::time::timeout(DEBOUNCE, self.events.recv()).await {
+            collect(&mut changed, event?);
+        }
+        Ok(changed)
+    }
+}
+
+/// Adds the paths of events that modify something, skipping reads.
+fn collect(changed: &mut Vec<PathBuf>, event: Event) {
+    if matches!(event.kind, EventKind::Access(_)) {
+        return;
+    }
+    for path in event.paths {
+        if !changed.contains(&path) {
+            changed.push(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_changes_are_debounced() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("sup-watch-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let dir = dir.canonicalize()?;
+        let mut watcher = Watcher::new(std::slice::from_ref(&dir))?;
+
+        let file = dir.join("app.conf.tpl");
+        tokio::spawn({
+            let file = file.clone();
+            async move {
+                for contents in ["a", "ab", "abc"] {
+                    std::fs::write(&file, contents).unwrap();
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                }
+            }
+        });
+
+        // Three quick writes come back as one change
+        let changed = tokio::time::timeout(Duration::from_secs(10), watcher.changed()).await??;
+        assert_eq!(changed, [file]);
+        assert!(tokio::time::timeout(DEBOUNCE, watcher.changed()).await.is_err());
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
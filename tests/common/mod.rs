@@ -0,0 +1,87 @@
+//! Helpers shared by the tests that run the sup-rs binary.
+
+// Each test binary uses its own subset
+#![allow(dead_code)]
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// Stand-in for ssh that runs the remote command locally.
+const FAKE_SSH: &str = "for last; do :; done\nexec sh -c \"$last\"\n";
+
+/// Fake ssh that runs the remote command in `hosts/<host>`, the host's
+/// home directory.
+pub const HOME_SSH: &str = r#"for arg; do host=$prev; prev=$arg; done
+mkdir -p "hosts/$host" && cd "hosts/$host" && exec sh -c "$prev"
+"#;
+
+/// A temporary directory holding a Supfile and the fake ssh, removed on drop.
+pub struct Fixture {
+    pub dir: PathBuf,
+}
+
+impl Fixture {
+    pub fn new(name: &str, supfile: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("sup-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("Supfile.yml"), supfile).unwrap();
+        std::fs::write(dir.join("ssh"), FAKE_SSH).unwrap();
+        Self { dir }
+    }
+
+    /// sup-rs run in the fixture with `args` and `ssh` as its ssh program,
+    /// without a terminal.
+    pub fn command(&self, ssh: &str, args: &[&str]) -> Command {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_sup-rs"));
+        command
+            .current_dir(&self.dir)
+            .args(args)
+            .env("SUP_SSH", ssh)
+            .env("HOME", &self.dir)
+            .stdin(Stdio::null());
+        command
+    }
+
+    /// Exit code of sup-rs run with `args`.
+    pub fn run(&self, ssh: &str, args: &[&str]) -> i32 {
+        let status = self.command(ssh, args)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .unwrap();
+        status.code().unwrap()
+    }
+
+    /// Stdout of sup-rs run with `args`, which must succeed.
+    pub fn stdout(&self, ssh: &str, args: &[&str]) -> String {
+        let output = self.command(ssh, args).output().unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+        assert!(output.status.success(), "{}{}", stdout, String::from_utf8_lossy(&output.stderr));
+        stdout
+    }
+
+    /// Writes `script` to `name` and returns an ssh program running it.
+    pub fn ssh_script(&self, name: &str, script: &str) -> String {
+        std::fs::write(self.path(name), script).unwrap();
+        format!("sh {}", self.path(name).display())
+    }
+
+    /// The JSON file `name`, such as a `--report`.
+    pub fn json(&self, name: &str) -> serde_json::Value {
+        serde_json::from_str(&std::fs::read_to_string(self.path(name)).unwrap()).unwrap()
+    }
+
+    pub fn fake_ssh(&self) -> String {
+        format!("sh {}", self.path("ssh").display())
+    }
+
+    pub fn path(&self, name: impl AsRef<Path>) -> PathBuf {
+        self.dir.join(name)
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
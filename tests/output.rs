@@ -0,0 +1,249 @@
+//! What runs leave behind: console output, the log file, output files and
+//! metrics.
+
+mod common;
+
+use common::Fixture;
+use serde_json::Value;
+
+const SUPFILE: &str = r#"
+version: "0.4"
+networks:
+  dev:
+    hosts: [app@web1, app@web2:2222]
+  prod:
+    hosts: [app@web1]
+    color: red
+    banner: "⚠ PRODUCTION"
+  broken:
+    inventory: exit 1
+    banner: "⚠ PRODUCTION"
+commands:
+  hello:
+    run: echo hello; echo oops >&2; printf '\033[32mgreen\033[0m\n'
+  greet:
+    run: echo hello
+  fail:
+    run: echo before; exit 3
+  migrate:
+    run: echo migrated
+    once: true
+  migrate-broken:
+    run: echo half-migrated; exit 3
+    once: true
+  dump:
+    run: head -c 300000 /dev/zero | tr '\0' x; echo; printf 'abc€def\n'; echo done
+  build:
+    local: echo built; echo warning >&2
+  login:
+    local: echo prompted
+    inherit_stdio: true
+  broken-build:
+    local: echo broken; exit 3
+"#;
+
+/// Output records of the log file as (host, stream, line).
+fn output_records(log: &str) -> Vec<(String, String, String)> {
+    log.lines()
+        .map(|line| serde_json::from_str::<Value>(line).unwrap())
+        .filter(|record| record["target"] == "sup::output")
+        .map(|record| {
+            let field = |name: &str| record["fields"][name].as_str().unwrap_or_default().to_string();
+            (field("host"), field("stream"), field("line"))
+        })
+        .collect()
+}
+
+#[test]
+fn test_local_output_is_prefixed_and_logged() {
+    let fixture = Fixture::new("local-output", SUPFILE);
+    let ssh = fixture.fake_ssh();
+    let log = fixture.path("sup.log");
+    let log_arg = log.to_str().unwrap();
+
+    let stdout = fixture.stdout(&ssh, &["--log-file", log_arg, "dev", "build"]);
+    assert!(stdout.contains("local built\n"), "{}", stdout);
+    assert!(stdout.contains("local warning\n"), "{}", stdout);
+    // The two streams are read concurrently, so their order is not fixed
+    let mut records = output_records(&std::fs::read_to_string(&log).unwrap());
+    records.sort();
+    assert_eq!(records, [
+        ("local".to_string(), "Stderr".to_string(), "warning".to_string()),
+        ("local".to_string(), "Stdout".to_string(), "built".to_string()),
+    ]);
+
+    // The exit status is kept
+    assert_eq!(fixture.run(&ssh, &["--log-file", log_arg, "dev", "broken-build"]), 3);
+    assert!(output_records(&std::fs::read_to_string(&log).unwrap())
+        .contains(&("local".to_string(), "Stdout".to_string(), "broken".to_string())));
+
+    // inherit_stdio hands the terminal to the command as is
+    std::fs::remove_file(&log).unwrap();
+    let stdout = fixture.stdout(&ssh, &["--log-file", log_arg, "dev", "login"]);
+    assert!(stdout.lines().any(|line| line == "prompted"), "{}", stdout);
+    assert!(output_records(&std::fs::read_to_string(&log).unwrap()).is_empty());
+}
+
+#[test]
+fn test_network_banner() {
+    let fixture = Fixture::new("network-style", SUPFILE);
+    let ssh = fixture.fake_ssh();
+
+    let stdout = fixture.stdout(&ssh, &["--no-color", "prod", "greet"]);
+    assert!(!stdout.contains('\x1b'), "{}", stdout);
+    let lines = stdout.lines().collect::<Vec<_>>();
+    assert_eq!(lines[0], "*** ⚠ PRODUCTION *** network prod");
+    assert!(lines[1].starts_with("==> greet on 1 host [prod] ⚠ PRODUCTION"), "{}", stdout);
+    assert!(stdout.contains("app@web1 hello\n"), "{}", stdout);
+    assert!(stdout.contains("Summary: 1 succeeded, 0 failed, 0 skipped ⚠ PRODUCTION"), "{}", stdout);
+
+    // Forced color paints the host prefix in the network's color
+    let stdout = fixture.stdout(&ssh, &["--color", "always", "prod", "greet"]);
+    assert!(stdout.contains("\x1b[31mapp@web1\x1b[0m hello"), "{}", stdout);
+
+    // The banner comes before the inventory, which here fails
+    let output = fixture.command(&ssh, &["--no-color", "broken", "greet"]).output().unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).starts_with("*** ⚠ PRODUCTION *** network broken\n"));
+
+    // Previews stay machine-readable
+    assert!(!fixture.stdout(&ssh, &["--print-config", "prod", "greet"]).contains("***"));
+}
+
+#[test]
+fn test_quiet_once() {
+    let fixture = Fixture::new("quiet-once", SUPFILE);
+    let ssh = fixture.fake_ssh();
+
+    // The single host's output is passed through unprefixed
+    let stdout = fixture.stdout(&ssh, &["dev", "migrate"]);
+    assert!(stdout.lines().any(|line| line == "migrated"), "{}", stdout);
+
+    let stdout = fixture.stdout(&ssh, &["--quiet", "dev", "migrate"]);
+    assert!(!stdout.contains("migrated"), "{}", stdout);
+
+    // A failure still shows what the host printed
+    let output = fixture.command(&ssh, &["--quiet", "dev", "migrate-broken"]).output().unwrap();
+    assert_eq!(output.status.code(), Some(3));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.lines().any(|line| line == "half-migrated"), "{}", stdout);
+}
+
+#[test]
+fn test_output_dir_and_json() {
+    let fixture = Fixture::new("output-sinks", SUPFILE);
+    let ssh = fixture.fake_ssh();
+    let plain = fixture.stdout(&ssh, &["dev", "hello"]);
+
+    let dir = fixture.path("out");
+    let json = fixture.path("out.jsonl");
+    let teed = fixture.stdout(&ssh, &["--output-dir", dir.to_str().unwrap(), "--output-json", json.to_str().unwrap(), "dev", "hello"]);
+    // The console shows the same host output; log lines, timings and how
+    // the hosts interleave differ
+    let host_lines = |stdout: &str| {
+        let mut lines = stdout.lines()
+            .filter(|line| line.starts_with("app@"))
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+        lines.sort();
+        lines
+    };
+    assert_eq!(host_lines(&teed), host_lines(&plain));
+    assert_eq!(host_lines(&teed).len(), 6);
+
+    let web2 = std::fs::read_to_string(dir.join("app@web2_2222.log")).unwrap();
+    let lines = web2.lines().map(|line| line.split_once(' ').unwrap().1).collect::<Vec<_>>();
+    assert_eq!(lines.len(), 4, "{}", web2);
+    assert!(lines.contains(&"[stderr] oops"), "{}", web2);
+    assert!(lines.contains(&"[stdout] green"), "{}", web2);
+    assert!(lines[3].starts_with("[done] ok in "), "{}", web2);
+    assert!(dir.join("app@web1.log").exists());
+
+    let records = std::fs::read_to_string(&json).unwrap().lines()
+        .map(|line| serde_json::from_str::<Value>(line).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(records.len(), 8);
+    assert!(records.iter().any(|r| r["host"] == "app@web1" && r["stream"] == "stderr" && r["line"] == "oops"));
+    assert_eq!(records.iter().filter(|r| r["done"] == true && r["error"].is_null()).count(), 2);
+
+    // Failures are recorded too, also for a single host's output
+    let code = fixture.run(&ssh, &["--output-dir", dir.to_str().unwrap(), "--host", "app@web1", "dev", "fail"]);
+    assert_ne!(code, 0);
+    let web1 = std::fs::read_to_string(dir.join("app@web1.log")).unwrap();
+    let lines = web1.lines().map(|line| line.split_once(' ').unwrap().1).collect::<Vec<_>>();
+    assert_eq!(lines[lines.len() - 2], "[stdout] before");
+    assert!(lines[lines.len() - 1].starts_with("[done] failed in "), "{}", web1);
+}
+
+#[test]
+fn test_long_lines_are_truncated_on_the_console() {
+    let fixture = Fixture::new("long-lines", SUPFILE);
+    let ssh = fixture.fake_ssh();
+    let dir = fixture.path("out");
+
+    let stdout = fixture.stdout(&ssh, &["--output-dir", dir.to_str().unwrap(), "--host", "app@web1", "dev", "dump"]);
+    let expected = format!("app@web1 {}[... truncated {} bytes]\n", "x".repeat(256 * 1024), 300_000 - 256 * 1024);
+    assert!(stdout.contains(&expected), "{}", stdout.len());
+    assert!(stdout.contains("app@web1 abc€def\n"), "{}", stdout.len());
+    // The output file has the whole line
+    let log = std::fs::read_to_string(dir.join("app@web1.log")).unwrap();
+    assert!(log.contains(&format!("[stdout] {}\n", "x".repeat(300_000))));
+    assert!(!log.contains("truncated"));
+
+    // The cut never splits "€"
+    let stdout = fixture.stdout(&ssh, &["--max-line-length", "5", "--host", "app@web1", "dev", "dump"]);
+    assert!(stdout.contains("app@web1 xxxxx[... truncated 299995 bytes]\n"), "{}", stdout);
+    assert!(stdout.contains("app@web1 abc[... truncated 6 bytes]\n"), "{}", stdout);
+    assert!(stdout.contains("app@web1 done\n"), "{}", stdout);
+
+    let stdout = fixture.stdout(&ssh, &["--max-line-length", "0", "--disable-prefix", "--host", "app@web1", "dev", "dump"]);
+    assert!(stdout.contains(&format!("{}\nabc€def\n", "x".repeat(300_000))));
+    assert!(!stdout.contains("truncated"));
+
+    // Unprefixed output that is not going to a terminal stays verbatim
+    for args in [&["--disable-prefix"][..], &["--disable-prefix", "--max-line-length", "5"]] {
+        let stdout = fixture.stdout(&ssh, &[args, &["--host", "app@web1", "dev", "dump"]].concat());
+        assert!(stdout.contains(&format!("{}\nabc€def\ndone\n", "x".repeat(300_000))), "{}", stdout.len());
+        assert!(!stdout.contains("truncated"));
+    }
+}
+
+#[test]
+fn test_metrics_file() {
+    let fixture = Fixture::new("metrics", r#"
+version: "0.4"
+networks:
+  prod:
+    hosts: [app@web1, app@web2]
+commands:
+  config:
+    upload:
+      - src: ./files
+        dst: /etc/app
+  restart:
+    run: systemctl restart app
+targets:
+  deploy: [config, restart]
+"#);
+    std::fs::create_dir_all(fixture.path("files")).unwrap();
+    std::fs::write(fixture.path("files/app.conf"), "port = 8080\n").unwrap();
+    std::fs::write(fixture.path("fixtures.yml"), "- host: web2\n  command: restart\n  exit_code: 1\n").unwrap();
+
+    let args = ["--ssh-backend", "mock", "--mock-fixtures", "fixtures.yml", "--metrics-file", "sup.prom", "prod", "deploy"];
+    assert_eq!(fixture.run("false", &args), 3);
+    let text = std::fs::read_to_string(fixture.path("sup.prom")).unwrap();
+    assert!(text.contains("sup_hosts_total{network=\"prod\"} 2\n"), "{}", text);
+    assert!(text.contains("sup_hosts_failed{network=\"prod\"} 1\n"), "{}", text);
+    assert!(text.contains("sup_command_duration_seconds{network=\"prod\",command=\"restart\"} "), "{}", text);
+    assert!(text.contains("sup_upload_bytes_total{network=\"prod\",host=\"app@web2\"} "), "{}", text);
+    // The rendering itself is checked in src/metrics.rs; the temporary
+    // file was renamed into place
+    let leftovers = std::fs::read_dir(fixture.path("")).unwrap()
+        .filter(|entry| entry.as_ref().unwrap().file_name().to_string_lossy().ends_with(".tmp"))
+        .count();
+    assert_eq!(leftovers, 0);
+
+    // A file that cannot be written never fails the run
+    let stdout = fixture.stdout("false", &["--ssh-backend", "mock", "--metrics-file", "missing/dir/sup.prom", "prod", "restart"]);
+    assert!(stdout.contains("Failed to write metrics"), "{}", stdout);
+}
@@ -0,0 +1,241 @@
+//! What sup shows without running anything: `--print-config`, `--plan`
+//! and `--doctor`, and how reproducible its output is.
+
+mod common;
+
+use common::Fixture;
+use serde_yaml::Value;
+use std::path::Path;
+
+const EXAMPLE: &str = include_str!("../example_full.yml");
+
+const SUPFILE: &str = r#"
+version: "0.4"
+env:
+  TIER: supfile
+  IMAGE: $(echo app:$TIER)
+secrets: [DB_PASS]
+networks:
+  prod:
+    hosts: [app@web1, app@web2]
+    inventory: echo app@web3; touch inventory-ran
+    ssh_options: ["-o", "ServerAliveInterval=30"]
+    env:
+      TIER: network
+      DB_PASS: hunter22
+  filtered:
+    hosts:
+      - app@web1
+      - {host: web2, user: app, disabled: true}
+    inventory: echo app@web3; echo app@web4
+    excluded_hosts: ['web3$']
+  keyed:
+    hosts: [app@web1]
+    ssh_options: ["-i", "missing_key"]
+commands:
+  deploy:
+    run: ./deploy.sh
+    serial: 2
+  hello:
+    run: echo hello
+  push:
+    local: touch pushed
+  upload-config:
+    local: touch uploaded
+targets:
+  release: [hello, [push, upload-config], deploy]
+"#;
+
+#[test]
+fn test_print_config() {
+    let fixture = Fixture::new("print-config", SUPFILE);
+    let ssh = fixture.fake_ssh();
+
+    let stdout = fixture.stdout(&ssh, &["--print-config", "--plan-skip-inventory", "-e", "TIER=cli", "--serial", "1", "prod", "deploy"]);
+    let config: Value = serde_yaml::from_str(&stdout).unwrap();
+
+    let network = &config["networks"][0];
+    assert_eq!(network["name"], "prod");
+    assert_eq!(network["hosts"], serde_yaml::from_str::<Value>("[app@web1, app@web2]").unwrap());
+    assert_eq!(network["inventory_pending"], "echo app@web3; touch inventory-ran");
+    assert!(!fixture.path("inventory-ran").exists());
+
+    // The command line wins, shell values see the layers before them, and
+    // secrets stay masked
+    assert_eq!(network["env"]["TIER"], "cli");
+    assert_eq!(network["env"]["IMAGE"], "app:supfile");
+    assert_eq!(network["env"]["DB_PASS"], "*****");
+    assert_eq!(network["env"]["SUP_NETWORK"], "prod");
+    assert!(network["env"].get("HOME").is_none());
+    assert!(!stdout.contains("hunter22"), "{}", stdout);
+
+    let ssh_args = network["ssh"].as_sequence().unwrap();
+    assert!(ssh_args.contains(&"ServerAliveInterval=30".into()), "{:?}", ssh_args);
+
+    // Command-line overrides are applied to the command
+    let command = &config["commands"][0];
+    assert_eq!(command["name"], "deploy");
+    assert_eq!(command["network"], "prod");
+    assert_eq!(command["run"], "./deploy.sh");
+    assert_eq!(command["serial"], 1);
+
+    // --once takes the place of the Supfile's serial
+    let config: Value = serde_yaml::from_str(&fixture.stdout(&ssh, &["--print-config", "--plan-skip-inventory", "--once", "prod", "deploy"])).unwrap();
+    assert_eq!(config["commands"][0]["once"], true);
+    assert!(config["commands"][0]["serial"].is_null(), "{:?}", config["commands"][0]);
+
+    // Without --plan-skip-inventory the inventory runs and its hosts count
+    let config: Value = serde_yaml::from_str(&fixture.stdout(&ssh, &["--print-config", "prod", "deploy"])).unwrap();
+    assert_eq!(config["networks"][0]["hosts"].as_sequence().unwrap().len(), 3);
+    assert!(config["networks"][0].get("inventory_pending").is_none());
+}
+
+#[test]
+fn test_print_config_env_sources() {
+    let fixture = Fixture::new("print-config-env", r#"
+version: "0.4"
+inherit_env: false
+inherit_env_only: [PATH]
+networks:
+  dev:
+    hosts: [app@web1]
+commands:
+  show:
+    local: echo shown
+"#);
+    let output = fixture.command(&fixture.fake_ssh(), &["--print-config", "dev", "show"])
+        .env("AWS_SECRET_ACCESS_KEY", "poison")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let config: Value = serde_yaml::from_slice(&output.stdout).unwrap();
+    let network = &config["networks"][0];
+    assert_eq!(network["env_sources"][0], "local environment: PATH only");
+    assert_eq!(network["env_sources"][3], "network dev env");
+    assert!(network["env"].get("PATH").is_some());
+    assert!(network["env"].get("AWS_SECRET_ACCESS_KEY").is_none());
+}
+
+#[test]
+fn test_plan() {
+    let fixture = Fixture::new("plan", SUPFILE);
+    let ssh = fixture.fake_ssh();
+
+    let stdout = fixture.stdout(&ssh, &["--plan", "release", "prod"]);
+    assert!(stdout.contains("├── parallel\n│   ├── push [prod]"), "{}", stdout);
+    assert!(!fixture.path("pushed").exists());
+
+    let stdout = fixture.stdout(&ssh, &["--plan", "hello", "filtered"]);
+    assert!(stdout.contains("run on 2 hosts"), "{}", stdout);
+    assert!(stdout.contains("excluded (supfile): app@web2, app@web3"), "{}", stdout);
+
+    // Nothing connects, so --ask-pass asks nothing
+    fixture.stdout(&ssh, &["--ask-pass", "--plan", "hello", "prod"]);
+}
+
+#[test]
+fn test_doctor() {
+    let fixture = Fixture::new("doctor", SUPFILE);
+    let ssh = fixture.fake_ssh();
+    let doctor = |args: &[&str]| {
+        fixture.command(&ssh, args)
+            .env("SSH_AUTH_SOCK", fixture.path(""))
+            .output()
+            .unwrap()
+    };
+
+    let output = doctor(&["--doctor", "--color", "never", "prod"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", stdout);
+    assert!(stdout.starts_with(&format!("OK      sup-rs    {}", env!("CARGO_PKG_VERSION"))), "{}", stdout);
+    assert!(stdout.contains("OK      Supfile   Supfile.yml parses: 3 networks, 4 commands"), "{}", stdout);
+    assert!(stdout.contains("OK      ssh agent"), "{}", stdout);
+
+    let output = doctor(&["--doctor", "--color", "never", "keyed"]);
+    assert_eq!(output.status.code(), Some(1));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("PROBLEM identity  missing_key cannot be read"), "{}", stdout);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("1 problem found"));
+
+    std::fs::write(fixture.path("Supfile.yml"), "networks: [").unwrap();
+    let output = doctor(&["--doctor", "--color", "never"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("PROBLEM Supfile"));
+}
+
+/// Stdout of a successful run with `args`, with the clock pinned.
+fn pinned(fixture: &Fixture, ssh: &str, args: &[&str]) -> String {
+    let output = fixture.command(ssh, args).env("SUP_FAKE_NOW", "1700000000").output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// Compares `actual` with the golden file `name`, or rewrites the file
+/// when `UPDATE_GOLDEN` is set.
+fn assert_golden(name: &str, actual: &str) {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden").join(name);
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        std::fs::write(&path, actual).unwrap();
+    }
+    let expected = std::fs::read_to_string(&path).unwrap();
+    assert_eq!(actual, expected, "{} changed; rerun with UPDATE_GOLDEN=1 if that is intended", name);
+}
+
+#[test]
+fn test_example_plan_is_golden() {
+    let fixture = Fixture::new("reproducible-plan", EXAMPLE);
+    let ssh = fixture.fake_ssh();
+
+    let plan = ["--plan", "deploy", "--plan-skip-inventory", "prod-us"];
+    let human = pinned(&fixture, &ssh, &plan);
+    assert_golden("example_full_deploy.plan", &human);
+    assert_eq!(pinned(&fixture, &ssh, &plan), human);
+
+    let json = [&plan[..], &["--plan-format", "json"]].concat();
+    assert_golden("example_full_deploy.json", &pinned(&fixture, &ssh, &json));
+
+    let config = ["--print-config", "--plan-skip-inventory", "prod-us", "upload-config"];
+    let first = pinned(&fixture, &ssh, &config);
+    assert!(first.contains("SUP_TIME: 2023-11-14T"), "{}", first);
+    assert_eq!(pinned(&fixture, &ssh, &config), first);
+
+    // Build environments set SOURCE_DATE_EPOCH for everything they run, so
+    // it leaves the clock alone
+    let output = fixture.command(&ssh, &config).env("SOURCE_DATE_EPOCH", "1700000000").output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("SUP_TIME: 2023-11-14T"));
+}
+
+#[test]
+fn test_reports_match_across_runs() {
+    let fixture = Fixture::new("reproducible-report", r#"
+version: "0.4"
+env:
+  ZONE: b
+  APP: a
+networks:
+  dev:
+    hosts: [app@web1, app@web2, app@web3]
+commands:
+  deploy:
+    run: echo $SUP_TIME
+    serial: 2
+"#);
+    let ssh = fixture.fake_ssh();
+
+    let report = |name: &str| {
+        pinned(&fixture, &ssh, &["--report", name, "dev", "deploy"]);
+        let mut report = fixture.json(name);
+        // Run ids tell runs apart and wall-clock durations vary on purpose
+        report["run_id"] = serde_json::Value::Null;
+        report["supfile"] = serde_json::Value::Null;
+        for command in report["commands"].as_array_mut().unwrap() {
+            command["duration_ms"] = serde_json::Value::Null;
+        }
+        report
+    };
+    let first = report("first.json");
+    assert_eq!(report("second.json"), first);
+    let started = first["commands"][0]["hosts"][0]["started"].as_str().unwrap();
+    assert!(started.starts_with("2023-11-1"), "{}", started);
+}
@@ -0,0 +1,591 @@
+//! Runs of the sup-rs binary end to end: exit codes, which commands and
+//! hosts run, the environment and input they get, and how targets and
+//! networks combine them.
+
+mod common;
+
+use common::Fixture;
+use std::time::{Duration, Instant};
+
+#[test]
+fn test_exit_codes() {
+    let fixture = Fixture::new("exit-codes", r#"
+version: "0.4"
+networks:
+  dev:
+    hosts: [app@web1, app@web2]
+commands:
+  ok:
+    local: "true"
+  build:
+    local: exit 7
+  restart:
+    run: exit 1
+  rollout:
+    run: "true"
+    canary: true
+"#);
+    let ssh = fixture.fake_ssh();
+
+    assert_eq!(fixture.run(&ssh, &["dev", "ok"]), 0);
+    assert_eq!(fixture.run(&ssh, &["--help"]), 0);
+
+    // Supfile problems
+    assert_eq!(fixture.run(&ssh, &["dev", "deploy"]), 1);
+    assert_eq!(fixture.run(&ssh, &["staging", "ok"]), 1);
+    assert_eq!(fixture.run(&ssh, &["-f", "missing.yml", "dev", "ok"]), 1);
+
+    assert_eq!(fixture.run(&ssh, &["--no-such-flag"]), 2);
+
+    // Failures on hosts, this machine included
+    assert_eq!(fixture.run(&ssh, &["dev", "restart"]), 3);
+    assert_eq!(fixture.run(&ssh, &["dev", "build"]), 3);
+
+    // Without a terminal the canary prompt is answered no
+    assert_eq!(fixture.run(&ssh, &["dev", "rollout"]), 4);
+    assert_eq!(fixture.run(&ssh, &["--yes", "dev", "rollout"]), 0);
+
+    assert_eq!(fixture.run("false", &["--check-hosts", "dev", "restart"]), 5);
+    assert_eq!(fixture.run("false", &["--ping", "dev"]), 5);
+}
+
+#[test]
+fn test_run_by_alias() {
+    let fixture = Fixture::new("command-aliases", r#"
+version: "0.4"
+networks:
+  prod:
+    hosts: [app@web1]
+commands:
+  deploy-app:
+    desc: Roll out the app
+    run: echo deploying
+    aliases: [rolling-update]
+  restart:
+    run: echo restarting
+targets:
+  release: [rolling-update, restart]
+"#);
+    let ssh = fixture.fake_ssh();
+
+    let stdout = fixture.stdout(&ssh, &["prod", "rolling-update"]);
+    assert!(stdout.contains("app@web1 deploying"), "{}", stdout);
+    // Reported under the name it is defined by
+    assert!(stdout.contains("==> deploy-app (Roll out the app) on 1 host"), "{}", stdout);
+
+    let stdout = fixture.stdout(&ssh, &["prod", "release"]);
+    assert!(stdout.contains("app@web1 deploying"), "{}", stdout);
+    assert!(stdout.contains("app@web1 restarting"), "{}", stdout);
+
+    let stdout = fixture.stdout(&ssh, &["--list"]);
+    assert!(stdout.contains("deploy-app (rolling-update) Roll out the app"), "{}", stdout);
+}
+
+const HOSTS: &str = r#"
+version: "0.4"
+networks:
+  dev:
+    hosts:
+      - app@web1
+      - app@web2
+      - {host: web3, user: app, disabled: true}
+    inventory: echo app@web4; echo app@db1
+    excluded_hosts: ['db1$']
+  empty: {}
+commands:
+  index:
+    run: echo "index $SUP_HOST_INDEX/$SUP_HOST_COUNT"
+  hosts:
+    local: echo "local $SUP_HOSTS $SUP_HOST_COUNT"
+  leader:
+    run: '[ "$SUP_HOST_INDEX" = 0 ] && echo "leading $SUP_HOSTS" || true'
+  optional:
+    run: echo optional
+    allow_empty_hosts: true
+targets:
+  release: [hosts, leader]
+"#;
+
+#[test]
+fn test_host_selection() {
+    let fixture = Fixture::new("host-selection", HOSTS);
+    let ssh = fixture.fake_ssh();
+
+    // The index follows the filtered list, without the excluded hosts
+    let stdout = fixture.stdout(&ssh, &["--report", "report.json", "--except", "web2", "dev", "index"]);
+    for (host, index) in [("web1", "0/2"), ("web4", "1/2")] {
+        assert!(stdout.contains(&format!("app@{} index {}", host, index)), "{}", stdout);
+    }
+    assert!(!stdout.contains("app@db1 index"), "{}", stdout);
+    assert!(stdout.contains("2 succeeded, 0 failed, 0 skipped"), "{}", stdout);
+    assert!(stdout.contains("EXCLUDED app@web3, app@db1: excluded (supfile)"), "{}", stdout);
+    let report = fixture.json("report.json");
+    assert_eq!(report["excluded"], serde_json::json!(["app@web3", "app@db1"]));
+
+    // Local and remote steps of a target agree on the hosts
+    let stdout = fixture.stdout(&ssh, &["--limit", "2", "dev", "release"]);
+    assert!(stdout.contains("local app@web1,app@web2 2"), "{}", stdout);
+    assert!(stdout.contains("leading app@web1,app@web2"), "{}", stdout);
+    assert_eq!(stdout.matches("leading").count(), 1, "{}", stdout);
+}
+
+#[test]
+fn test_empty_hosts() {
+    let fixture = Fixture::new("empty-hosts", HOSTS);
+    let ssh = fixture.fake_ssh();
+
+    let output = fixture.command(&ssh, &["empty", "index"]).output().unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(1), "{}", stderr);
+    assert!(stderr.contains("No hosts to run index on: network empty has no hosts and no inventory"), "{}", stderr);
+
+    let stdout = fixture.stdout(&ssh, &["--allow-empty-hosts", "--only", "web9", "dev", "index"]);
+    assert!(stdout.contains("No hosts to run index on"), "{}", stdout);
+    assert!(!stdout.contains(" index 0/"), "{}", stdout);
+    assert_eq!(fixture.run(&ssh, &["empty", "optional"]), 0);
+}
+
+#[test]
+fn test_guards_on_unreachable_host() {
+    let fixture = Fixture::new("guards-unreachable", r#"
+version: "0.4"
+networks:
+  prod:
+    hosts: [app@web1, app@web2]
+commands:
+  cleanup:
+    run: rm /tmp/installer
+    removes: /tmp/installer
+  restart:
+    run: systemctl restart app
+    when: systemctl is-enabled app
+"#);
+    // ssh exits 255 when it cannot reach web2
+    std::fs::write(fixture.path("fixtures.yml"), "- host: web2\n  command: test -e|is-enabled\n  exit_code: 255\n  stderr: Connection refused\n").unwrap();
+
+    let args = ["--ssh-backend", "mock", "--mock-fixtures", "fixtures.yml", "prod", "cleanup"];
+    let output = fixture.command("false", &args).output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(3), "{}", stdout);
+    assert!(!stdout.contains("SKIPPED"), "{}", stdout);
+    assert!(stdout.contains("1 succeeded, 1 failed, 0 skipped"), "{}", stdout);
+    assert!(stdout.contains("FAILED  app@web2 (cleanup after "), "{}", stdout);
+    assert!(stdout.contains("Failed to reach app@web2: Connection refused"), "{}", stdout);
+
+    let args = ["--ssh-backend", "mock", "--mock-fixtures", "fixtures.yml", "prod", "restart"];
+    let output = fixture.command("false", &args).output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(3), "{}", stdout);
+    assert!(!stdout.contains("when failed"), "{}", stdout);
+    assert!(stdout.contains("FAILED  app@web2 (restart after "), "{}", stdout);
+}
+
+#[test]
+fn test_when_sees_the_run_env() {
+    let fixture = Fixture::new("when-env", r#"
+version: "0.4"
+networks:
+  prod:
+    hosts: [app@web1, app@web2, app@web3]
+commands:
+  leader:
+    run: echo "leading $SUP_HOSTS"
+    when: '[ "$SUP_HOST_INDEX" = 1 ] && [ "$SUP_ARG_1" = now ]'
+"#);
+    let stdout = fixture.stdout(&fixture.fake_ssh(), &["prod", "leader", "--", "now"]);
+    assert!(stdout.contains("app@web2 leading app@web1,app@web2,app@web3"), "{}", stdout);
+    assert_eq!(stdout.matches("leading").count(), 1, "{}", stdout);
+    assert_eq!(stdout.matches("SKIPPED (when failed)").count(), 2, "{}", stdout);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_sudo() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let fixture = Fixture::new("sudo", r#"
+version: "0.4"
+networks:
+  dev:
+    hosts: [app@web1]
+commands:
+  as-user:
+    run: sudo -u deploy ./migrate.sh
+  pipeline:
+    run: cd /tmp && echo config | sudo tee /etc/app.conf
+  explicit:
+    run: cd /opt/app && systemctl restart $SERVICE
+    sudo: true
+"#);
+    // Stands in for sudo on the hosts and prints what it was asked to run
+    std::fs::create_dir_all(fixture.path("bin")).unwrap();
+    std::fs::write(fixture.path("bin/sudo"), "#!/bin/sh\necho \"sudo $*\"\n").unwrap();
+    std::fs::set_permissions(fixture.path("bin/sudo"), std::fs::Permissions::from_mode(0o755)).unwrap();
+    let path = format!("{}:{}", fixture.path("bin").display(), std::env::var("PATH").unwrap());
+    let run = |args: &[&str]| {
+        let output = fixture.command(&fixture.fake_ssh(), args).env("PATH", &path).output().unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+        assert!(output.status.success(), "{}{}", stdout, String::from_utf8_lossy(&output.stderr));
+        stdout
+    };
+
+    // The user's own sudo keeps its options and its place in the command
+    let stdout = run(&["dev", "as-user"]);
+    assert!(stdout.contains("sudo -u deploy ./migrate.sh"), "{}", stdout);
+    let stdout = run(&["dev", "pipeline"]);
+    assert!(stdout.contains("sudo tee /etc/app.conf"), "{}", stdout);
+    assert!(!stdout.contains("sudo -E"), "{}", stdout);
+
+    // The field wraps everything, leaving expansion to the root shell
+    let stdout = run(&["dev", "explicit"]);
+    assert!(stdout.contains("sudo -E bash -c cd /opt/app && systemctl restart $SERVICE"), "{}", stdout);
+    let stdout = run(&["dev", "--run", "uptime", "--sudo"]);
+    assert!(stdout.contains("sudo -E bash -c uptime"), "{}", stdout);
+}
+
+#[test]
+fn test_stdin_feeds() {
+    let fixture = Fixture::new("stdin-feeds", r#"
+version: "0.4"
+env:
+  SQL_DIR: sql
+networks:
+  dev:
+    hosts: [app@web1, app@web2]
+commands:
+  inline:
+    run: cat
+    stdin_data: |
+      select 1;
+  file:
+    run: wc -c
+    stdin_file: $SQL_DIR/seed.sql
+"#);
+    let ssh = fixture.fake_ssh();
+    std::fs::create_dir_all(fixture.path("sql")).unwrap();
+    let payload = "insert into t values (1);\n".repeat(10_000);
+    std::fs::write(fixture.path("sql/seed.sql"), &payload).unwrap();
+
+    let stdout = fixture.stdout(&ssh, &["--disable-prefix", "dev", "inline"]);
+    assert_eq!(stdout.lines().filter(|line| *line == "select 1;").count(), 2, "{}", stdout);
+
+    // Every host gets the whole file
+    let stdout = fixture.stdout(&ssh, &["--disable-prefix", "dev", "file"]);
+    let counts = stdout.lines().filter(|line| line.trim() == payload.len().to_string()).count();
+    assert_eq!(counts, 2, "{}", stdout);
+}
+
+#[test]
+fn test_env_inherit() {
+    let supfile = r#"
+version: "0.4"
+inherit_env_only: [PATH]
+networks:
+  dev:
+    hosts: [app@web1]
+commands:
+  show:
+    local: echo "key=${AWS_SECRET_ACCESS_KEY:-unset} home=${HOME:-unset} path=${PATH:+set}"
+"#;
+    let fixture = Fixture::new("env-inherit", supfile);
+    let show = |args: &[&str]| {
+        let output = fixture.command(&fixture.fake_ssh(), args)
+            .env("AWS_SECRET_ACCESS_KEY", "poison")
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    // Inherited by default, for compatibility
+    assert!(show(&["dev", "show"]).contains("key=poison home=/"));
+    let stdout = show(&["--no-env-inherit", "dev", "show"]);
+    assert!(stdout.contains("key=unset home=unset path=set"), "{}", stdout);
+
+    // The Supfile can turn it off too
+    std::fs::write(fixture.path("Supfile.yml"), format!("inherit_env: false\n{}", supfile)).unwrap();
+    let stdout = show(&["dev", "show"]);
+    assert!(stdout.contains("key=unset home=unset path=set"), "{}", stdout);
+}
+
+#[test]
+fn test_secrets_reach_remote_commands() {
+    let fixture = Fixture::new("remote-secrets", r#"
+version: "0.4"
+env:
+  TIER: staging
+secrets: [DOCKER_PASS]
+networks:
+  dev:
+    hosts: [app@web1]
+    env:
+      REGION: eu
+commands:
+  login:
+    run: docker login -p "$DOCKER_PASS"
+"#);
+    // Answers only if the host is given the real value and the declared env
+    std::fs::write(fixture.path("fixtures.yml"), r#"
+- command: "export DOCKER_PASS='s3cr3t-value'; export REGION='eu'; export TIER='staging'; .*docker login"
+  stdout: "Login Succeeded for s3cr3t-value\n"
+"#).unwrap();
+
+    let args = ["--ssh-backend", "mock", "--mock-fixtures", "fixtures.yml", "--transcript", "run.yml", "dev", "login"];
+    let output = fixture.command("false", &args)
+        .env("DOCKER_PASS", "s3cr3t-value")
+        .env("AWS_SECRET_ACCESS_KEY", "poison")
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}{}", stdout, String::from_utf8_lossy(&output.stderr));
+    assert!(stdout.contains("app@web1 Login Succeeded for *****\n"), "{}", stdout);
+    assert!(!stdout.contains("s3cr3t-value"), "{}", stdout);
+
+    // The transcript masks it too, and the inherited env stays local
+    let transcript = std::fs::read_to_string(fixture.path("run.yml")).unwrap();
+    assert!(transcript.contains("export DOCKER_PASS='[redacted]';"), "{}", transcript);
+    assert!(!transcript.contains("s3cr3t-value"), "{}", transcript);
+    assert!(!transcript.contains("AWS_SECRET_ACCESS_KEY"), "{}", transcript);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_inventory_finds_tools_on_path() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let fixture = Fixture::new("inventory-env", r#"
+version: "0.4"
+networks:
+  cloud:
+    inventory: list-hosts
+commands:
+  uptime:
+    run: echo up
+"#);
+    // A tool that exists only in a directory added to PATH
+    let bin = fixture.path("bin");
+    std::fs::create_dir_all(&bin).unwrap();
+    let tool = bin.join("list-hosts");
+    std::fs::write(&tool, "#!/bin/sh\nprintf 'app@web1\\napp@web2\\n'\n").unwrap();
+    std::fs::set_permissions(&tool, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let path = format!("{}:{}", bin.display(), std::env::var("PATH").unwrap());
+    let output = fixture.command(&fixture.fake_ssh(), &["cloud", "uptime"])
+        .env("PATH", path)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(stdout.contains("on 2 hosts"), "{}", stdout);
+    assert!(stdout.contains("app@web2"), "{}", stdout);
+}
+
+const DETACH: &str = r#"
+version: "0.4"
+networks:
+  dev:
+    hosts: [app@web1, app@web2]
+commands:
+  migrate:
+    run: echo migrating; sleep 30
+    detach: true
+    detach_log: logs/migrate.log
+  crash:
+    run: echo oops; exit 3
+    detach: true
+"#;
+
+#[test]
+fn test_detach_returns_promptly() {
+    let fixture = Fixture::new("detach", DETACH);
+
+    let started = Instant::now();
+    let stdout = fixture.stdout(&fixture.fake_ssh(), &["-f", "Supfile.yml", "dev", "migrate", "--only", "web1"]);
+    assert!(started.elapsed() < Duration::from_secs(10), "took {:?}", started.elapsed());
+    assert!(stdout.contains("detached as PID "), "{}", stdout);
+    // The hint reaches the same Supfile and hosts
+    assert!(stdout.contains("sup-rs -f Supfile.yml dev --only web1 --run 'tail -f logs/migrate.log'"), "{}", stdout);
+
+    let pid = stdout.split("detached as PID ").nth(1).unwrap()
+        .split(',').next().unwrap();
+    let log = std::fs::read_to_string(fixture.path("logs/migrate.log")).unwrap();
+    assert_eq!(log, "migrating\n");
+    let _ = std::process::Command::new("kill").arg(pid).status();
+}
+
+#[test]
+fn test_detach_reports_early_exit() {
+    let fixture = Fixture::new("detach-crash", DETACH);
+
+    let output = fixture.command(&fixture.fake_ssh(), &["dev", "crash"]).output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(3));
+    assert!(stdout.contains("exited with status 3 before detaching"), "{}", stdout);
+    assert!(!stdout.contains("follow the output"), "{}", stdout);
+}
+
+#[test]
+fn test_max_runtime() {
+    let fixture = Fixture::new("max-runtime", r#"
+version: "0.4"
+networks:
+  dev:
+    hosts: [app@web1, app@web2]
+commands:
+  slow:
+    run: sleep 30
+    serial: 1
+  after:
+    run: echo after
+targets:
+  release: [slow, after]
+"#);
+
+    let started = Instant::now();
+    let output = fixture.command(&fixture.fake_ssh(), &["--max-runtime", "1s", "dev", "release"]).output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(6), "{}{}", stdout, stderr);
+    assert!(started.elapsed() < Duration::from_secs(10), "took {:?}", started.elapsed());
+    assert!(stderr.contains("Run exceeded --max-runtime of 1.0s"), "{}", stderr);
+
+    // The host in flight, the batch not started and the next command
+    assert_eq!(stdout.matches("deadline exceeded").count(), 4, "{}", stdout);
+    assert!(!stdout.contains("after\n"), "{}", stdout);
+}
+
+const PARALLEL: &str = r#"
+version: "0.4"
+networks:
+  dev:
+    hosts: [app@web1]
+commands:
+  build:
+    local: echo build >> order.log
+  push:
+    local: sleep 1; echo push >> order.log
+  upload-config:
+    local: echo upload-config >> order.log
+  broken:
+    local: echo broken >> order.log; exit 1
+  rollout:
+    local: echo rollout >> order.log
+  hushed:
+    run: sleep 1; echo hushed
+    quiet: true
+  loud:
+    run: echo loud
+targets:
+  deploy: [build, [push, upload-config], rollout]
+  failing: [build, [push, broken], rollout]
+  mixed: [[hushed, loud]]
+"#;
+
+fn order(fixture: &Fixture) -> Vec<String> {
+    std::fs::read_to_string(fixture.path("order.log")).unwrap().lines().map(str::to_string).collect()
+}
+
+#[test]
+fn test_parallel_groups() {
+    let fixture = Fixture::new("parallel-groups", PARALLEL);
+
+    fixture.stdout(&fixture.fake_ssh(), &["dev", "deploy"]);
+    // The group starts after build, its members overlap (push is listed
+    // first but finishes last), and rollout waits for both
+    assert_eq!(order(&fixture), ["build", "upload-config", "push", "rollout"]);
+
+    // The failing member does not cut the others short, and nothing after
+    // the group runs
+    std::fs::remove_file(fixture.path("order.log")).unwrap();
+    assert_ne!(fixture.run(&fixture.fake_ssh(), &["dev", "failing"]), 0);
+    assert_eq!(order(&fixture), ["build", "broken", "push"]);
+}
+
+#[test]
+fn test_parallel_group_quiet_member() {
+    let fixture = Fixture::new("parallel-groups-quiet", PARALLEL);
+
+    let stdout = fixture.stdout(&fixture.fake_ssh(), &["--color", "never", "dev", "mixed"]);
+    // Each member keeps its own quiet setting while they run side by side
+    assert!(stdout.contains("==> loud"), "{}", stdout);
+    assert!(!stdout.contains("==> hushed"), "{}", stdout);
+    assert!(!stdout.contains("hushed\n"), "{}", stdout);
+    assert_eq!(stdout.matches("Connecting to app@web1").count(), 1, "{}", stdout);
+}
+
+const NETWORKS: &str = r#"
+version: "0.4"
+networks:
+  us:
+    hosts: [app@us1, app@us2]
+    env: {REGION: us}
+  eu:
+    inventory: printf 'eu1\nus2\n'
+    user: app
+    env: {REGION: eu}
+  all:
+    include: [us, eu]
+    hosts: [app@bastion]
+commands:
+  status:
+    run: echo up
+  wipe-cache:
+    run: rm -rf /var/cache/app
+    forbidden_networks: [eu]
+"#;
+
+#[test]
+fn test_network_union() {
+    let fixture = Fixture::new("network-union", NETWORKS);
+    // eu1 is down
+    let ssh = fixture.ssh_script("eu1-down", r#"for arg; do host=$prev; prev=$arg; done
+[ "$host" = app@eu1 ] && exit 7
+exec sh -c "$prev"
+"#);
+
+    let output = fixture.command(&ssh, &["--color", "never", "--report", "report.json", "us,eu", "status"]).output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(3), "{}", String::from_utf8_lossy(&output.stderr));
+    // us2 is in both networks but runs once
+    assert!(stdout.contains("==> status on 3 hosts [us,eu]"), "{}", stdout);
+    assert!(stdout.contains("FAILED  app@eu1 [eu]"), "{}", stdout);
+    assert!(stdout.contains("Env REGION differs between the combined networks; using eu's value"), "{}", stdout);
+    let report = fixture.json("report.json");
+    assert_eq!(report["network"], "us,eu");
+    assert_eq!(report["host_networks"], serde_json::json!({"app@us1": "us", "app@us2": "us", "app@eu1": "eu"}));
+
+    // The flag form means the same
+    let output = fixture.command(&ssh, &["--network", "us", "--network", "eu", "status"]).output().unwrap();
+    assert!(String::from_utf8_lossy(&output.stdout).contains("on 3 hosts [us,eu]"));
+}
+
+#[test]
+fn test_network_include() {
+    let fixture = Fixture::new("network-include", NETWORKS);
+    let ssh = fixture.fake_ssh();
+
+    let stdout = fixture.stdout(&ssh, &["--report", "report.json", "all", "status"]);
+    assert!(stdout.contains("status on 4 hosts [all]"), "{}", stdout);
+    let report = fixture.json("report.json");
+    assert_eq!(report["host_networks"]["app@eu1"], "eu");
+    assert_eq!(report["host_networks"]["app@bastion"], "all");
+
+    assert!(fixture.stdout(&ssh, &["--list"]).contains("1 hosts + us + eu"));
+}
+
+#[test]
+fn test_forbidden_member_network() {
+    let fixture = Fixture::new("network-forbidden-member", NETWORKS);
+    let ssh = fixture.fake_ssh();
+
+    // A forbidden network is refused whether it is named directly, in a
+    // union, or through a network that includes it
+    for network in ["eu", "us,eu", "all"] {
+        let output = fixture.command(&ssh, &[network, "wipe-cache"]).output().unwrap();
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert_eq!(output.status.code(), Some(1), "{}: {}", network, stderr);
+        assert!(stderr.contains("Command wipe-cache must never run against network eu"), "{}: {}", network, stderr);
+        assert!(!String::from_utf8_lossy(&output.stdout).contains("==> wipe-cache"));
+    }
+    fixture.stdout(&ssh, &["us", "wipe-cache"]);
+}
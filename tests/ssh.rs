@@ -0,0 +1,221 @@
+//! How sup reaches hosts: host key checks, `--ask-pass` and the mock
+//! backend.
+
+mod common;
+
+use common::Fixture;
+use serde_json::Value;
+
+const SUPFILE: &str = r#"
+version: "0.4"
+networks:
+  dev:
+    hosts: [app@web1, app@web2:2222]
+  prod:
+    hosts: [app@web1, app@web2, app@web3]
+  broken:
+    inventory: exit 1
+commands:
+  hello:
+    run: echo hello
+  restart:
+    run: systemctl restart app
+    serial: 1
+  migrate:
+    run: ./migrate.sh
+    once: true
+"#;
+
+/// Fake ssh that shows `SHA256:$KEY-host` as the host key when verbose,
+/// logging its arguments to `$SSH_LOG` if set.
+const VERBOSE_SSH: &str = r#"[ -n "$SSH_LOG" ] && echo "$*" >> "$SSH_LOG"
+for arg; do
+  case $arg in -v) verbose=1 ;; esac
+  host=$prev; prev=$arg
+done
+[ -n "$verbose" ] && echo "debug1: Server host key: ssh-ed25519 SHA256:${KEY:-key}-${host#*@}" >&2
+exec sh -c "$prev"
+"#;
+
+fn fixture(name: &str, pins: &str) -> (Fixture, String) {
+    let fixture = Fixture::new(name, &format!("{}{}", SUPFILE, pins));
+    let ssh = fixture.ssh_script("verbose-ssh", VERBOSE_SSH);
+    (fixture, ssh)
+}
+
+#[test]
+fn test_report_records_host_keys() {
+    let (fixture, ssh) = fixture("host-keys-report", "");
+
+    fixture.stdout(&ssh, &["--report", "report.json", "dev", "hello"]);
+    let report = fixture.json("report.json");
+    assert_eq!(report["host_keys"]["web1"], "SHA256:key-web1");
+    // The port tells hosts on one address apart; -p is not the host
+    assert_eq!(report["host_keys"]["web2:2222"], "SHA256:key-web2");
+}
+
+#[test]
+fn test_pinned_host_keys() {
+    let pins = "host_keys:\n  web1: SHA256:key-web1\n  \"web2:2222\": SHA256:key-web2\n";
+    let (fixture, ssh) = fixture("host-keys-pinned", pins);
+
+    let log = fixture.path("ssh.log");
+    let output = fixture.command(&ssh, &["dev", "hello"]).env("SSH_LOG", &log).output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("hello"));
+
+    // The probes record the keys that match the pins, and the command's
+    // connections accept only those
+    let log = std::fs::read_to_string(&log).unwrap();
+    let (probes, sessions): (Vec<_>, Vec<_>) = log.lines().partition(|line| line.contains(" -v "));
+    assert_eq!(probes.len(), 2, "{}", log);
+    assert!(probes.iter().all(|line| line.contains("StrictHostKeyChecking=accept-new")), "{}", log);
+    assert!(!sessions.is_empty(), "{}", log);
+    assert!(sessions.iter().all(|line| line.contains("GlobalKnownHostsFile=/dev/null -o StrictHostKeyChecking=yes")), "{}", log);
+    let known_hosts = log.split_whitespace()
+        .find_map(|arg| arg.strip_prefix("UserKnownHostsFile="))
+        .unwrap();
+    assert!(!std::path::Path::new(known_hosts).exists(), "{} was left behind", known_hosts);
+
+    let output = fixture.command(&ssh, &["dev", "hello"]).env("KEY", "new").output().unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr.contains("2 hosts failed host key verification"), "{}", stderr);
+    assert!(stderr.contains("Host key of web1 has CHANGED: pinned SHA256:key-web1, presented SHA256:new-web1"), "{}", stderr);
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("hello"));
+
+    let output = fixture.command(&ssh, &["--host", "app@web3", "dev", "hello"]).output().unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr.contains("Host key of web3 is unknown: SHA256:key-web3 is not pinned"), "{}", stderr);
+
+    // The mock backend connects to nothing, so there are no keys to check
+    fixture.stdout("false", &["--ssh-backend", "mock", "--transcript", "run.yml", "prod", "migrate"]);
+}
+
+#[test]
+fn test_learn_host_keys() {
+    let (fixture, ssh) = fixture("host-keys-learn", "");
+
+    let stdout = fixture.stdout(&ssh, &["--learn-host-keys", "dev"]);
+    assert!(stdout.contains("web1 SHA256:key-web1"), "{}", stdout);
+    let learned = std::fs::read_to_string(fixture.path("host_keys.yml")).unwrap();
+    let learned: serde_yaml::Value = serde_yaml::from_str(&learned).unwrap();
+    assert_eq!(learned["host_keys"]["web1"], "SHA256:key-web1");
+
+    // Keys that cannot be read fail the run, the rest are still written
+    assert_eq!(fixture.run("false", &["--learn-host-keys", "--host", "app@web9", "dev"]), 5);
+    let learned = std::fs::read_to_string(fixture.path("host_keys.yml")).unwrap();
+    assert!(learned.contains("web1"), "{}", learned);
+}
+
+#[test]
+fn test_control_dir_is_removed_on_errors() {
+    let fixture = Fixture::new("control-dir", SUPFILE);
+    let tmp = fixture.path("tmp");
+    std::fs::create_dir_all(&tmp).unwrap();
+    let leftovers = || std::fs::read_dir(&tmp).unwrap().map(|entry| entry.unwrap().file_name()).collect::<Vec<_>>();
+
+    // The report resolves the hosts before anything runs, and the inventory fails
+    let output = fixture.command(&fixture.fake_ssh(), &["--report", "report.json", "broken", "hello"])
+        .env("TMPDIR", &tmp)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(leftovers().is_empty(), "{:?}", leftovers());
+
+    let output = fixture.command(&fixture.fake_ssh(), &["dev", "hello"]).env("TMPDIR", &tmp).output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(leftovers().is_empty(), "{:?}", leftovers());
+}
+
+#[test]
+fn test_ask_pass_needs_a_terminal() {
+    let fixture = Fixture::new("ask-pass", SUPFILE);
+
+    let output = fixture.command(&fixture.fake_ssh(), &["--ask-pass", "dev", "hello"]).output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(2), "{}{}", stdout, stderr);
+    assert!(stderr.contains("--ask-pass needs a terminal"), "{}", stderr);
+    assert!(!stdout.contains("app@web1"), "{}", stdout);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_askpass_program_asks_the_run() {
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixListener;
+
+    let fixture = Fixture::new("ask-pass-program", SUPFILE);
+    let socket = fixture.path("socket");
+    let listener = UnixListener::bind(&socket).unwrap();
+    // Stands in for the run: answers password prompts only
+    let server = std::thread::spawn(move || {
+        let mut prompts = Vec::new();
+        for stream in listener.incoming().take(2) {
+            let mut stream = stream.unwrap();
+            let mut prompt = String::new();
+            stream.read_to_string(&mut prompt).unwrap();
+            if prompt.contains("password") {
+                stream.write_all(b"+s3cret").unwrap();
+            }
+            prompts.push(prompt);
+        }
+        prompts
+    });
+
+    let ask = |prompt: &str| {
+        let mut command = fixture.command("false", &[prompt]);
+        command.env("SUP_ASKPASS_SOCKET", &socket);
+        command.output().unwrap()
+    };
+    let output = ask("admin@appliance1's password: ");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "s3cret");
+    let output = ask("Enter passphrase for key '/root/.ssh/id_rsa': ");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+    assert_eq!(server.join().unwrap(), ["admin@appliance1's password: ", "Enter passphrase for key '/root/.ssh/id_rsa': "]);
+}
+
+#[test]
+fn test_mock_backend() {
+    let fixture = Fixture::new("mock-backend", SUPFILE);
+    std::fs::write(fixture.path("fixtures.yml"), r#"
+- host: web2
+  command: restart
+  exit_code: 1
+  stderr: "Job for app.service failed\n"
+"#).unwrap();
+    // No ssh may run
+    let ssh = "false";
+    let transcript = || serde_yaml::from_str::<Value>(&std::fs::read_to_string(fixture.path("run.yml")).unwrap()).unwrap();
+
+    fixture.stdout(ssh, &["--ssh-backend", "mock", "--transcript", "run.yml", "prod", "migrate"]);
+    let run = transcript();
+    assert_eq!(run["version"], 1);
+    let hosts = run["hosts"].as_object().unwrap();
+    assert_eq!(hosts.len(), 1, "{:?}", hosts);
+    let call = &run["hosts"]["app@web1"][0];
+    assert_eq!(call["kind"], "session");
+    assert!(call["command"].as_str().unwrap().ends_with("./migrate.sh"), "{:?}", call);
+    assert_eq!(call["exit_code"], 0);
+
+    // A failing host stops the serial run before the next batch
+    let args = ["--ssh-backend", "mock", "--mock-fixtures", "fixtures.yml", "--transcript", "run.yml", "prod", "restart"];
+    let output = fixture.command(ssh, &args).output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(3), "{}", stdout);
+    assert!(stdout.contains("Job for app.service failed"), "{}", stdout);
+    let run = transcript();
+    assert_eq!(run["hosts"]["app@web1"][0]["exit_code"], 0);
+    assert_eq!(run["hosts"]["app@web2"][0]["exit_code"], 1);
+    assert!(run["hosts"].get("app@web3").is_none(), "{:?}", run);
+
+    // Without --transcript it follows the summary on stdout
+    let stdout = fixture.stdout(ssh, &["--ssh-backend", "mock", "prod", "migrate"]);
+    assert!(stdout.contains("version: 1\nhosts:\n  app@web1:\n  - kind: session\n"), "{}", stdout);
+
+    assert_eq!(fixture.run(ssh, &["--transcript", "run.yml", "prod", "migrate"]), 2);
+}
@@ -0,0 +1,107 @@
+//! Uploads end to end: what lands on each host, retries, and what a failed
+//! upload reports.
+
+mod common;
+
+use common::{Fixture, HOME_SSH};
+
+const SUPFILE: &str = r#"
+version: "0.4"
+env:
+  TIER: staging
+networks:
+  dev:
+    hosts: [app@web1, app@web2, app@web3]
+commands:
+  config:
+    upload:
+      - from_command: 'echo "tier: $TIER"; echo rendered >&2; echo run >> runs.log'
+        dst: etc/app/config.yml
+        mode: "0600"
+  empty:
+    upload:
+      - from_command: "true"
+        dst: etc/app/empty
+  allowed:
+    upload:
+      - from_command: "true"
+        dst: etc/app/empty
+        allow_empty: true
+  ship:
+    upload:
+      - src: ./dist
+        dst: releases/
+        retries: 1
+  ship-once:
+    upload:
+      - src: ./dist
+        dst: releases/
+"#;
+
+const HOSTS: [&str; 3] = ["app@web1", "app@web2", "app@web3"];
+
+fn fixture(name: &str) -> (Fixture, String) {
+    let fixture = Fixture::new(name, SUPFILE);
+    std::fs::create_dir_all(fixture.path("dist")).unwrap();
+    std::fs::write(fixture.path("dist/app.txt"), "v2\n").unwrap();
+    let ssh = fixture.ssh_script("home-ssh", HOME_SSH);
+    (fixture, ssh)
+}
+
+#[test]
+fn test_upload_from_command() {
+    let (fixture, ssh) = fixture("upload-from-command");
+
+    let stdout = fixture.stdout(&ssh, &["dev", "config"]);
+    assert!(stdout.contains("rendered"), "{}", stdout);
+    for host in HOSTS {
+        let written = fixture.path("hosts").join(host).join("etc/app/config.yml");
+        assert_eq!(std::fs::read_to_string(written).unwrap(), "tier: staging\n");
+    }
+    // The command runs once, not once per host
+    assert_eq!(std::fs::read_to_string(fixture.path("runs.log")).unwrap(), "run\n");
+
+    std::fs::remove_dir_all(fixture.path("hosts")).unwrap();
+    let output = fixture.command(&ssh, &["dev", "empty"]).output().unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("printed nothing; set allow_empty: true"), "{}", stderr);
+    assert!(!fixture.path("hosts").exists());
+
+    fixture.stdout(&ssh, &["dev", "allowed"]);
+    assert_eq!(std::fs::read(fixture.path("hosts/app@web2/etc/app/empty")).unwrap(), b"");
+}
+
+#[test]
+fn test_retried_upload_completes() {
+    let (fixture, ssh) = fixture("upload-retry");
+
+    let output = fixture.command(&ssh, &["dev", "ship"])
+        .env("SUP_UPLOAD_FAIL_ONCE", "app@web2")
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}{}", stdout, String::from_utf8_lossy(&output.stderr));
+    assert!(stdout.contains("failed (attempt 1 of 2), retrying"), "{}", stdout);
+    for host in HOSTS {
+        let uploaded = fixture.path("hosts").join(host).join("releases/dist/app.txt");
+        assert_eq!(std::fs::read_to_string(uploaded).unwrap(), "v2\n");
+    }
+}
+
+#[test]
+fn test_failed_upload_reports_fleet_state() {
+    let (fixture, ssh) = fixture("upload-no-retry");
+
+    let output = fixture.command(&ssh, &["dev", "ship-once"])
+        .env("SUP_UPLOAD_FAIL_ONCE", "app@web2")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(3));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Upload to app@web2 failed; completed on app@web1; not run on app@web3"), "{}", stderr);
+    assert!(fixture.path("hosts/app@web1/releases/dist/app.txt").exists());
+    assert!(!fixture.path("hosts/app@web3").exists());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("1 succeeded, 1 failed, 1 skipped"), "{}", stdout);
+}